@@ -1,8 +1,22 @@
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use std::{
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{ArgGroup, Parser, Subcommand};
 use futures_util::StreamExt;
+use tokio_util::sync::CancellationToken;
 
-use dreamquill_core_sdk::{db, llm, server, telemetry};
+use dreamquill_core_sdk::{
+    archive, base_url,
+    commands::{self, ChatCommand},
+    context, crypto, db, debug_bundle, doctor, exporter, finalize, importer, language, llm, models,
+    paths, pipeline, provider_sync, refusal, server, sql_console,
+    telemetry::{self, Instrument},
+    transcript,
+};
 
 /**
  * \brief CLI 程序入口，适配 M1 最小可聊场景。
@@ -46,6 +60,60 @@ enum Commands {
         chat_id: Option<i64>,
         #[arg(long)]
         prompt: String,
+        /** \brief 在回复下方显示耗时、token 估计与生成速度。 */
+        #[arg(long, default_value_t = false)]
+        stats: bool,
+        /** \brief 本次请求覆盖的模型名。 */
+        #[arg(long)]
+        model: Option<String>,
+        /** \brief 本次请求覆盖的温度。 */
+        #[arg(long)]
+        temperature: Option<f64>,
+        /** \brief 本次请求覆盖的输出 token 上限。 */
+        #[arg(long)]
+        max_tokens: Option<u32>,
+        /** \brief 本次请求覆盖的系统指令。 */
+        #[arg(long)]
+        system: Option<String>,
+        /** \brief 额外的停止串（可重复），与已保存的停止串合并。 */
+        #[arg(long = "stop")]
+        stop: Vec<String>,
+        /** \brief 引用同一会话中的早先消息（可重复），作为引用块随提示发送。 */
+        #[arg(long = "quote", value_name = "MESSAGE_ID", requires = "chat_id")]
+        quotes: Vec<i64>,
+        /** \brief 将每个原始流式增量连同时间戳与流 ID 追加写入该文件（JSON Lines）。 */
+        #[arg(long, value_name = "FILE")]
+        tee: Option<PathBuf>,
+        /** \brief 保存会话的回复语言（如 zh、en），回复语言不符时自动以更强的指令重问一次。 */
+        #[arg(long, value_name = "LANG")]
+        reply_language: Option<String>,
+        /** \brief 提示原文只发送给模型，会话中保存为占位文本 `[redacted prompt]`。 */
+        #[arg(long, default_value_t = false)]
+        redact_prompt: bool,
+    },
+
+    /**
+     * \brief 交互式多轮对话：逐行读取输入并流式显示回复，支持 `/new`、`/switch`、`/provider`、`/exit`。
+     */
+    Repl {
+        /** \brief 继续已有会话，缺省在发送第一条消息时新建。 */
+        #[arg(long)]
+        chat_id: Option<i64>,
+        /** \brief 命名会话：名称已存在时恢复其会话，否则把首条消息创建的会话绑定到该名称。 */
+        #[arg(long, value_name = "NAME", conflicts_with = "chat_id")]
+        session: Option<String>,
+        /** \brief 使用的 Provider，缺省取会话绑定的 Provider 或默认 Provider。 */
+        #[arg(long)]
+        provider_id: Option<i64>,
+        /** \brief 在每条回复下方显示耗时、token 估计与生成速度。 */
+        #[arg(long, default_value_t = false)]
+        stats: bool,
+        /** \brief 本次会话覆盖的模型名。 */
+        #[arg(long)]
+        model: Option<String>,
+        /** \brief 本次会话覆盖的系统指令。 */
+        #[arg(long)]
+        system: Option<String>,
     },
 
     /**
@@ -54,17 +122,1082 @@ enum Commands {
     Serve {
         #[arg(long, default_value = "127.0.0.1:5173")]
         addr: String,
+        /** \brief 每个客户端每分钟写操作次数上限，0 表示不限流（覆盖 DREAMQUILL_RATE_LIMIT）。 */
+        #[arg(long)]
+        rate_limit: Option<u32>,
+        /** \brief 每个客户端每分钟聊天次数上限，0 表示不限流（覆盖 DREAMQUILL_CHAT_RATE_LIMIT）。 */
+        #[arg(long)]
+        chat_rate_limit: Option<u32>,
+        /** \brief 删除会话/Provider 需两步确认（覆盖 DREAMQUILL_CONFIRM_DELETES）。 */
+        #[arg(long, default_value_t = false)]
+        confirm_deletes: bool,
+        /** \brief 聊天 SSE 断线后的建议重连间隔（毫秒，覆盖 DREAMQUILL_SSE_RETRY_MS）。 */
+        #[arg(long)]
+        sse_retry_ms: Option<u32>,
+        /** \brief 流式增量转录文件（覆盖 DREAMQUILL_TRANSCRIPT）。 */
+        #[arg(long, value_name = "FILE")]
+        tee: Option<PathBuf>,
+        /** \brief `/api` 与 `/v1` 要求的 Bearer 令牌（覆盖 DREAMQUILL_AUTH_TOKEN 与 `auth-token set` 保存的令牌）。 */
+        #[arg(long, value_name = "TOKEN")]
+        auth_token: Option<String>,
+        /** \brief 允许跨域访问的来源，可重复指定，`*` 表示任意来源（覆盖 DREAMQUILL_CORS_ORIGINS）。 */
+        #[arg(long = "cors-origin", value_name = "ORIGIN")]
+        cors_origins: Vec<String>,
+        /** \brief 跨域请求允许的方法，可重复指定，缺省为 GET/POST/PUT/PATCH/DELETE（覆盖 DREAMQUILL_CORS_METHODS）。 */
+        #[arg(long = "cors-method", value_name = "METHOD")]
+        cors_methods: Vec<String>,
+    },
+
+    /**
+     * \brief 启动 gRPC 服务（Provider 增删改查、流式聊天、健康检查），需以 `grpc` feature 构建。
+     */
+    #[cfg(feature = "grpc")]
+    Grpc {
+        #[arg(long, default_value = "127.0.0.1:50051")]
+        addr: String,
+    },
+
+    /**
+     * \brief 从第三方导出文件导入历史会话。
+     */
+    Import {
+        #[command(subcommand)]
+        source: ImportSource,
+        /** \brief 导入会话绑定的 Provider ID，缺省不绑定。 */
+        #[arg(long, global = true)]
+        provider_id: Option<i64>,
+    },
+
+    /**
+     * \brief 导出会话：Obsidian 笔记（按年/月分目录）、OpenAI 微调 JSONL 或带校验清单的迁移归档。
+     */
+    #[command(group(ArgGroup::new("target").required(true).args(["obsidian", "finetune", "archive"])))]
+    Export {
+        /** \brief 目标笔记库目录。 */
+        #[arg(long, value_name = "DIR")]
+        obsidian: Option<PathBuf>,
+        /** \brief 微调数据输出文件（JSONL，已脱敏）。 */
+        #[arg(long, value_name = "FILE")]
+        finetune: Option<PathBuf>,
+        /** \brief 迁移归档目录：全部会话与记录 SHA-256、记录数的清单，可用 `import archive` 导入。 */
+        #[arg(long, value_name = "DIR")]
+        archive: Option<PathBuf>,
+        /** \brief 仅导出指定会话（可重复），用于 --finetune。 */
+        #[arg(long = "chat-id", requires = "finetune")]
+        chat_ids: Vec<i64>,
+        /** \brief 仅导出同时带有这些标签的会话（可重复），用于 --finetune。 */
+        #[arg(long = "tag", requires = "finetune")]
+        tags: Vec<String>,
+    },
+
+    /**
+     * \brief 导出流水线：保存“来源筛选 → 模板 → 输出”定义并按需运行，生成周报、项目日志等自定义报告。
+     */
+    Pipeline {
+        #[command(subcommand)]
+        action: PipelineAction,
+    },
+
+    /**
+     * \brief 生成问题反馈用的诊断包（zip）：版本、表结构、脱敏配置、遥测日志、最近转录与完整性检查。
+     */
+    DebugBundle {
+        /** \brief 输出文件。 */
+        #[arg(
+            long,
+            short,
+            value_name = "FILE",
+            default_value = "dreamquill-debug.zip"
+        )]
+        out: PathBuf,
+        /** \brief 流式转录文件，默认取 DREAMQUILL_TRANSCRIPT。 */
+        #[arg(long, value_name = "FILE")]
+        transcript: Option<PathBuf>,
+        /** \brief 打包最近多少条转录。 */
+        #[arg(long, default_value_t = debug_bundle::DEFAULT_TRANSCRIPTS)]
+        transcripts: usize,
+    },
+
+    /**
+     * \brief 启动诊断：数据库、安全存储、Provider 连通性、界面资源、日志目录与端口；存在失败项时以非零状态退出。
+     */
+    Doctor {
+        /** \brief 检查该监听地址是否可用（与 `serve --addr` 一致）。 */
+        #[arg(long, default_value = "127.0.0.1:5173")]
+        addr: String,
+        /** \brief 跳过 Provider 连通性检查，不发起网络请求。 */
+        #[arg(long)]
+        offline: bool,
+        /** \brief 将检查结果写入 JSON 报告。 */
+        #[arg(long, value_name = "FILE")]
+        json: Option<PathBuf>,
+    },
+
+    /**
+     * \brief 在线备份数据库，缺省写入数据目录的 `backups/`。
+     */
+    Backup {
+        /** \brief 备份文件，已存在时报错。 */
+        #[arg(value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+
+    /**
+     * \brief 用备份文件替换当前数据库；替换前会先把当前数据库备份到 `backups/`。
+     */
+    Restore {
+        file: PathBuf,
+        /** \brief 确认替换；缺省时只说明将发生什么。 */
+        #[arg(long)]
+        yes: bool,
+    },
+
+    /**
+     * \brief 会话管理：列出、查看、删除、重命名与分支。
+     */
+    Chats {
+        #[command(subcommand)]
+        action: ChatsAction,
+    },
+
+    /**
+     * \brief 命名会话（`repl --session`）管理。
+     */
+    Sessions {
+        #[command(subcommand)]
+        action: SessionsAction,
+    },
+
+    /**
+     * \brief 只读 SQL 控制台：执行单条 SELECT，需先用 `--enable` 开启；每次查询都写入审计日志。
+     */
+    #[command(group(ArgGroup::new("mode").required(true).args(["query", "enable", "disable"])))]
+    Sql {
+        /** \brief 要执行的 SELECT 语句。 */
+        query: Option<String>,
+        /** \brief 开启控制台。 */
+        #[arg(long)]
+        enable: bool,
+        /** \brief 关闭控制台。 */
+        #[arg(long)]
+        disable: bool,
+        /** \brief 返回行数上限。 */
+        #[arg(long, default_value_t = sql_console::DEFAULT_MAX_ROWS)]
+        max_rows: usize,
+        /** \brief 执行时限（毫秒）。 */
+        #[arg(long)]
+        timeout_ms: Option<u64>,
+        /** \brief 以 JSON 输出结果。 */
+        #[arg(long)]
+        json: bool,
+    },
+
+    /**
+     * \brief 管理 HTTP 服务保存的访问令牌；`serve` 在未指定 `--auth-token` 时使用。
+     */
+    AuthToken {
+        #[command(subcommand)]
+        action: AuthTokenAction,
+    },
+
+    /**
+     * \brief Provider 维护命令。
+     */
+    Provider {
+        #[command(subcommand)]
+        action: ProviderAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ProviderAction {
+    /**
+     * \brief 并发检查全部 Provider 的鉴权、默认模型与延迟；默认 Provider 不可用时以非零状态退出。
+     */
+    Audit {
+        /** \brief 将检查结果写入 JSON 报告。 */
+        #[arg(long, value_name = "FILE")]
+        json: Option<PathBuf>,
+    },
+    /**
+     * \brief 按 providers.yaml 声明同步 Provider：新增、更新，并删除文件中未列出的 Provider。
+     */
+    Sync {
+        /** \brief Provider 声明文件，密钥通过 `api_key_env` 引用环境变量。 */
+        #[arg(long, value_name = "FILE")]
+        file: PathBuf,
+        /** \brief 只打印同步计划，不修改数据库。 */
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /**
+     * \brief 查看或设置 api_base 主机名白名单/黑名单；不带参数时打印当前策略。
+     * \details 条目为主机名或 `*.domain`；指定 `--allow`/`--deny` 时替换对应列表，运行中的 `serve` 需重启后生效。
+     */
+    Hosts {
+        /** \brief 允许的主机，可重复；设置后只放行这些主机。 */
+        #[arg(long, value_name = "HOST")]
+        allow: Vec<String>,
+        /** \brief 拒绝的主机，可重复，优先于白名单。 */
+        #[arg(long, value_name = "HOST")]
+        deny: Vec<String>,
+        /** \brief 清空两个列表，不再限制主机。 */
+        #[arg(long, conflicts_with_all = ["allow", "deny"])]
+        clear: bool,
+    },
+    /**
+     * \brief 加密数据库中仍以明文保存的 API Key（新写入的密钥已自动加密），可重复执行。
+     */
+    EncryptKeys,
+    /**
+     * \brief 更换或丢失主密钥后，用旧主密钥（环境变量 DREAMQUILL_OLD_MASTER_KEY，可为旧钥匙串条目或密钥文件的内容）
+     * 重新加密当前无法解密的 API Key。
+     */
+    Rekey,
+}
+
+#[derive(Subcommand, Debug)]
+enum AuthTokenAction {
+    /** \brief 保存指定令牌（至少 16 个字符）。 */
+    Set { token: String },
+    /** \brief 生成随机令牌、保存并打印。 */
+    Generate,
+    /** \brief 清除保存的令牌，之后 `serve` 不再鉴权（除非另行指定）。 */
+    Clear,
+}
+
+#[derive(Subcommand, Debug)]
+enum SessionsAction {
+    /** \brief 列出命名会话（最近使用的在前）。 */
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum ChatsAction {
+    /** \brief 列出会话（置顶在前，其余最新在前）。 */
+    List {
+        /** \brief 仅列出绑定该 Provider 的会话。 */
+        #[arg(long)]
+        provider_id: Option<i64>,
+        /** \brief 同时列出已归档的会话。 */
+        #[arg(long)]
+        all: bool,
+    },
+    /** \brief 按时间顺序打印会话中的全部消息。 */
+    Show { id: i64 },
+    /** \brief 删除会话及其消息、标签与备选回复。 */
+    Delete {
+        id: i64,
+        /** \brief 确认删除；缺省时只显示将删除的消息数。 */
+        #[arg(long)]
+        yes: bool,
+    },
+    /** \brief 修改会话标题。 */
+    Rename { id: i64, title: String },
+    /** \brief 置顶会话，使其始终排在列表最前。 */
+    Pin {
+        id: i64,
+        /** \brief 取消置顶。 */
+        #[arg(long)]
+        off: bool,
+    },
+    /** \brief 归档会话，默认列表不再显示。 */
+    Archive {
+        id: i64,
+        /** \brief 取消归档。 */
+        #[arg(long)]
+        off: bool,
+    },
+    /** \brief 复制会话为新分支，可只保留到指定消息为止。 */
+    Branch {
+        id: i64,
+        /** \brief 分支包含的最后一条消息 ID，缺省复制全部。 */
+        #[arg(long, value_name = "MESSAGE_ID")]
+        until: Option<i64>,
+        /** \brief 新会话标题，缺省为“Chat <id> 分支”。 */
+        #[arg(long)]
+        title: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PipelineAction {
+    /** \brief 列出已保存的流水线。 */
+    List,
+    /** \brief 从 JSON 定义文件新建或覆盖同名流水线。 */
+    Save {
+        name: String,
+        /** \brief 流水线定义（`source`、`template`、`output`）。 */
+        #[arg(long, value_name = "FILE")]
+        file: PathBuf,
+    },
+    /** \brief 运行流水线，把结果写入目录。 */
+    Run {
+        name: String,
+        /** \brief 输出目录。 */
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        out: PathBuf,
+        /** \brief 只把渲染结果打印到标准输出，不写文件。 */
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /** \brief 删除流水线。 */
+    Delete { name: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum ImportSource {
+    /** \brief ChatGPT 数据导出中的 conversations.json。 */
+    Chatgpt { file: PathBuf },
+    /** \brief Claude 数据导出中的 conversations.json。 */
+    Claude { file: PathBuf },
+    /** \brief `export --archive` 生成的迁移归档目录；先校验清单，再按会话新建、合并或跳过。 */
+    Archive {
+        dir: PathBuf,
+        /** \brief 只校验并列出将新建、合并与跳过的会话，不写入数据库。 */
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/**
+ * \brief 逐个列出归档导入计划中的会话，最后输出汇总。
+ */
+fn print_import_plan(plan: &archive::ImportPlan) {
+    for chat in &plan.chats {
+        match chat.action {
+            archive::PlanAction::Create => {
+                println!("create\t{}\t{} messages", chat.title, chat.messages)
+            }
+            archive::PlanAction::Merge { chat_id } => {
+                println!(
+                    "merge\t{}\t#{} +{} messages",
+                    chat.title, chat_id, chat.messages
+                )
+            }
+            archive::PlanAction::Skip { chat_id } => {
+                println!("skip\t{}\t#{} up to date", chat.title, chat_id)
+            }
+        }
+    }
+    println!(
+        "{}: {} created, {} merged, {} skipped, {} messages",
+        if plan.committed {
+            "imported"
+        } else {
+            "dry run"
+        },
+        plan.created,
+        plan.merged,
+        plan.skipped,
+        plan.messages
+    );
+}
+
+/**
+ * \brief 流式输出统计：首 token 延迟、耗时、token 估计与速度。
+ */
+struct StreamStats {
+    label: String,
+    started: Instant,
+    first_token: Option<Duration>,
+    text: String,
+    last_render: Option<Instant>,
+    live: bool,
+}
+
+impl StreamStats {
+    fn new(provider: &str, model: &str) -> Self {
+        Self {
+            label: format!("{}/{}", provider, model),
+            started: Instant::now(),
+            first_token: None,
+            text: String::new(),
+            last_render: None,
+            live: std::io::stderr().is_terminal(),
+        }
+    }
+
+    fn on_delta(&mut self, delta: &str) {
+        if self.first_token.is_none() {
+            self.first_token = Some(self.started.elapsed());
+        }
+        self.text.push_str(delta);
+        if self.live
+            && self
+                .last_render
+                .map(|t| t.elapsed() >= Duration::from_millis(250))
+                .unwrap_or(true)
+        {
+            self.last_render = Some(Instant::now());
+            self.render_title();
+        }
+    }
+
+    fn summary(&self) -> String {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let tokens = llm::estimate_tokens(&self.text);
+        let rate = if elapsed > 0.0 {
+            tokens as f64 / elapsed
+        } else {
+            0.0
+        };
+        let first = self
+            .first_token
+            .map(|d| format!("{:.2}s", d.as_secs_f64()))
+            .unwrap_or_else(|| "-".to_string());
+        format!(
+            "[{}] elapsed {:.2}s | first token {} | ~{} tokens | {:.1} tok/s",
+            self.label, elapsed, first, tokens, rate
+        )
+    }
+
+    /** \brief 正文占用 stdout，实时状态写入终端标题，避免与流式文本交错。 */
+    fn render_title(&self) {
+        let mut err = std::io::stderr();
+        let _ = write!(err, "\x1b]0;{}\x07", self.summary());
+        let _ = err.flush();
+    }
+
+    fn finish(&self) {
+        if self.live {
+            let _ = write!(std::io::stderr(), "\x1b]0;dreamquill\x07");
+        }
+        eprintln!("{}", self.summary());
+    }
+}
+
+/**
+ * \brief 单轮对话的可选项，`chat` 与 `repl` 共用。
+ */
+struct TurnOptions {
+    stats: bool,
+    overrides: llm::RequestOverrides,
+    /** \brief 额外的停止串，与已保存的停止串合并。 */
+    stop: Vec<String>,
+    tee: Option<transcript::TranscriptTee>,
+    /** \brief 提示原文不落库，只保存占位文本。 */
+    redact_prompt: bool,
+}
+
+/**
+ * \brief 单轮对话的输入：新的用户提示，或重新生成某条助手回复。
+ */
+enum TurnInput<'a> {
+    Prompt {
+        text: &'a str,
+        quotes: &'a [i64],
+    },
+    /** \brief 与服务端一致：旧回复存为备选回复并截断会话，再按剩余历史生成。 */
+    Regen {
+        message_id: i64,
     },
 }
 
+/**
+ * \brief 保存用户消息（重新生成时改为截断会话）、流式输出并保存回复，随后按需执行拒答重试与回复语言约束。
+ * \details Ctrl-C 只中止本轮生成，已收到的部分照常保存。
+ */
+async fn chat_turn(
+    conn: &rusqlite::Connection,
+    provider: &models::Provider,
+    chat_id: i64,
+    input: TurnInput<'_>,
+    turn: &TurnOptions,
+) -> Result<()> {
+    let (plan, prompt_len) = match input {
+        TurnInput::Prompt { text, quotes } => {
+            let quotes =
+                db::resolve_quotes(conn, chat_id, quotes).context("resolve quotes failed")?;
+            let plan = if turn.redact_prompt {
+                let id = db::insert_redacted_user_message(conn, chat_id, &quotes)
+                    .context("insert user message failed")?;
+                context::plan(conn, chat_id, Some(provider))
+                    .context("load messages failed")?
+                    .with_redacted_prompt(id, text)
+            } else {
+                db::insert_user_message(conn, chat_id, text, &quotes)
+                    .context("insert user message failed")?;
+                context::plan(conn, chat_id, Some(provider)).context("load messages failed")?
+            };
+            (plan, text.len())
+        }
+        TurnInput::Regen { message_id } => {
+            db::supersede_and_truncate(conn, chat_id, message_id)
+                .context("supersede reply failed")?;
+            let plan =
+                context::plan(conn, chat_id, Some(provider)).context("load messages failed")?;
+            (plan, 0)
+        }
+    };
+
+    let selection = plan.select(Some(provider)).await;
+    selection
+        .save_embeddings(conn)
+        .context("save embeddings failed")?;
+    let messages = selection.messages;
+
+    telemetry::log_event(
+        "cli.chat",
+        &format!(
+            "provider={}({}) chat_id={} prompt_len={}",
+            provider.name, provider.provider_type, chat_id, prompt_len
+        ),
+    );
+
+    let started = Instant::now();
+    let mut first_token_ms: Option<i64> = None;
+    let overrides = &turn.overrides;
+    if let Some(d) = llm::model_deprecation(provider, overrides.model_for(provider)) {
+        eprintln!("warning: {}", d.message);
+        telemetry::log_event("cli.chat", &format!("chat_id={} {}", chat_id, d.message));
+    }
+    // Ctrl-C 中止上游请求，已收到的部分照常保存
+    let cancel = CancellationToken::new();
+    let ctrl_c = {
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                cancel.cancel();
+            }
+        })
+    };
+    let mut stream = match llm::stream_chat_cancellable(provider, &messages, overrides, &cancel)
+        .instrument(telemetry::chat_span(chat_id, provider))
+        .await
+    {
+        Ok(stream) => stream,
+        Err(e) if e.is::<llm::Cancelled>() => {
+            eprintln!("cancelled");
+            return Ok(());
+        }
+        Err(e) => return Err(e.context("create stream failed")),
+    };
+
+    let mut stops = db::get_stop_strings(conn).context("load stop strings failed")?;
+    stops.extend(turn.stop.iter().cloned());
+    let max_output_chars =
+        db::get_max_output_chars(conn).context("load max output length failed")?;
+    let mut trimmer = llm::StopTrimmer::new(&stops).with_max_chars(max_output_chars);
+    let mut stream_stats = turn
+        .stats
+        .then(|| StreamStats::new(&provider.name, overrides.model_for(provider)));
+    let stream_id = transcript::stream_id("cli", chat_id);
+    let mut assistant_buf = String::new();
+    while let Some(delta) = stream
+        .as_mut()
+        .next()
+        .await
+        .transpose()
+        .context("stream error")?
+    {
+        if first_token_ms.is_none() {
+            first_token_ms = Some(started.elapsed().as_millis() as i64);
+        }
+        if let Some(t) = turn.tee.as_ref() {
+            if let Err(e) = t.append(&stream_id, &delta) {
+                eprintln!("transcript write failed: {}", e);
+            }
+        }
+        let visible = trimmer.push(&delta);
+        print!("{}", visible);
+        assistant_buf.push_str(&visible);
+        if let Some(s) = stream_stats.as_mut() {
+            s.on_delta(&visible);
+        }
+        std::io::stdout().flush().ok();
+        if trimmer.stopped() {
+            break;
+        }
+    }
+    ctrl_c.abort();
+    let tail = trimmer.finish();
+    print!("{}", tail);
+    assistant_buf.push_str(&tail);
+    println!();
+    if cancel.is_cancelled() {
+        eprintln!("cancelled, keeping {} chars", assistant_buf.chars().count());
+        telemetry::log_event(
+            "cli.chat",
+            &format!(
+                "cancelled chat_id={} kept_len={}",
+                chat_id,
+                assistant_buf.len()
+            ),
+        );
+        if assistant_buf.is_empty() {
+            return Ok(());
+        }
+    }
+    if let Some(reason) = trimmer.truncation() {
+        if trimmer.limit_reached() {
+            eprintln!("(truncated: max output length reached)");
+        }
+        telemetry::log_event(
+            "cli.chat",
+            &format!(
+                "output truncated ({}) chat_id={} trimmed={:?}",
+                reason,
+                chat_id,
+                trimmer.remainder()
+            ),
+        );
+    }
+    if let Some(s) = stream_stats.as_ref() {
+        s.finish();
+    }
+
+    let timing = db::GenerationTiming {
+        provider_id: Some(provider.id),
+        first_token_ms,
+        duration_ms: Some(started.elapsed().as_millis() as i64),
+    };
+    let message_id = finalize::finalize(
+        conn,
+        &finalize::FinishedReply {
+            chat_id,
+            content: &assistant_buf,
+            prompt: &messages,
+            timing,
+            metadata: trimmer.annotate(overrides.to_metadata()),
+            partial: cancel.is_cancelled(),
+        },
+    )
+    .context("save assistant reply failed")?
+    .message_id;
+
+    if let Some(policy) =
+        refusal::retry_policy_for(conn, &assistant_buf).filter(|_| !cancel.is_cancelled())
+    {
+        let retry_provider = match policy.provider_id {
+            Some(pid) => db::get_provider_by_id(conn, pid)
+                .context("load retry provider failed")?
+                .context("retry provider not found")?,
+            None => provider.clone(),
+        };
+        eprintln!(
+            "refusal detected, retrying with provider {}...",
+            retry_provider.name
+        );
+        let variant = refusal::retry(&retry_provider, message_id, &policy)
+            .await
+            .context("refusal retry failed")?;
+        println!("{}", variant.content);
+        eprintln!("(saved as variant id={})", variant.id);
+    } else if let Some(mismatch) =
+        language::mismatch_for(conn, chat_id, &assistant_buf).filter(|_| !cancel.is_cancelled())
+    {
+        eprintln!(
+            "reply language {} != {}, re-asking...",
+            mismatch.detected, mismatch.expected
+        );
+        let variant = language::enforce(provider, message_id, &mismatch)
+            .await
+            .context("language re-ask failed")?;
+        println!("{}", variant.content);
+        eprintln!("(saved as variant id={})", variant.id);
+    }
+    Ok(())
+}
+
+/** \brief REPL 特有的补充说明，附在共享命令帮助之后。 */
+const REPL_HELP_NOTES: &str =
+    "以 --session 启动时，命名会话随 /new、/switch、/branch 指向当前会话。
+/exit 也可用 Ctrl-D；生成过程中按 Ctrl-C 只中止本轮回复。";
+
+/** \brief 恢复命名会话时回显的最近消息条数。 */
+const REPL_RESUME_MESSAGES: usize = 4;
+
+/**
+ * \brief 以 `--session` 启动时，把命名会话指向当前会话。
+ */
+fn bind_session(conn: &rusqlite::Connection, session: Option<&str>, chat_id: i64) -> Result<()> {
+    if let Some(name) = session {
+        db::bind_named_session(conn, name, chat_id).context("save session failed")?;
+    }
+    Ok(())
+}
+
+/**
+ * \brief 恢复命名会话时回显最近几条消息，便于接着聊。
+ */
+fn print_resumed_history(conn: &rusqlite::Connection, name: &str, chat_id: i64) -> Result<()> {
+    let messages = db::load_messages(conn, chat_id).context("load messages failed")?;
+    let visible: Vec<_> = messages.iter().filter(|m| m.role != "system").collect();
+    println!(
+        "resumed session {} (chat {}, {} messages)",
+        name,
+        chat_id,
+        visible.len()
+    );
+    for message in &visible[visible.len().saturating_sub(REPL_RESUME_MESSAGES)..] {
+        println!("[{}] {}", message.role, message.content);
+    }
+    Ok(())
+}
+
+/**
+ * \brief 交互式多轮对话循环；消息与会话均经 `db` 持久化。
+ */
+async fn repl(
+    conn: &rusqlite::Connection,
+    mut chat_id: Option<i64>,
+    session: Option<&str>,
+    provider_id: Option<i64>,
+    mut turn: TurnOptions,
+) -> Result<()> {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    if let Some(name) = session {
+        chat_id = db::get_named_session(conn, name).context("load session failed")?;
+        if let Some(id) = chat_id {
+            bind_session(conn, session, id)?;
+            print_resumed_history(conn, name, id)?;
+        }
+    }
+    if let Some(id) = chat_id {
+        db::get_chat(conn, id)
+            .context("load chat failed")?
+            .with_context(|| format!("chat id {} not found", id))?;
+    }
+    let mut provider = match provider_id {
+        Some(pid) => db::get_provider_by_id(conn, pid)
+            .context("load provider failed")?
+            .with_context(|| format!("provider id {} not found", pid))?,
+        None => match chat_id
+            .map(|id| db::get_provider_for_chat(conn, id))
+            .transpose()
+            .context("load provider failed")?
+            .flatten()
+        {
+            Some(p) => p,
+            None => db::get_default_provider(conn).context("load provider failed")?
+                .context("no default provider, run: dreamquill init --api-base ... --api-key ... --model ...")?,
+        },
+    };
+    if let Some(id) = chat_id.filter(|_| provider_id.is_none()) {
+        db::apply_chat_model(conn, id, &mut provider).context("load chat model failed")?;
+    }
+    println!(
+        "provider={} chat={}{}，输入 /help 查看命令",
+        provider.name,
+        chat_id.map_or_else(|| "new".to_string(), |id| id.to_string()),
+        session.map_or_else(String::new, |name| format!(" session={}", name))
+    );
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        print!(
+            "{}> ",
+            chat_id.map_or_else(|| "new".to_string(), |id| id.to_string())
+        );
+        std::io::stdout().flush().ok();
+        let line = tokio::select! {
+            line = lines.next_line() => line.context("read stdin failed")?,
+            _ = tokio::signal::ctrl_c() => None,
+        };
+        let Some(line) = line else {
+            println!();
+            break;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let cmd = match commands::parse(line) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        match cmd {
+            Some(ChatCommand::Exit) => break,
+            Some(ChatCommand::Help) => println!("{}\n{}", commands::HELP, REPL_HELP_NOTES),
+            Some(ChatCommand::New) => {
+                chat_id = None;
+                println!("new chat will be created with the next message");
+            }
+            Some(ChatCommand::Switch { chat_id: id }) => match db::get_chat(conn, id)? {
+                Some(chat) => {
+                    chat_id = Some(id);
+                    bind_session(conn, session, id)?;
+                    if let Some(p) = db::get_provider_for_chat(conn, id)? {
+                        provider = p;
+                        db::apply_chat_model(conn, id, &mut provider)?;
+                    }
+                    println!(
+                        "switched to chat {} ({}), provider={}",
+                        id, chat.title, provider.name
+                    );
+                }
+                None => eprintln!("chat id {} not found", id),
+            },
+            Some(ChatCommand::Provider { provider_id: pid }) => {
+                match db::get_provider_by_id(conn, pid)? {
+                    Some(p) => {
+                        if let Some(id) = chat_id {
+                            db::set_chat_provider(conn, id, Some(p.id))
+                                .context("save chat provider failed")?;
+                        }
+                        provider = p;
+                        println!("provider={}", provider.name);
+                    }
+                    None => eprintln!("provider id {} not found", pid),
+                }
+            }
+            Some(ChatCommand::Model { model }) => {
+                turn.overrides.model = model;
+                println!("model={}", turn.overrides.model_for(&provider));
+            }
+            Some(ChatCommand::System { instruction }) => {
+                println!(
+                    "{}",
+                    if instruction.is_some() {
+                        "system instruction set for following messages"
+                    } else {
+                        "system instruction cleared"
+                    }
+                );
+                turn.overrides.system_instruction = instruction;
+            }
+            Some(ChatCommand::Branch { until }) => {
+                let Some(id) = chat_id else {
+                    eprintln!("no chat to branch yet");
+                    continue;
+                };
+                let title = format!("Chat {} 分支", id);
+                let new_id =
+                    db::clone_chat_until(conn, id, &title, until).context("branch chat failed")?;
+                telemetry::log_event(
+                    "cli.repl",
+                    &format!(
+                        "branch chat={} -> new_chat={} until={:?}",
+                        id, new_id, until
+                    ),
+                );
+                chat_id = Some(new_id);
+                bind_session(conn, session, new_id)?;
+                println!("switched to branch chat {} ({})", new_id, title);
+            }
+            Some(ChatCommand::Regen) => {
+                let last_reply = match chat_id {
+                    Some(id) => db::regenerable_reply_id(conn, id)
+                        .context("load messages failed")?
+                        .map(|message_id| (id, message_id)),
+                    None => None,
+                };
+                let Some((id, message_id)) = last_reply else {
+                    eprintln!("nothing to regenerate");
+                    continue;
+                };
+                if let Err(e) =
+                    chat_turn(conn, &provider, id, TurnInput::Regen { message_id }, &turn).await
+                {
+                    eprintln!("error: {:#}", e);
+                }
+            }
+            None => {
+                let id = match chat_id {
+                    Some(id) => id,
+                    None => {
+                        let id =
+                            db::create_chat(conn, &format!("{} 会话", provider.name), provider.id)
+                                .context("create chat failed")?;
+                        println!("Created chat id={} (provider={})", id, provider.name);
+                        bind_session(conn, session, id)?;
+                        chat_id = Some(id);
+                        id
+                    }
+                };
+                // 单轮失败不退出，便于切换 Provider 后重试
+                if let Err(e) = chat_turn(
+                    conn,
+                    &provider,
+                    id,
+                    TurnInput::Prompt {
+                        text: line,
+                        quotes: &[],
+                    },
+                    &turn,
+                )
+                .await
+                {
+                    eprintln!("error: {:#}", e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/**
+ * \brief 把经过的秒数格式化为 `5m ago` 这样的相对时间。
+ */
+fn format_age(secs: i64) -> String {
+    match secs.max(0) {
+        s if s < 60 => "just now".to_string(),
+        s if s < 3600 => format!("{}m ago", s / 60),
+        s if s < 86_400 => format!("{}h ago", s / 3600),
+        s => format!("{}d ago", s / 86_400),
+    }
+}
+
+/**
+ * \brief 以制表符分隔打印查询结果，末尾附行数与耗时。
+ */
+fn print_query_result(result: &sql_console::QueryResult) {
+    println!("{}", result.columns.join("\t"));
+    for row in &result.rows {
+        let cells: Vec<String> = row
+            .iter()
+            .map(|v| match v {
+                serde_json::Value::Null => "NULL".to_string(),
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            })
+            .collect();
+        println!("{}", cells.join("\t"));
+    }
+    println!(
+        "({} rows{}, {} ms)",
+        result.rows.len(),
+        if result.truncated { ", truncated" } else { "" },
+        result.elapsed_ms
+    );
+}
+
+/**
+ * \brief 以表格打印 Provider 健康检查结果，默认 Provider 以 `*` 标出。
+ */
+fn print_health_table(results: &[llm::ProviderHealth], default_id: Option<i64>) {
+    fn flag(value: Option<bool>) -> &'static str {
+        match value {
+            Some(true) => "ok",
+            Some(false) => "FAIL",
+            None => "?",
+        }
+    }
+    println!(
+        "{:<2}{:>4}  {:<16} {:<10} {:<24} {:<5} {:<6} {:>8}  ERROR",
+        "", "ID", "NAME", "TYPE", "MODEL", "AUTH", "MODEL", "LATENCY"
+    );
+    for r in results {
+        println!(
+            "{:<2}{:>4}  {:<16} {:<10} {:<24} {:<5} {:<6} {:>6}ms  {}",
+            if Some(r.provider_id) == default_id {
+                "*"
+            } else {
+                ""
+            },
+            r.provider_id,
+            r.name,
+            r.provider_type,
+            r.model,
+            flag(r.auth_ok),
+            flag(r.model_available),
+            r.latency_ms,
+            r.error.as_deref().unwrap_or("-")
+        );
+    }
+    for r in results {
+        if let Some(d) = r.deprecation.as_ref() {
+            println!(
+                "warning: provider {} ({}): {}",
+                r.provider_id, r.name, d.message
+            );
+        }
+    }
+}
+
+/**
+ * \brief 用备份替换当前数据库；先把当前数据库备份到 `backups/`，当前文件已损坏无法备份时只给出警告。
+ */
+fn restore_database(file: &Path, yes: bool) -> Result<()> {
+    let target = paths::db_path();
+    if !yes {
+        bail!(
+            "restoring replaces all data in {} with {}; re-run with --yes (the current database is backed up to {} first)",
+            target.display(),
+            file.display(),
+            paths::backup_dir().display()
+        );
+    }
+    let mut conn = db::open_db_at(target).context("open database failed")?;
+    match db::backup_to_dir(&conn, &paths::backup_dir()) {
+        Ok(info) => println!("current database saved to {}", info.path),
+        Err(e) => eprintln!("warning: could not back up the current database: {:#}", e),
+    }
+    db::restore_from(&mut conn, file).context("restore database failed")?;
+    println!("restored {} into {}", file.display(), target.display());
+    Ok(())
+}
+
+fn print_doctor_report(report: &doctor::DoctorReport) {
+    for check in &report.checks {
+        println!(
+            "[{:<4}] {:<24} {}",
+            check.status.label(),
+            check.name,
+            check.detail
+        );
+        if let Some(hint) = check.hint.as_deref() {
+            println!("{:<32}-> {}", "", hint);
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    if let Err(e) = telemetry::init(&telemetry::Sinks::from_env()) {
+        eprintln!("Warning: telemetry init failed: {:#}", e);
+    }
+    let result = run(cli).await;
+    db::shutdown_write_behind();
+    telemetry::shutdown();
+    result
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    // 诊断需在打开并迁移数据库之前执行，否则无法如实报告数据库问题
+    if let Commands::Doctor {
+        addr,
+        offline,
+        json,
+    } = &cli.command
+    {
+        let report = doctor::run(&doctor::DoctorOptions {
+            addr: Some(addr.clone()),
+            check_ui: true,
+            probe_providers: !offline,
+            ..Default::default()
+        })
+        .await;
+        print_doctor_report(&report);
+        if let Some(file) = json {
+            let out = std::fs::File::create(file)
+                .with_context(|| format!("create {} failed", file.display()))?;
+            serde_json::to_writer_pretty(std::io::BufWriter::new(out), &report)
+                .context("write report failed")?;
+            println!("report written to {}", file.display());
+        }
+        if report.worst() == doctor::CheckStatus::Fail {
+            bail!("doctor found problems, see the hints above");
+        }
+        return Ok(());
+    }
+
+    // 恢复需在迁移之前执行，当前数据库损坏时也能用备份覆盖
+    if let Commands::Restore { file, yes } = &cli.command {
+        return restore_database(file, *yes);
+    }
 
     let conn = db::open_default_db().context("open database failed")?;
-    db::migrate(&conn).context("apply migrations failed")?;
     let telemetry_enabled = db::get_telemetry_enabled(&conn).unwrap_or(false);
     telemetry::set_enabled(telemetry_enabled);
+    llm::set_retry_policy(db::get_retry_policy(&conn).context("load retry policy failed")?);
+    llm::set_host_policy(db::get_host_policy(&conn).context("load host policy failed")?);
 
     match cli.command {
         Commands::Init {
@@ -79,6 +1212,16 @@ async fn main() -> Result<()> {
                 &conn, &name, &provider, &api_base, &api_key, &model, None,
             )
             .context("save provider failed")?;
+            let created =
+                db::get_provider_by_id(&conn, provider_id).context("load provider failed")?;
+            db::insert_audit_log(
+                &conn,
+                "cli",
+                "provider.create",
+                Some(&format!("provider:{}", provider_id)),
+                &db::provider_changes(None, created.as_ref()),
+            )
+            .context("write audit log failed")?;
             db::set_telemetry_enabled(&conn, enable_telemetry).context("save telemetry failed")?;
             telemetry::set_enabled(enable_telemetry);
             println!(
@@ -86,8 +1229,21 @@ async fn main() -> Result<()> {
                 provider_id, name, provider, api_base, model
             );
         }
-        Commands::Chat { chat_id, prompt } => {
-            let provider = db::get_default_provider(&conn).context("load provider failed")?
+        Commands::Chat {
+            chat_id,
+            prompt,
+            stats,
+            model,
+            temperature,
+            max_tokens,
+            system,
+            stop,
+            quotes,
+            tee,
+            reply_language,
+            redact_prompt,
+        } => {
+            let mut provider = db::get_default_provider(&conn).context("load provider failed")?
                 .context("no default provider, run: dreamquill init --api-base ... --api-key ... --model ...")?;
 
             let chat_id = match chat_id {
@@ -101,46 +1257,606 @@ async fn main() -> Result<()> {
                 }
             };
 
-            db::insert_message(&conn, chat_id, "user", &prompt)
-                .context("insert user message failed")?;
+            // 会话级模型只对其绑定的 Provider 有效
+            let bound = db::get_chat(&conn, chat_id)
+                .context("load chat failed")?
+                .and_then(|c| c.provider_id);
+            if bound == Some(provider.id) {
+                db::apply_chat_model(&conn, chat_id, &mut provider)
+                    .context("load chat model failed")?;
+            }
 
-            let messages = db::load_messages(&conn, chat_id).context("load messages failed")?;
+            if let Some(language) = reply_language {
+                let setting = models::ReplyLanguage {
+                    language,
+                    enforce: true,
+                };
+                db::set_chat_reply_language(&conn, chat_id, Some(&setting))
+                    .context("save reply language failed")?;
+            }
 
+            let overrides = llm::RequestOverrides {
+                model,
+                temperature,
+                max_tokens,
+                system_instruction: system,
+            }
+            .normalized();
+            let tee = tee
+                .map(transcript::TranscriptTee::open)
+                .transpose()
+                .context("open transcript failed")?;
+            let turn = TurnOptions {
+                stats,
+                overrides,
+                stop,
+                tee,
+                redact_prompt,
+            };
+            chat_turn(
+                &conn,
+                &provider,
+                chat_id,
+                TurnInput::Prompt {
+                    text: &prompt,
+                    quotes: &quotes,
+                },
+                &turn,
+            )
+            .await?;
+        }
+        Commands::Repl {
+            chat_id,
+            session,
+            provider_id,
+            stats,
+            model,
+            system,
+        } => {
+            let turn = TurnOptions {
+                stats,
+                overrides: llm::RequestOverrides {
+                    model,
+                    system_instruction: system,
+                    ..Default::default()
+                }
+                .normalized(),
+                stop: Vec::new(),
+                tee: None,
+                redact_prompt: false,
+            };
+            repl(&conn, chat_id, session.as_deref(), provider_id, turn).await?;
+        }
+        Commands::Serve {
+            addr,
+            rate_limit,
+            chat_rate_limit,
+            confirm_deletes,
+            sse_retry_ms,
+            tee,
+            auth_token,
+            cors_origins,
+            cors_methods,
+        } => {
+            let mut options = server::ServerOptions::from_env();
+            if let Some(limit) = rate_limit {
+                options.mutation_rate_limit = limit;
+            }
+            if let Some(limit) = chat_rate_limit {
+                options.chat_rate_limit = limit;
+            }
+            if confirm_deletes {
+                options.confirm_deletes = true;
+            }
+            if let Some(ms) = sse_retry_ms {
+                options.sse_retry_ms = ms;
+            }
+            if tee.is_some() {
+                options.transcript_path = tee;
+            }
+            if auth_token.is_some() {
+                options.auth_token = auth_token;
+            }
+            if !cors_origins.is_empty() {
+                options.cors_origins = cors_origins;
+            }
+            if !cors_methods.is_empty() {
+                options.cors_methods = cors_methods;
+            }
+            server::run_with_options(&addr, options).await?;
+        }
+        #[cfg(feature = "grpc")]
+        Commands::Grpc { addr } => {
+            let dq = dreamquill_core_sdk::DreamQuill::builder().build()?;
+            println!("gRPC listening on {}", addr);
+            dreamquill_core_sdk::grpc::serve(&addr, dq).await?;
+        }
+        Commands::Import {
+            source,
+            provider_id,
+        } => {
+            let (format, file) = match source {
+                ImportSource::Chatgpt { file } => (importer::ImportFormat::ChatGpt, file),
+                ImportSource::Claude { file } => (importer::ImportFormat::Claude, file),
+                ImportSource::Archive { dir, dry_run } => {
+                    let plan = if dry_run {
+                        archive::verify_archive(&conn, &dir)
+                    } else {
+                        archive::import_archive(&conn, &dir, provider_id)
+                    }
+                    .context("import archive failed")?;
+                    print_import_plan(&plan);
+                    return Ok(());
+                }
+            };
+            let raw = std::fs::read_to_string(&file)
+                .with_context(|| format!("read {} failed", file.display()))?;
+            let chats = importer::parse_export(format, &raw)?;
+            let summary = importer::import_chats(&conn, &chats, provider_id)
+                .context("import chats failed")?;
+            println!(
+                "imported {} chats, {} messages ({} empty skipped)",
+                summary.chats, summary.messages, summary.skipped
+            );
+        }
+        Commands::Export {
+            obsidian,
+            finetune,
+            archive: archive_dir,
+            chat_ids,
+            tags,
+        } => {
+            if let Some(dir) = archive_dir {
+                let manifest =
+                    archive::export_archive(&conn, &dir).context("export archive failed")?;
+                println!(
+                    "exported {} chats, {} messages to {} (schema v{})",
+                    manifest.counts.chats,
+                    manifest.counts.messages,
+                    dir.display(),
+                    manifest.schema_version
+                );
+            }
+            if let Some(dir) = obsidian {
+                let summary =
+                    exporter::export_obsidian(&conn, &dir).context("export chats failed")?;
+                println!("exported {} notes to {}", summary.notes, dir.display());
+            }
+            if let Some(file) = finetune {
+                let mut out = std::io::BufWriter::new(
+                    std::fs::File::create(&file)
+                        .with_context(|| format!("create {} failed", file.display()))?,
+                );
+                let selection = exporter::FinetuneSelection { chat_ids, tags };
+                let summary = exporter::export_finetune(&conn, &selection, &mut out)
+                    .context("export finetune data failed")?;
+                println!(
+                    "exported {} examples from {} chats to {}",
+                    summary.examples,
+                    summary.chats,
+                    file.display()
+                );
+            }
+        }
+        Commands::Pipeline { action } => match action {
+            PipelineAction::List => {
+                for p in db::list_export_pipelines(&conn).context("load pipelines failed")? {
+                    let output = &p.definition.output;
+                    println!(
+                        "{}\t{}\t{:?}{}",
+                        p.id,
+                        p.name,
+                        output.format,
+                        if output.per_chat { " per-chat" } else { "" }
+                    );
+                }
+            }
+            PipelineAction::Save { name, file } => {
+                let text = std::fs::read_to_string(&file)
+                    .with_context(|| format!("read {} failed", file.display()))?;
+                let definition: db::PipelineDefinition = serde_json::from_str(&text)
+                    .with_context(|| format!("parse {} failed", file.display()))?;
+                pipeline::validate(&definition)?;
+                let existing = db::find_export_pipeline(&conn, &name)?.map(|p| p.id);
+                let id = db::save_export_pipeline(&conn, existing, &name, &definition)?;
+                println!("saved pipeline {} (id={})", name.trim(), id);
+            }
+            PipelineAction::Run { name, out, dry_run } => {
+                let saved = db::find_export_pipeline(&conn, &name)?
+                    .ok_or_else(|| anyhow!("pipeline '{}' not found", name))?;
+                if dry_run {
+                    let run = pipeline::preview(&conn, &saved)?;
+                    for file in &run.files {
+                        println!("==> {} <==\n{}", file.file_name, file.content);
+                    }
+                } else {
+                    let (run, paths) = pipeline::run(&conn, &saved, &out)?;
+                    for path in &paths {
+                        println!("{}", path.display());
+                    }
+                    println!(
+                        "exported {} messages from {} chats into {} files",
+                        run.messages,
+                        run.chats,
+                        paths.len()
+                    );
+                }
+            }
+            PipelineAction::Delete { name } => {
+                let saved = db::find_export_pipeline(&conn, &name)?
+                    .ok_or_else(|| anyhow!("pipeline '{}' not found", name))?;
+                db::delete_export_pipeline(&conn, saved.id)?;
+                println!("deleted pipeline {}", saved.name);
+            }
+        },
+        Commands::Backup { out } => {
+            let info = match out {
+                Some(out) => db::backup_to(&conn, &out),
+                None => db::backup_to_dir(&conn, &paths::backup_dir()),
+            }
+            .context("backup database failed")?;
             telemetry::log_event(
-                "cli.chat",
-                &format!(
-                    "provider={}({}) chat_id={} prompt_len={}",
-                    provider.name,
-                    provider.provider_type,
-                    chat_id,
-                    prompt.len()
-                ),
+                "cli.backup",
+                &format!("path={} bytes={}", info.path, info.bytes),
             );
-
-            let mut stream = llm::stream_chat(&provider, &messages)
-                .await
-                .context("create stream failed")?;
-
-            let mut assistant_buf = String::new();
-            while let Some(delta) = stream
-                .as_mut()
-                .next()
-                .await
-                .transpose()
-                .context("stream error")?
-            {
-                print!("{}", delta);
-                assistant_buf.push_str(&delta);
-                use std::io::Write;
-                std::io::stdout().flush().ok();
+            println!("backup written to {} ({} bytes)", info.path, info.bytes);
+        }
+        Commands::DebugBundle {
+            out,
+            transcript,
+            transcripts,
+        } => {
+            let options = debug_bundle::BundleOptions {
+                app: format!("cli {}", env!("CARGO_PKG_VERSION")),
+                transcript_path: transcript.or_else(|| {
+                    std::env::var_os("DREAMQUILL_TRANSCRIPT")
+                        .filter(|v| !v.is_empty())
+                        .map(PathBuf::from)
+                }),
+                transcripts,
+                ..Default::default()
+            };
+            let summary = debug_bundle::create(&conn, &out, &options)
+                .context("create debug bundle failed")?;
+            println!(
+                "debug bundle written to {} ({} files)",
+                summary.path,
+                summary.entries.len()
+            );
+            if !summary.integrity_ok {
+                eprintln!(
+                    "warning: database integrity check reported problems, see integrity.json"
+                );
             }
-            println!();
-
-            db::insert_message(&conn, chat_id, "assistant", &assistant_buf)
-                .context("insert assistant message failed")?;
         }
-        Commands::Serve { addr } => {
-            server::run(&addr).await?;
+        Commands::Provider {
+            action: ProviderAction::EncryptKeys,
+        } => {
+            let count = db::encrypt_provider_keys(&conn).context("encrypt api keys failed")?;
+            telemetry::log_event("cli.provider", &format!("encrypt api keys count={}", count));
+            println!("encrypted {} plaintext API key(s)", count);
+        }
+        Commands::Provider {
+            action: ProviderAction::Rekey,
+        } => {
+            let old = std::env::var(crypto::OLD_MASTER_KEY_ENV)
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+                .with_context(|| {
+                    format!(
+                        "set {} to the previous master key",
+                        crypto::OLD_MASTER_KEY_ENV
+                    )
+                })?;
+            let count =
+                db::rekey_provider_keys(&conn, &old).context("re-encrypt api keys failed")?;
+            telemetry::log_event("cli.provider", &format!("rekey api keys count={}", count));
+            println!(
+                "re-encrypted {} API key(s) with the current master key",
+                count
+            );
+        }
+        Commands::Provider {
+            action: ProviderAction::Sync { file, dry_run },
+        } => {
+            let text = std::fs::read_to_string(&file)
+                .with_context(|| format!("read {} failed", file.display()))?;
+            let specs = provider_sync::parse_providers_yaml(&text)
+                .with_context(|| format!("parse {} failed", file.display()))?;
+            let plan = provider_sync::plan_sync(&conn, &specs, |var| std::env::var(var).ok())?;
+            for action in &plan.actions {
+                match action {
+                    provider_sync::SyncAction::Create { name } => println!("+ create {}", name),
+                    provider_sync::SyncAction::Update { id, name, fields } => {
+                        println!("~ update {} (id={}): {}", name, id, fields.join(", "))
+                    }
+                    provider_sync::SyncAction::Delete { id, name, chats } => {
+                        println!("- delete {} (id={}, {} chats unassigned)", name, id, chats)
+                    }
+                    provider_sync::SyncAction::Unchanged { name, .. } => {
+                        println!("= unchanged {}", name)
+                    }
+                }
+            }
+            if let Some(name) = &plan.set_default {
+                println!("* default -> {}", name);
+            }
+            if !plan.has_changes() {
+                println!("providers already in sync");
+            } else if dry_run {
+                println!("dry run, no changes applied");
+            } else {
+                provider_sync::apply_sync(&conn, &plan, "cli").context("apply sync failed")?;
+                println!("providers synced from {}", file.display());
+            }
+        }
+        Commands::Provider {
+            action: ProviderAction::Hosts { allow, deny, clear },
+        } => {
+            let before = db::get_host_policy(&conn).context("load host policy failed")?;
+            let policy = if clear {
+                Some(models::HostPolicy::default())
+            } else if allow.is_empty() && deny.is_empty() {
+                None
+            } else {
+                Some(models::HostPolicy {
+                    allow: if allow.is_empty() {
+                        before.allow.clone()
+                    } else {
+                        allow
+                    },
+                    deny: if deny.is_empty() {
+                        before.deny.clone()
+                    } else {
+                        deny
+                    },
+                })
+            };
+            let current = match policy {
+                Some(policy) => {
+                    let saved = db::set_host_policy(&conn, &policy)?;
+                    db::insert_audit_log(
+                        &conn,
+                        "cli",
+                        "config.host_policy",
+                        None,
+                        &serde_json::json!({ "before": before, "after": saved }),
+                    )
+                    .context("write audit log failed")?;
+                    saved
+                }
+                None => before,
+            };
+            let show = |list: &[String]| {
+                if list.is_empty() {
+                    "(none)".to_string()
+                } else {
+                    list.join(", ")
+                }
+            };
+            println!("allow: {}", show(&current.allow));
+            println!("deny:  {}", show(&current.deny));
+            for provider in db::list_providers(&conn).context("load providers failed")? {
+                if let Err(e) = base_url::check_host(&provider.api_base, &current) {
+                    println!(
+                        "warning: provider {} (id={}): {}",
+                        provider.name, provider.id, e
+                    );
+                }
+            }
+        }
+        Commands::AuthToken { action } => {
+            let had_token = db::get_server_auth_token(&conn)
+                .context("load auth token failed")?
+                .is_some();
+            let token = match action {
+                AuthTokenAction::Set { token } => Some(token),
+                AuthTokenAction::Generate => Some(server::generate_auth_token()?),
+                AuthTokenAction::Clear => None,
+            };
+            db::set_server_auth_token(&conn, token.as_deref())?;
+            db::insert_audit_log(
+                &conn,
+                "cli",
+                "settings.update",
+                None,
+                &serde_json::json!({"server_auth_token": {"from": had_token, "to": token.is_some()}}),
+            )
+            .context("write audit log failed")?;
+            match token {
+                Some(token) => println!(
+                    "Saved auth token: {}\nrestart `dreamquill serve` to apply; open the web UI once via http://<addr>/#token={}",
+                    token.trim(),
+                    token.trim()
+                ),
+                None => println!("Auth token cleared; restart `dreamquill serve` to apply"),
+            }
+        }
+        // 已在打开数据库前处理
+        Commands::Sessions {
+            action: SessionsAction::List,
+        } => {
+            let sessions = db::list_named_sessions(&conn).context("list sessions failed")?;
+            if sessions.is_empty() {
+                println!("no sessions, start one with: dreamquill repl --session <name>");
+            }
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64);
+            for session in sessions {
+                println!(
+                    "{:<16} chat={:<6} {:>4} msgs  {:>9}  {}",
+                    session.name,
+                    session.chat_id,
+                    session.message_count,
+                    format_age(now - session.last_used_at),
+                    session.title
+                );
+            }
+        }
+        Commands::Chats { action } => match action {
+            ChatsAction::List { provider_id, all } => {
+                let chats = db::list_chats(&conn, provider_id, all).context("list chats failed")?;
+                if chats.is_empty() {
+                    println!("no chats");
+                }
+                for chat in chats {
+                    let messages =
+                        db::count_messages(&conn, chat.id).context("count messages failed")?;
+                    let provider = chat
+                        .provider_id
+                        .map_or_else(|| "-".to_string(), |id| id.to_string());
+                    let flags = match (chat.pinned, chat.archived) {
+                        (true, true) => "PA",
+                        (true, false) => "P ",
+                        (false, true) => " A",
+                        (false, false) => "  ",
+                    };
+                    println!(
+                        "{:>6} {} {:>4} msgs  provider={:<4} {}",
+                        chat.id, flags, messages, provider, chat.title
+                    );
+                }
+            }
+            ChatsAction::Show { id } => {
+                let chat = db::get_chat(&conn, id)
+                    .context("load chat failed")?
+                    .with_context(|| format!("chat id {} not found", id))?;
+                println!("# {} (id={})", chat.title, chat.id);
+                for message in
+                    db::load_messages_with_meta(&conn, id).context("load messages failed")?
+                {
+                    println!("\n[{}] {}", message.id, message.role);
+                    println!("{}", message.content);
+                }
+            }
+            ChatsAction::Delete { id, yes } => {
+                let chat = db::get_chat(&conn, id)
+                    .context("load chat failed")?
+                    .with_context(|| format!("chat id {} not found", id))?;
+                let messages = db::count_messages(&conn, id).context("count messages failed")?;
+                if !yes {
+                    bail!(
+                        "chat {} ({}) has {} messages; re-run with --yes to delete it",
+                        id,
+                        chat.title,
+                        messages
+                    );
+                }
+                db::delete_chat(&conn, id).context("delete chat failed")?;
+                telemetry::log_event("cli.chats", &format!("delete chat id={}", id));
+                println!("Deleted chat id={} ({} messages)", id, messages);
+            }
+            ChatsAction::Rename { id, title } => {
+                let title = title.trim();
+                if title.is_empty() {
+                    bail!("title must not be empty");
+                }
+                db::update_chat_title(&conn, id, title).context("rename chat failed")?;
+                telemetry::log_event(
+                    "cli.chats",
+                    &format!("rename chat id={} title={}", id, title),
+                );
+                println!("Renamed chat id={} to {}", id, title);
+            }
+            ChatsAction::Pin { id, off } => {
+                db::set_chat_pinned(&conn, id, !off).context("pin chat failed")?;
+                telemetry::log_event("cli.chats", &format!("pin chat id={} pinned={}", id, !off));
+                println!("{} chat id={}", if off { "Unpinned" } else { "Pinned" }, id);
+            }
+            ChatsAction::Archive { id, off } => {
+                db::set_chat_archived(&conn, id, !off).context("archive chat failed")?;
+                telemetry::log_event(
+                    "cli.chats",
+                    &format!("archive chat id={} archived={}", id, !off),
+                );
+                println!(
+                    "{} chat id={}",
+                    if off { "Unarchived" } else { "Archived" },
+                    id
+                );
+            }
+            ChatsAction::Branch { id, until, title } => {
+                let title = title.unwrap_or_else(|| format!("Chat {} 分支", id));
+                let new_id =
+                    db::clone_chat_until(&conn, id, &title, until).context("branch chat failed")?;
+                telemetry::log_event(
+                    "cli.chats",
+                    &format!(
+                        "branch chat={} -> new_chat={} until={:?}",
+                        id, new_id, until
+                    ),
+                );
+                println!("Created chat id={} ({})", new_id, title);
+            }
+        },
+        Commands::Sql {
+            query,
+            enable,
+            disable,
+            max_rows,
+            timeout_ms,
+            json,
+        } => {
+            if enable || disable {
+                let previous =
+                    db::get_sql_console_enabled(&conn).context("load sql console failed")?;
+                db::set_sql_console_enabled(&conn, enable).context("save sql console failed")?;
+                db::insert_audit_log(
+                    &conn,
+                    "cli",
+                    "settings.update",
+                    None,
+                    &serde_json::json!({"sql_console_enabled": {"from": previous, "to": enable}}),
+                )
+                .context("write audit log failed")?;
+                println!(
+                    "SQL console {}",
+                    if enable { "enabled" } else { "disabled" }
+                );
+                return Ok(());
+            }
+            let query = query.unwrap_or_default();
+            let limits = sql_console::QueryLimits::new(Some(max_rows), timeout_ms);
+            let result = sql_console::execute(&conn, "cli", &query, limits)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&result)?);
+            } else {
+                print_query_result(&result);
+            }
+        }
+        Commands::Doctor { .. } | Commands::Restore { .. } => {}
+        Commands::Provider {
+            action: ProviderAction::Audit { json },
+        } => {
+            let providers = db::list_providers(&conn).context("load providers failed")?;
+            if providers.is_empty() {
+                bail!("no provider configured, run `dreamquill init` first");
+            }
+            let default_id = db::get_default_provider(&conn)
+                .context("load default provider failed")?
+                .map(|p| p.id);
+            let results =
+                futures_util::future::join_all(providers.iter().map(llm::check_provider_health))
+                    .await;
+            print_health_table(&results, default_id);
+            if let Some(file) = json {
+                let out = std::fs::File::create(&file)
+                    .with_context(|| format!("create {} failed", file.display()))?;
+                serde_json::to_writer_pretty(std::io::BufWriter::new(out), &results)
+                    .context("write report failed")?;
+                println!("report written to {}", file.display());
+            }
+            if let Some(broken) = results
+                .iter()
+                .find(|r| Some(r.provider_id) == default_id && !r.is_healthy())
+            {
+                bail!(
+                    "default provider {} (id={}) is unhealthy",
+                    broken.name,
+                    broken.provider_id
+                );
+            }
         }
     }
 
@@ -1,9 +1,13 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use dreamquill_core_sdk::{db, llm, telemetry};
+use dreamquill_core_sdk::{
+    archive, autotag, backfill, client, commands, context, db, debug_bundle, doctor, finalize,
+    language, llm, model_cache, paths, pipeline, refusal, rerun, sql_console,
+    telemetry::{self, Instrument},
+};
 use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use tauri::Emitter;
 use tauri_plugin_secure_storage::{OptionsRequest, SecureStorageExt};
@@ -17,6 +21,11 @@ struct ProviderRecordDto {
     api_base: String,
     api_key: String,
     model: String,
+    #[serde(flatten)]
+    generation: dreamquill_core_sdk::models::GenerationSettings,
+    proxy_url: Option<String>,
+    /** \brief 数据库中的密钥无法用当前主密钥解密，需要重新填写。 */
+    api_key_unreadable: bool,
     is_default: bool,
 }
 
@@ -34,6 +43,11 @@ struct ProviderRequestDto {
     api_base: String,
     api_key: String,
     model: String,
+    #[serde(default, flatten)]
+    generation: dreamquill_core_sdk::models::GenerationSettings,
+    /** \brief 代理地址（http/https/socks5/socks5h），为空时直连。 */
+    #[serde(default)]
+    proxy_url: Option<String>,
     #[serde(default)]
     telemetry_enabled: Option<bool>,
     #[serde(default)]
@@ -45,6 +59,25 @@ struct ChatSummaryDto {
     id: i64,
     title: String,
     provider_id: Option<i64>,
+    last_read_message_id: Option<i64>,
+    pinned: bool,
+    archived: bool,
+    /** \brief 会话级模型覆盖，为空时使用 Provider 的默认模型。 */
+    model: Option<String>,
+}
+
+impl From<db::ChatSummary> for ChatSummaryDto {
+    fn from(chat: db::ChatSummary) -> Self {
+        Self {
+            id: chat.id,
+            title: chat.title,
+            provider_id: chat.provider_id,
+            last_read_message_id: chat.last_read_message_id,
+            pinned: chat.pinned,
+            archived: chat.archived,
+            model: chat.model,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -52,6 +85,14 @@ struct StoredMessageDto {
     id: i64,
     role: String,
     content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_token_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+    /** \brief 取消生成时保存的不完整回复。 */
+    partial: bool,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -61,11 +102,49 @@ struct ChatMessagesDto {
     messages: Vec<StoredMessageDto>,
 }
 
+#[derive(Debug, Serialize, Clone)]
+struct ChatMessagesPageDto {
+    chat_id: i64,
+    provider_id: Option<i64>,
+    messages: Vec<StoredMessageDto>,
+    /** \brief 加载更早一页时传入的游标，为空表示已到最早消息。 */
+    next_cursor: Option<i64>,
+}
+
+impl From<db::StoredMessage> for StoredMessageDto {
+    fn from(msg: db::StoredMessage) -> Self {
+        Self {
+            id: msg.id,
+            role: msg.role,
+            content: msg.content,
+            first_token_ms: msg.first_token_ms,
+            duration_ms: msg.duration_ms,
+            metadata: msg.metadata,
+            partial: msg.partial,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct ChatResultDto {
     chat_id: i64,
     reply: String,
     logs: Vec<String>,
+    /** \brief 新会话首轮回复后自动生成的标题。 */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    /** \brief 回复是否被停止串或输出上限截断。 */
+    truncated: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateChatRequestDto {
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    provider_id: Option<i64>,
+    #[serde(default)]
+    system_prompt: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -87,6 +166,26 @@ struct HealthPreviewRequestDto {
     api_base: String,
     api_key: String,
     model: String,
+    #[serde(default)]
+    proxy_url: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SecretAliasDto {
+    alias: String,
+    provider_id: Option<i64>,
+    provider_name: Option<String>,
+    has_secret: bool,
+    orphaned: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct SecretPruneResultDto {
+    dry_run: bool,
+    /** \brief 已清理（或 dry-run 下将清理）的孤立别名。 */
+    pruned: Vec<String>,
+    /** \brief Provider 引用但安全存储中缺失密钥的别名，仅报告不处理。 */
+    missing: Vec<String>,
 }
 
 /**
@@ -132,6 +231,34 @@ fn emit_event<T: Serialize>(app: &tauri::AppHandle, name: &str, payload: &Stream
     }
 }
 
+/** \brief 推送 `dq:chunk`：默认载荷为增量文本，开启进度字段时为 `ChunkPayload`。 */
+fn emit_chunk(
+    app: &tauri::AppHandle,
+    stream_id: &str,
+    text: String,
+    counter: Option<&mut llm::ChunkCounter>,
+) {
+    let stream_id = stream_id.to_string();
+    match counter {
+        Some(counter) => emit_event(
+            app,
+            "dq:chunk",
+            &StreamEventPayload {
+                stream_id,
+                data: counter.payload(text),
+            },
+        ),
+        None => emit_event(
+            app,
+            "dq:chunk",
+            &StreamEventPayload {
+                stream_id,
+                data: text,
+            },
+        ),
+    }
+}
+
 fn anyhow_to_string(err: anyhow::Error) -> String {
     err.to_string()
 }
@@ -142,6 +269,10 @@ fn provider_secret_alias(id: i64) -> String {
     format!("{SECRET_PREFIX}:{id}")
 }
 
+fn provider_signing_alias(id: i64) -> String {
+    format!("{SECRET_PREFIX}:{id}:signing")
+}
+
 fn store_provider_secret(app: &tauri::AppHandle, alias: &str, key: &str) -> Result<(), String> {
     let req = OptionsRequest {
         prefixed_key: Some(alias.to_string()),
@@ -168,6 +299,87 @@ fn load_provider_secret(app: &tauri::AppHandle, alias: &str) -> Result<Option<St
         .map_err(|e| e.to_string())
 }
 
+/**
+ * \brief 从安全存储中删除密钥条目；条目不存在时直接返回。
+ * \details 旧版本以写入空串表示清除，这类空串条目同样会被删除。
+ */
+fn clear_provider_secret(app: &tauri::AppHandle, alias: &str) -> Result<(), String> {
+    if load_provider_secret(app, alias)?.is_none() {
+        return Ok(());
+    }
+    let req = OptionsRequest {
+        prefixed_key: Some(alias.to_string()),
+        data: None,
+        sync: None,
+        keychain_access: None,
+    };
+    app.secure_storage()
+        .remove_item(app.clone(), req)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/**
+ * \brief 以安全存储为后端的密钥来源，供 SDK 诊断等只依赖 `SecretStore` 的功能使用。
+ */
+struct SecureStorageSecrets(tauri::AppHandle);
+
+impl client::SecretStore for SecureStorageSecrets {
+    fn get(&self, alias: &str) -> anyhow::Result<Option<String>> {
+        load_provider_secret(&self.0, alias)
+            .map(|secret| secret.filter(|s| !s.is_empty()))
+            .map_err(anyhow::Error::msg)
+    }
+}
+
+fn secret_exists(app: &tauri::AppHandle, alias: &str) -> Result<bool, String> {
+    Ok(load_provider_secret(app, alias)?
+        .map(|secret| !secret.is_empty())
+        .unwrap_or(false))
+}
+
+/**
+ * \brief 汇总数据库引用的别名与按主键推算的候选别名，标记孤立项。
+ */
+fn collect_secret_aliases(
+    app: &tauri::AppHandle,
+    conn: &rusqlite::Connection,
+) -> Result<Vec<SecretAliasDto>, String> {
+    let providers = db::list_providers(conn).map_err(anyhow_to_string)?;
+    let high_water = db::provider_id_high_water(conn).map_err(anyhow_to_string)?;
+
+    let mut items = Vec::new();
+    let mut seen = HashSet::new();
+    for provider in &providers {
+        if let Some(alias) = provider.secret_alias.clone() {
+            seen.insert(alias.clone());
+            items.push(SecretAliasDto {
+                has_secret: secret_exists(app, &alias)?,
+                alias,
+                provider_id: Some(provider.id),
+                provider_name: Some(provider.name.clone()),
+                orphaned: false,
+            });
+        }
+    }
+    for id in 1..=high_water {
+        let alias = provider_secret_alias(id);
+        if seen.contains(&alias) {
+            continue;
+        }
+        if secret_exists(app, &alias)? {
+            items.push(SecretAliasDto {
+                alias,
+                provider_id: None,
+                provider_name: None,
+                has_secret: true,
+                orphaned: true,
+            });
+        }
+    }
+    Ok(items)
+}
+
 fn hydrate_provider_secret(
     app: &tauri::AppHandle,
     provider: &mut dreamquill_core_sdk::models::Provider,
@@ -181,6 +393,40 @@ fn hydrate_provider_secret(
             }
         }
     }
+    if let Some(signing) = provider.signing.as_mut().filter(|s| s.secret.is_empty()) {
+        signing.secret = load_provider_secret(app, &provider_signing_alias(provider.id))?
+            .filter(|secret| !secret.is_empty())
+            .ok_or_else(|| "未找到请求签名密钥，请重新配置".to_string())?;
+    }
+    Ok(())
+}
+
+/**
+ * \brief 记录桌面端审计日志；写入失败只记遥测。
+ */
+fn record_audit(
+    conn: &rusqlite::Connection,
+    action: &str,
+    target: Option<String>,
+    changes: serde_json::Value,
+) {
+    if let Err(e) = db::insert_audit_log(conn, "desktop", action, target.as_deref(), &changes) {
+        telemetry::log_error("desktop.audit", &format!("audit write failed: {}", e));
+    }
+}
+
+fn apply_telemetry_setting(conn: &rusqlite::Connection, enabled: bool) -> Result<(), String> {
+    let previous = db::get_telemetry_enabled(conn).map_err(anyhow_to_string)?;
+    db::set_telemetry_enabled(conn, enabled).map_err(anyhow_to_string)?;
+    telemetry::set_enabled(enabled);
+    if previous != enabled {
+        record_audit(
+            conn,
+            "settings.update",
+            None,
+            serde_json::json!({"telemetry_enabled": {"from": previous, "to": enabled}}),
+        );
+    }
     Ok(())
 }
 
@@ -202,6 +448,9 @@ fn build_state(conn: &rusqlite::Connection) -> Result<ProviderStateDto, anyhow::
                 p.api_key
             },
             model: p.model,
+            generation: p.generation,
+            proxy_url: p.proxy_url,
+            api_key_unreadable: p.api_key_unreadable,
             is_default: default_id.map(|d| d == p.id).unwrap_or(false),
         })
         .collect();
@@ -288,26 +537,37 @@ fn pick_provider(
         }
         hydrate_provider_secret(app_handle, &mut provider)?;
     }
+    if let Some(chat_id_value) = chat_id {
+        db::apply_chat_model(conn, chat_id_value, &mut provider).map_err(anyhow_to_string)?;
+    }
     Ok(provider)
 }
 
 #[tauri::command]
 async fn dq_get_config() -> Result<ProviderStateDto, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
     build_state(&conn).map_err(anyhow_to_string)
 }
 
+/** \brief 校验表单中的代理地址，空白视为未设置。 */
+fn validate_proxy(proxy_url: Option<&str>) -> Result<(), String> {
+    match proxy_url.map(str::trim).filter(|u| !u.is_empty()) {
+        Some(url) => llm::parse_proxy_url(url)
+            .map(|_| ())
+            .map_err(anyhow_to_string),
+        None => Ok(()),
+    }
+}
+
 #[tauri::command]
 async fn dq_create_provider(
     app: tauri::AppHandle,
     payload: ProviderRequestDto,
 ) -> Result<ProviderStateDto, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
+    validate_proxy(payload.proxy_url.as_deref())?;
     if let Some(enabled) = payload.telemetry_enabled {
-        db::set_telemetry_enabled(&conn, enabled).map_err(anyhow_to_string)?;
-        telemetry::set_enabled(enabled);
+        apply_telemetry_setting(&conn, enabled)?;
     }
     let key_input_trimmed = payload.api_key.trim();
     let sanitized_api_key = if key_input_trimmed.is_empty() {
@@ -345,6 +605,24 @@ async fn dq_create_provider(
     } else {
         db::set_provider_secret_alias(&conn, id, None).map_err(anyhow_to_string)?;
     }
+    db::set_provider_generation(&conn, id, &payload.generation.clone().normalized())
+        .map_err(anyhow_to_string)?;
+    db::set_provider_proxy(&conn, id, payload.proxy_url.as_deref()).map_err(anyhow_to_string)?;
+    let created = db::get_provider_by_id(&conn, id).map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "provider.create",
+        Some(format!("provider:{}", id)),
+        db::provider_changes(None, created.as_ref()),
+    );
+    if payload.set_default.unwrap_or(false) {
+        record_audit(
+            &conn,
+            "provider.select_default",
+            Some(format!("provider:{}", id)),
+            serde_json::json!({}),
+        );
+    }
     telemetry::log_event(
         "desktop.provider",
         &format!("create name={} type={}", payload.name, payload.provider),
@@ -359,10 +637,10 @@ async fn dq_update_provider(
     payload: ProviderRequestDto,
 ) -> Result<ProviderStateDto, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
     let existing = db::get_provider_by_id(&conn, id)
         .map_err(anyhow_to_string)?
         .ok_or_else(|| "指定的 Provider 不存在".to_string())?;
+    validate_proxy(payload.proxy_url.as_deref())?;
 
     let key_input_trimmed = payload.api_key.trim();
     let mut alias = existing.secret_alias.clone();
@@ -388,12 +666,31 @@ async fn dq_update_provider(
         alias.as_deref(),
     )
     .map_err(anyhow_to_string)?;
+    db::set_provider_generation(&conn, id, &payload.generation.clone().normalized())
+        .map_err(anyhow_to_string)?;
+    db::set_provider_proxy(&conn, id, payload.proxy_url.as_deref()).map_err(anyhow_to_string)?;
+    let updated = db::get_provider_by_id(&conn, id).map_err(anyhow_to_string)?;
+    let mut changes = db::provider_changes(Some(&existing), updated.as_ref());
+    if !key_input_trimmed.is_empty() {
+        changes["api_key"] = serde_json::json!("changed");
+    }
+    record_audit(
+        &conn,
+        "provider.update",
+        Some(format!("provider:{}", id)),
+        changes,
+    );
     if payload.set_default.unwrap_or(false) {
         db::set_default_provider_id(&conn, id).map_err(anyhow_to_string)?;
+        record_audit(
+            &conn,
+            "provider.select_default",
+            Some(format!("provider:{}", id)),
+            serde_json::json!({}),
+        );
     }
     if let Some(enabled) = payload.telemetry_enabled {
-        db::set_telemetry_enabled(&conn, enabled).map_err(anyhow_to_string)?;
-        telemetry::set_enabled(enabled);
+        apply_telemetry_setting(&conn, enabled)?;
     }
     telemetry::log_event(
         "desktop.provider",
@@ -405,75 +702,220 @@ async fn dq_update_provider(
 #[tauri::command]
 async fn dq_delete_provider(app: tauri::AppHandle, id: i64) -> Result<ProviderStateDto, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
-    if let Some(provider) = db::get_provider_by_id(&conn, id).map_err(anyhow_to_string)? {
-        if let Some(alias) = provider.secret_alias {
-            let _ = store_provider_secret(&app, &alias, "");
-        }
+    let before = db::get_provider_by_id(&conn, id).map_err(anyhow_to_string)?;
+    if let Some(alias) = before.as_ref().and_then(|p| p.secret_alias.as_deref()) {
+        let _ = clear_provider_secret(&app, alias);
     }
     db::delete_provider(&conn, id).map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "provider.delete",
+        Some(format!("provider:{}", id)),
+        db::provider_changes(before.as_ref(), None),
+    );
     telemetry::log_event("desktop.provider", &format!("delete id={}", id));
     build_state(&conn).map_err(anyhow_to_string)
 }
 
+/** @brief 列出安全存储别名及其与 Provider 的对应关系。 */
+#[tauri::command]
+async fn dq_list_secret_aliases(app: tauri::AppHandle) -> Result<Vec<SecretAliasDto>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    collect_secret_aliases(&app, &conn)
+}
+
+/** @brief 清理未被任何 Provider 引用的密钥；`dry_run` 为真时仅返回将清理的别名。 */
+#[tauri::command]
+async fn dq_prune_secrets(
+    app: tauri::AppHandle,
+    dry_run: Option<bool>,
+) -> Result<SecretPruneResultDto, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let dry_run = dry_run.unwrap_or(true);
+    let items = collect_secret_aliases(&app, &conn)?;
+
+    let mut pruned = Vec::new();
+    let mut missing = Vec::new();
+    for item in items {
+        if item.orphaned {
+            if !dry_run {
+                clear_provider_secret(&app, &item.alias)?;
+            }
+            pruned.push(item.alias);
+        } else if !item.has_secret {
+            missing.push(item.alias);
+        }
+    }
+    telemetry::log_event(
+        "desktop.secrets",
+        &format!(
+            "prune dry_run={} pruned={} missing={}",
+            dry_run,
+            pruned.len(),
+            missing.len()
+        ),
+    );
+    Ok(SecretPruneResultDto {
+        dry_run,
+        pruned,
+        missing,
+    })
+}
+
 #[tauri::command]
 async fn dq_select_provider(id: i64) -> Result<ProviderStateDto, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
+    let previous = db::get_default_provider_id(&conn).map_err(anyhow_to_string)?;
     db::set_default_provider_id(&conn, id).map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "provider.select_default",
+        Some(format!("provider:{}", id)),
+        serde_json::json!({"default_provider_id": {"from": previous, "to": id}}),
+    );
     telemetry::log_event("desktop.provider", &format!("select-default id={}", id));
     build_state(&conn).map_err(anyhow_to_string)
 }
 
+/**
+ * \brief 设置或清除 Provider 的请求签名；签名密钥存入安全存储，数据库只保留请求头配置。
+ */
 #[tauri::command]
-async fn dq_list_chats() -> Result<Vec<ChatSummaryDto>, String> {
+async fn dq_set_provider_signing(
+    app: tauri::AppHandle,
+    id: i64,
+    signing: Option<dreamquill_core_sdk::models::RequestSigning>,
+) -> Result<ProviderStateDto, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
-    let chats = db::list_chats(&conn, None).map_err(anyhow_to_string)?;
-    Ok(chats
-        .into_iter()
-        .map(|chat| ChatSummaryDto {
-            id: chat.id,
-            title: chat.title,
-            provider_id: chat.provider_id,
-        })
-        .collect())
+    let before = db::get_provider_by_id(&conn, id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "指定的模型服务不存在".to_string())?;
+    let alias = provider_signing_alias(id);
+    let stored = match signing {
+        Some(signing) => {
+            if signing.header.trim().is_empty() || signing.secret.is_empty() {
+                return Err("签名请求头与密钥不能为空".to_string());
+            }
+            store_provider_secret(&app, &alias, &signing.secret)?;
+            Some(dreamquill_core_sdk::models::RequestSigning {
+                secret: String::new(),
+                ..signing
+            })
+        }
+        None => {
+            clear_provider_secret(&app, &alias)?;
+            None
+        }
+    };
+    db::set_provider_signing(&conn, id, stored.as_ref()).map_err(anyhow_to_string)?;
+    let header_of = |s: Option<&dreamquill_core_sdk::models::RequestSigning>| {
+        s.map(|s| s.header.trim().to_string())
+    };
+    record_audit(
+        &conn,
+        "provider.signing",
+        Some(format!("provider:{}", id)),
+        serde_json::json!({"signing_header": {
+            "from": header_of(before.signing.as_ref()),
+            "to": header_of(stored.as_ref()),
+        }}),
+    );
+    build_state(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 列出会话，置顶的在前；默认不含已归档会话。
+ */
+#[tauri::command]
+async fn dq_list_chats(include_archived: Option<bool>) -> Result<Vec<ChatSummaryDto>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let chats =
+        db::list_chats(&conn, None, include_archived.unwrap_or(false)).map_err(anyhow_to_string)?;
+    Ok(chats.into_iter().map(ChatSummaryDto::from).collect())
+}
+
+/** @brief 创建空会话，便于在发送首条消息前设置人设与上下文。 */
+#[tauri::command]
+async fn dq_create_chat(payload: CreateChatRequestDto) -> Result<ChatSummaryDto, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let provider = match payload.provider_id {
+        Some(pid) => Some(
+            db::get_provider_by_id(&conn, pid)
+                .map_err(anyhow_to_string)?
+                .ok_or_else(|| "指定的模型服务不存在".to_string())?,
+        ),
+        None => db::get_default_provider(&conn).map_err(anyhow_to_string)?,
+    };
+    let title = payload
+        .title
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .unwrap_or_else(|| match &provider {
+            Some(p) => format!("{} 会话", p.name),
+            None => "新会话".to_string(),
+        });
+    let provider_id = provider.as_ref().map(|p| p.id);
+    let chat_id =
+        db::create_empty_chat(&conn, &title, provider_id, payload.system_prompt.as_deref())
+            .map_err(anyhow_to_string)?;
+    telemetry::log_event(
+        "desktop.chat",
+        &format!("create chat id={} provider={:?}", chat_id, provider_id),
+    );
+    Ok(ChatSummaryDto {
+        id: chat_id,
+        title,
+        provider_id,
+        last_read_message_id: None,
+        pinned: false,
+        archived: false,
+        model: None,
+    })
 }
 
 #[tauri::command]
 async fn dq_get_chat_messages(chat_id: i64) -> Result<ChatMessagesDto, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
     let provider = db::get_provider_for_chat(&conn, chat_id).map_err(anyhow_to_string)?;
     let messages = db::load_messages_with_meta(&conn, chat_id).map_err(anyhow_to_string)?;
     Ok(ChatMessagesDto {
         chat_id,
         provider_id: provider.map(|p| p.id),
-        messages: messages
+        messages: messages.into_iter().map(StoredMessageDto::from).collect(),
+    })
+}
+
+/**
+ * \brief 按游标分页获取会话消息，打开长会话时先取最新一页，再按需向前加载。
+ */
+#[tauri::command]
+async fn dq_get_chat_messages_page(
+    chat_id: i64,
+    before: Option<i64>,
+    limit: Option<i64>,
+) -> Result<ChatMessagesPageDto, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let provider = db::get_provider_for_chat(&conn, chat_id).map_err(anyhow_to_string)?;
+    let page = db::load_messages_page(&conn, chat_id, before, limit.unwrap_or(50))
+        .map_err(anyhow_to_string)?;
+    Ok(ChatMessagesPageDto {
+        chat_id,
+        provider_id: provider.map(|p| p.id),
+        messages: page
+            .messages
             .into_iter()
-            .map(|msg| StoredMessageDto {
-                id: msg.id,
-                role: msg.role,
-                content: msg.content,
-            })
+            .map(StoredMessageDto::from)
             .collect(),
+        next_cursor: page.next_cursor,
     })
 }
 
 #[tauri::command]
 async fn dq_delete_chat(chat_id: i64) -> Result<Vec<ChatSummaryDto>, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
     db::delete_chat(&conn, chat_id).map_err(anyhow_to_string)?;
-    let chats = db::list_chats(&conn, None).map_err(anyhow_to_string)?;
-    Ok(chats
-        .into_iter()
-        .map(|chat| ChatSummaryDto {
-            id: chat.id,
-            title: chat.title,
-            provider_id: chat.provider_id,
-        })
-        .collect())
+    let chats = db::list_chats(&conn, None, false).map_err(anyhow_to_string)?;
+    Ok(chats.into_iter().map(ChatSummaryDto::from).collect())
 }
 
 #[tauri::command]
@@ -482,7 +924,6 @@ async fn dq_branch_chat(
     payload: BranchRequestDto,
 ) -> Result<BranchResultDto, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
     let telemetry_enabled = db::get_telemetry_enabled(&conn).map_err(anyhow_to_string)?;
     telemetry::set_enabled(telemetry_enabled);
 
@@ -504,57 +945,273 @@ async fn dq_branch_chat(
     })
 }
 
+/**
+ * \brief 显式切换或解除会话绑定的模型服务，并广播 `dq:chat-updated` 事件。
+ */
 #[tauri::command]
-async fn dq_rename_chat(chat_id: i64, title: String) -> Result<ChatSummaryDto, String> {
-    let trimmed = title.trim();
-    if trimmed.is_empty() {
-        return Err("会话标题不能为空".to_string());
-    }
-
+async fn dq_set_chat_provider(
+    app: tauri::AppHandle,
+    chat_id: i64,
+    provider_id: Option<i64>,
+) -> Result<ChatSummaryDto, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
-    db::update_chat_title(&conn, chat_id, trimmed)
-        .map_err(anyhow_to_string)?;
-    let provider = db::get_provider_for_chat(&conn, chat_id).map_err(anyhow_to_string)?;
+    let chat = db::get_chat(&conn, chat_id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "会话不存在".to_string())?;
+    if let Some(pid) = provider_id {
+        if db::get_provider_by_id(&conn, pid)
+            .map_err(anyhow_to_string)?
+            .is_none()
+        {
+            return Err("指定的模型服务不存在".to_string());
+        }
+    }
+    db::set_chat_provider(&conn, chat_id, provider_id).map_err(anyhow_to_string)?;
     telemetry::log_event(
         "desktop.chat",
-        &format!("rename chat id={} title={}", chat_id, trimmed),
+        &format!(
+            "set chat provider id={} from={:?} to={:?}",
+            chat_id, chat.provider_id, provider_id
+        ),
     );
-    Ok(ChatSummaryDto {
-        id: chat_id,
-        title: trimmed.to_string(),
-        provider_id: provider.map(|p| p.id),
-    })
+    let summary = chat_summary_dto(&conn, chat_id)?;
+    if let Err(e) = app.emit("dq:chat-updated", &summary) {
+        eprintln!("emit dq:chat-updated failed: {}", e);
+    }
+    Ok(summary)
 }
 
+/**
+ * \brief 设置会话级模型，None 或空字符串恢复 Provider 默认模型，并广播 `dq:chat-updated` 事件。
+ */
 #[tauri::command]
-async fn dq_list_models(
+async fn dq_set_chat_model(
     app: tauri::AppHandle,
-    provider_id: Option<i64>,
-) -> Result<Vec<String>, String> {
+    chat_id: i64,
+    model: Option<String>,
+) -> Result<ChatSummaryDto, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
-    let provider = pick_provider(Some(&app), &conn, None, provider_id)?;
-    llm::list_models(&provider).await.map_err(anyhow_to_string)
+    let chat = db::get_chat(&conn, chat_id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "会话不存在".to_string())?;
+    db::set_chat_model(&conn, chat_id, model.as_deref()).map_err(anyhow_to_string)?;
+    let summary = chat_summary_dto(&conn, chat_id)?;
+    telemetry::log_event(
+        "desktop.chat",
+        &format!(
+            "set chat model id={} from={:?} to={:?}",
+            chat_id, chat.model, summary.model
+        ),
+    );
+    if let Err(e) = app.emit("dq:chat-updated", &summary) {
+        eprintln!("emit dq:chat-updated failed: {}", e);
+    }
+    Ok(summary)
 }
 
+/**
+ * \brief 查询会话是否开启流中断自动续写。
+ */
 #[tauri::command]
-async fn dq_send_chat(
-    app: tauri::AppHandle,
-    prompt: String,
-    chat_id: Option<i64>,
-    provider_id: Option<i64>,
-    stream: Option<bool>,
-    debug: Option<bool>,
-    regen_message_id: Option<i64>,
-) -> Result<ChatResultDto, String> {
-    let prompt_trimmed = prompt.trim();
-    if regen_message_id.is_some() && !prompt_trimmed.is_empty() {
-        return Err("prompt 与 regen_message_id 不可同时提供".to_string());
-    }
-
+async fn dq_get_chat_stream_retry(chat_id: i64) -> Result<bool, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_chat(&conn, chat_id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "会话不存在".to_string())?;
+    db::get_chat_stream_retry(&conn, chat_id).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 开关会话的流中断自动续写。
+ */
+#[tauri::command]
+async fn dq_set_chat_stream_retry(chat_id: i64, enabled: bool) -> Result<bool, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_chat(&conn, chat_id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "会话不存在".to_string())?;
+    db::set_chat_stream_retry(&conn, chat_id, enabled).map_err(anyhow_to_string)?;
+    telemetry::log_event(
+        "desktop.chat",
+        &format!("set chat stream_retry id={} enabled={}", chat_id, enabled),
+    );
+    Ok(enabled)
+}
+
+/**
+ * \brief 查询会话的上下文选择策略。
+ */
+#[tauri::command]
+async fn dq_get_chat_context_strategy(
+    chat_id: i64,
+) -> Result<dreamquill_core_sdk::models::ContextStrategy, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_chat(&conn, chat_id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "会话不存在".to_string())?;
+    db::get_chat_context_strategy(&conn, chat_id).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 设置会话的上下文选择策略：全部历史或按相关性挑选。
+ */
+#[tauri::command]
+async fn dq_set_chat_context_strategy(
+    chat_id: i64,
+    strategy: dreamquill_core_sdk::models::ContextStrategy,
+) -> Result<dreamquill_core_sdk::models::ContextStrategy, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_chat(&conn, chat_id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "会话不存在".to_string())?;
+    db::set_chat_context_strategy(&conn, chat_id, &strategy).map_err(anyhow_to_string)?;
+    telemetry::log_event(
+        "desktop.chat",
+        &format!(
+            "set chat context_strategy id={} strategy={:?}",
+            chat_id, strategy
+        ),
+    );
+    Ok(strategy)
+}
+
+/**
+ * \brief 读取会话的回复语言设置，未设置时为 None。
+ */
+#[tauri::command]
+async fn dq_get_chat_reply_language(
+    chat_id: i64,
+) -> Result<Option<dreamquill_core_sdk::models::ReplyLanguage>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_chat_reply_language(&conn, chat_id).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 设置会话的回复语言，None 清除；开启约束后回复语言不符会自动重问一次。
+ */
+#[tauri::command]
+async fn dq_set_chat_reply_language(
+    chat_id: i64,
+    setting: Option<dreamquill_core_sdk::models::ReplyLanguage>,
+) -> Result<Option<dreamquill_core_sdk::models::ReplyLanguage>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_chat(&conn, chat_id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "会话不存在".to_string())?;
+    db::set_chat_reply_language(&conn, chat_id, setting.as_ref()).map_err(anyhow_to_string)?;
+    telemetry::log_event(
+        "desktop.chat",
+        &format!(
+            "set chat reply_language id={} setting={:?}",
+            chat_id, setting
+        ),
+    );
+    db::get_chat_reply_language(&conn, chat_id).map_err(anyhow_to_string)
+}
+
+#[tauri::command]
+async fn dq_rename_chat(chat_id: i64, title: String) -> Result<ChatSummaryDto, String> {
+    let trimmed = title.trim();
+    if trimmed.is_empty() {
+        return Err("会话标题不能为空".to_string());
+    }
+
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::update_chat_title(&conn, chat_id, trimmed).map_err(anyhow_to_string)?;
+    let provider = db::get_provider_for_chat(&conn, chat_id).map_err(anyhow_to_string)?;
+    let chat = db::get_chat(&conn, chat_id).map_err(anyhow_to_string)?;
+    telemetry::log_event(
+        "desktop.chat",
+        &format!("rename chat id={} title={}", chat_id, trimmed),
+    );
+    Ok(ChatSummaryDto {
+        id: chat_id,
+        title: trimmed.to_string(),
+        provider_id: provider.map(|p| p.id),
+        last_read_message_id: chat.as_ref().and_then(|c| c.last_read_message_id),
+        pinned: chat.as_ref().is_some_and(|c| c.pinned),
+        archived: chat.as_ref().is_some_and(|c| c.archived),
+        model: chat.and_then(|c| c.model),
+    })
+}
+
+/**
+ * \brief 置顶或取消置顶会话。
+ */
+#[tauri::command]
+async fn dq_set_chat_pinned(chat_id: i64, pinned: bool) -> Result<ChatSummaryDto, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::set_chat_pinned(&conn, chat_id, pinned).map_err(anyhow_to_string)?;
+    chat_summary_dto(&conn, chat_id)
+}
+
+/**
+ * \brief 归档或恢复会话；归档的会话不出现在默认列表中。
+ */
+#[tauri::command]
+async fn dq_set_chat_archived(chat_id: i64, archived: bool) -> Result<ChatSummaryDto, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::set_chat_archived(&conn, chat_id, archived).map_err(anyhow_to_string)?;
+    chat_summary_dto(&conn, chat_id)
+}
+
+fn chat_summary_dto(conn: &rusqlite::Connection, chat_id: i64) -> Result<ChatSummaryDto, String> {
+    db::get_chat(conn, chat_id)
+        .map_err(anyhow_to_string)?
+        .map(ChatSummaryDto::from)
+        .ok_or_else(|| "会话不存在".to_string())
+}
+
+/**
+ * \brief 标记会话已读到指定消息，省略 `message_id` 时标记到最新消息。
+ */
+#[tauri::command]
+async fn dq_mark_read(chat_id: i64, message_id: Option<i64>) -> Result<ChatSummaryDto, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let chat = db::get_chat(&conn, chat_id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "会话不存在".to_string())?;
+    let last_read_message_id = db::mark_chat_read(&conn, chat_id, message_id)
+        .map_err(anyhow_to_string)?
+        .or(chat.last_read_message_id);
+    Ok(ChatSummaryDto {
+        last_read_message_id,
+        ..ChatSummaryDto::from(chat)
+    })
+}
+
+#[tauri::command]
+async fn dq_list_models(
+    app: tauri::AppHandle,
+    provider_id: Option<i64>,
+) -> Result<Vec<String>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let provider = pick_provider(Some(&app), &conn, None, provider_id)?;
+    model_cache::list_models_cached(&provider)
+        .await
+        .map_err(anyhow_to_string)
+}
+
+#[tauri::command]
+async fn dq_send_chat(
+    app: tauri::AppHandle,
+    prompt: String,
+    chat_id: Option<i64>,
+    provider_id: Option<i64>,
+    stream: Option<bool>,
+    debug: Option<bool>,
+    regen_message_id: Option<i64>,
+    overrides: Option<llm::RequestOverrides>,
+    quote_ids: Option<Vec<i64>>,
+    redact_prompt: Option<bool>,
+) -> Result<ChatResultDto, String> {
+    let overrides = overrides.unwrap_or_default().normalized();
+    let prompt_trimmed = prompt.trim();
+    if regen_message_id.is_some() && !prompt_trimmed.is_empty() {
+        return Err("prompt 与 regen_message_id 不可同时提供".to_string());
+    }
+
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
 
     let provider = pick_provider(Some(&app), &conn, chat_id, provider_id)?;
     let telemetry_enabled = db::get_telemetry_enabled(&conn).map_err(anyhow_to_string)?;
@@ -571,6 +1228,8 @@ async fn dq_send_chat(
         }
     };
 
+    let redact_prompt = redact_prompt.unwrap_or(false);
+    let mut redacted_id = None;
     if let Some(message_id) = regen_message_id {
         let metas = db::load_messages_with_meta(&conn, chat_id).map_err(anyhow_to_string)?;
         let target = metas
@@ -580,15 +1239,36 @@ async fn dq_send_chat(
         if target.role != "assistant" {
             return Err("仅支持对助手消息重新生成".to_string());
         }
-        db::delete_messages_from(&conn, chat_id, message_id).map_err(anyhow_to_string)?;
+        db::supersede_and_truncate(&conn, chat_id, message_id).map_err(anyhow_to_string)?;
     } else {
         if prompt_trimmed.is_empty() {
             return Err("发送内容不能为空".to_string());
         }
-        db::insert_message(&conn, chat_id, "user", prompt_trimmed).map_err(anyhow_to_string)?;
+        let quotes = db::resolve_quotes(&conn, chat_id, quote_ids.as_deref().unwrap_or_default())
+            .map_err(anyhow_to_string)?;
+        if redact_prompt {
+            redacted_id = Some(
+                db::insert_redacted_user_message(&conn, chat_id, &quotes)
+                    .map_err(anyhow_to_string)?,
+            );
+        } else {
+            db::insert_user_message(&conn, chat_id, prompt_trimmed, &quotes)
+                .map_err(anyhow_to_string)?;
+        }
     }
 
-    let messages = db::load_messages(&conn, chat_id).map_err(anyhow_to_string)?;
+    let mut plan = context::plan(&conn, chat_id, Some(&provider)).map_err(anyhow_to_string)?;
+    if let Some(message_id) = redacted_id {
+        plan = plan.with_redacted_prompt(message_id, prompt_trimmed);
+    }
+    let selection = plan.select(Some(&provider)).await;
+    if let Err(err) = selection.save_embeddings(&conn) {
+        telemetry::log_event(
+            "desktop.chat",
+            &format!("embedding cache write failed: {}", err),
+        );
+    }
+    let messages = selection.messages;
 
     let mut logs = Vec::new();
     let debug_flag = debug.unwrap_or(false);
@@ -598,7 +1278,7 @@ async fn dq_send_chat(
             provider.name,
             provider.provider_type,
             provider.api_base,
-            provider.model,
+            overrides.model_for(&provider),
             chat_id,
             messages.len()
         ));
@@ -626,13 +1306,33 @@ async fn dq_send_chat(
 
     let prefer_stream = stream.unwrap_or(true);
     let mut reply = String::new();
+    let started = std::time::Instant::now();
+    let mut first_token_ms: Option<i64> = None;
+    let mut trimmer =
+        llm::StopTrimmer::new(&db::get_stop_strings(&conn).map_err(anyhow_to_string)?)
+            .with_max_chars(db::get_max_output_chars(&conn).map_err(anyhow_to_string)?);
 
     if prefer_stream {
-        match llm::stream_chat(&provider, &messages).await {
+        let (opened, retries) =
+            llm::count_retries(llm::stream_chat_with(&provider, &messages, &overrides))
+                .instrument(telemetry::chat_span(chat_id, &provider))
+                .await;
+        if debug_flag && retries > 0 {
+            logs.push(format!("retries -> {} before first token", retries));
+        }
+        match opened {
             Ok(mut s) => {
                 while let Some(item) = s.as_mut().next().await {
                     match item {
-                        Ok(delta) => reply.push_str(&delta),
+                        Ok(delta) => {
+                            if first_token_ms.is_none() {
+                                first_token_ms = Some(started.elapsed().as_millis() as i64);
+                            }
+                            reply.push_str(&trimmer.push(&delta));
+                            if trimmer.stopped() {
+                                break;
+                            }
+                        }
                         Err(err) => {
                             let msg = format!("stream err: {}", err);
                             logs.push(msg.clone());
@@ -641,38 +1341,143 @@ async fn dq_send_chat(
                         }
                     }
                 }
+                reply.push_str(&trimmer.finish());
             }
             Err(err) => {
                 let msg = format!("stream failed: {}", err);
                 logs.push(msg.clone());
                 telemetry::log_error("desktop.chat", &msg);
-                reply = llm::chat_once(&provider, &messages)
+                let full = llm::chat_once_with(&provider, &messages, &overrides)
+                    .instrument(telemetry::chat_span(chat_id, &provider))
                     .await
                     .map_err(anyhow_to_string)?;
+                reply = trimmer.trim_full(&full);
             }
         }
     } else {
-        reply = llm::chat_once(&provider, &messages)
+        let full = llm::chat_once_with(&provider, &messages, &overrides)
+            .instrument(telemetry::chat_span(chat_id, &provider))
             .await
             .map_err(anyhow_to_string)?;
+        reply = trimmer.trim_full(&full);
+    }
+    if let Some(reason) = trimmer.truncation().filter(|_| debug_flag) {
+        logs.push(format!(
+            "output truncated ({}), trimmed -> {:?}",
+            reason,
+            trimmer.remainder()
+        ));
     }
 
     if reply.is_empty() {
         return Err("模型未返回任何内容".to_string());
     }
 
-    db::insert_message(&conn, chat_id, "assistant", &reply).map_err(anyhow_to_string)?;
+    let timing = db::GenerationTiming {
+        provider_id: Some(provider.id),
+        first_token_ms: first_token_ms.or(Some(started.elapsed().as_millis() as i64)),
+        duration_ms: Some(started.elapsed().as_millis() as i64),
+    };
+    let done = finalize::finalize(
+        &conn,
+        &finalize::FinishedReply {
+            chat_id,
+            content: &reply,
+            prompt: &messages,
+            timing,
+            metadata: trimmer.annotate(overrides.to_metadata()),
+            partial: false,
+        },
+    )
+    .map_err(anyhow_to_string)?;
+    let message_id = done.message_id;
+    let first_title = finalize::spawn_followups(&conn, &provider, chat_id, &done);
+    drop(conn);
+    match retry_refusal(&app, &provider, message_id, &reply).await {
+        Some(Ok(variant)) => logs.push(format!(
+            "refusal detected, retry saved as variant {}",
+            variant.id
+        )),
+        Some(Err(e)) => logs.push(format!("refusal retry failed: {}", e)),
+        None => match enforce_reply_language(&provider, chat_id, message_id, &reply).await {
+            Some(Ok(variant)) => logs.push(format!(
+                "reply language mismatch, re-ask saved as variant {}",
+                variant.id
+            )),
+            Some(Err(e)) => logs.push(format!("language re-ask failed: {}", e)),
+            None => {}
+        },
+    }
+
+    let title = backfill::wait_first_title(first_title).await;
 
     Ok(ChatResultDto {
         chat_id,
         reply,
         logs,
+        title,
+        truncated: trimmer.stopped(),
     })
 }
 
+/**
+ * \brief 回复被判定为拒答且策略开启自动重试时，用备用（或原）Provider 重试一次，结果保存为备选回复。
+ * \details 未触发重试时返回 None；数据库连接不跨越 await 持有。
+ */
+async fn retry_refusal(
+    app: &tauri::AppHandle,
+    provider: &dreamquill_core_sdk::models::Provider,
+    message_id: i64,
+    content: &str,
+) -> Option<Result<db::MessageVariant, String>> {
+    let (policy, retry_provider) = {
+        let conn = db::open_default_db().ok()?;
+        let policy = refusal::retry_policy_for(&conn, content)?;
+        let retry_provider = match policy.provider_id {
+            Some(pid) => match pick_provider(Some(app), &conn, None, Some(pid)) {
+                Ok(p) => p,
+                Err(e) => return Some(Err(e)),
+            },
+            None => provider.clone(),
+        };
+        (policy, retry_provider)
+    };
+    Some(
+        refusal::retry(&retry_provider, message_id, &policy)
+            .await
+            .map_err(anyhow_to_string),
+    )
+}
+
+/**
+ * \brief 会话开启回复语言约束且回复语言不符时，以更强的语言指令重问一次，结果保存为备选回复。
+ * \details 未触发时返回 None；数据库连接不跨越 await 持有。
+ */
+async fn enforce_reply_language(
+    provider: &dreamquill_core_sdk::models::Provider,
+    chat_id: i64,
+    message_id: i64,
+    content: &str,
+) -> Option<Result<db::MessageVariant, String>> {
+    let mismatch = {
+        let conn = db::open_default_db().ok()?;
+        language::mismatch_for(&conn, chat_id, content)?
+    };
+    Some(
+        language::enforce(provider, message_id, &mismatch)
+            .await
+            .map_err(anyhow_to_string),
+    )
+}
+
 /**
  * \brief 流式聊天（通过事件推送到前端）。
- * \details 前端需监听 `dq:meta`/`dq:log`/`dq:chunk`/`dq:error`/`dq:end`，并根据 `stream_id` 过滤所属事件。
+ * \details 前端需监听 `dq:meta`/`dq:log`/`dq:chunk`/`dq:error`/`dq:variant`/`dq:cancelled`/`dq:end`，并根据 `stream_id` 过滤所属事件；
+ * `dq:variant` 仅在拒答自动重试或回复语言重问后出现，携带保存的备选回复；
+ * `dq:cancelled` 在 `dq_cancel_stream` 取消后、`dq:end` 之前发送，data 为 `{chat_id, message_id, kept_chars}`，
+ * 已收到的部分保存为 `partial` 消息（未收到任何内容时 `message_id` 为空）。
+ * `progress` 为 true 时 `dq:chunk` 的 data 为 `{delta, cumulative_chars, chunk_index, elapsed_ms}`。
+ * `dq:end` 的 data 中 `truncated` 为 true 表示回复被停止串或输出上限截断。
  */
 #[tauri::command]
 async fn dq_send_chat_stream(
@@ -684,15 +1489,19 @@ async fn dq_send_chat_stream(
     stream: Option<bool>,
     debug: Option<bool>,
     regen_message_id: Option<i64>,
+    overrides: Option<llm::RequestOverrides>,
+    quote_ids: Option<Vec<i64>>,
+    progress: Option<bool>,
+    redact_prompt: Option<bool>,
     registry_state: tauri::State<'_, StreamRegistry>,
 ) -> Result<(), String> {
+    let overrides = overrides.unwrap_or_default().normalized();
     let prompt_trimmed = prompt.trim();
     if regen_message_id.is_some() && !prompt_trimmed.is_empty() {
         return Err("prompt 与 regen_message_id 不可同时提供".to_string());
     }
 
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
 
     let provider = pick_provider(Some(&app), &conn, chat_id, provider_id)?;
     // 事件通道标识
@@ -710,6 +1519,8 @@ async fn dq_send_chat_stream(
         }
     };
 
+    let redact_prompt = redact_prompt.unwrap_or(false);
+    let mut redacted_id = None;
     if let Some(message_id) = regen_message_id {
         let metas = db::load_messages_with_meta(&conn, chat_id).map_err(anyhow_to_string)?;
         let target = metas
@@ -719,15 +1530,36 @@ async fn dq_send_chat_stream(
         if target.role != "assistant" {
             return Err("仅支持对助手消息重新生成".to_string());
         }
-        db::delete_messages_from(&conn, chat_id, message_id).map_err(anyhow_to_string)?;
+        db::supersede_and_truncate(&conn, chat_id, message_id).map_err(anyhow_to_string)?;
     } else {
         if prompt_trimmed.is_empty() {
             return Err("发送内容不能为空".to_string());
         }
-        db::insert_message(&conn, chat_id, "user", prompt_trimmed).map_err(anyhow_to_string)?;
+        let quotes = db::resolve_quotes(&conn, chat_id, quote_ids.as_deref().unwrap_or_default())
+            .map_err(anyhow_to_string)?;
+        if redact_prompt {
+            redacted_id = Some(
+                db::insert_redacted_user_message(&conn, chat_id, &quotes)
+                    .map_err(anyhow_to_string)?,
+            );
+        } else {
+            db::insert_user_message(&conn, chat_id, prompt_trimmed, &quotes)
+                .map_err(anyhow_to_string)?;
+        }
     }
 
-    let messages = db::load_messages(&conn, chat_id).map_err(anyhow_to_string)?;
+    let mut plan = context::plan(&conn, chat_id, Some(&provider)).map_err(anyhow_to_string)?;
+    if let Some(message_id) = redacted_id {
+        plan = plan.with_redacted_prompt(message_id, prompt_trimmed);
+    }
+    let selection = plan.select(Some(&provider)).await;
+    if let Err(err) = selection.save_embeddings(&conn) {
+        telemetry::log_event(
+            "desktop.chat",
+            &format!("embedding cache write failed: {}", err),
+        );
+    }
+    let messages = selection.messages;
 
     // meta 事件
     emit_event(
@@ -738,6 +1570,20 @@ async fn dq_send_chat_stream(
             data: serde_json::json!({"chat_id": chat_id}),
         },
     );
+    if let Some(d) = llm::model_deprecation(&provider, overrides.model_for(&provider)) {
+        telemetry::log_event(
+            "desktop.chat",
+            &format!("chat_id={} {}", chat_id, d.message),
+        );
+        emit_event(
+            &app,
+            "dq:log",
+            &StreamEventPayload {
+                stream_id: sid.clone(),
+                data: format!("warning -> {}", d.message),
+            },
+        );
+    }
 
     let action_label = if regen_message_id.is_some() {
         "regenerate"
@@ -762,7 +1608,7 @@ async fn dq_send_chat_stream(
                     provider.name,
                     provider.provider_type,
                     provider.api_base,
-                    provider.model,
+                    overrides.model_for(&provider),
                     chat_id,
                     messages.len()
                 ),
@@ -787,116 +1633,223 @@ async fn dq_send_chat_stream(
         inner: registry_state.inner.clone(),
     };
     let cancel_token = registry.register(&sid);
+    let mut trimmer =
+        llm::StopTrimmer::new(&db::get_stop_strings(&conn).map_err(anyhow_to_string)?)
+            .with_max_chars(db::get_max_output_chars(&conn).map_err(anyhow_to_string)?);
+    let checkpoint_prompt = if redact_prompt {
+        db::REDACTED_PROMPT
+    } else {
+        prompt_trimmed
+    };
+    let mut checkpointer =
+        db::GenerationCheckpointer::begin(&conn, chat_id, Some(provider.id), checkpoint_prompt);
+    let stream_retries = if db::get_chat_stream_retry(&conn, chat_id).map_err(anyhow_to_string)? {
+        llm::STREAM_RETRY_LIMIT
+    } else {
+        0
+    };
+
+    let progress = progress.unwrap_or(false);
 
     // 后台任务：推送增量并持久化助手回复
-    tokio::spawn(async move {
+    let span = telemetry::chat_span(chat_id, &provider);
+    let task = async move {
         let mut assistant_buf = String::new();
+        let started = std::time::Instant::now();
+        let mut first_token_ms: Option<i64> = None;
+        let mut counter = progress.then(llm::ChunkCounter::new);
 
         if prefer_stream {
-            match llm::stream_chat(&provider, &messages).await {
-                Ok(s) => {
-                    use futures_util::StreamExt;
-                    let mut stream = s;
-                    loop {
-                        tokio::select! {
-                            _ = cancel_token.cancelled() => {
-                                emit_event(
-                                    &app2,
-                                    "dq:log",
-                                    &StreamEventPayload {
-                                        stream_id: sid.clone(),
-                                        data: "用户已取消当前回复".to_string(),
-                                    },
-                                );
-                                break;
-                            }
-                            item = stream.next() => {
-                                match item {
-                                    Some(Ok(delta)) => {
-                                        assistant_buf.push_str(&delta);
-                                        emit_event(
-                                            &app2,
-                                            "dq:chunk",
-                                            &StreamEventPayload { stream_id: sid.clone(), data: delta },
-                                        );
+            let mut attempt = 0;
+            loop {
+                let request = if attempt == 0 {
+                    messages.clone()
+                } else {
+                    llm::continuation_messages(&messages, &assistant_buf)
+                };
+                let (opened, retries) = llm::count_retries(llm::stream_chat_cancellable(
+                    &provider,
+                    &request,
+                    &overrides,
+                    &cancel_token,
+                ))
+                .await;
+                if debug && retries > 0 {
+                    emit_event(
+                        &app2,
+                        "dq:log",
+                        &StreamEventPayload {
+                            stream_id: sid.clone(),
+                            data: format!("retries -> {} before first token", retries),
+                        },
+                    );
+                }
+                let failure = match opened {
+                    Ok(mut stream) => {
+                        use futures_util::StreamExt;
+                        let mut failure = None;
+                        loop {
+                            tokio::select! {
+                                biased;
+                                _ = cancel_token.cancelled() => {
+                                    emit_event(
+                                        &app2,
+                                        "dq:log",
+                                        &StreamEventPayload {
+                                            stream_id: sid.clone(),
+                                            data: "用户已取消当前回复".to_string(),
+                                        },
+                                    );
+                                    break;
+                                }
+                                item = stream.next() => {
+                                    match item {
+                                        Some(Ok(delta)) => {
+                                            if first_token_ms.is_none() {
+                                                first_token_ms =
+                                                    Some(started.elapsed().as_millis() as i64);
+                                            }
+                                            let visible = trimmer.push(&delta);
+                                            if !visible.is_empty() {
+                                                assistant_buf.push_str(&visible);
+                                                checkpointer.on_progress(&assistant_buf);
+                                                emit_chunk(&app2, &sid, visible, counter.as_mut());
+                                            }
+                                            if trimmer.stopped() {
+                                                break;
+                                            }
+                                        }
+                                        Some(Err(e)) => {
+                                            failure = Some(format!("{}", e));
+                                            break;
+                                        }
+                                        None => break,
                                     }
-                                    Some(Err(e)) => {
-                                        telemetry::log_error(
-                                            "desktop.chat.stream",
-                                            &format!("stream error: {}", e),
-                                        );
+                                }
+                            }
+                        }
+                        failure
+                    }
+                    Err(_) if cancel_token.is_cancelled() => {
+                        emit_event(
+                            &app2,
+                            "dq:log",
+                            &StreamEventPayload {
+                                stream_id: sid.clone(),
+                                data: "用户已取消当前回复".to_string(),
+                            },
+                        );
+                        None
+                    }
+                    Err(e) if attempt > 0 => Some(format!("stream failed: {}", e)),
+                    Err(e) => {
+                        telemetry::log_error(
+                            "desktop.chat.stream",
+                            &format!("stream failed: {}", e),
+                        );
+                        // 回退一次性
+                        match llm::chat_once_cancellable(
+                            &provider,
+                            &messages,
+                            &overrides,
+                            &cancel_token,
+                        )
+                        .await
+                        {
+                            Ok(full) => {
+                                let full = trimmer.trim_full(&full);
+                                if !cancel_token.is_cancelled() {
+                                    if !full.is_empty() {
+                                        assistant_buf.push_str(&full);
+                                        emit_chunk(&app2, &sid, full, counter.as_mut());
+                                    } else {
                                         emit_event(
                                             &app2,
                                             "dq:error",
                                             &StreamEventPayload {
                                                 stream_id: sid.clone(),
-                                                data: format!("{}", e),
+                                                data: "模型未返回任何内容".to_string(),
                                             },
                                         );
-                                        break;
                                     }
-                                    None => break,
                                 }
                             }
+                            Err(_) if cancel_token.is_cancelled() => {}
+                            Err(e2) => {
+                                emit_event(
+                                    &app2,
+                                    "dq:error",
+                                    &StreamEventPayload {
+                                        stream_id: sid.clone(),
+                                        data: format!("chat_once failed: {}", e2),
+                                    },
+                                );
+                            }
                         }
+                        None
                     }
+                };
+
+                let Some(error) = failure else { break };
+                if attempt < stream_retries
+                    && llm::is_retryable_error(&error)
+                    && !cancel_token.is_cancelled()
+                {
+                    attempt += 1;
+                    // 暂存的尾部已收到且不是停止串，先输出，续写从其后开始
+                    let held = trimmer.finish();
+                    if !held.is_empty() {
+                        assistant_buf.push_str(&held);
+                        emit_chunk(&app2, &sid, held, counter.as_mut());
+                    }
+                    let note = format!(
+                        "stream interrupted ({}), retrying {}/{} and continuing after {} chars",
+                        error,
+                        attempt,
+                        stream_retries,
+                        assistant_buf.chars().count()
+                    );
+                    telemetry::log_event(
+                        "desktop.chat.stream",
+                        &format!("chat_id={} {}", chat_id, note),
+                    );
+                    emit_event(
+                        &app2,
+                        "dq:log",
+                        &StreamEventPayload {
+                            stream_id: sid.clone(),
+                            data: note,
+                        },
+                    );
+                    tokio::time::sleep(llm::stream_retry_delay(attempt)).await;
+                    continue;
                 }
-                Err(e) => {
-                    telemetry::log_error("desktop.chat.stream", &format!("stream failed: {}", e));
-                    // 回退一次性
-                    match llm::chat_once(&provider, &messages).await {
-                        Ok(full) => {
-                            if !cancel_token.is_cancelled() {
-                                if !full.is_empty() {
-                                    assistant_buf.push_str(&full);
-                                    emit_event(
-                                        &app2,
-                                        "dq:chunk",
-                                        &StreamEventPayload {
-                                            stream_id: sid.clone(),
-                                            data: full,
-                                        },
-                                    );
-                                } else {
-                                    emit_event(
-                                        &app2,
-                                        "dq:error",
-                                        &StreamEventPayload {
-                                            stream_id: sid.clone(),
-                                            data: "模型未返回任何内容".to_string(),
-                                        },
-                                    );
-                                }
-                            }
-                        }
-                        Err(e2) => {
-                            emit_event(
-                                &app2,
-                                "dq:error",
-                                &StreamEventPayload {
-                                    stream_id: sid.clone(),
-                                    data: format!("chat_once failed: {}", e2),
-                                },
-                            );
-                        }
-                    }
-                }
-            }
-        } else {
-            match llm::chat_once(&provider, &messages).await {
-                Ok(full) => {
-                    if !cancel_token.is_cancelled() {
-                        if !full.is_empty() {
-                            assistant_buf.push_str(&full);
-                            emit_event(
-                                &app2,
-                                "dq:chunk",
-                                &StreamEventPayload {
-                                    stream_id: sid.clone(),
-                                    data: full,
-                                },
-                            );
-                        } else {
+                telemetry::log_error("desktop.chat.stream", &format!("stream error: {}", error));
+                emit_event(
+                    &app2,
+                    "dq:error",
+                    &StreamEventPayload {
+                        stream_id: sid.clone(),
+                        data: error,
+                    },
+                );
+                break;
+            }
+            let tail = trimmer.finish();
+            if !tail.is_empty() && !cancel_token.is_cancelled() {
+                assistant_buf.push_str(&tail);
+                emit_chunk(&app2, &sid, tail, counter.as_mut());
+            }
+        } else {
+            match llm::chat_once_cancellable(&provider, &messages, &overrides, &cancel_token).await
+            {
+                Ok(full) => {
+                    let full = trimmer.trim_full(&full);
+                    if !cancel_token.is_cancelled() {
+                        if !full.is_empty() {
+                            assistant_buf.push_str(&full);
+                            emit_chunk(&app2, &sid, full, counter.as_mut());
+                        } else {
                             emit_event(
                                 &app2,
                                 "dq:error",
@@ -908,6 +1861,7 @@ async fn dq_send_chat_stream(
                         }
                     }
                 }
+                Err(_) if cancel_token.is_cancelled() => {}
                 Err(e) => {
                     telemetry::log_error(
                         "desktop.chat.stream",
@@ -925,29 +1879,799 @@ async fn dq_send_chat_stream(
             }
         }
 
-        // 持久化助手回复
+        if let Some(reason) = trimmer.truncation() {
+            telemetry::log_event(
+                "desktop.chat.stream",
+                &format!(
+                    "output truncated ({}) chat_id={} trimmed_len={}",
+                    reason,
+                    chat_id,
+                    trimmer.remainder().len()
+                ),
+            );
+            if debug {
+                emit_event(
+                    &app2,
+                    "dq:log",
+                    &StreamEventPayload {
+                        stream_id: sid.clone(),
+                        data: format!(
+                            "output truncated ({}), trimmed -> {:?}",
+                            reason,
+                            trimmer.remainder()
+                        ),
+                    },
+                );
+            }
+        }
+
+        // 持久化助手回复（一次性回退路径以完整回复到达时间作为首 token 时间）；
+        // 用户取消时已收到的部分同样保存，并标记为不完整回复
+        let duration_ms = started.elapsed().as_millis() as i64;
+        let first_token_ms = first_token_ms.or(Some(duration_ms));
+        let cancelled = cancel_token.is_cancelled();
+        let mut message_id = None;
+        let mut first_title = None;
         if !assistant_buf.is_empty() {
-            if let Ok(conn2) = db::open_default_db() {
-                let _ = db::insert_message(&conn2, chat_id, "assistant", &assistant_buf);
+            let reply = finalize::FinishedReply {
+                chat_id,
+                content: &assistant_buf,
+                prompt: &messages,
+                timing: db::GenerationTiming {
+                    provider_id: Some(provider.id),
+                    first_token_ms,
+                    duration_ms: Some(duration_ms),
+                },
+                metadata: trimmer.annotate(overrides.to_metadata()),
+                partial: cancelled,
+            };
+            let saved = db::open_default_db().and_then(|conn2| {
+                let done = finalize::finalize(&conn2, &reply)?;
+                Ok((conn2, done))
+            });
+            match saved {
+                Ok((conn2, done)) => {
+                    message_id = Some(done.message_id);
+                    first_title = finalize::spawn_followups(&conn2, &provider, chat_id, &done);
+                }
+                Err(e) => {
+                    telemetry::log_error(
+                        "desktop.chat.stream",
+                        &format!("save reply failed: {}", e),
+                    );
+                    emit_event(
+                        &app2,
+                        "dq:error",
+                        &StreamEventPayload {
+                            stream_id: sid.clone(),
+                            data: format!("save reply failed: {}", e),
+                        },
+                    );
+                }
             }
         }
 
+        checkpointer.finish();
         registry.remove(&sid);
 
+        if cancelled {
+            emit_event(
+                &app2,
+                "dq:cancelled",
+                &StreamEventPayload {
+                    stream_id: sid.clone(),
+                    data: serde_json::json!({
+                        "chat_id": chat_id,
+                        "message_id": message_id,
+                        "kept_chars": assistant_buf.chars().count(),
+                    }),
+                },
+            );
+        }
+
+        if let Some(id) = message_id.filter(|_| !cancelled) {
+            let outcome = match retry_refusal(&app2, &provider, id, &assistant_buf).await {
+                Some(result) => Some(("refusal retry", result)),
+                None => enforce_reply_language(&provider, chat_id, id, &assistant_buf)
+                    .await
+                    .map(|result| ("language re-ask", result)),
+            };
+            match outcome {
+                Some((_, Ok(variant))) => emit_event(
+                    &app2,
+                    "dq:variant",
+                    &StreamEventPayload {
+                        stream_id: sid.clone(),
+                        data: variant,
+                    },
+                ),
+                Some((action, Err(e))) => emit_event(
+                    &app2,
+                    "dq:log",
+                    &StreamEventPayload {
+                        stream_id: sid.clone(),
+                        data: format!("{} failed: {}", action, e),
+                    },
+                ),
+                None => {}
+            }
+        }
+
+        if let Some(title) = backfill::wait_first_title(first_title).await {
+            emit_event(
+                &app2,
+                "dq:title",
+                &StreamEventPayload {
+                    stream_id: sid.clone(),
+                    data: serde_json::json!({ "chat_id": chat_id, "title": title }),
+                },
+            );
+        }
+
         // 结束事件
         emit_event(
             &app2,
             "dq:end",
             &StreamEventPayload {
                 stream_id: sid.clone(),
-                data: serde_json::json!({"chat_id": chat_id}),
+                data: serde_json::json!({
+                    "chat_id": chat_id,
+                    "first_token_ms": first_token_ms,
+                    "duration_ms": duration_ms,
+                    "truncated": trimmer.stopped(),
+                }),
             },
         );
-    });
+    };
+    tokio::spawn(task.instrument(span));
 
     Ok(())
 }
 
+/**
+ * \brief 按 Provider 与模型汇总的请求指标（首 token 延迟、总耗时、输出速度）；`since` 为 Unix 秒。
+ */
+#[tauri::command]
+async fn dq_get_stats(since: Option<i64>) -> Result<Vec<db::RequestStats>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::request_stats(&conn, since).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 获取会话标签（含自动分类置信度）。
+ */
+#[tauri::command]
+async fn dq_get_chat_tags(chat_id: i64) -> Result<Vec<db::ChatTag>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::list_chat_tags(&conn, chat_id).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 获取会话的滚动摘要，尚未生成时返回空。
+ */
+#[tauri::command]
+async fn dq_get_chat_summary(chat_id: i64) -> Result<Option<db::ConversationSummary>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_conversation_summary(&conn, chat_id).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 生成问题反馈用的诊断包（zip），配置与转录均已脱敏。
+ */
+#[tauri::command]
+async fn dq_create_debug_bundle(
+    path: String,
+    transcript_path: Option<String>,
+    transcripts: Option<usize>,
+) -> Result<debug_bundle::BundleSummary, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let options = debug_bundle::BundleOptions {
+        app: format!("desktop {}", env!("CARGO_PKG_VERSION")),
+        transcript_path: transcript_path
+            .filter(|p| !p.trim().is_empty())
+            .map(std::path::PathBuf::from),
+        transcripts: transcripts.unwrap_or(debug_bundle::DEFAULT_TRANSCRIPTS),
+        ..Default::default()
+    };
+    debug_bundle::create(&conn, std::path::Path::new(&path), &options).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 启动标题与标签补全：后台逐个处理占位标题的会话，按每分钟调用次数限流。
+ */
+#[tauri::command]
+async fn dq_start_backfill(
+    app: tauri::AppHandle,
+    provider_id: Option<i64>,
+    calls_per_minute: Option<u32>,
+) -> Result<backfill::BackfillProgress, String> {
+    if backfill::progress().running {
+        return Err("补全任务正在运行".to_string());
+    }
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let provider = pick_provider(Some(&app), &conn, None, provider_id)?;
+    let provider_id = provider.id;
+    let progress = backfill::start(
+        provider,
+        calls_per_minute.unwrap_or(backfill::DEFAULT_CALLS_PER_MINUTE),
+    )
+    .map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "maintenance.backfill",
+        Some(format!("provider:{}", provider_id)),
+        serde_json::json!({
+            "chats": progress.total,
+            "calls_per_minute": progress.calls_per_minute,
+        }),
+    );
+    Ok(progress)
+}
+
+/**
+ * \brief 查看补全进度。
+ */
+#[tauri::command]
+async fn dq_backfill_progress() -> Result<backfill::BackfillProgress, String> {
+    Ok(backfill::progress())
+}
+
+/**
+ * \brief 停止补全任务，当前调用完成后退出。
+ */
+#[tauri::command]
+async fn dq_cancel_backfill() -> Result<backfill::BackfillProgress, String> {
+    Ok(backfill::cancel())
+}
+
+/**
+ * \brief 手动重新打标签：按配置的候选标签集合调用会话绑定的模型服务分类。
+ */
+#[tauri::command]
+async fn dq_autotag_chat(app: tauri::AppHandle, chat_id: i64) -> Result<Vec<db::ChatTag>, String> {
+    let provider = {
+        let conn = db::open_default_db().map_err(anyhow_to_string)?;
+        if db::get_chat(&conn, chat_id)
+            .map_err(anyhow_to_string)?
+            .is_none()
+        {
+            return Err("会话不存在".to_string());
+        }
+        pick_provider(Some(&app), &conn, Some(chat_id), None)?
+    };
+    autotag::autotag_chat(&provider, chat_id)
+        .await
+        .map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 用其他 Provider/模型重新回答一条用户消息，结果保存为备选回复，不分支会话。
+ */
+#[tauri::command]
+async fn dq_rerun_message(
+    app: tauri::AppHandle,
+    message_id: i64,
+    provider_id: i64,
+    overrides: Option<llm::RequestOverrides>,
+) -> Result<db::MessageVariant, String> {
+    let provider = {
+        let conn = db::open_default_db().map_err(anyhow_to_string)?;
+        db::message_context(&conn, message_id)
+            .map_err(anyhow_to_string)?
+            .ok_or_else(|| "消息不存在".to_string())?;
+        pick_provider(Some(&app), &conn, None, Some(provider_id))?
+    };
+    let overrides = overrides.unwrap_or_default().normalized();
+    rerun::rerun_message(&provider, message_id, &overrides)
+        .await
+        .map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 温度效果预览：以会话历史为上下文，用一组温度并发生成简短回复，结果不写入会话。
+ */
+#[tauri::command]
+async fn dq_preview_temperatures(
+    app: tauri::AppHandle,
+    chat_id: i64,
+    prompt: String,
+    temperatures: Vec<f64>,
+    provider_id: Option<i64>,
+    overrides: Option<llm::RequestOverrides>,
+) -> Result<Vec<rerun::TemperatureVariant>, String> {
+    let provider = {
+        let conn = db::open_default_db().map_err(anyhow_to_string)?;
+        if db::get_chat(&conn, chat_id)
+            .map_err(anyhow_to_string)?
+            .is_none()
+        {
+            return Err("会话不存在".to_string());
+        }
+        pick_provider(Some(&app), &conn, Some(chat_id), provider_id)?
+    };
+    let overrides = overrides.unwrap_or_default().normalized();
+    rerun::preview_temperatures(&provider, Some(chat_id), &prompt, &temperatures, &overrides)
+        .await
+        .map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 列出用户消息的备选回复。
+ */
+#[tauri::command]
+async fn dq_list_message_variants(message_id: i64) -> Result<Vec<db::MessageVariant>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::list_message_variants(&conn, message_id).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 列出会话中某条消息的备选回复（含重新生成前的旧回复），助手消息按其前一条用户消息查询。
+ */
+#[tauri::command]
+async fn dq_list_variants(
+    chat_id: i64,
+    message_id: i64,
+) -> Result<Vec<db::MessageVariant>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::list_chat_message_variants(&conn, chat_id, message_id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "消息不存在".to_string())
+}
+
+/**
+ * \brief 编辑消息正文，旧内容保存为历史版本。
+ */
+#[tauri::command]
+async fn dq_edit_message(message_id: i64, content: String) -> Result<StoredMessageDto, String> {
+    let content = content.trim();
+    if content.is_empty() {
+        return Err("消息内容不能为空".to_string());
+    }
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let message = db::edit_message(&conn, message_id, content)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "消息不存在".to_string())?;
+    telemetry::log_event(
+        "desktop.message",
+        &format!("edit message id={}", message_id),
+    );
+    Ok(message.into())
+}
+
+/**
+ * \brief 列出消息的历史版本。
+ */
+#[tauri::command]
+async fn dq_list_message_revisions(message_id: i64) -> Result<Vec<db::MessageRevision>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::list_message_revisions(&conn, message_id).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 将消息回退到历史版本，回退前的内容同样保留为版本。
+ */
+#[tauri::command]
+async fn dq_revert_message(message_id: i64, revision_id: i64) -> Result<StoredMessageDto, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let message = db::revert_message(&conn, message_id, revision_id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "消息不存在".to_string())?;
+    telemetry::log_event(
+        "desktop.message",
+        &format!("revert message id={} revision={}", message_id, revision_id),
+    );
+    Ok(message.into())
+}
+
+/**
+ * \brief 列出保存的智能列表（命名的搜索/筛选组合）。
+ */
+#[tauri::command]
+async fn dq_list_smart_lists() -> Result<Vec<db::SmartList>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::list_smart_lists(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 新建（`id` 为空）或更新智能列表。
+ */
+#[tauri::command]
+async fn dq_save_smart_list(
+    id: Option<i64>,
+    name: String,
+    filter: db::ChatFilter,
+) -> Result<db::SmartList, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let id = db::save_smart_list(&conn, id, &name, &filter).map_err(anyhow_to_string)?;
+    db::get_smart_list(&conn, id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "智能列表不存在".to_string())
+}
+
+/**
+ * \brief 删除智能列表，返回剩余列表。
+ */
+#[tauri::command]
+async fn dq_delete_smart_list(id: i64) -> Result<Vec<db::SmartList>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::delete_smart_list(&conn, id).map_err(anyhow_to_string)?;
+    db::list_smart_lists(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 按智能列表的筛选条件解析出会话列表。
+ */
+#[tauri::command]
+async fn dq_resolve_smart_list(id: i64) -> Result<Vec<ChatSummaryDto>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let list = db::get_smart_list(&conn, id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "智能列表不存在".to_string())?;
+    let chats = db::search_chats(&conn, &list.filter).map_err(anyhow_to_string)?;
+    Ok(chats.into_iter().map(ChatSummaryDto::from).collect())
+}
+
+/**
+ * \brief 读取客户端侧停止串，流式回复命中任一停止串时截断。
+ */
+#[tauri::command]
+async fn dq_get_stop_strings() -> Result<Vec<String>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_stop_strings(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 更新客户端侧停止串。
+ */
+#[tauri::command]
+async fn dq_set_stop_strings(stop_strings: Vec<String>) -> Result<Vec<String>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let before = db::get_stop_strings(&conn).map_err(anyhow_to_string)?;
+    let saved = db::set_stop_strings(&conn, &stop_strings).map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "config.stop_strings",
+        None,
+        serde_json::json!({ "before": before, "after": saved }),
+    );
+    Ok(saved)
+}
+
+/**
+ * \brief 读取单次回复的输出字符数上限，未设置时为 `null`。
+ */
+#[tauri::command]
+async fn dq_get_max_output_chars() -> Result<Option<usize>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_max_output_chars(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 更新单次回复的输出字符数上限，`null` 或 0 表示不限制；达到上限时截断回复并取消上游请求。
+ */
+#[tauri::command]
+async fn dq_set_max_output_chars(max_chars: Option<usize>) -> Result<Option<usize>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let before = db::get_max_output_chars(&conn).map_err(anyhow_to_string)?;
+    let saved = db::set_max_output_chars(&conn, max_chars).map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "config.max_output",
+        None,
+        serde_json::json!({ "before": before, "after": saved }),
+    );
+    Ok(saved)
+}
+
+/**
+ * \brief 解析聊天输入中的 `/` 命令，语义与 CLI REPL 相同；普通消息返回 `null`。
+ */
+#[tauri::command]
+async fn dq_parse_command(line: String) -> Result<Option<commands::ChatCommand>, String> {
+    commands::parse(&line).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 读取 LLM 请求重试策略（尝试次数与退避时间）。
+ */
+#[tauri::command]
+async fn dq_get_retry_policy() -> Result<dreamquill_core_sdk::models::RetryPolicy, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_retry_policy(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 更新 LLM 请求重试策略，立即对后续请求生效。
+ */
+#[tauri::command]
+async fn dq_set_retry_policy(
+    policy: dreamquill_core_sdk::models::RetryPolicy,
+) -> Result<dreamquill_core_sdk::models::RetryPolicy, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let before = db::get_retry_policy(&conn).map_err(anyhow_to_string)?;
+    let saved = db::set_retry_policy(&conn, &policy).map_err(anyhow_to_string)?;
+    llm::set_retry_policy(saved);
+    record_audit(
+        &conn,
+        "config.retry_policy",
+        None,
+        serde_json::json!({ "before": before, "after": saved }),
+    );
+    Ok(saved)
+}
+
+/**
+ * \brief 读取 Provider api_base 的主机名白名单/黑名单。
+ */
+#[tauri::command]
+async fn dq_get_host_policy() -> Result<dreamquill_core_sdk::models::HostPolicy, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_host_policy(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 更新主机名白名单/黑名单，立即对后续保存与请求生效。
+ */
+#[tauri::command]
+async fn dq_set_host_policy(
+    policy: dreamquill_core_sdk::models::HostPolicy,
+) -> Result<dreamquill_core_sdk::models::HostPolicy, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let before = db::get_host_policy(&conn).map_err(anyhow_to_string)?;
+    let saved = db::set_host_policy(&conn, &policy).map_err(anyhow_to_string)?;
+    llm::set_host_policy(saved.clone());
+    record_audit(
+        &conn,
+        "config.host_policy",
+        None,
+        serde_json::json!({ "before": before, "after": saved }),
+    );
+    Ok(saved)
+}
+
+/**
+ * \brief 读取只读 SQL 控制台开关。
+ */
+#[tauri::command]
+async fn dq_get_sql_console_enabled() -> Result<bool, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_sql_console_enabled(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 开启或关闭只读 SQL 控制台。
+ */
+#[tauri::command]
+async fn dq_set_sql_console_enabled(enabled: bool) -> Result<bool, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let before = db::get_sql_console_enabled(&conn).map_err(anyhow_to_string)?;
+    db::set_sql_console_enabled(&conn, enabled).map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "config.sql_console",
+        None,
+        serde_json::json!({ "before": before, "after": enabled }),
+    );
+    Ok(enabled)
+}
+
+/**
+ * \brief 在只读 SQL 控制台执行单条 SELECT；控制台未开启时报错，每次查询都写入审计日志。
+ */
+#[tauri::command]
+async fn dq_run_sql(
+    sql: String,
+    max_rows: Option<usize>,
+    timeout_ms: Option<u64>,
+) -> Result<sql_console::QueryResult, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let limits = sql_console::QueryLimits::new(max_rows, timeout_ms);
+    sql_console::execute(&conn, "desktop", &sql, limits).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 列出已保存的导出流水线。
+ */
+#[tauri::command]
+async fn dq_list_export_pipelines() -> Result<Vec<db::ExportPipeline>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::list_export_pipelines(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 新建（`id` 为空）或修改导出流水线，保存前校验模板。
+ */
+#[tauri::command]
+async fn dq_save_export_pipeline(
+    id: Option<i64>,
+    name: String,
+    definition: db::PipelineDefinition,
+) -> Result<db::ExportPipeline, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    pipeline::validate(&definition).map_err(anyhow_to_string)?;
+    let id = db::save_export_pipeline(&conn, id, &name, &definition).map_err(anyhow_to_string)?;
+    db::get_export_pipeline(&conn, id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "导出流水线不存在".to_string())
+}
+
+/**
+ * \brief 删除导出流水线，返回剩余流水线。
+ */
+#[tauri::command]
+async fn dq_delete_export_pipeline(id: i64) -> Result<Vec<db::ExportPipeline>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::delete_export_pipeline(&conn, id).map_err(anyhow_to_string)?;
+    db::list_export_pipelines(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 运行导出流水线，返回渲染好的文件名与内容，由前端决定保存位置。
+ */
+#[tauri::command]
+async fn dq_run_export_pipeline(id: i64) -> Result<pipeline::PipelineRun, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let saved = db::get_export_pipeline(&conn, id)
+        .map_err(anyhow_to_string)?
+        .ok_or_else(|| "导出流水线不存在".to_string())?;
+    let run = pipeline::preview(&conn, &saved).map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "export.pipeline",
+        Some(saved.name.clone()),
+        serde_json::json!({ "chats": run.chats, "messages": run.messages, "files": run.files.len() }),
+    );
+    Ok(run)
+}
+
+/**
+ * \brief 导出全部会话为迁移归档目录（`chats.jsonl` 与带 SHA-256 的 `manifest.json`）。
+ */
+#[tauri::command]
+async fn dq_export_archive(dir: String) -> Result<archive::ArchiveManifest, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let manifest =
+        archive::export_archive(&conn, std::path::Path::new(&dir)).map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "export.archive",
+        Some(dir),
+        serde_json::json!({ "chats": manifest.counts.chats, "messages": manifest.counts.messages }),
+    );
+    Ok(manifest)
+}
+
+/**
+ * \brief 校验迁移归档并返回导入计划（将新建、合并与跳过的会话），不写入数据库。
+ */
+#[tauri::command]
+async fn dq_verify_archive(dir: String) -> Result<archive::ImportPlan, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    archive::verify_archive(&conn, std::path::Path::new(&dir)).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 校验并导入迁移归档；`provider_id` 为空时新建会话按 Provider 名称匹配。
+ */
+#[tauri::command]
+async fn dq_import_archive(
+    dir: String,
+    provider_id: Option<i64>,
+) -> Result<archive::ImportPlan, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let plan = archive::import_archive(&conn, std::path::Path::new(&dir), provider_id)
+        .map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "import.archive",
+        Some(dir),
+        serde_json::json!({ "created": plan.created, "merged": plan.merged, "skipped": plan.skipped, "messages": plan.messages }),
+    );
+    Ok(plan)
+}
+
+#[tauri::command]
+async fn dq_get_autotag_config() -> Result<db::AutotagConfig, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_autotag_config(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 更新自动打标签配置（触发阈值与候选标签集合）。
+ */
+#[tauri::command]
+async fn dq_set_autotag_config(config: db::AutotagConfig) -> Result<db::AutotagConfig, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let before = db::get_autotag_config(&conn).map_err(anyhow_to_string)?;
+    let saved = db::set_autotag_config(&conn, &config).map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "config.autotag",
+        None,
+        serde_json::json!({ "before": before, "after": saved }),
+    );
+    Ok(saved)
+}
+
+/**
+ * \brief 获取滚动摘要配置。
+ */
+#[tauri::command]
+async fn dq_get_summary_config() -> Result<db::SummaryConfig, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_summary_config(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 更新滚动摘要配置（触发阈值与保留原文的最近消息数）。
+ */
+#[tauri::command]
+async fn dq_set_summary_config(config: db::SummaryConfig) -> Result<db::SummaryConfig, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let before = db::get_summary_config(&conn).map_err(anyhow_to_string)?;
+    let saved = db::set_summary_config(&conn, &config).map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "config.summary",
+        None,
+        serde_json::json!({ "before": before, "after": saved }),
+    );
+    Ok(saved)
+}
+
+/**
+ * \brief 获取拒答处理策略。
+ */
+#[tauri::command]
+async fn dq_get_refusal_policy() -> Result<db::RefusalPolicy, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::get_refusal_policy(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 更新拒答处理策略（是否自动重试、重试提示与备用 Provider）。
+ */
+#[tauri::command]
+async fn dq_set_refusal_policy(policy: db::RefusalPolicy) -> Result<db::RefusalPolicy, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let before = db::get_refusal_policy(&conn).map_err(anyhow_to_string)?;
+    let saved = db::set_refusal_policy(&conn, &policy).map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "config.refusal_policy",
+        None,
+        serde_json::json!({ "before": before, "after": saved }),
+    );
+    Ok(saved)
+}
+
+/**
+ * \brief 列出上次运行中断（崩溃或强制退出）的生成任务，供启动时提示恢复。
+ */
+#[tauri::command]
+async fn dq_list_interrupted() -> Result<Vec<db::InterruptedGeneration>, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::list_interrupted_generations(&conn).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 处理中断的生成：`finalize` 将部分回复保存为助手消息，`discard` 丢弃。
+ */
+#[tauri::command]
+async fn dq_resolve_interrupted(
+    id: i64,
+    action: String,
+) -> Result<Vec<db::InterruptedGeneration>, String> {
+    let keep_partial = match action.as_str() {
+        "finalize" => true,
+        "discard" => false,
+        other => return Err(format!("未知操作: {}", other)),
+    };
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    db::resolve_interrupted_generation(&conn, id, keep_partial).map_err(anyhow_to_string)?;
+    db::list_interrupted_generations(&conn).map_err(anyhow_to_string)
+}
+
 /** @brief 取消指定流式聊天任务。 */
 #[tauri::command]
 async fn dq_cancel_stream(
@@ -961,6 +2685,57 @@ async fn dq_cancel_stream(
     Ok(())
 }
 
+/**
+ * \brief 启动诊断：数据库、安全存储、Provider 连通性与日志目录。
+ */
+#[tauri::command]
+async fn dq_doctor(app: tauri::AppHandle) -> Result<doctor::DoctorReport, String> {
+    let secrets = SecureStorageSecrets(app);
+    Ok(doctor::run(&doctor::DoctorOptions {
+        secret_store: Some(&secrets),
+        ..Default::default()
+    })
+    .await)
+}
+
+/**
+ * \brief 诊断面板：最近 `limit` 条遥测事件（默认 200，从旧到新）。
+ */
+#[tauri::command]
+async fn dq_recent_telemetry(
+    limit: Option<usize>,
+) -> Result<Vec<telemetry::TelemetryEvent>, String> {
+    telemetry::read_recent(limit.unwrap_or(200)).map_err(anyhow_to_string)
+}
+
+/**
+ * \brief 备份数据库：指定 `path`（如保存对话框选择的文件）时写到该处，否则写入数据目录的 `backups/`。
+ */
+#[tauri::command]
+async fn dq_backup_db(path: Option<String>) -> Result<db::BackupInfo, String> {
+    let conn = db::open_default_db().map_err(anyhow_to_string)?;
+    let info = match path.filter(|p| !p.trim().is_empty()) {
+        Some(path) => db::backup_to(&conn, std::path::Path::new(&path)),
+        None => db::backup_to_dir(&conn, &paths::backup_dir()),
+    }
+    .map_err(anyhow_to_string)?;
+    record_audit(
+        &conn,
+        "admin.backup",
+        Some(info.path.clone()),
+        serde_json::json!({"bytes": info.bytes}),
+    );
+    Ok(info)
+}
+
+/**
+ * \brief 启动时模型列表预热进度。
+ */
+#[tauri::command]
+async fn dq_model_warmup_status() -> Result<model_cache::WarmupStatus, String> {
+    Ok(model_cache::warmup_status())
+}
+
 /**
  * \brief Provider 健康检查：尝试列出模型，返回可用性。
  */
@@ -970,16 +2745,17 @@ async fn dq_health_check(
     provider_id: Option<i64>,
 ) -> Result<serde_json::Value, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
     let provider = pick_provider(Some(&app), &conn, None, provider_id)?;
-    match llm::list_models(&provider).await {
+    let deprecation = llm::model_deprecation(&provider, &provider.model);
+    match model_cache::list_models_cached(&provider).await {
         Ok(list) => Ok(serde_json::json!({
             "ok": true,
             "provider_id": provider.id,
             "provider": provider.provider_type,
             "base": provider.api_base,
             "model": provider.model,
-            "models": list.len()
+            "models": list.len(),
+            "deprecation": deprecation
         })),
         Err(e) => Ok(serde_json::json!({
             "ok": false,
@@ -987,7 +2763,8 @@ async fn dq_health_check(
             "provider": provider.provider_type,
             "base": provider.api_base,
             "model": provider.model,
-            "error": e.to_string()
+            "error": e.to_string(),
+            "deprecation": deprecation
         })),
     }
 }
@@ -998,22 +2775,26 @@ async fn dq_health_check_preview(
     payload: HealthPreviewRequestDto,
 ) -> Result<serde_json::Value, String> {
     let conn = db::open_default_db().map_err(anyhow_to_string)?;
-    db::migrate(&conn).map_err(anyhow_to_string)?;
     let telemetry_enabled = db::get_telemetry_enabled(&conn).map_err(anyhow_to_string)?;
     telemetry::set_enabled(telemetry_enabled);
+    validate_proxy(payload.proxy_url.as_deref())?;
 
     let provider = dreamquill_core_sdk::models::Provider {
         id: -1,
-        name: payload
-            .name
-            .unwrap_or_else(|| "临时健康检查".to_string()),
+        name: payload.name.unwrap_or_else(|| "临时健康检查".to_string()),
         provider_type: payload.provider,
         api_base: payload.api_base,
         api_key: payload.api_key,
         model: payload.model,
         secret_alias: None,
+        api_prefix: None,
+        signing: None,
+        generation: Default::default(),
+        proxy_url: payload.proxy_url.filter(|u| !u.trim().is_empty()),
+        api_key_unreadable: false,
     };
 
+    let deprecation = llm::model_deprecation(&provider, &provider.model);
     match llm::list_models(&provider).await {
         Ok(list) => Ok(serde_json::json!({
             "ok": true,
@@ -1021,7 +2802,8 @@ async fn dq_health_check_preview(
             "provider": provider.provider_type,
             "base": provider.api_base,
             "model": provider.model,
-            "models": list.len()
+            "models": list.len(),
+            "deprecation": deprecation
         })),
         Err(e) => Ok(serde_json::json!({
             "ok": false,
@@ -1029,19 +2811,38 @@ async fn dq_health_check_preview(
             "provider": provider.provider_type,
             "base": provider.api_base,
             "model": provider.model,
-            "error": e.to_string()
+            "error": e.to_string(),
+            "deprecation": deprecation
         })),
     }
 }
 
 fn main() {
+    if let Err(e) = telemetry::init(&telemetry::Sinks::from_env()) {
+        eprintln!("Warning: telemetry init failed: {:#}", e);
+    }
     tauri::Builder::default()
         .manage(StreamRegistry::default())
         .plugin(tauri_plugin_secure_storage::init())
-        .setup(|_app| {
-            if let Ok(conn) = db::open_default_db() {
-                let _ = db::migrate(&conn);
+        .setup(|app| {
+            // 主机策略读不出时拒绝启动，不能带着空策略放行请求
+            let conn =
+                db::open_default_db().map_err(|e| format!("load host policy failed: {:#}", e))?;
+            let host_policy = db::get_host_policy(&conn)
+                .map_err(|e| format!("load host policy failed: {:#}", e))?;
+            llm::set_host_policy(host_policy);
+            if let Ok(policy) = db::get_retry_policy(&conn) {
+                llm::set_retry_policy(policy);
             }
+            let handle = app.handle().clone();
+            let providers: Vec<_> = model_cache::warmup_targets(&conn)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|id| pick_provider(Some(&handle), &conn, None, Some(id)).ok())
+                .collect();
+            tauri::async_runtime::spawn(async move {
+                model_cache::start_warmup(providers);
+            });
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
@@ -1049,19 +2850,92 @@ fn main() {
             dq_create_provider,
             dq_update_provider,
             dq_delete_provider,
+            dq_list_secret_aliases,
+            dq_prune_secrets,
             dq_select_provider,
+            dq_set_provider_signing,
             dq_list_chats,
+            dq_create_chat,
             dq_get_chat_messages,
+            dq_get_chat_messages_page,
             dq_delete_chat,
             dq_branch_chat,
             dq_rename_chat,
+            dq_set_chat_pinned,
+            dq_set_chat_archived,
+            dq_mark_read,
+            dq_set_chat_provider,
+            dq_set_chat_model,
+            dq_get_chat_stream_retry,
+            dq_set_chat_stream_retry,
+            dq_get_chat_context_strategy,
+            dq_set_chat_context_strategy,
+            dq_get_chat_reply_language,
+            dq_set_chat_reply_language,
+            dq_get_stats,
+            dq_get_chat_tags,
+            dq_get_chat_summary,
+            dq_autotag_chat,
+            dq_create_debug_bundle,
+            dq_start_backfill,
+            dq_backfill_progress,
+            dq_cancel_backfill,
+            dq_get_autotag_config,
+            dq_set_autotag_config,
+            dq_get_summary_config,
+            dq_set_summary_config,
+            dq_get_refusal_policy,
+            dq_set_refusal_policy,
+            dq_rerun_message,
+            dq_preview_temperatures,
+            dq_list_message_variants,
+            dq_list_variants,
+            dq_edit_message,
+            dq_list_message_revisions,
+            dq_revert_message,
+            dq_list_smart_lists,
+            dq_save_smart_list,
+            dq_delete_smart_list,
+            dq_resolve_smart_list,
+            dq_get_stop_strings,
+            dq_set_stop_strings,
+            dq_get_max_output_chars,
+            dq_set_max_output_chars,
+            dq_parse_command,
+            dq_get_retry_policy,
+            dq_set_retry_policy,
+            dq_get_host_policy,
+            dq_set_host_policy,
+            dq_get_sql_console_enabled,
+            dq_set_sql_console_enabled,
+            dq_run_sql,
+            dq_list_export_pipelines,
+            dq_save_export_pipeline,
+            dq_delete_export_pipeline,
+            dq_run_export_pipeline,
+            dq_export_archive,
+            dq_verify_archive,
+            dq_import_archive,
             dq_list_models,
             dq_send_chat,
             dq_send_chat_stream,
             dq_cancel_stream,
+            dq_list_interrupted,
+            dq_resolve_interrupted,
             dq_health_check,
+            dq_model_warmup_status,
+            dq_doctor,
+            dq_recent_telemetry,
+            dq_backup_db,
             dq_health_check_preview
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|_app, event| {
+            // 退出前写完检查点等缓冲中的写操作
+            if let tauri::RunEvent::Exit = event {
+                db::shutdown_write_behind();
+                telemetry::shutdown();
+            }
+        });
 }
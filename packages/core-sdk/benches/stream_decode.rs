@@ -0,0 +1,171 @@
+/*
+ * \brief 流式解析热路径基准：对比旧的“拷贝块 + JSON 树”解析与 `SseDecoder` + 按字段反序列化的分配次数与耗时。
+ * \details 运行：`cargo bench -p dreamquill-core-sdk --bench stream_decode`
+ */
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use dreamquill_core_sdk::{llm, sse::SseDecoder};
+use serde_json::Value;
+
+/** \brief 统计分配次数与字节数的全局分配器。 */
+struct CountingAlloc;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static ALLOC_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        ALLOC_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        ALLOC_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/** \brief 模拟一次长回复：`deltas` 个 OpenAI 流式分片，按 `chunk_size` 字节切成网络块。 */
+fn openai_stream(deltas: usize, chunk_size: usize) -> Vec<Vec<u8>> {
+    let mut body = String::new();
+    for i in 0..deltas {
+        body.push_str(&format!(
+            "data: {{\"id\":\"chatcmpl-1\",\"object\":\"chat.completion.chunk\",\"created\":1700000000,\
+             \"model\":\"qwen2.5-7b-instruct\",\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"tok{} \"}},\
+             \"logprobs\":null,\"finish_reason\":null}}]}}\n\n",
+            i % 10
+        ));
+    }
+    body.push_str("data: [DONE]\n\n");
+    body.as_bytes()
+        .chunks(chunk_size)
+        .map(<[u8]>::to_vec)
+        .collect()
+}
+
+/** \brief 改造前的解析方式：整块拷贝、整体转字符串并构建完整 JSON 树。 */
+fn legacy_decode(chunks: &[Vec<u8>]) -> usize {
+    fn find_double_newline(buf: &[u8]) -> Option<usize> {
+        buf.windows(2).position(|w| w == b"\n\n")
+    }
+    fn extract_data_line(block: &[u8]) -> Option<String> {
+        let text = String::from_utf8_lossy(block);
+        text.lines().map(str::trim_start).find_map(|line| {
+            line.strip_prefix("data:")
+                .map(|rest| rest.trim().to_string())
+        })
+    }
+    fn parse_delta(line: &str) -> Option<String> {
+        let v: Value = serde_json::from_str(line).ok()?;
+        v.get("choices")?
+            .get(0)?
+            .get("delta")?
+            .get("content")?
+            .as_str()
+            .map(|s| s.to_string())
+    }
+
+    let mut buf = Vec::<u8>::new();
+    let mut total = 0;
+    for chunk in chunks {
+        buf.extend_from_slice(chunk);
+        while let Some(pos) = find_double_newline(&buf) {
+            let block = buf.drain(..pos + 2).collect::<Vec<u8>>();
+            if let Some(line) = extract_data_line(&block) {
+                if line == "[DONE]" {
+                    break;
+                }
+                if let Some(delta) = parse_delta(&line) {
+                    total += black_box(delta).len();
+                }
+            }
+        }
+    }
+    total
+}
+
+/** \brief 当前的解析方式。 */
+fn decoder_decode(chunks: &[Vec<u8>]) -> usize {
+    let mut decoder = SseDecoder::new();
+    let mut total = 0;
+    for chunk in chunks {
+        decoder.push(chunk);
+        while let Some(data) = decoder.next_data() {
+            if data == "[DONE]" {
+                break;
+            }
+            if let Some(delta) = llm::parse_openai_delta(&data) {
+                total += black_box(delta).len();
+            }
+        }
+    }
+    total
+}
+
+struct Sample {
+    allocs: usize,
+    bytes: usize,
+    elapsed: Duration,
+    output: usize,
+}
+
+fn measure(rounds: u32, f: impl Fn() -> usize) -> Sample {
+    black_box(f());
+    let allocs = ALLOCS.load(Ordering::Relaxed);
+    let bytes = ALLOC_BYTES.load(Ordering::Relaxed);
+    let started = Instant::now();
+    let mut output = 0;
+    for _ in 0..rounds {
+        output = f();
+    }
+    Sample {
+        allocs: (ALLOCS.load(Ordering::Relaxed) - allocs) / rounds as usize,
+        bytes: (ALLOC_BYTES.load(Ordering::Relaxed) - bytes) / rounds as usize,
+        elapsed: started.elapsed() / rounds,
+        output,
+    }
+}
+
+fn main() {
+    const DELTAS: usize = 20_000;
+    const ROUNDS: u32 = 10;
+    println!(
+        "{:<10} {:>7} {:>12} {:>14} {:>12}",
+        "parser", "chunk", "allocs/run", "alloc bytes", "time/run"
+    );
+    for chunk_size in [64, 512, 4096] {
+        let chunks = openai_stream(DELTAS, chunk_size);
+        let legacy = measure(ROUNDS, || legacy_decode(&chunks));
+        let current = measure(ROUNDS, || decoder_decode(&chunks));
+        assert_eq!(legacy.output, current.output, "parsers disagree");
+        for (name, sample) in [("legacy", &legacy), ("decoder", &current)] {
+            println!(
+                "{:<10} {:>7} {:>12} {:>14} {:>12.2?}",
+                name, chunk_size, sample.allocs, sample.bytes, sample.elapsed
+            );
+        }
+        println!(
+            "{:<10} {:>7} {:>11.1}x {:>13.1}x {:>11.1}x",
+            "reduction",
+            chunk_size,
+            legacy.allocs as f64 / current.allocs.max(1) as f64,
+            legacy.bytes as f64 / current.bytes.max(1) as f64,
+            legacy.elapsed.as_secs_f64() / current.elapsed.as_secs_f64().max(f64::EPSILON),
+        );
+    }
+}
@@ -0,0 +1,15 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    compile_protos();
+}
+
+/** \brief 用纯 Rust 的 protox 解析 proto，无需安装 protoc。 */
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    println!("cargo:rerun-if-changed=proto/dreamquill.proto");
+    let fds =
+        protox::compile(["dreamquill.proto"], ["proto"]).expect("parse proto/dreamquill.proto");
+    tonic_build::configure()
+        .compile_fds(fds)
+        .expect("generate grpc code");
+}
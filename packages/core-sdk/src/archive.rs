@@ -0,0 +1,470 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::db::{self, StoredMessage};
+
+/** \brief 清单中的格式标识。 */
+pub const ARCHIVE_FORMAT: &str = "dreamquill-archive";
+/** \brief 当前归档结构版本；导入时拒绝更高的版本。 */
+pub const ARCHIVE_SCHEMA_VERSION: u32 = 1;
+/** \brief 清单文件名。 */
+pub const MANIFEST_FILE: &str = "manifest.json";
+/** \brief 会话数据文件名（JSON Lines，每行一个会话）。 */
+pub const CHATS_FILE: &str = "chats.jsonl";
+
+/**
+ * \brief 归档清单：结构版本、各数据文件的 SHA-256 与记录数，导入前据此校验完整性。
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub format: String,
+    pub schema_version: u32,
+    /** \brief 导出时的 SDK 版本。 */
+    pub app_version: String,
+    /** \brief 导出时间（Unix 秒）。 */
+    pub created_at: i64,
+    pub counts: ArchiveCounts,
+    pub files: Vec<ArchiveFile>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveCounts {
+    pub chats: usize,
+    pub messages: usize,
+}
+
+/** \brief 归档中的数据文件。 */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveFile {
+    /** \brief 相对归档目录的路径。 */
+    pub path: String,
+    /** \brief 文件内容的 SHA-256（小写十六进制）。 */
+    pub sha256: String,
+    pub bytes: u64,
+}
+
+/**
+ * \brief 归档中的一个会话。
+ * \details `key` 由会话创建时间与首条消息计算，在不同机器上保持不变，导入时据此识别同一会话。
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedChat {
+    pub key: String,
+    pub title: String,
+    pub created_at: Option<i64>,
+    /** \brief 导出时绑定的 Provider 名称（不含密钥）。 */
+    pub provider: Option<String>,
+    pub messages: Vec<ArchivedMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedMessage {
+    pub role: String,
+    pub content: String,
+    pub created_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+}
+
+/**
+ * \brief 导入时对单个会话的处理方式。
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PlanAction {
+    /** \brief 新建会话。 */
+    Create,
+    /** \brief 本地已有该会话且是归档的前缀，追加缺少的消息。 */
+    Merge { chat_id: i64 },
+    /** \brief 本地已包含归档中的全部消息。 */
+    Skip { chat_id: i64 },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedChat {
+    pub key: String,
+    pub title: String,
+    #[serde(flatten)]
+    pub action: PlanAction,
+    /** \brief 将写入的消息数。 */
+    pub messages: usize,
+    /** \brief 导入后对应的本地会话 ID；预览时新建的会话为空。 */
+    pub chat_id: Option<i64>,
+}
+
+/**
+ * \brief 导入计划：校验通过的清单与每个会话的处理方式；预览与实际导入返回同一结构。
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportPlan {
+    pub manifest: ArchiveManifest,
+    pub chats: Vec<PlannedChat>,
+    pub created: usize,
+    pub merged: usize,
+    pub skipped: usize,
+    /** \brief 将写入的消息总数。 */
+    pub messages: usize,
+    /** \brief 是否已写入数据库。 */
+    pub committed: bool,
+}
+
+/**
+ * \brief 导出全部会话到目录：`chats.jsonl` 与记录其 SHA-256 和记录数的 `manifest.json`。
+ * \details Provider 只记录名称，不导出地址与密钥；已存在的同名文件会被覆盖。
+ */
+pub fn export_archive(conn: &Connection, dir: &Path) -> Result<ArchiveManifest> {
+    std::fs::create_dir_all(dir).with_context(|| format!("create {} failed", dir.display()))?;
+    let providers = db::list_providers(conn)?;
+    let mut chats = db::list_chats(conn, None, true)?;
+    chats.sort_by_key(|c| c.id);
+
+    let mut data = Vec::new();
+    let mut counts = ArchiveCounts::default();
+    for chat in chats {
+        let messages = db::load_messages_with_meta(conn, chat.id)?;
+        let archived = ArchivedChat {
+            key: chat_key(chat.created_at, &messages),
+            title: chat.title,
+            created_at: chat.created_at,
+            provider: chat
+                .provider_id
+                .and_then(|pid| providers.iter().find(|p| p.id == pid))
+                .map(|p| p.name.clone()),
+            messages: messages
+                .into_iter()
+                .map(|m| ArchivedMessage {
+                    role: m.role,
+                    content: m.content,
+                    created_at: m.created_at,
+                    metadata: m.metadata,
+                })
+                .collect(),
+        };
+        counts.chats += 1;
+        counts.messages += archived.messages.len();
+        serde_json::to_writer(&mut data, &archived)?;
+        data.write_all(b"\n")?;
+    }
+
+    let path = dir.join(CHATS_FILE);
+    std::fs::write(&path, &data).with_context(|| format!("write {} failed", path.display()))?;
+    let manifest = ArchiveManifest {
+        format: ARCHIVE_FORMAT.to_string(),
+        schema_version: ARCHIVE_SCHEMA_VERSION,
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        created_at: time::OffsetDateTime::now_utc().unix_timestamp(),
+        counts,
+        files: vec![ArchiveFile {
+            path: CHATS_FILE.to_string(),
+            sha256: sha256_hex(&data),
+            bytes: data.len() as u64,
+        }],
+    };
+    let path = dir.join(MANIFEST_FILE);
+    std::fs::write(&path, serde_json::to_vec_pretty(&manifest)?)
+        .with_context(|| format!("write {} failed", path.display()))?;
+    Ok(manifest)
+}
+
+/**
+ * \brief 校验归档并生成导入计划，不写入数据库。
+ * \details 清单格式或版本不符、文件缺失、大小或 SHA-256 不一致、记录数与清单不符时返回错误。
+ */
+pub fn verify_archive(conn: &Connection, dir: &Path) -> Result<ImportPlan> {
+    let (manifest, chats) = read_archive(dir)?;
+    plan_import(conn, manifest, &chats)
+}
+
+/**
+ * \brief 校验归档并在单个事务中按计划导入：新建会话、向本地会话追加缺少的消息，其余跳过。
+ * \param provider_id 新建会话绑定的 Provider；为空时按归档中的 Provider 名称匹配本地 Provider。
+ */
+pub fn import_archive(
+    conn: &Connection,
+    dir: &Path,
+    provider_id: Option<i64>,
+) -> Result<ImportPlan> {
+    let (manifest, chats) = read_archive(dir)?;
+    let mut plan = plan_import(conn, manifest, &chats)?;
+    let providers = db::list_providers(conn)?;
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+    let tx = conn.unchecked_transaction()?;
+    for (planned, chat) in plan.chats.iter_mut().zip(&chats) {
+        let (chat_id, skip) = match planned.action {
+            PlanAction::Skip { chat_id } => {
+                planned.chat_id = Some(chat_id);
+                continue;
+            }
+            PlanAction::Merge { chat_id } => (chat_id, chat.messages.len() - planned.messages),
+            PlanAction::Create => {
+                let provider = provider_id.or_else(|| {
+                    let name = chat.provider.as_deref()?;
+                    providers.iter().find(|p| p.name == name).map(|p| p.id)
+                });
+                let created_at = chat.created_at.unwrap_or(now);
+                (
+                    db::create_chat_at(&tx, &chat.title, provider, created_at)?,
+                    0,
+                )
+            }
+        };
+        for m in &chat.messages[skip..] {
+            db::insert_message_with_meta_at(
+                &tx,
+                chat_id,
+                &m.role,
+                &m.content,
+                m.created_at.or(chat.created_at).unwrap_or(now),
+                m.metadata.as_ref(),
+            )?;
+        }
+        planned.chat_id = Some(chat_id);
+    }
+    tx.commit()?;
+    plan.committed = true;
+    Ok(plan)
+}
+
+/** \brief 读取清单并逐个校验数据文件，返回解析后的会话。 */
+fn read_archive(dir: &Path) -> Result<(ArchiveManifest, Vec<ArchivedChat>)> {
+    let path = dir.join(MANIFEST_FILE);
+    let raw = std::fs::read(&path).with_context(|| format!("read {} failed", path.display()))?;
+    let manifest: ArchiveManifest =
+        serde_json::from_slice(&raw).with_context(|| format!("parse {} failed", path.display()))?;
+    if manifest.format != ARCHIVE_FORMAT {
+        bail!("not a DreamQuill archive (format {:?})", manifest.format);
+    }
+    if manifest.schema_version > ARCHIVE_SCHEMA_VERSION {
+        bail!(
+            "archive schema version {} is newer than supported version {}",
+            manifest.schema_version,
+            ARCHIVE_SCHEMA_VERSION
+        );
+    }
+
+    let mut chats = None;
+    for file in &manifest.files {
+        if file.path.contains(['/', '\\']) || file.path.starts_with('.') {
+            bail!("invalid file path {:?} in manifest", file.path);
+        }
+        let path = dir.join(&file.path);
+        let data =
+            std::fs::read(&path).with_context(|| format!("read {} failed", path.display()))?;
+        if data.len() as u64 != file.bytes {
+            bail!(
+                "{}: size {} does not match manifest ({})",
+                file.path,
+                data.len(),
+                file.bytes
+            );
+        }
+        let digest = sha256_hex(&data);
+        if !digest.eq_ignore_ascii_case(&file.sha256) {
+            bail!(
+                "{}: checksum mismatch (expected {}, got {})",
+                file.path,
+                file.sha256,
+                digest
+            );
+        }
+        if file.path == CHATS_FILE {
+            chats = Some(parse_chats(&data)?);
+        }
+    }
+    let chats = chats.ok_or_else(|| anyhow!("manifest does not list {}", CHATS_FILE))?;
+
+    let counts = ArchiveCounts {
+        chats: chats.len(),
+        messages: chats.iter().map(|c| c.messages.len()).sum(),
+    };
+    if counts != manifest.counts {
+        bail!(
+            "record counts do not match manifest: {} chats / {} messages, expected {} / {}",
+            counts.chats,
+            counts.messages,
+            manifest.counts.chats,
+            manifest.counts.messages
+        );
+    }
+    Ok((manifest, chats))
+}
+
+fn parse_chats(data: &[u8]) -> Result<Vec<ArchivedChat>> {
+    let mut chats = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let chat = serde_json::from_str(&line)
+            .with_context(|| format!("{} line {}: invalid chat record", CHATS_FILE, i + 1))?;
+        chats.push(chat);
+    }
+    Ok(chats)
+}
+
+/**
+ * \brief 按会话 key 与本地会话比对：消息完全包含时跳过，本地为归档前缀时追加，其余（含分歧）新建。
+ */
+fn plan_import(
+    conn: &Connection,
+    manifest: ArchiveManifest,
+    chats: &[ArchivedChat],
+) -> Result<ImportPlan> {
+    let mut local: HashMap<String, (i64, Vec<StoredMessage>)> = HashMap::new();
+    for chat in db::list_chats(conn, None, true)? {
+        let messages = db::load_messages_with_meta(conn, chat.id)?;
+        local
+            .entry(chat_key(chat.created_at, &messages))
+            .or_insert((chat.id, messages));
+    }
+
+    let mut plan = ImportPlan {
+        manifest,
+        chats: Vec::with_capacity(chats.len()),
+        created: 0,
+        merged: 0,
+        skipped: 0,
+        messages: 0,
+        committed: false,
+    };
+    for chat in chats {
+        let (action, messages) = match local.get(&chat.key) {
+            Some((chat_id, existing)) => {
+                let common = existing
+                    .iter()
+                    .zip(&chat.messages)
+                    .take_while(|(l, a)| l.role == a.role && l.content == a.content)
+                    .count();
+                if common == chat.messages.len() {
+                    (PlanAction::Skip { chat_id: *chat_id }, 0)
+                } else if common == existing.len() {
+                    (
+                        PlanAction::Merge { chat_id: *chat_id },
+                        chat.messages.len() - common,
+                    )
+                } else {
+                    (PlanAction::Create, chat.messages.len())
+                }
+            }
+            None => (PlanAction::Create, chat.messages.len()),
+        };
+        match action {
+            PlanAction::Create => plan.created += 1,
+            PlanAction::Merge { .. } => plan.merged += 1,
+            PlanAction::Skip { .. } => plan.skipped += 1,
+        }
+        plan.messages += messages;
+        plan.chats.push(PlannedChat {
+            key: chat.key.clone(),
+            title: chat.title.clone(),
+            action,
+            messages,
+            chat_id: None,
+        });
+    }
+    Ok(plan)
+}
+
+/** \brief 会话 key：创建时间与首条消息（角色、正文）的 SHA-256，取前 16 字节。 */
+fn chat_key(created_at: Option<i64>, messages: &[StoredMessage]) -> String {
+    let mut seed = created_at.unwrap_or_default().to_string();
+    if let Some(first) = messages.first() {
+        seed.push('\n');
+        seed.push_str(&first.role);
+        seed.push('\n');
+        seed.push_str(&first.content);
+    }
+    sha256_hex(seed.as_bytes())[..32].to_string()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(ring::digest::digest(&ring::digest::SHA256, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mem_conn() -> Connection {
+        let conn = Connection::open_in_memory().expect("open");
+        db::migrate(&conn).expect("migrate");
+        conn
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "dq-archive-{}-{}-{}",
+            name,
+            std::process::id(),
+            time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_round_trip_then_merge_and_skip() {
+        let source = mem_conn();
+        let chat_id = db::create_chat_at(&source, "迁移", None, 1_700_000_000).unwrap();
+        db::insert_message_at(&source, chat_id, "user", "hello", 1_700_000_001).unwrap();
+        db::insert_message_at(&source, chat_id, "assistant", "hi", 1_700_000_002).unwrap();
+        let dir = temp_dir("round-trip");
+        let manifest = export_archive(&source, &dir).unwrap();
+        assert_eq!(
+            manifest.counts,
+            ArchiveCounts {
+                chats: 1,
+                messages: 2
+            }
+        );
+
+        let target = mem_conn();
+        let preview = verify_archive(&target, &dir).unwrap();
+        assert_eq!((preview.created, preview.messages), (1, 2));
+        assert!(!preview.committed);
+        assert!(db::list_chats(&target, None, true).unwrap().is_empty());
+
+        let imported = import_archive(&target, &dir, None).unwrap();
+        let new_id = imported.chats[0].chat_id.unwrap();
+        assert_eq!(db::load_messages(&target, new_id).unwrap().len(), 2);
+        let again = verify_archive(&target, &dir).unwrap();
+        assert_eq!(again.chats[0].action, PlanAction::Skip { chat_id: new_id });
+
+        db::insert_message_at(&source, chat_id, "user", "more", 1_700_000_003).unwrap();
+        export_archive(&source, &dir).unwrap();
+        let merged = import_archive(&target, &dir, None).unwrap();
+        assert_eq!(
+            merged.chats[0].action,
+            PlanAction::Merge { chat_id: new_id }
+        );
+        assert_eq!(merged.messages, 1);
+        assert_eq!(db::load_messages(&target, new_id).unwrap().len(), 3);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_tampered_archive_is_rejected() {
+        let conn = mem_conn();
+        let chat_id = db::create_chat_at(&conn, "c", None, 1).unwrap();
+        db::insert_message_at(&conn, chat_id, "user", "hello", 2).unwrap();
+        let dir = temp_dir("tampered");
+        export_archive(&conn, &dir).unwrap();
+
+        let path = dir.join(CHATS_FILE);
+        let data = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, data.replace("hello", "hallo")).unwrap();
+        let err = verify_archive(&conn, &dir).unwrap_err().to_string();
+        assert!(err.contains("checksum mismatch"), "{}", err);
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{bail, Result};
+use rusqlite::Connection;
+use serde_json::Value;
+
+use crate::{
+    db::{self, ChatTag},
+    llm,
+    models::{Message, Provider},
+    telemetry,
+};
+
+/** \brief 送入分类请求的会话文本上限（字符），只保留最近的部分。 */
+const TRANSCRIPT_CHAR_LIMIT: usize = 6000;
+
+/**
+ * \brief 构造分类请求：要求模型只从候选标签中选择并给出置信度。
+ */
+pub fn build_prompt(taxonomy: &[String], messages: &[Message]) -> Vec<Message> {
+    let mut transcript = String::new();
+    for msg in messages.iter().filter(|m| m.role != "system") {
+        transcript.push_str(&format!("[{}] {}\n", msg.role, msg.content.trim()));
+    }
+    let total = transcript.chars().count();
+    if total > TRANSCRIPT_CHAR_LIMIT {
+        transcript = transcript
+            .chars()
+            .skip(total - TRANSCRIPT_CHAR_LIMIT)
+            .collect();
+    }
+
+    vec![
+        Message {
+            role: "system".to_string(),
+            content: format!(
+                "You classify conversations by topic. Choose zero or more tags ONLY from this list: {}. \
+                 Reply with a JSON array like [{{\"tag\": \"name\", \"confidence\": 0.0-1.0}}] and nothing else.",
+                serde_json::to_string(taxonomy).unwrap_or_default()
+            ),
+        },
+        Message {
+            role: "user".to_string(),
+            content: transcript,
+        },
+    ]
+}
+
+/**
+ * \brief 解析模型回复中的标签数组；忽略候选集合之外的标签，置信度限制在 0~1。
+ */
+pub fn parse_tags(reply: &str, taxonomy: &[String]) -> Vec<(String, f64)> {
+    let start = reply.find('[');
+    let end = reply.rfind(']');
+    let json = match (start, end) {
+        (Some(s), Some(e)) if s < e => &reply[s..=e],
+        _ => return Vec::new(),
+    };
+    let items = match serde_json::from_str::<Value>(json) {
+        Ok(Value::Array(items)) => items,
+        _ => return Vec::new(),
+    };
+
+    let mut tags: Vec<(String, f64)> = Vec::new();
+    for item in items {
+        let (name, confidence) = match &item {
+            Value::String(s) => (s.as_str(), 1.0),
+            Value::Object(_) => match item.get("tag").and_then(|t| t.as_str()) {
+                Some(t) => (
+                    t,
+                    item.get("confidence")
+                        .and_then(|c| c.as_f64())
+                        .unwrap_or(1.0),
+                ),
+                None => continue,
+            },
+            _ => continue,
+        };
+        let canonical = match taxonomy
+            .iter()
+            .find(|t| t.eq_ignore_ascii_case(name.trim()))
+        {
+            Some(t) => t,
+            None => continue,
+        };
+        if !tags.iter().any(|(t, _)| t == canonical) {
+            tags.push((canonical.clone(), confidence.clamp(0.0, 1.0)));
+        }
+    }
+    tags
+}
+
+/**
+ * \brief 调用 Provider 对会话打标签并写入 `auto` 标签，返回最新标签列表。
+ * \details 数据库连接不跨越 await 持有，可在后台任务中调用。
+ */
+pub async fn autotag_chat(provider: &Provider, chat_id: i64) -> Result<Vec<ChatTag>> {
+    let (taxonomy, messages) = db::run(move |conn| {
+        let config = db::get_autotag_config(conn)?;
+        Ok((config.taxonomy, db::load_messages(conn, chat_id)?))
+    })
+    .await?;
+    if taxonomy.is_empty() {
+        bail!("autotag taxonomy is empty");
+    }
+    if messages.is_empty() {
+        bail!("chat {} has no messages", chat_id);
+    }
+
+    let reply = llm::chat_once(provider, &build_prompt(&taxonomy, &messages)).await?;
+    let tags = parse_tags(&reply, &taxonomy);
+
+    db::run(move |conn| {
+        db::replace_auto_tags(conn, chat_id, &tags)?;
+        telemetry::log_event(
+            "autotag",
+            &format!("chat_id={} tags={}", chat_id, tags.len()),
+        );
+        db::list_chat_tags(conn, chat_id)
+    })
+    .await
+}
+
+/**
+ * \brief 会话消息数达到阈值且尚无自动标签时，在后台发起一次打标签。
+ */
+pub fn spawn_if_due(conn: &Connection, provider: Provider, chat_id: i64) {
+    let due = (|| -> Result<bool> {
+        let config = db::get_autotag_config(conn)?;
+        if config.min_messages == 0 || config.taxonomy.is_empty() {
+            return Ok(false);
+        }
+        if db::count_messages(conn, chat_id)? < config.min_messages {
+            return Ok(false);
+        }
+        Ok(!db::list_chat_tags(conn, chat_id)?
+            .iter()
+            .any(|t| t.source == "auto"))
+    })();
+
+    match due {
+        Ok(true) => {
+            tokio::spawn(async move {
+                if let Err(e) = autotag_chat(&provider, chat_id).await {
+                    telemetry::log_error("autotag", &format!("chat_id={} err={}", chat_id, e));
+                }
+            });
+        }
+        Ok(false) => {}
+        Err(e) => telemetry::log_error("autotag", &format!("check due failed: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn taxonomy() -> Vec<String> {
+        vec!["Rust".to_string(), "Database".to_string()]
+    }
+
+    #[test]
+    fn test_parse_tags_filters_unknown_and_clamps() {
+        let reply = "```json\n[{\"tag\": \"rust\", \"confidence\": 1.7}, {\"tag\": \"cooking\", \"confidence\": 0.9}, \"Database\"]\n```";
+        let tags = parse_tags(reply, &taxonomy());
+        assert_eq!(
+            tags,
+            vec![("Rust".to_string(), 1.0), ("Database".to_string(), 1.0)]
+        );
+        assert!(parse_tags("no tags here", &taxonomy()).is_empty());
+    }
+
+    #[test]
+    fn test_build_prompt_skips_system_and_truncates() {
+        let messages = vec![
+            Message {
+                role: "system".to_string(),
+                content: "secret instructions".to_string(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: "x".repeat(TRANSCRIPT_CHAR_LIMIT * 2),
+            },
+        ];
+        let prompt = build_prompt(&taxonomy(), &messages);
+        assert_eq!(prompt.len(), 2);
+        assert!(prompt[0].content.contains("[\"Rust\",\"Database\"]"));
+        assert!(!prompt[1].content.contains("secret"));
+        assert_eq!(prompt[1].content.chars().count(), TRANSCRIPT_CHAR_LIMIT);
+    }
+}
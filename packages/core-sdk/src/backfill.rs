@@ -0,0 +1,406 @@
+use std::{sync::Mutex, time::Duration};
+
+use anyhow::{bail, Result};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{
+    autotag, db, llm,
+    models::{Message, Provider},
+    telemetry,
+};
+
+/** \brief 默认每分钟最多发起的模型调用次数。 */
+pub const DEFAULT_CALLS_PER_MINUTE: u32 = 6;
+
+/** \brief 每分钟调用次数上限，避免补全任务挤占正常聊天的配额。 */
+pub const MAX_CALLS_PER_MINUTE: u32 = 30;
+
+/** \brief 送入标题请求的会话文本上限（字符），只保留开头部分。 */
+const TITLE_CHAR_LIMIT: usize = 2000;
+
+/** \brief 生成标题的最大长度（字符）。 */
+const TITLE_MAX_CHARS: usize = 40;
+
+/** \brief 结束生成前等待首轮自动标题的最长时间，超时后标题仍在后台写入，只是不再随本次回复推送。 */
+pub const FIRST_TITLE_WAIT: Duration = Duration::from_secs(10);
+
+/**
+ * \brief 标题与标签补全任务的进度快照。
+ */
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BackfillProgress {
+    pub running: bool,
+    /** \brief 本轮待处理的会话数。 */
+    pub total: usize,
+    pub processed: usize,
+    pub titled: usize,
+    pub tagged: usize,
+    pub failed: usize,
+    /** \brief 每分钟模型调用次数上限。 */
+    pub calls_per_minute: u32,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub cancelled: bool,
+    pub last_error: Option<String>,
+}
+
+static PROGRESS: Lazy<Mutex<BackfillProgress>> =
+    Lazy::new(|| Mutex::new(BackfillProgress::default()));
+
+fn update(f: impl FnOnce(&mut BackfillProgress)) {
+    if let Ok(mut guard) = PROGRESS.lock() {
+        f(&mut guard);
+    }
+}
+
+/**
+ * \brief 当前（或最近一轮）补全任务的进度。
+ */
+pub fn progress() -> BackfillProgress {
+    PROGRESS.lock().map(|g| g.clone()).unwrap_or_default()
+}
+
+/**
+ * \brief 请求停止正在运行的补全任务；当前调用完成后退出。
+ */
+pub fn cancel() -> BackfillProgress {
+    update(|p| {
+        if p.running {
+            p.cancelled = true;
+        }
+    });
+    progress()
+}
+
+fn cancelled() -> bool {
+    PROGRESS.lock().map(|g| g.cancelled).unwrap_or(true)
+}
+
+/**
+ * \brief 是否为创建时的占位标题（如 `OpenAI 会话`、`新会话`、`ChatGPT 导入会话`）。
+ */
+pub fn is_placeholder_title(title: &str) -> bool {
+    let title = title.trim();
+    title.is_empty() || title.ends_with("会话")
+}
+
+/**
+ * \brief 构造标题请求：取会话开头的对话，要求模型只回复一行简短标题。
+ */
+pub fn build_title_prompt(messages: &[Message]) -> Vec<Message> {
+    let mut transcript = String::new();
+    for msg in messages.iter().filter(|m| m.role != "system") {
+        transcript.push_str(&format!("[{}] {}\n", msg.role, msg.content.trim()));
+        if transcript.chars().count() >= TITLE_CHAR_LIMIT {
+            break;
+        }
+    }
+    let transcript: String = transcript.chars().take(TITLE_CHAR_LIMIT).collect();
+
+    vec![
+        Message {
+            role: "system".to_string(),
+            content: "You write titles for conversations. Reply with a short title (at most 8 words) \
+                      in the conversation's language, without quotes or trailing punctuation, and nothing else."
+                .to_string(),
+        },
+        Message {
+            role: "user".to_string(),
+            content: transcript,
+        },
+    ]
+}
+
+/**
+ * \brief 从模型回复中提取标题：取首个非空行，去掉引号、`Title:` 前缀与句末标点并截断。
+ */
+pub fn parse_title(reply: &str) -> Option<String> {
+    let line = reply.lines().map(str::trim).find(|l| !l.is_empty())?;
+    let line = line
+        .strip_prefix("Title:")
+        .or_else(|| line.strip_prefix("标题："))
+        .unwrap_or(line)
+        .trim();
+    let line = line
+        .trim_matches(|c: char| {
+            matches!(
+                c,
+                '"' | '\'' | '“' | '”' | '「' | '」' | '《' | '》' | '*' | '#'
+            )
+        })
+        .trim_end_matches(['.', '。', '!', '！'])
+        .trim();
+    if line.is_empty() {
+        return None;
+    }
+    Some(line.chars().take(TITLE_MAX_CHARS).collect())
+}
+
+/**
+ * \brief 为会话生成标题并写回；期间若用户已手动改名则保留用户标题。
+ * \details 数据库连接不跨越 await 持有，可在后台任务中调用。
+ */
+pub async fn title_chat(provider: &Provider, chat_id: i64) -> Result<Option<String>> {
+    let messages = db::run(move |conn| db::load_messages(conn, chat_id)).await?;
+    if messages.iter().all(|m| m.role == "system") {
+        bail!("chat {} has no messages", chat_id);
+    }
+
+    let reply = llm::chat_once(provider, &build_title_prompt(&messages)).await?;
+    let title = match parse_title(&reply) {
+        Some(t) => t,
+        None => bail!("empty title reply"),
+    };
+
+    db::run(move |conn| match db::get_chat(conn, chat_id)? {
+        Some(chat) if is_placeholder_title(&chat.title) => {
+            db::update_chat_title(conn, chat_id, &title)?;
+            Ok(Some(title))
+        }
+        _ => Ok(None),
+    })
+    .await
+}
+
+/**
+ * \brief 是否为新会话的首轮回复之后：仍为占位标题且恰有一条助手消息。
+ */
+pub fn first_exchange_due(conn: &rusqlite::Connection, chat_id: i64) -> Result<bool> {
+    let Some(chat) = db::get_chat(conn, chat_id)? else {
+        return Ok(false);
+    };
+    if !is_placeholder_title(&chat.title) {
+        return Ok(false);
+    }
+    let replies = db::load_messages(conn, chat_id)?
+        .iter()
+        .filter(|m| m.role == "assistant")
+        .count();
+    Ok(replies == 1)
+}
+
+/**
+ * \brief 在后台为新会话生成标题，返回任务句柄（结果为写入的新标题）；是否需要由调用方按 `first_exchange_due` 判断。
+ * \details 失败只记遥测；调用方可等待句柄以便向前端推送标题，不等待时任务照常完成。
+ */
+pub fn spawn_title(provider: Provider, chat_id: i64) -> tokio::task::JoinHandle<Option<String>> {
+    tokio::spawn(async move {
+        match title_chat(&provider, chat_id).await {
+            Ok(title) => {
+                if title.is_some() {
+                    telemetry::log_event("autotitle", &format!("chat_id={}", chat_id));
+                }
+                title
+            }
+            Err(e) => {
+                telemetry::log_error("autotitle", &format!("chat_id={} err={}", chat_id, e));
+                None
+            }
+        }
+    })
+}
+
+/**
+ * \brief 等待 `spawn_title` 的结果，最多 `FIRST_TITLE_WAIT`；未启动、失败或超时时返回 `None`。
+ */
+pub async fn wait_first_title(
+    handle: Option<tokio::task::JoinHandle<Option<String>>>,
+) -> Option<String> {
+    tokio::time::timeout(FIRST_TITLE_WAIT, handle?)
+        .await
+        .ok()?
+        .ok()
+        .flatten()
+}
+
+/**
+ * \brief 待补全的会话：占位标题、且至少有一条非系统消息。
+ */
+fn candidate_chats(conn: &rusqlite::Connection) -> Result<Vec<i64>> {
+    let mut ids = Vec::new();
+    for chat in db::list_chats(conn, None, true)? {
+        if !is_placeholder_title(&chat.title) {
+            continue;
+        }
+        let messages = db::load_messages(conn, chat.id)?;
+        if messages.iter().any(|m| m.role != "system") {
+            ids.push(chat.id);
+        }
+    }
+    Ok(ids)
+}
+
+/**
+ * \brief 启动后台补全：逐个为占位标题的会话生成标题，并在配置了候选标签时补打自动标签。
+ * \details 同一时间只运行一轮；所有模型调用串行执行，相邻调用间隔 `60 / calls_per_minute` 秒。
+ */
+pub fn start(provider: Provider, calls_per_minute: u32) -> Result<BackfillProgress> {
+    let calls_per_minute = calls_per_minute.clamp(1, MAX_CALLS_PER_MINUTE);
+    let (chat_ids, tagging) = {
+        let conn = db::open_default_db()?;
+        let config = db::get_autotag_config(&conn)?;
+        (candidate_chats(&conn)?, !config.taxonomy.is_empty())
+    };
+
+    {
+        let mut guard = PROGRESS
+            .lock()
+            .map_err(|_| anyhow::anyhow!("backfill progress lock poisoned"))?;
+        if guard.running {
+            bail!("backfill already running");
+        }
+        *guard = BackfillProgress {
+            running: true,
+            total: chat_ids.len(),
+            calls_per_minute,
+            started_at: Some(db::unix_now()),
+            ..Default::default()
+        };
+    }
+    telemetry::log_event(
+        "backfill",
+        &format!(
+            "start chats={} per_minute={} provider={}",
+            chat_ids.len(),
+            calls_per_minute,
+            provider.name
+        ),
+    );
+
+    tokio::spawn(async move {
+        let mut ticker =
+            tokio::time::interval(Duration::from_secs_f64(60.0 / calls_per_minute as f64));
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        for chat_id in chat_ids {
+            if cancelled() {
+                break;
+            }
+            ticker.tick().await;
+            let mut error = None;
+            match title_chat(&provider, chat_id).await {
+                Ok(Some(_)) => update(|p| p.titled += 1),
+                Ok(None) => {}
+                Err(e) => error = Some(e.to_string()),
+            }
+
+            let needs_tags = tagging
+                && error.is_none()
+                && db::run(move |conn| db::list_chat_tags(conn, chat_id))
+                    .await
+                    .map(|tags| !tags.iter().any(|t| t.source == "auto"))
+                    .unwrap_or(false);
+            if needs_tags && !cancelled() {
+                ticker.tick().await;
+                match autotag::autotag_chat(&provider, chat_id).await {
+                    Ok(_) => update(|p| p.tagged += 1),
+                    Err(e) => error = Some(e.to_string()),
+                }
+            }
+
+            if let Some(e) = &error {
+                telemetry::log_error("backfill", &format!("chat_id={} err={}", chat_id, e));
+            }
+            update(|p| {
+                p.processed += 1;
+                if error.is_some() {
+                    p.failed += 1;
+                    p.last_error = error;
+                }
+            });
+        }
+        update(|p| {
+            p.running = false;
+            p.finished_at = Some(db::unix_now());
+        });
+        let done = progress();
+        telemetry::log_event(
+            "backfill",
+            &format!(
+                "finish processed={} titled={} tagged={} failed={} cancelled={}",
+                done.processed, done.titled, done.tagged, done.failed, done.cancelled
+            ),
+        );
+    });
+
+    Ok(progress())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_placeholder_titles_cover_default_names() {
+        assert!(is_placeholder_title("OpenAI 会话"));
+        assert!(is_placeholder_title("新会话"));
+        assert!(is_placeholder_title("ChatGPT 导入会话"));
+        assert!(is_placeholder_title("  "));
+        assert!(!is_placeholder_title("Rust 生命周期问题"));
+    }
+
+    #[test]
+    fn test_parse_title_cleans_reply() {
+        assert_eq!(
+            parse_title("\n\"Borrow checker basics.\"\nextra"),
+            Some("Borrow checker basics".to_string())
+        );
+        assert_eq!(
+            parse_title("标题：《数据库索引》"),
+            Some("数据库索引".to_string())
+        );
+        assert_eq!(
+            parse_title(&"x".repeat(100)).map(|t| t.chars().count()),
+            Some(TITLE_MAX_CHARS)
+        );
+        assert!(parse_title("  \n \"\" ").is_none());
+    }
+
+    #[test]
+    fn test_build_title_prompt_skips_system_and_truncates() {
+        let messages = vec![
+            Message {
+                role: "system".to_string(),
+                content: "secret instructions".to_string(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: "y".repeat(TITLE_CHAR_LIMIT * 2),
+            },
+        ];
+        let prompt = build_title_prompt(&messages);
+        assert_eq!(prompt.len(), 2);
+        assert!(!prompt[1].content.contains("secret"));
+        assert_eq!(prompt[1].content.chars().count(), TITLE_CHAR_LIMIT);
+    }
+
+    #[test]
+    fn test_first_exchange_due_only_after_first_reply() {
+        let conn = rusqlite::Connection::open_in_memory().expect("open db");
+        db::migrate(&conn).expect("migrate");
+        let provider_id = db::insert_provider(
+            &conn,
+            "p",
+            "openai",
+            "https://api.example.com",
+            "k",
+            "m",
+            None,
+        )
+        .unwrap();
+        let chat_id = db::create_chat(&conn, "p 会话", provider_id).unwrap();
+        db::insert_message(&conn, chat_id, "user", "hello").unwrap();
+        assert!(!first_exchange_due(&conn, chat_id).unwrap());
+        db::insert_message(&conn, chat_id, "assistant", "hi").unwrap();
+        assert!(first_exchange_due(&conn, chat_id).unwrap());
+        db::insert_message(&conn, chat_id, "user", "again").unwrap();
+        db::insert_message(&conn, chat_id, "assistant", "sure").unwrap();
+        assert!(!first_exchange_due(&conn, chat_id).unwrap());
+
+        let renamed = db::create_chat(&conn, "p 会话", provider_id).unwrap();
+        db::insert_message(&conn, renamed, "user", "hello").unwrap();
+        db::insert_message(&conn, renamed, "assistant", "hi").unwrap();
+        db::update_chat_title(&conn, renamed, "Greetings").unwrap();
+        assert!(!first_exchange_due(&conn, renamed).unwrap());
+    }
+}
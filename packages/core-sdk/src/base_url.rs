@@ -0,0 +1,255 @@
+use anyhow::{anyhow, bail, Result};
+
+use crate::models::HostPolicy;
+
+/**
+ * \brief 常被误粘贴到 api_base 末尾的接口路径（小写分段），归一化时去掉。
+ */
+const ENDPOINT_SUFFIXES: &[&[&str]] = &[
+    &["chat", "completions"],
+    &["completions"],
+    &["responses"],
+    &["messages"],
+    &["embeddings"],
+    &["models"],
+];
+
+/** \brief Gemini `models/{model}:{method}` 形式的接口方法。 */
+const GEMINI_METHODS: &[&str] = &[
+    "generatecontent",
+    "streamgeneratecontent",
+    "embedcontent",
+    "batchembedcontents",
+    "counttokens",
+];
+
+/**
+ * \brief 归一化 Provider 的 api_base，供 Provider 增改与请求构造共用。
+ * \details 去掉首尾空白、引号与末尾斜杠；缺少协议时本机与内网地址补 `http://`，其余补 `https://`；
+ * 误粘贴的完整接口路径（如 `.../v1/chat/completions`、`.../v1beta/models/gemini-pro:generateContent`）
+ * 截去接口部分，保留版本段。只接受 http/https，且不能带查询参数。
+ */
+pub fn normalize(raw: &str) -> Result<String> {
+    let trimmed = raw
+        .trim()
+        .trim_matches(|c| matches!(c, '"' | '\'' | '<' | '>' | '`'))
+        .trim();
+    if trimmed.is_empty() {
+        bail!("api_base is required");
+    }
+    let with_scheme = if trimmed.contains("://") {
+        trimmed.to_string()
+    } else {
+        let host = trimmed.split(['/', '?', '#']).next().unwrap_or_default();
+        let scheme = if is_local_host(host) { "http" } else { "https" };
+        format!("{}://{}", scheme, trimmed)
+    };
+    let mut url = reqwest::Url::parse(&with_scheme)
+        .map_err(|e| anyhow!("invalid api_base {:?}: {}", trimmed, e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!(
+            "unsupported api_base scheme {:?}, expected http or https",
+            url.scheme()
+        );
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        bail!("api_base {:?} has no host", trimmed);
+    }
+    if url.query().is_some() {
+        bail!("api_base {:?} must not contain a query string", trimmed);
+    }
+    url.set_fragment(None);
+
+    let mut segments: Vec<String> = url
+        .path_segments()
+        .map(|s| s.filter(|s| !s.is_empty()).map(str::to_string).collect())
+        .unwrap_or_default();
+    strip_endpoint(&mut segments);
+    url.set_path(&segments.join("/"));
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+/**
+ * \brief 按主机名白名单/黑名单校验 api_base，未通过时报错并指明主机。
+ * \details api_base 先经 `normalize` 归一化；策略为空时直接放行。
+ */
+pub fn check_host(api_base: &str, policy: &HostPolicy) -> Result<()> {
+    if policy.is_empty() {
+        return Ok(());
+    }
+    let base = normalize(api_base)?;
+    let url =
+        reqwest::Url::parse(&base).map_err(|e| anyhow!("invalid api_base {:?}: {}", base, e))?;
+    let host = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    if policy.deny.iter().any(|p| host_matches(&host, p)) {
+        bail!("api_base host {:?} is denied by the host policy", host);
+    }
+    if !policy.allow.is_empty() && !policy.allow.iter().any(|p| host_matches(&host, p)) {
+        bail!("api_base host {:?} is not in the host allowlist", host);
+    }
+    Ok(())
+}
+
+/**
+ * \brief 校验重定向目标的主机：规则同 `check_host`，目标地址中的查询参数与片段不参与校验。
+ */
+pub fn check_redirect(target: &reqwest::Url, policy: &HostPolicy) -> Result<()> {
+    let mut url = target.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    check_host(url.as_str(), policy).map_err(|e| anyhow!("redirect blocked: {}", e))
+}
+
+/** \brief `pattern` 为主机名时精确匹配，`*.` 开头时匹配其任意子域。 */
+fn host_matches(host: &str, pattern: &str) -> bool {
+    let pattern = pattern.trim().trim_end_matches('.').to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|rest| rest.len() > 1 && rest.ends_with('.')),
+        None => host == pattern,
+    }
+}
+
+/**
+ * \brief 从路径末尾去掉误粘贴的接口段：先处理 Gemini 的 `models/{model}:{method}`，再反复去掉通用接口后缀。
+ */
+fn strip_endpoint(segments: &mut Vec<String>) {
+    if let Some(pos) = segments
+        .iter()
+        .rposition(|s| s.eq_ignore_ascii_case("models"))
+    {
+        let is_gemini_call = segments.get(pos + 1).is_some_and(|s| {
+            s.rsplit_once(':')
+                .is_some_and(|(_, m)| GEMINI_METHODS.contains(&m.to_ascii_lowercase().as_str()))
+        });
+        if is_gemini_call && pos + 2 == segments.len() {
+            segments.truncate(pos);
+        }
+    }
+    while let Some(suffix) = ENDPOINT_SUFFIXES.iter().find(|suffix| {
+        segments.len() >= suffix.len()
+            && segments[segments.len() - suffix.len()..]
+                .iter()
+                .zip(suffix.iter())
+                .all(|(s, e)| s.eq_ignore_ascii_case(e))
+    }) {
+        segments.truncate(segments.len() - suffix.len());
+    }
+}
+
+/**
+ * \brief 本机或内网地址（localhost、回环、私有网段、`.local`），缺少协议时按 http 处理。
+ */
+fn is_local_host(host_port: &str) -> bool {
+    let host = match host_port.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host_port.rsplit_once(':').map_or(host_port, |(h, _)| h),
+    };
+    let host = host.to_ascii_lowercase();
+    if host == "localhost" || host.ends_with(".localhost") || host.ends_with(".local") {
+        return true;
+    }
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => ip.is_loopback() || ip.is_private() || ip.is_unspecified(),
+        Ok(std::net::IpAddr::V6(ip)) => ip.is_loopback(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_messy_pasted_bases() {
+        let cases = [
+            ("https://api.openai.com/v1/", "https://api.openai.com/v1"),
+            ("  https://api.openai.com/v1  ", "https://api.openai.com/v1"),
+            ("\"https://api.openai.com/v1\"", "https://api.openai.com/v1"),
+            ("api.openai.com/v1", "https://api.openai.com/v1"),
+            ("localhost:11434/v1", "http://localhost:11434/v1"),
+            ("127.0.0.1:8080", "http://127.0.0.1:8080"),
+            ("192.168.1.20:1234/v1/", "http://192.168.1.20:1234/v1"),
+            (
+                "https://api.openai.com/v1/chat/completions",
+                "https://api.openai.com/v1",
+            ),
+            (
+                "https://api.deepseek.com/chat/completions/",
+                "https://api.deepseek.com",
+            ),
+            ("https://api.anthropic.com/v1/messages", "https://api.anthropic.com/v1"),
+            ("https://api.openai.com/v1/models", "https://api.openai.com/v1"),
+            (
+                "https://generativelanguage.googleapis.com/v1beta/models/gemini-1.5-pro:generateContent",
+                "https://generativelanguage.googleapis.com/v1beta",
+            ),
+            ("https://gw.example.com//openai//v1//", "https://gw.example.com/openai/v1"),
+            ("HTTPS://API.Example.COM/V1", "https://api.example.com/V1"),
+            ("https://api.example.com/v1#frag", "https://api.example.com/v1"),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(normalize(raw).unwrap(), expected, "input {:?}", raw);
+        }
+    }
+
+    #[test]
+    fn test_normalize_rejects_unusable_bases() {
+        assert!(normalize("   ").is_err());
+        assert!(normalize("ftp://api.example.com").is_err());
+        assert!(normalize("https://").is_err());
+        assert!(normalize("https://api.example.com/v1?api-version=1").is_err());
+        assert!(normalize("https://exa mple.com").is_err());
+    }
+
+    #[test]
+    fn test_normalize_is_idempotent() {
+        let once = normalize("api.openai.com/v1/chat/completions").unwrap();
+        assert_eq!(normalize(&once).unwrap(), once);
+    }
+
+    #[test]
+    fn test_check_host_allow_and_deny() {
+        let policy = HostPolicy {
+            allow: vec!["api.openai.com".into(), "*.example.com".into()],
+            deny: vec!["evil.example.com".into()],
+        }
+        .normalized();
+        assert!(check_host("https://api.openai.com/v1", &policy).is_ok());
+        assert!(check_host("API.OpenAI.com/v1/chat/completions", &policy).is_ok());
+        assert!(check_host("https://gw.example.com/v1", &policy).is_ok());
+        assert!(check_host("https://example.com/v1", &policy).is_err());
+        assert!(check_host("https://evil.example.com/v1", &policy).is_err());
+        assert!(check_host("https://api.openai.com.attacker.net/v1", &policy).is_err());
+        assert!(check_host("http://127.0.0.1:8080", &policy).is_err());
+
+        let deny_only = HostPolicy {
+            allow: Vec::new(),
+            deny: vec!["*.attacker.net".into()],
+        };
+        assert!(check_host("https://x.attacker.net", &deny_only).is_err());
+        assert!(check_host("https://api.deepseek.com", &deny_only).is_ok());
+        assert!(check_host("https://anything.test", &HostPolicy::default()).is_ok());
+    }
+
+    #[test]
+    fn test_check_redirect_applies_host_policy() {
+        let policy = HostPolicy {
+            allow: vec!["api.openai.com".into()],
+            deny: Vec::new(),
+        }
+        .normalized();
+        let ok = reqwest::Url::parse("https://api.openai.com/v1/models?page=2#top").unwrap();
+        assert!(check_redirect(&ok, &policy).is_ok());
+        let away = reqwest::Url::parse("http://169.254.169.254/latest/meta-data?x=1").unwrap();
+        let err = check_redirect(&away, &policy).unwrap_err().to_string();
+        assert!(err.contains("redirect blocked"), "{err}");
+    }
+}
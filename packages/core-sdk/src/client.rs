@@ -0,0 +1,458 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{anyhow, Context, Result};
+use async_stream::try_stream;
+use futures_util::{Stream, StreamExt};
+use rusqlite::Connection;
+
+use crate::{
+    context, db, finalize, llm,
+    models::{Message, Provider},
+    paths, telemetry,
+};
+
+/**
+ * \brief 按别名读取 Provider 密钥，供数据库中只保存了 `secret_alias` 的 Provider 使用。
+ */
+pub trait SecretStore: Send + Sync {
+    /** \brief 读取别名对应的密钥，不存在时返回 `None`。 */
+    fn get(&self, alias: &str) -> Result<Option<String>>;
+}
+
+/**
+ * \brief 从环境变量读取密钥：别名 `provider:3` 对应 `DREAMQUILL_SECRET_PROVIDER_3`。
+ */
+#[derive(Debug, Clone, Default)]
+pub struct EnvSecretStore;
+
+impl EnvSecretStore {
+    /** \brief 别名对应的环境变量名：大写，非字母数字字符替换为下划线。 */
+    pub fn var_name(alias: &str) -> String {
+        let suffix: String = alias
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        format!("DREAMQUILL_SECRET_{}", suffix)
+    }
+}
+
+impl SecretStore for EnvSecretStore {
+    fn get(&self, alias: &str) -> Result<Option<String>> {
+        Ok(std::env::var(Self::var_name(alias))
+            .ok()
+            .filter(|v| !v.is_empty()))
+    }
+}
+
+/**
+ * \brief 内存密钥表，便于测试或由宿主应用自行加载密钥后注入。
+ */
+#[derive(Debug, Clone, Default)]
+pub struct MemorySecretStore {
+    secrets: HashMap<String, String>,
+}
+
+impl MemorySecretStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** \brief 写入一条别名到密钥的映射。 */
+    pub fn insert(&mut self, alias: impl Into<String>, secret: impl Into<String>) -> &mut Self {
+        self.secrets.insert(alias.into(), secret.into());
+        self
+    }
+}
+
+impl SecretStore for MemorySecretStore {
+    fn get(&self, alias: &str) -> Result<Option<String>> {
+        Ok(self.secrets.get(alias).cloned())
+    }
+}
+
+/**
+ * \brief `DreamQuill` 构建器。
+ */
+pub struct DreamQuillBuilder {
+    db_path: PathBuf,
+    secret_store: Arc<dyn SecretStore>,
+}
+
+impl Default for DreamQuillBuilder {
+    fn default() -> Self {
+        Self {
+            db_path: paths::db_path().to_path_buf(),
+            secret_store: Arc::new(EnvSecretStore),
+        }
+    }
+}
+
+impl DreamQuillBuilder {
+    /** \brief SQLite 数据库路径，缺省与 `db::open_default_db` 相同（见 `paths::db_path`）。 */
+    pub fn db_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.db_path = path.as_ref().to_path_buf();
+        self
+    }
+
+    /** \brief 密钥来源，缺省为 `EnvSecretStore`。 */
+    pub fn secret_store(mut self, store: impl SecretStore + 'static) -> Self {
+        self.secret_store = Arc::new(store);
+        self
+    }
+
+    /**
+     * \brief 打开数据库并执行迁移，加载其中保存的请求重试策略。
+     */
+    pub fn build(self) -> Result<DreamQuill> {
+        let dq = DreamQuill {
+            db_path: self.db_path,
+            secrets: self.secret_store,
+        };
+        let conn = dq.connection()?;
+        db::migrate(&conn).context("migrate database failed")?;
+        llm::set_retry_policy(db::get_retry_policy(&conn)?);
+        Ok(dq)
+    }
+}
+
+/**
+ * \brief 嵌入式入口：不经过 HTTP 服务或 Tauri，直接复用 Provider、会话与持久化。
+ * \details 只持有数据库路径与密钥来源，每次操作按需打开连接，可在线程间克隆共享。
+ *
+ * ```no_run
+ * use dreamquill_core_sdk::{client::ChatEvent, DreamQuill};
+ * use futures_util::StreamExt;
+ *
+ * # async fn demo() -> anyhow::Result<()> {
+ * let dq = DreamQuill::builder().db_path("app.db").build()?;
+ * let chat_id = dq.create_chat("demo", None)?;
+ * let mut stream = dq.chat(chat_id).send("你好").stream().await?;
+ * while let Some(event) = stream.next().await {
+ *     match event? {
+ *         ChatEvent::Delta(text) => print!("{}", text),
+ *         ChatEvent::Done(reply) => println!("\n(message id={})", reply.message_id),
+ *     }
+ * }
+ * # Ok(())
+ * # }
+ * ```
+ */
+#[derive(Clone)]
+pub struct DreamQuill {
+    db_path: PathBuf,
+    secrets: Arc<dyn SecretStore>,
+}
+
+impl DreamQuill {
+    pub fn builder() -> DreamQuillBuilder {
+        DreamQuillBuilder::default()
+    }
+
+    /** \brief 数据库路径。 */
+    pub fn db_path(&self) -> &Path {
+        &self.db_path
+    }
+
+    /**
+     * \brief 打开一个新的数据库连接，可直接配合 `db` 模块的底层函数使用。
+     */
+    pub fn connection(&self) -> Result<Connection> {
+        db::open_db_at(&self.db_path)
+    }
+
+    /** \brief 已配置的全部 Provider（密钥未填充）。 */
+    pub fn providers(&self) -> Result<Vec<Provider>> {
+        db::list_providers(&self.connection()?)
+    }
+
+    /**
+     * \brief 取指定 Provider（缺省为默认 Provider），并从密钥来源填充 `api_key`。
+     */
+    pub fn provider(&self, provider_id: Option<i64>) -> Result<Provider> {
+        let conn = self.connection()?;
+        let provider = match provider_id {
+            Some(id) => db::get_provider_by_id(&conn, id)?
+                .ok_or_else(|| anyhow!("provider id {} not found", id))?,
+            None => db::get_default_provider(&conn)?
+                .ok_or_else(|| anyhow!("no default provider configured"))?,
+        };
+        self.hydrate(provider)
+    }
+
+    fn hydrate(&self, mut provider: Provider) -> Result<Provider> {
+        if !provider.api_key.is_empty() {
+            return Ok(provider);
+        }
+        if let Some(alias) = provider.secret_alias.as_deref() {
+            provider.api_key = self.secrets.get(alias)?.ok_or_else(|| {
+                anyhow!("secret {} for provider {} not found", alias, provider.name)
+            })?;
+        }
+        Ok(provider)
+    }
+
+    /**
+     * \brief 新建会话，返回会话 ID；`provider_id` 为空时不绑定，发送时使用默认 Provider。
+     */
+    pub fn create_chat(&self, title: &str, provider_id: Option<i64>) -> Result<i64> {
+        db::create_empty_chat(&self.connection()?, title, provider_id, None)
+    }
+
+    /** \brief 操作指定会话。 */
+    pub fn chat(&self, chat_id: i64) -> ChatHandle {
+        ChatHandle {
+            dq: self.clone(),
+            chat_id,
+        }
+    }
+}
+
+/**
+ * \brief 单个会话的操作句柄。
+ */
+pub struct ChatHandle {
+    dq: DreamQuill,
+    chat_id: i64,
+}
+
+impl ChatHandle {
+    pub fn id(&self) -> i64 {
+        self.chat_id
+    }
+
+    /** \brief 会话中的全部消息，按时间正序。 */
+    pub fn messages(&self) -> Result<Vec<db::StoredMessage>> {
+        db::load_messages_with_meta(&self.dq.connection()?, self.chat_id)
+    }
+
+    /** \brief 准备发送一条用户消息，调用 `stream` 或 `complete` 后才真正写入并请求模型。 */
+    pub fn send(&self, prompt: impl Into<String>) -> SendRequest {
+        SendRequest {
+            dq: self.dq.clone(),
+            chat_id: self.chat_id,
+            prompt: prompt.into(),
+            provider_id: None,
+            overrides: llm::RequestOverrides::default(),
+            redact_prompt: false,
+        }
+    }
+}
+
+/**
+ * \brief 一条已保存的助手回复。
+ */
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub message_id: i64,
+    pub content: String,
+    /** \brief 回复是否被停止串或输出上限截断。 */
+    pub truncated: bool,
+}
+
+/**
+ * \brief 流式回复事件：增量文本，结束时附带已保存的完整回复。
+ */
+#[derive(Debug, Clone)]
+pub enum ChatEvent {
+    Delta(String),
+    Done(Reply),
+}
+
+/** \brief `SendRequest::stream` 返回的事件流。 */
+pub type ChatStream = Pin<Box<dyn Stream<Item = Result<ChatEvent>> + Send>>;
+
+/**
+ * \brief 待发送的用户消息及其请求参数。
+ */
+pub struct SendRequest {
+    dq: DreamQuill,
+    chat_id: i64,
+    prompt: String,
+    provider_id: Option<i64>,
+    overrides: llm::RequestOverrides,
+    redact_prompt: bool,
+}
+
+impl SendRequest {
+    /** \brief 指定本次使用的 Provider，缺省依次取会话绑定的与默认的 Provider。 */
+    pub fn provider(mut self, provider_id: i64) -> Self {
+        self.provider_id = Some(provider_id);
+        self
+    }
+
+    /** \brief 单次参数覆盖（模型、温度、输出上限、系统指令）。 */
+    pub fn overrides(mut self, overrides: llm::RequestOverrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /** \brief 提示原文只发送给模型，会话中保存为占位文本 `db::REDACTED_PROMPT`。 */
+    pub fn redact_prompt(mut self) -> Self {
+        self.redact_prompt = true;
+        self
+    }
+
+    /**
+     * \brief 写入用户消息并开始流式请求；流结束（或命中停止串、达到输出上限）后助手回复写入数据库并产出 `ChatEvent::Done`。
+     */
+    pub async fn stream(self) -> Result<ChatStream> {
+        let prompt = self.prompt.trim().to_string();
+        if prompt.is_empty() {
+            return Err(anyhow!("prompt must not be empty"));
+        }
+        let conn = self.dq.connection()?;
+        let chat = db::get_chat(&conn, self.chat_id)?
+            .ok_or_else(|| anyhow!("chat id {} not found", self.chat_id))?;
+        let provider = self.dq.provider(self.provider_id.or(chat.provider_id))?;
+        let overrides = self.overrides.normalized();
+        let stops = db::get_stop_strings(&conn)?;
+        let max_output_chars = db::get_max_output_chars(&conn)?;
+        let plan = if self.redact_prompt {
+            let id = db::insert_redacted_user_message(&conn, self.chat_id, &[])?;
+            context::plan(&conn, self.chat_id, Some(&provider))?.with_redacted_prompt(id, &prompt)
+        } else {
+            db::insert_user_message(&conn, self.chat_id, &prompt, &[])?;
+            context::plan(&conn, self.chat_id, Some(&provider))?
+        };
+        drop(conn);
+        let selection = plan.select(Some(&provider)).await;
+        selection.save_embeddings(&self.dq.connection()?)?;
+        let messages: Vec<Message> = selection.messages;
+
+        telemetry::log_event(
+            "sdk.chat",
+            &format!(
+                "provider={}({}) chat_id={} prompt_len={}",
+                provider.name,
+                provider.provider_type,
+                self.chat_id,
+                prompt.len()
+            ),
+        );
+
+        let dq = self.dq;
+        let chat_id = self.chat_id;
+        let stream = try_stream! {
+            let started = Instant::now();
+            let mut first_token_ms: Option<i64> = None;
+            let mut trimmer = llm::StopTrimmer::new(&stops).with_max_chars(max_output_chars);
+            let mut assistant_buf = String::new();
+            let mut upstream = llm::stream_chat_with(&provider, &messages, &overrides).await?;
+            while let Some(delta) = upstream.next().await {
+                let delta = delta?;
+                if first_token_ms.is_none() {
+                    first_token_ms = Some(started.elapsed().as_millis() as i64);
+                }
+                let visible = trimmer.push(&delta);
+                if !visible.is_empty() {
+                    assistant_buf.push_str(&visible);
+                    yield ChatEvent::Delta(visible);
+                }
+                if trimmer.stopped() {
+                    break;
+                }
+            }
+            let tail = trimmer.finish();
+            if !tail.is_empty() {
+                assistant_buf.push_str(&tail);
+                yield ChatEvent::Delta(tail);
+            }
+
+            let timing = db::GenerationTiming {
+                provider_id: Some(provider.id),
+                first_token_ms,
+                duration_ms: Some(started.elapsed().as_millis() as i64),
+            };
+            let message_id = finalize::finalize(
+                &dq.connection()?,
+                &finalize::FinishedReply {
+                    chat_id,
+                    content: &assistant_buf,
+                    prompt: &messages,
+                    timing,
+                    metadata: trimmer.annotate(overrides.to_metadata()),
+                    partial: false,
+                },
+            )?
+            .message_id;
+            yield ChatEvent::Done(Reply {
+                message_id,
+                content: assistant_buf,
+                truncated: trimmer.stopped(),
+            });
+        };
+        Ok(Box::pin(stream))
+    }
+
+    /**
+     * \brief 发送并等待完整回复。
+     */
+    pub async fn complete(self) -> Result<Reply> {
+        let mut stream = self.stream().await?;
+        while let Some(event) = stream.next().await {
+            if let ChatEvent::Done(reply) = event? {
+                return Ok(reply);
+            }
+        }
+        Err(anyhow!("stream ended without a reply"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_migrates_and_hydrates_secrets() {
+        let path =
+            std::env::temp_dir().join(format!("dreamquill-client-{}.db", db::process_session_id()));
+        let _ = std::fs::remove_file(&path);
+        let mut secrets = MemorySecretStore::new();
+        secrets.insert("provider:vault", "sk-from-store");
+        let dq = DreamQuill::builder()
+            .db_path(&path)
+            .secret_store(secrets)
+            .build()
+            .expect("build");
+
+        let conn = dq.connection().expect("connect");
+        let id = db::insert_provider(
+            &conn,
+            "vaulted",
+            "openai",
+            "https://api.example.com",
+            "",
+            "gpt-4o",
+            Some("provider:vault"),
+        )
+        .expect("insert provider");
+        db::set_default_provider_id(&conn, id).expect("default");
+        assert_eq!(
+            dq.provider(None).expect("provider").api_key,
+            "sk-from-store"
+        );
+
+        db::set_provider_secret_alias(&conn, id, Some("provider:missing")).expect("alias");
+        assert!(dq.provider(Some(id)).is_err());
+
+        let chat_id = dq.create_chat("embedded", Some(id)).expect("chat");
+        assert!(dq.chat(chat_id).messages().expect("messages").is_empty());
+        assert_eq!(
+            EnvSecretStore::var_name("provider:3"),
+            "DREAMQUILL_SECRET_PROVIDER_3"
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+
+/**
+ * \brief 对话中以 `/` 开头的命令，CLI REPL 与桌面端（`dq_parse_command`）共用同一套语义。
+ * \details 序列化为 `{"command": "model", "model": "gpt-4o"}` 这样的带标签对象。
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ChatCommand {
+    /** \brief 新建会话（发送下一条消息时创建）。 */
+    New,
+    /** \brief 切换到已有会话。 */
+    Switch {
+        chat_id: i64,
+    },
+    /** \brief 切换 Provider 并绑定到当前会话。 */
+    Provider {
+        provider_id: i64,
+    },
+    /** \brief 之后的请求使用指定模型；为空时恢复 Provider 的默认模型。 */
+    Model {
+        model: Option<String>,
+    },
+    /** \brief 之后的请求使用指定系统指令；为空时恢复会话原有的 system 消息。 */
+    System {
+        instruction: Option<String>,
+    },
+    /** \brief 复制当前会话为新分支并切换过去，可只保留到指定消息为止。 */
+    Branch {
+        until: Option<i64>,
+    },
+    /** \brief 重新生成最后一条助手回复，旧回复保存为备选回复。 */
+    Regen,
+    Help,
+    Exit,
+}
+
+/** \brief 各命令的用法说明，`/help` 时显示。 */
+pub const HELP: &str = "/new                新建会话（发送第一条消息时创建）
+/switch <chat_id>   切换到已有会话
+/provider <id>      切换 Provider，并绑定到当前会话
+/model [name]       之后的消息使用指定模型，省略名称恢复默认
+/system [text]      之后的消息使用指定系统指令，省略内容恢复默认
+/branch [msg_id]    复制当前会话为分支并切换过去，可只保留到指定消息
+/regen              重新生成最后一条回复（旧回复保存为备选回复）
+/exit               退出";
+
+/**
+ * \brief 解析一行输入；不以 `/` 开头的普通消息返回 `Ok(None)`。
+ * \details 未知命令与缺少或无效参数时返回带用法的错误。
+ */
+pub fn parse(line: &str) -> Result<Option<ChatCommand>> {
+    let Some(rest) = line.trim().strip_prefix('/') else {
+        return Ok(None);
+    };
+    let (name, arg) = match rest.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (rest, ""),
+    };
+    let optional = |arg: &str| (!arg.is_empty()).then(|| arg.to_string());
+    let id = |usage: &str| -> Result<i64> { arg.parse().map_err(|_| anyhow!("usage: {}", usage)) };
+    let cmd = match name {
+        "new" => ChatCommand::New,
+        "switch" => ChatCommand::Switch {
+            chat_id: id("/switch <chat_id>")?,
+        },
+        "provider" => ChatCommand::Provider {
+            provider_id: id("/provider <id>")?,
+        },
+        "model" => {
+            if arg.contains(char::is_whitespace) {
+                bail!("usage: /model [name]");
+            }
+            ChatCommand::Model {
+                model: optional(arg),
+            }
+        }
+        "system" => ChatCommand::System {
+            instruction: optional(arg),
+        },
+        "branch" => ChatCommand::Branch {
+            until: match arg {
+                "" => None,
+                _ => Some(id("/branch [message_id]")?),
+            },
+        },
+        "regen" => ChatCommand::Regen,
+        "help" => ChatCommand::Help,
+        "exit" | "quit" => ChatCommand::Exit,
+        other => bail!("unknown command /{}, type /help", other),
+    };
+    Ok(Some(cmd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands_and_arguments() {
+        assert_eq!(parse("hello /model x").unwrap(), None);
+        assert_eq!(
+            parse("/model gpt-4o").unwrap(),
+            Some(ChatCommand::Model {
+                model: Some("gpt-4o".to_string())
+            })
+        );
+        assert_eq!(
+            parse("/model").unwrap(),
+            Some(ChatCommand::Model { model: None })
+        );
+        assert_eq!(
+            parse("/system  You are terse.\tAnswer briefly. ").unwrap(),
+            Some(ChatCommand::System {
+                instruction: Some("You are terse.\tAnswer briefly.".to_string())
+            })
+        );
+        assert_eq!(
+            parse("/branch 12").unwrap(),
+            Some(ChatCommand::Branch { until: Some(12) })
+        );
+        assert_eq!(
+            parse("/branch").unwrap(),
+            Some(ChatCommand::Branch { until: None })
+        );
+        assert_eq!(parse("/regen").unwrap(), Some(ChatCommand::Regen));
+        assert_eq!(parse("/quit").unwrap(), Some(ChatCommand::Exit));
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_input() {
+        assert!(parse("/switch").is_err());
+        assert!(parse("/provider abc").is_err());
+        assert!(parse("/branch x").is_err());
+        assert!(parse("/model a b").is_err());
+        let err = parse("/frobnicate").unwrap_err().to_string();
+        assert!(err.contains("/frobnicate"));
+    }
+
+    #[test]
+    fn test_serializes_as_tagged_object() {
+        let value = serde_json::to_value(ChatCommand::Switch { chat_id: 3 }).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({"command": "switch", "chat_id": 3})
+        );
+        let value = serde_json::to_value(ChatCommand::Regen).unwrap();
+        assert_eq!(value, serde_json::json!({"command": "regen"}));
+    }
+}
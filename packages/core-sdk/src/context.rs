@@ -0,0 +1,665 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::{
+    db, llm, markdown,
+    models::{ContextStrategy, Message as ChatMessage, Provider, RelevanceSettings},
+    summarize, telemetry,
+};
+
+/** \brief 本地哈希向量的模型名；Provider 没有向量接口或调用失败时使用，不写入缓存。 */
+pub const LOCAL_EMBEDDING_MODEL: &str = "local-hash-256";
+
+const LOCAL_EMBEDDING_DIMS: usize = 256;
+
+/** \brief 送去计算向量的单条文本上限（字符），避免超出向量模型的输入长度。 */
+const EMBED_INPUT_CHARS: usize = 4000;
+
+/**
+ * \brief 历史消息入选或落选的原因。
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextReason {
+    /** \brief 策略为 `full`，全部历史照常发送。 */
+    Full,
+    System,
+    /** \brief 属于最近 `keep_recent` 条之内。 */
+    Recent,
+    /** \brief 与新提示足够相似且在预算之内。 */
+    Relevant,
+    LowRelevance,
+    OverBudget,
+    /** \brief 已并入会话的滚动摘要，由摘要代替原文发送。 */
+    Summarized,
+}
+
+impl ContextReason {
+    pub fn included(self) -> bool {
+        matches!(
+            self,
+            Self::Full | Self::System | Self::Recent | Self::Relevant
+        )
+    }
+}
+
+/**
+ * \brief 单条历史消息的选择结果；`score` 为所在轮次与新提示的最高余弦相似度。
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct ContextNote {
+    pub message_id: i64,
+    pub included: bool,
+    pub reason: ContextReason,
+    pub score: Option<f32>,
+}
+
+/**
+ * \brief 会话的待选上下文：历史消息、策略与已缓存的向量。
+ * \details 读取数据库的部分与计算向量的部分分开，便于在异步任务中完成选择而不持有连接。
+ */
+pub struct ContextPlan {
+    strategy: ContextStrategy,
+    history: Vec<(i64, ChatMessage)>,
+    pending: Option<String>,
+    /** \brief 本次请求中以原文替换占位文本的消息，其向量不写入缓存。 */
+    redacted: Option<i64>,
+    embedding_model: Option<String>,
+    cached: HashMap<i64, Vec<f32>>,
+    summary: Option<String>,
+    /** \brief 被摘要覆盖、不再参与选择的历史消息。 */
+    summarized: Vec<i64>,
+}
+
+/**
+ * \brief 选择结果：按原顺序排列的入选消息，以及每条历史的说明。
+ */
+#[derive(Debug, Clone)]
+pub struct ContextSelection {
+    pub messages: Vec<ChatMessage>,
+    pub notes: Vec<ContextNote>,
+    /** \brief 实际使用的向量模型，`full` 策略时为空。 */
+    pub embedding_model: Option<String>,
+    /** \brief 远端向量不可用、退回本地哈希向量。 */
+    pub fallback: bool,
+    /** \brief 作为 system 消息注入的滚动摘要。 */
+    pub rolling_summary: Option<String>,
+    new_embeddings: Vec<(i64, Vec<f32>)>,
+}
+
+/**
+ * \brief 读取会话历史、上下文策略与对应向量模型的缓存。
+ * \details 会话有滚动摘要时，摘要覆盖的非系统消息不再进入历史，由摘要代替。
+ */
+pub fn plan(conn: &Connection, chat_id: i64, provider: Option<&Provider>) -> Result<ContextPlan> {
+    let strategy = db::get_chat_context_strategy(conn, chat_id)?;
+    let digest = db::get_conversation_summary(conn, chat_id)?;
+    let through = digest.as_ref().map_or(0, |s| s.through_message_id);
+    let mut summarized = Vec::new();
+    let history = db::load_messages_with_meta(conn, chat_id)?
+        .into_iter()
+        .filter(|m| {
+            let covered = m.role != "system" && m.id <= through;
+            if covered {
+                summarized.push(m.id);
+            }
+            !covered
+        })
+        .map(|m| {
+            let content = markdown::expand_quotes(&m.content, m.metadata.as_ref());
+            (
+                m.id,
+                ChatMessage {
+                    role: m.role,
+                    content,
+                },
+            )
+        })
+        .collect();
+    let embedding_model = match &strategy {
+        ContextStrategy::Full => None,
+        ContextStrategy::Relevance(settings) => {
+            provider.and_then(|p| remote_embedding_model(settings, p))
+        }
+    };
+    let cached = match &embedding_model {
+        Some(model) => db::load_message_embeddings(conn, chat_id, model)?,
+        None => HashMap::new(),
+    };
+    Ok(ContextPlan {
+        strategy,
+        history,
+        pending: None,
+        redacted: None,
+        embedding_model,
+        cached,
+        summary: digest.map(|s| s.content),
+        summarized,
+    })
+}
+
+fn remote_embedding_model(settings: &RelevanceSettings, provider: &Provider) -> Option<String> {
+    let default = llm::default_embedding_model(provider)?;
+    Some(
+        settings
+            .embedding_model
+            .clone()
+            .filter(|m| !m.trim().is_empty())
+            .unwrap_or_else(|| default.to_string()),
+    )
+}
+
+impl ContextPlan {
+    pub fn strategy(&self) -> &ContextStrategy {
+        &self.strategy
+    }
+
+    /**
+     * \brief 追加尚未写入会话的用户消息（如上下文预览中的草稿），它作为新提示参与选择并总会保留。
+     */
+    pub fn with_pending_prompt(mut self, prompt: &str) -> Self {
+        self.pending = Some(prompt.to_string());
+        self
+    }
+
+    /**
+     * \brief 用提示原文替换已写入的占位消息（见 `db::insert_redacted_user_message`），只作用于本次请求。
+     * \details 引用展开后原文仍位于消息末尾；该消息的向量不写入缓存，避免原文以向量形式留存。
+     */
+    pub fn with_redacted_prompt(mut self, message_id: i64, prompt: &str) -> Self {
+        if let Some((_, message)) = self.history.iter_mut().find(|(id, _)| *id == message_id) {
+            if let Some(head) = message.content.strip_suffix(db::REDACTED_PROMPT) {
+                message.content = format!("{}{}", head, prompt);
+            }
+        }
+        self.redacted = Some(message_id);
+        self
+    }
+
+    /**
+     * \brief 按策略挑选发送给模型的历史，有滚动摘要时把它放在开头的 system 消息之后。
+     * \details 以待发送消息（没有时为最后一条 user 消息）作为新提示，最后一条消息总会保留；
+     * 远端向量调用失败时退回本地哈希向量，不会中断请求。
+     */
+    pub async fn select(mut self, provider: Option<&Provider>) -> ContextSelection {
+        let summary = self.summary.take();
+        let summarized = std::mem::take(&mut self.summarized);
+        let mut selection = self.select_history(provider).await;
+        if let Some(summary) = summary {
+            let at = selection
+                .messages
+                .iter()
+                .take_while(|m| m.role == "system")
+                .count();
+            selection
+                .messages
+                .insert(at, summarize::system_message(&summary));
+            selection.rolling_summary = Some(summary);
+        }
+        let notes = summarized.into_iter().map(|id| ContextNote {
+            message_id: id,
+            included: false,
+            reason: ContextReason::Summarized,
+            score: None,
+        });
+        selection.notes.splice(0..0, notes);
+        selection
+    }
+
+    async fn select_history(self, provider: Option<&Provider>) -> ContextSelection {
+        let settings = match &self.strategy {
+            ContextStrategy::Full => {
+                let notes = self
+                    .history
+                    .iter()
+                    .map(|(id, _)| ContextNote {
+                        message_id: *id,
+                        included: true,
+                        reason: ContextReason::Full,
+                        score: None,
+                    })
+                    .collect();
+                let mut messages: Vec<ChatMessage> =
+                    self.history.into_iter().map(|(_, m)| m).collect();
+                messages.extend(self.pending.map(user_message));
+                return ContextSelection {
+                    messages,
+                    notes,
+                    embedding_model: None,
+                    fallback: false,
+                    rolling_summary: None,
+                    new_embeddings: Vec::new(),
+                };
+            }
+            ContextStrategy::Relevance(settings) => settings.clone(),
+        };
+
+        let (query, query_id) = match &self.pending {
+            Some(prompt) => (prompt.clone(), None),
+            None => self
+                .history
+                .iter()
+                .rev()
+                .find(|(_, m)| m.role == "user")
+                .map(|(id, m)| (m.content.clone(), Some(*id)))
+                .unwrap_or_default(),
+        };
+        let total = self.history.len() + self.pending.is_some() as usize;
+        let recent_from = total.saturating_sub(settings.keep_recent.max(1));
+        let candidates: Vec<usize> = (0..recent_from.min(self.history.len()))
+            .filter(|&i| self.history[i].1.role != "system")
+            .collect();
+
+        let mut fallback = self.embedding_model.is_none();
+        let mut new_embeddings = Vec::new();
+        let mut vectors: HashMap<i64, Vec<f32>> = HashMap::new();
+        let mut query_vector = None;
+        if let (Some(model), Some(provider)) = (&self.embedding_model, provider) {
+            let missing: Vec<usize> = candidates
+                .iter()
+                .copied()
+                .filter(|&i| !self.cached.contains_key(&self.history[i].0))
+                .collect();
+            let mut texts: Vec<String> = missing
+                .iter()
+                .map(|&i| embed_input(&self.history[i].1.content))
+                .collect();
+            texts.push(embed_input(&query));
+            match llm::embed_texts(provider, model, &texts).await {
+                Ok(mut embedded) => {
+                    query_vector = embedded.pop();
+                    for (&i, vector) in missing.iter().zip(embedded) {
+                        new_embeddings.push((self.history[i].0, vector));
+                    }
+                    if let (Some(id), Some(vector)) = (query_id, &query_vector) {
+                        if Some(id) != self.redacted {
+                            new_embeddings.push((id, vector.clone()));
+                        }
+                    }
+                    vectors = self.cached;
+                    vectors.extend(new_embeddings.iter().cloned());
+                }
+                Err(err) => {
+                    telemetry::log_event(
+                        "context",
+                        &format!(
+                            "embeddings via {} failed, using local vectors: {}",
+                            model, err
+                        ),
+                    );
+                    fallback = true;
+                }
+            }
+        } else {
+            fallback = true;
+        }
+        if fallback {
+            new_embeddings.clear();
+            query_vector = Some(local_embedding(&query));
+            vectors = candidates
+                .iter()
+                .map(|&i| {
+                    let (id, m) = &self.history[i];
+                    (*id, local_embedding(&m.content))
+                })
+                .collect();
+        }
+
+        let mut reasons: Vec<Option<ContextReason>> = self
+            .history
+            .iter()
+            .enumerate()
+            .map(|(i, (_, m))| {
+                if m.role == "system" {
+                    Some(ContextReason::System)
+                } else if i >= recent_from {
+                    Some(ContextReason::Recent)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let mut scores = vec![None; self.history.len()];
+
+        // 一轮为一条 user 消息及其后的回复，整轮入选或落选，避免只带上半段对话。
+        let mut turns: Vec<Vec<usize>> = Vec::new();
+        for &i in &candidates {
+            let starts_turn = self.history[i].1.role == "user"
+                || turns
+                    .last()
+                    .and_then(|t| t.last())
+                    .is_none_or(|&prev| prev + 1 != i);
+            if starts_turn {
+                turns.push(vec![i]);
+            } else if let Some(turn) = turns.last_mut() {
+                turn.push(i);
+            }
+        }
+        let query_vector = query_vector.filter(|_| !query.trim().is_empty());
+        let mut ranked: Vec<(f32, Vec<usize>)> = turns
+            .into_iter()
+            .map(|turn| {
+                let score = match &query_vector {
+                    Some(q) => turn
+                        .iter()
+                        .filter_map(|&i| vectors.get(&self.history[i].0))
+                        .map(|v| cosine(q, v))
+                        .fold(f32::MIN, f32::max),
+                    None => 0.0,
+                };
+                (score, turn)
+            })
+            .collect();
+        // 没有可比较的新提示时按时间倒序填充预算。
+        if query_vector.is_some() {
+            ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        } else {
+            ranked.reverse();
+        }
+        let mut budget = settings.max_tokens;
+        for (score, turn) in ranked {
+            let tokens: usize = turn
+                .iter()
+                .map(|&i| llm::estimate_tokens(&self.history[i].1.content))
+                .sum();
+            let reason = if query_vector.is_some() && score < settings.min_score {
+                ContextReason::LowRelevance
+            } else if tokens > budget {
+                ContextReason::OverBudget
+            } else {
+                budget -= tokens;
+                ContextReason::Relevant
+            };
+            for i in turn {
+                reasons[i] = Some(reason);
+                scores[i] = query_vector.as_ref().map(|_| score.max(-1.0));
+            }
+        }
+
+        let mut messages = Vec::new();
+        let mut notes = Vec::new();
+        for (((id, message), reason), score) in self.history.into_iter().zip(reasons).zip(scores) {
+            let reason = reason.unwrap_or(ContextReason::OverBudget);
+            if reason.included() {
+                messages.push(message);
+            }
+            notes.push(ContextNote {
+                message_id: id,
+                included: reason.included(),
+                reason,
+                score,
+            });
+        }
+        messages.extend(self.pending.map(user_message));
+        ContextSelection {
+            messages,
+            notes,
+            embedding_model: Some(if fallback {
+                LOCAL_EMBEDDING_MODEL.to_string()
+            } else {
+                self.embedding_model.unwrap_or_default()
+            }),
+            fallback,
+            rolling_summary: None,
+            new_embeddings,
+        }
+    }
+}
+
+impl ContextSelection {
+    /**
+     * \brief 缓存本次新计算的远端向量；本地哈希向量不缓存。
+     */
+    pub fn save_embeddings(&self, conn: &Connection) -> Result<()> {
+        match &self.embedding_model {
+            Some(model) if !self.fallback && !self.new_embeddings.is_empty() => {
+                db::save_message_embeddings(conn, model, &self.new_embeddings)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * \brief 与 `save_embeddings` 相同，但在阻塞线程池上写入，供异步调用方使用；写入后不再重复保存。
+     */
+    pub async fn persist_embeddings(&mut self) -> Result<()> {
+        if self.fallback || self.new_embeddings.is_empty() {
+            return Ok(());
+        }
+        let Some(model) = self.embedding_model.clone() else {
+            return Ok(());
+        };
+        let embeddings = std::mem::take(&mut self.new_embeddings);
+        db::run(move |conn| db::save_message_embeddings(conn, &model, &embeddings)).await
+    }
+
+    /** \brief 一行摘要，写入调试日志。 */
+    pub fn summary(&self) -> String {
+        let included = self.notes.iter().filter(|n| n.included).count();
+        format!(
+            "context {}/{} messages via {}",
+            included,
+            self.notes.len(),
+            self.embedding_model.as_deref().unwrap_or("full history")
+        )
+    }
+}
+
+fn user_message(content: String) -> ChatMessage {
+    ChatMessage {
+        role: "user".to_string(),
+        content,
+    }
+}
+
+fn embed_input(text: &str) -> String {
+    text.chars().take(EMBED_INPUT_CHARS).collect()
+}
+
+/**
+ * \brief 本地哈希向量：英文按词、中日韩文字按相邻二字切分后散列到固定维度，再做 L2 归一化。
+ */
+pub fn local_embedding(text: &str) -> Vec<f32> {
+    let mut vector = vec![0f32; LOCAL_EMBEDDING_DIMS];
+    let mut add = |feature: &str| {
+        let hash = fnv1a(feature.as_bytes());
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        vector[(hash % LOCAL_EMBEDDING_DIMS as u64) as usize] += sign;
+    };
+    let lower = text.to_lowercase();
+    let mut word = String::new();
+    let mut cjk_run: Vec<char> = Vec::new();
+    for ch in lower.chars().chain(std::iter::once(' ')) {
+        if llm::is_cjk(ch) {
+            cjk_run.push(ch);
+        } else {
+            flush_cjk(&mut cjk_run, &mut add);
+        }
+        if ch.is_alphanumeric() && !llm::is_cjk(ch) {
+            word.push(ch);
+        } else if !word.is_empty() {
+            add(&word);
+            word.clear();
+        }
+    }
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    vector
+}
+
+fn flush_cjk(run: &mut Vec<char>, add: &mut impl FnMut(&str)) {
+    if run.len() == 1 {
+        add(&run[0].to_string());
+    }
+    for pair in run.windows(2) {
+        add(&pair.iter().collect::<String>());
+    }
+    run.clear();
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/** \brief 余弦相似度，维度不一致或存在零向量时为 0。 */
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let na = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let nb = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if na == 0.0 || nb == 0.0 {
+        0.0
+    } else {
+        dot / (na * nb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mem_conn() -> Connection {
+        let conn = Connection::open_in_memory().expect("open");
+        db::migrate(&conn).expect("migrate");
+        conn
+    }
+
+    #[test]
+    fn test_local_embedding_similarity() {
+        let rust = local_embedding("How do I borrow a Rust vector mutably?");
+        let rust2 = local_embedding("rust vector borrow rules");
+        let soup = local_embedding("番茄鸡蛋汤怎么做");
+        assert!(cosine(&rust, &rust2) > cosine(&rust, &soup));
+        assert!(cosine(&soup, &local_embedding("鸡蛋汤的做法")) > 0.2);
+        assert_eq!(cosine(&local_embedding(""), &rust), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_relevance_selects_related_turns() {
+        let conn = mem_conn();
+        let pid = db::insert_provider(&conn, "p", "claude", "x", "", "m", None).unwrap();
+        let chat_id = db::create_chat(&conn, "c", pid).unwrap();
+        let sys = db::insert_message(&conn, chat_id, "system", "be brief").unwrap();
+        let soup = db::insert_message(&conn, chat_id, "user", "番茄鸡蛋汤怎么做").unwrap();
+        let soup_reply = db::insert_message(&conn, chat_id, "assistant", "先炒鸡蛋再煮汤").unwrap();
+        let rust =
+            db::insert_message(&conn, chat_id, "user", "explain rust vector borrow").unwrap();
+        db::insert_message(&conn, chat_id, "assistant", "a vector borrow is").unwrap();
+        let last =
+            db::insert_message(&conn, chat_id, "user", "more on rust vector borrow").unwrap();
+
+        let full = plan(&conn, chat_id, None).unwrap().select(None).await;
+        assert_eq!(full.messages.len(), 6);
+        assert!(full.notes.iter().all(|n| n.reason == ContextReason::Full));
+
+        let strategy = ContextStrategy::Relevance(RelevanceSettings {
+            keep_recent: 1,
+            min_score: 0.3,
+            ..Default::default()
+        });
+        db::set_chat_context_strategy(&conn, chat_id, &strategy).unwrap();
+        let provider = db::get_provider_by_id(&conn, pid).unwrap().unwrap();
+        let selection = plan(&conn, chat_id, Some(&provider))
+            .unwrap()
+            .select(Some(&provider))
+            .await;
+        assert!(selection.fallback);
+        assert_eq!(
+            selection.embedding_model.as_deref(),
+            Some(LOCAL_EMBEDDING_MODEL)
+        );
+        let note = |id| selection.notes.iter().find(|n| n.message_id == id).unwrap();
+        assert_eq!(note(sys).reason, ContextReason::System);
+        assert_eq!(note(last).reason, ContextReason::Recent);
+        assert_eq!(note(rust).reason, ContextReason::Relevant);
+        assert_eq!(note(soup).reason, ContextReason::LowRelevance);
+        assert_eq!(note(soup_reply).reason, ContextReason::LowRelevance);
+        assert_eq!(selection.messages.len(), 4);
+        selection.save_embeddings(&conn).unwrap();
+
+        let preview = plan(&conn, chat_id, Some(&provider))
+            .unwrap()
+            .with_pending_prompt("番茄鸡蛋汤要煮多久")
+            .select(Some(&provider))
+            .await;
+        let note = |id| preview.notes.iter().find(|n| n.message_id == id).unwrap();
+        assert_eq!(note(soup).reason, ContextReason::Relevant);
+        assert_eq!(note(last).reason, ContextReason::LowRelevance);
+        assert_eq!(
+            preview.messages.last().unwrap().content,
+            "番茄鸡蛋汤要煮多久"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redacted_prompt_is_sent_but_not_stored() {
+        let conn = mem_conn();
+        let pid = db::insert_provider(&conn, "p", "openai", "x", "", "m", None).unwrap();
+        let chat_id = db::create_chat(&conn, "c", pid).unwrap();
+        let answer = db::insert_message(&conn, chat_id, "assistant", "the key is rotated").unwrap();
+        let quotes = db::resolve_quotes(&conn, chat_id, &[answer]).unwrap();
+        let id = db::insert_redacted_user_message(&conn, chat_id, &quotes).unwrap();
+
+        let selection = plan(&conn, chat_id, None)
+            .unwrap()
+            .with_redacted_prompt(id, "sk-secret: is this valid?")
+            .select(None)
+            .await;
+        let sent = &selection.messages.last().unwrap().content;
+        assert!(sent.starts_with("> [#"));
+        assert!(sent.ends_with("sk-secret: is this valid?"));
+        assert!(!sent.contains(db::REDACTED_PROMPT));
+
+        let stored: String = conn
+            .query_row("SELECT content FROM messages WHERE id=?1", [id], |r| {
+                r.get(0)
+            })
+            .unwrap();
+        assert_eq!(stored, db::REDACTED_PROMPT);
+    }
+
+    #[tokio::test]
+    async fn test_summary_replaces_covered_history() {
+        let conn = mem_conn();
+        let chat_id = db::create_chat_at(&conn, "c", None, 1).unwrap();
+        db::insert_message(&conn, chat_id, "system", "be brief").unwrap();
+        let old = db::insert_message(&conn, chat_id, "user", "my name is Ada").unwrap();
+        let old_reply = db::insert_message(&conn, chat_id, "assistant", "hi Ada").unwrap();
+        db::insert_message(&conn, chat_id, "user", "what is my name?").unwrap();
+        db::save_conversation_summary(
+            &conn,
+            &db::ConversationSummary {
+                chat_id,
+                content: "The user is Ada.".to_string(),
+                through_message_id: old_reply,
+                message_count: 2,
+                provider_id: None,
+                updated_at: 1,
+            },
+        )
+        .unwrap();
+
+        let selection = plan(&conn, chat_id, None).unwrap().select(None).await;
+        let roles: Vec<&str> = selection.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "system", "user"]);
+        assert!(selection.messages[1].content.ends_with("The user is Ada."));
+        assert_eq!(
+            selection.rolling_summary.as_deref(),
+            Some("The user is Ada.")
+        );
+        for id in [old, old_reply] {
+            let note = selection.notes.iter().find(|n| n.message_id == id).unwrap();
+            assert_eq!(note.reason, ContextReason::Summarized);
+            assert!(!note.included);
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::{io::Write, num::NonZeroU32};
+
+use anyhow::{anyhow, bail, Context, Result};
+use once_cell::sync::Lazy;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::paths;
+
+/** \brief 指定主密钥的环境变量：64 位十六进制视为原始密钥，其他内容视为口令。 */
+pub const MASTER_KEY_ENV: &str = "DREAMQUILL_MASTER_KEY";
+
+/** \brief `provider rekey` 读取旧主密钥的环境变量，取值规则同 `DREAMQUILL_MASTER_KEY`。 */
+pub const OLD_MASTER_KEY_ENV: &str = "DREAMQUILL_OLD_MASTER_KEY";
+
+/** \brief 显式改用密钥文件的环境变量：值为文件路径，文件不存在时生成随机主密钥写入（仅本用户可读）。 */
+pub const MASTER_KEY_FILE_ENV: &str = "DREAMQUILL_MASTER_KEY_FILE";
+
+/** \brief 旧版本默认写在数据目录下的密钥文件名，首次使用系统钥匙串时迁入钥匙串后删除。 */
+pub const KEY_FILE_NAME: &str = "master.key";
+
+/** \brief 主密钥在系统钥匙串（macOS 钥匙串、Windows 凭据管理器、Linux Secret Service）中的服务名与账户名。 */
+pub const KEYCHAIN_SERVICE: &str = "dreamquill";
+pub const KEYCHAIN_ACCOUNT: &str = "master-key";
+
+/** \brief 加密后字段的前缀，不带前缀的值视为旧版明文。 */
+const PREFIX: &str = "enc:v1:";
+
+/** \brief 附加认证数据，密文不能被挪作其他用途的字段。 */
+const AAD: &[u8] = b"dreamquill:providers.api_key";
+
+/** \brief 口令派生密钥的迭代次数与固定盐值。 */
+const PBKDF2_ROUNDS: u32 = 210_000;
+const PBKDF2_SALT: &[u8] = b"dreamquill-master-key-v1";
+
+/** \brief 单元测试使用的固定密钥，避免在用户数据目录生成密钥文件。 */
+const TEST_KEY: [u8; 32] = [7; 32];
+
+static MASTER_KEY: Lazy<std::result::Result<[u8; 32], String>> =
+    Lazy::new(|| load_master_key().map_err(|e| format!("{:#}", e)));
+
+/** \brief 值是否为本模块生成的密文。 */
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+/**
+ * \brief 用主密钥加密字段（AES-256-GCM，每次随机 nonce），空字符串原样返回。
+ */
+pub fn encrypt(plain: &str) -> Result<String> {
+    if plain.is_empty() {
+        return Ok(String::new());
+    }
+    seal(master_key()?, plain)
+}
+
+/**
+ * \brief 解密字段；不带密文前缀的值按明文原样返回，兼容加密前写入的数据。
+ */
+pub fn decrypt(stored: &str) -> Result<String> {
+    if !is_encrypted(stored) {
+        return Ok(stored.to_string());
+    }
+    open(master_key()?, stored)
+}
+
+/**
+ * \brief 用指定的主密钥（64 位十六进制或口令）解密字段，供更换主密钥后重新加密旧数据。
+ */
+pub fn decrypt_with(secret: &str, stored: &str) -> Result<String> {
+    if !is_encrypted(stored) {
+        return Ok(stored.to_string());
+    }
+    open(&key_from_env(secret.trim()), stored)
+}
+
+/** \brief 用指定主密钥加密，测试中模拟其他机器或旧主密钥写入的密文。 */
+#[cfg(test)]
+pub(crate) fn encrypt_with(secret: &str, plain: &str) -> Result<String> {
+    seal(&key_from_env(secret), plain)
+}
+
+fn master_key() -> Result<&'static [u8; 32]> {
+    MASTER_KEY
+        .as_ref()
+        .map_err(|e| anyhow!("master key unavailable: {}", e))
+}
+
+/**
+ * \brief 主密钥来源，依次为：`DREAMQUILL_MASTER_KEY`（原始密钥或口令）、`DREAMQUILL_MASTER_KEY_FILE` 指定的密钥文件、系统钥匙串。
+ * \details 默认保存在系统钥匙串，不与数据库放在同一目录，复制数据目录不会同时带走密钥。
+ * 钥匙串不可用（如无桌面会话的服务器）时直接报错，需显式设置口令或密钥文件，不会悄悄退回到数据目录下的文件。
+ */
+fn load_master_key() -> Result<[u8; 32]> {
+    if cfg!(test) {
+        return Ok(TEST_KEY);
+    }
+    if let Some(value) = std::env::var(MASTER_KEY_ENV).ok().filter(|v| !v.is_empty()) {
+        return Ok(key_from_env(&value));
+    }
+    if let Some(path) = std::env::var_os(MASTER_KEY_FILE_ENV).filter(|v| !v.is_empty()) {
+        return load_or_create_key_file(std::path::Path::new(&path));
+    }
+    load_keychain_key().with_context(|| {
+        format!(
+            "system keychain unavailable; set {} to a passphrase or {} to a key file",
+            MASTER_KEY_ENV, MASTER_KEY_FILE_ENV
+        )
+    })
+}
+
+/**
+ * \brief 读取钥匙串中的主密钥；没有时迁入旧版 `master.key`，再没有则生成新密钥。
+ * \details 两个进程同时首次启动时可能各自写入，写入后重新读取钥匙串，以最终保存的值为准。
+ */
+fn load_keychain_key() -> Result<[u8; 32]> {
+    let entry = keyring::Entry::new(KEYCHAIN_SERVICE, KEYCHAIN_ACCOUNT)?;
+    match entry.get_password() {
+        Ok(text) => {
+            return parse_hex_key(text.trim()).context("keychain entry is not a valid master key")
+        }
+        Err(keyring::Error::NoEntry) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let legacy = paths::data_dir().join(KEY_FILE_NAME);
+    let key = if legacy.exists() {
+        read_key_file(&legacy)?
+    } else {
+        random_key()?
+    };
+    entry.set_password(&hex::encode(key))?;
+    let stored = parse_hex_key(entry.get_password()?.trim())?;
+    if legacy.exists() && stored == key {
+        // 已迁入钥匙串，删除与数据库同目录的明文密钥文件
+        if let Err(e) = std::fs::remove_file(&legacy) {
+            crate::telemetry::log_error(
+                "crypto.master_key",
+                &format!("remove {} failed: {}", legacy.display(), e),
+            );
+        }
+    }
+    Ok(stored)
+}
+
+/**
+ * \brief 读取密钥文件，不存在时生成并以 `create_new` 写入（仅本用户可读）。
+ * \details 另一个进程抢先创建时（`AlreadyExists`）改为读取对方写入的密钥，两个进程最终使用同一密钥。
+ */
+fn load_or_create_key_file(path: &std::path::Path) -> Result<[u8; 32]> {
+    if path.exists() {
+        return read_key_file(path);
+    }
+    let key = random_key()?;
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    match options.open(path) {
+        Ok(mut file) => {
+            writeln!(file, "{}", hex::encode(key))?;
+            file.sync_all()?;
+            Ok(key)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => read_key_file(path),
+        Err(e) => Err(e).with_context(|| format!("create {} failed", path.display())),
+    }
+}
+
+/**
+ * \brief 读取密钥文件；并发创建方可能尚未写完，内容为空时短暂等待后重读。
+ */
+fn read_key_file(path: &std::path::Path) -> Result<[u8; 32]> {
+    for _ in 0..20 {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read {} failed", path.display()))?;
+        if !text.trim().is_empty() {
+            return parse_hex_key(text.trim())
+                .with_context(|| format!("{} is not a valid key file", path.display()));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    bail!("{} is empty", path.display())
+}
+
+fn random_key() -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| anyhow!("generate master key failed"))?;
+    Ok(key)
+}
+
+fn key_from_env(value: &str) -> [u8; 32] {
+    if let Ok(key) = parse_hex_key(value) {
+        return key;
+    }
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ROUNDS).expect("non-zero rounds"),
+        PBKDF2_SALT,
+        value.as_bytes(),
+        &mut key,
+    );
+    key
+}
+
+fn parse_hex_key(text: &str) -> Result<[u8; 32]> {
+    let bytes = hex::decode(text)?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("expected 32 bytes of hex"))
+}
+
+fn aead_key(key: &[u8; 32]) -> Result<LessSafeKey> {
+    let unbound = UnboundKey::new(&AES_256_GCM, key).map_err(|_| anyhow!("invalid key"))?;
+    Ok(LessSafeKey::new(unbound))
+}
+
+fn seal(key: &[u8; 32], plain: &str) -> Result<String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow!("generate nonce failed"))?;
+    let mut data = plain.as_bytes().to_vec();
+    aead_key(key)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(AAD),
+            &mut data,
+        )
+        .map_err(|_| anyhow!("encrypt failed"))?;
+    let mut blob = nonce.to_vec();
+    blob.extend_from_slice(&data);
+    Ok(format!("{}{}", PREFIX, hex::encode(blob)))
+}
+
+fn open(key: &[u8; 32], stored: &str) -> Result<String> {
+    let blob = hex::decode(&stored[PREFIX.len()..]).context("malformed ciphertext")?;
+    if blob.len() < NONCE_LEN {
+        bail!("malformed ciphertext");
+    }
+    let (nonce, sealed) = blob.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("malformed nonce"))?;
+    let mut data = sealed.to_vec();
+    let plain = aead_key(key)?
+        .open_in_place(nonce, Aad::from(AAD), &mut data)
+        .map_err(|_| {
+            anyhow!(
+                "decrypt failed: the master key differs from the one used to encrypt (check {})",
+                MASTER_KEY_ENV
+            )
+        })?;
+    Ok(String::from_utf8(plain.to_vec())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_plaintext_passthrough() {
+        let sealed = encrypt("sk-secret").unwrap();
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed.contains("sk-secret"));
+        assert_ne!(
+            sealed,
+            encrypt("sk-secret").unwrap(),
+            "fresh nonce each time"
+        );
+        assert_eq!(decrypt(&sealed).unwrap(), "sk-secret");
+        assert_eq!(decrypt("sk-legacy").unwrap(), "sk-legacy");
+        assert_eq!(encrypt("").unwrap(), "");
+
+        let other = key_from_env("correct horse battery staple");
+        assert_ne!(other, TEST_KEY);
+        assert!(open(&other, &sealed).is_err());
+        let mut tampered = sealed.clone();
+        let flipped = if tampered.ends_with('0') { '1' } else { '0' };
+        tampered.pop();
+        tampered.push(flipped);
+        assert!(decrypt(&tampered).is_err());
+        assert_eq!(key_from_env(&hex::encode(TEST_KEY)), TEST_KEY);
+    }
+
+    #[test]
+    fn test_concurrent_key_file_creation_agrees_on_one_key() {
+        let dir = std::env::temp_dir().join(format!("dq-key-race-{}", std::process::id()));
+        let path = dir.join("master.key");
+        let _ = std::fs::remove_dir_all(&dir);
+        let keys: Vec<[u8; 32]> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| s.spawn(|| load_or_create_key_file(&path)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap().expect("load key"))
+                .collect()
+        });
+        assert!(keys.iter().all(|k| *k == keys[0]));
+        assert_eq!(read_key_file(&path).unwrap(), keys[0]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
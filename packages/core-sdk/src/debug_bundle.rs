@@ -0,0 +1,414 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use flate2::{write::DeflateEncoder, Compression};
+use rusqlite::Connection;
+use serde::Serialize;
+use serde_json::{json, Value};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{db, redact, telemetry};
+
+/** \brief 默认打包的最近流式转录条数。 */
+pub const DEFAULT_TRANSCRIPTS: usize = 5;
+
+/** \brief 默认打包的遥测日志尾部行数。 */
+pub const DEFAULT_LOG_LINES: usize = 500;
+
+/** \brief 配置键名含这些片段时整值隐去，不做逐项脱敏。 */
+const SECRET_KEY_HINTS: &[&str] = &["key", "secret", "token", "password"];
+
+/**
+ * \brief 诊断包选项。
+ */
+#[derive(Debug, Clone)]
+pub struct BundleOptions {
+    /** \brief 调用方标识与版本，如 `cli 0.1.0`。 */
+    pub app: String,
+    /** \brief 流式转录文件（`--tee` / `DREAMQUILL_TRANSCRIPT`），为空时不打包转录。 */
+    pub transcript_path: Option<PathBuf>,
+    pub transcripts: usize,
+    pub log_lines: usize,
+}
+
+impl Default for BundleOptions {
+    fn default() -> Self {
+        Self {
+            app: String::new(),
+            transcript_path: None,
+            transcripts: DEFAULT_TRANSCRIPTS,
+            log_lines: DEFAULT_LOG_LINES,
+        }
+    }
+}
+
+/**
+ * \brief 诊断包生成结果。
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct BundleSummary {
+    pub path: String,
+    /** \brief 包内文件名。 */
+    pub entries: Vec<String>,
+    pub integrity_ok: bool,
+}
+
+struct CentralEntry {
+    name: String,
+    crc: u32,
+    compressed: u32,
+    size: u32,
+    offset: u32,
+}
+
+/**
+ * \brief 最小 zip 写入器：每个文件以 deflate 压缩，文件名按 UTF-8 标记。
+ */
+struct ZipWriter<W: Write> {
+    out: W,
+    offset: u32,
+    entries: Vec<CentralEntry>,
+    dos_time: u16,
+    dos_date: u16,
+}
+
+impl<W: Write> ZipWriter<W> {
+    fn new(out: W, now: OffsetDateTime) -> Self {
+        let dos_time =
+            ((now.hour() as u16) << 11) | ((now.minute() as u16) << 5) | (now.second() as u16 / 2);
+        let dos_date = (((now.year() - 1980).max(0) as u16) << 9)
+            | ((u8::from(now.month()) as u16) << 5)
+            | now.day() as u16;
+        Self {
+            out,
+            offset: 0,
+            entries: Vec::new(),
+            dos_time,
+            dos_date,
+        }
+    }
+
+    fn add(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        let entry = CentralEntry {
+            name: name.to_string(),
+            crc: crc32fast::hash(data),
+            compressed: u32::try_from(compressed.len()).context("zip entry too large")?,
+            size: u32::try_from(data.len()).context("zip entry too large")?,
+            offset: self.offset,
+        };
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        header.extend_from_slice(&20u16.to_le_bytes());
+        header.extend_from_slice(&0x0800u16.to_le_bytes());
+        header.extend_from_slice(&8u16.to_le_bytes());
+        header.extend_from_slice(&self.dos_time.to_le_bytes());
+        header.extend_from_slice(&self.dos_date.to_le_bytes());
+        header.extend_from_slice(&entry.crc.to_le_bytes());
+        header.extend_from_slice(&entry.compressed.to_le_bytes());
+        header.extend_from_slice(&entry.size.to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        self.out.write_all(&header)?;
+        self.out.write_all(&compressed)?;
+        self.offset += (header.len() + compressed.len()) as u32;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W> {
+        let start = self.offset;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            directory.extend_from_slice(&20u16.to_le_bytes());
+            directory.extend_from_slice(&20u16.to_le_bytes());
+            directory.extend_from_slice(&0x0800u16.to_le_bytes());
+            directory.extend_from_slice(&8u16.to_le_bytes());
+            directory.extend_from_slice(&self.dos_time.to_le_bytes());
+            directory.extend_from_slice(&self.dos_date.to_le_bytes());
+            directory.extend_from_slice(&entry.crc.to_le_bytes());
+            directory.extend_from_slice(&entry.compressed.to_le_bytes());
+            directory.extend_from_slice(&entry.size.to_le_bytes());
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0u8; 12]);
+            directory.extend_from_slice(&entry.offset.to_le_bytes());
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+        let count = self.entries.len() as u16;
+        let mut end = Vec::with_capacity(22);
+        end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        end.extend_from_slice(&[0u8; 4]);
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        end.extend_from_slice(&start.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.out.write_all(&directory)?;
+        self.out.write_all(&end)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/**
+ * \brief 脱敏后的配置与 Provider 列表：不含 API Key、签名密钥，敏感配置整值隐去。
+ */
+pub fn redacted_settings(conn: &Connection) -> Result<Value> {
+    let mut config = serde_json::Map::new();
+    for (key, value) in db::list_config_entries(conn)? {
+        let lower = key.to_ascii_lowercase();
+        let value = if SECRET_KEY_HINTS.iter().any(|h| lower.contains(h)) {
+            "[REDACTED]".to_string()
+        } else {
+            redact::redact_pii(&value)
+        };
+        config.insert(key, Value::String(value));
+    }
+    let default_id = db::get_default_provider_id(conn)?;
+    let providers: Vec<Value> = db::list_providers(conn)?
+        .into_iter()
+        .map(|p| {
+            json!({
+                "id": p.id,
+                "name": p.name,
+                "provider_type": p.provider_type,
+                "api_base": p.api_base,
+                "api_prefix": p.api_prefix,
+                "model": p.model,
+                "generation": p.generation,
+                "has_api_key": !p.api_key.is_empty(),
+                "secure_storage": p.secret_alias.is_some(),
+                "signing_header": p.signing.map(|s| s.header),
+                "is_default": default_id == Some(p.id),
+            })
+        })
+        .collect();
+    Ok(json!({ "config": config, "providers": providers }))
+}
+
+/**
+ * \brief 文本文件末尾的若干行，逐行脱敏。
+ */
+fn tail_lines(path: &Path, limit: usize) -> Option<String> {
+    let text = std::fs::read_to_string(path).ok()?;
+    let lines: Vec<&str> = text.lines().collect();
+    let start = lines.len().saturating_sub(limit);
+    Some(
+        lines[start..]
+            .iter()
+            .map(|l| redact::redact_pii(l))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
+
+/**
+ * \brief 从转录文件中取最近 `limit` 个流，按流拼接增量并脱敏，返回 (stream_id, 文本)。
+ */
+pub fn recent_transcripts(text: &str, limit: usize) -> Vec<(String, String)> {
+    let mut streams: HashMap<String, String> = HashMap::new();
+    let mut order: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let Ok(v) = serde_json::from_str::<Value>(line) else {
+            continue;
+        };
+        let (Some(id), Some(delta)) = (v["stream_id"].as_str(), v["delta"].as_str()) else {
+            continue;
+        };
+        if !streams.contains_key(id) {
+            order.push(id.to_string());
+        }
+        streams.entry(id.to_string()).or_default().push_str(delta);
+    }
+    let start = order.len().saturating_sub(limit);
+    order[start..]
+        .iter()
+        .map(|id| {
+            let body = streams.remove(id).unwrap_or_default();
+            (id.clone(), redact::redact_pii(&body))
+        })
+        .collect()
+}
+
+fn safe_file_name(id: &str) -> String {
+    id.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/**
+ * \brief 生成供问题反馈使用的诊断包（zip）：版本、表结构、脱敏配置、遥测日志尾部、最近转录与完整性检查。
+ */
+pub fn create(conn: &Connection, out: &Path, options: &BundleOptions) -> Result<BundleSummary> {
+    let now = OffsetDateTime::now_utc();
+    let schema: serde_json::Map<String, Value> = db::schema_layout(conn)?
+        .into_iter()
+        .map(|(table, columns)| (table, json!(columns)))
+        .collect();
+    let info = json!({
+        "app": options.app,
+        "core_sdk_version": env!("CARGO_PKG_VERSION"),
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+        "created_at": now.format(&Rfc3339)?,
+        "sqlite_version": rusqlite::version(),
+        "schema": schema,
+    });
+    let integrity = db::integrity_check(conn)?;
+
+    if let Some(dir) = out.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("create bundle dir {}", dir.display()))?;
+    }
+    let file =
+        std::fs::File::create(out).with_context(|| format!("create bundle {}", out.display()))?;
+    let mut zip = ZipWriter::new(std::io::BufWriter::new(file), now);
+    let mut entries = Vec::new();
+    let mut add = |zip: &mut ZipWriter<_>, name: String, data: Vec<u8>| -> Result<()> {
+        zip.add(&name, &data)?;
+        entries.push(name);
+        Ok(())
+    };
+
+    add(
+        &mut zip,
+        "info.json".into(),
+        serde_json::to_vec_pretty(&info)?,
+    )?;
+    add(
+        &mut zip,
+        "settings.json".into(),
+        serde_json::to_vec_pretty(&redacted_settings(conn)?)?,
+    )?;
+    add(
+        &mut zip,
+        "integrity.json".into(),
+        serde_json::to_vec_pretty(&integrity)?,
+    )?;
+    if let Some(log) = tail_lines(&telemetry::log_path(), options.log_lines) {
+        add(&mut zip, "telemetry.log".into(), log.into_bytes())?;
+    }
+    if let Some(path) = &options.transcript_path {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read transcript {}", path.display()))?;
+        for (id, body) in recent_transcripts(&text, options.transcripts) {
+            add(
+                &mut zip,
+                format!("transcripts/{}.txt", safe_file_name(&id)),
+                body.into_bytes(),
+            )?;
+        }
+    }
+    zip.finish()?;
+
+    telemetry::log_event(
+        "debug_bundle",
+        &format!(
+            "create path={} entries={} integrity_ok={}",
+            out.display(),
+            entries.len(),
+            integrity.ok
+        ),
+    );
+    Ok(BundleSummary {
+        path: out.display().to_string(),
+        entries,
+        integrity_ok: integrity.ok,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_zip_writer_round_trips_entries() {
+        let mut zip = ZipWriter::new(Vec::new(), OffsetDateTime::now_utc());
+        zip.add("a.txt", b"hello hello hello").unwrap();
+        zip.add("dir/b.json", b"{}").unwrap();
+        let bytes = zip.finish().unwrap();
+
+        let end = &bytes[bytes.len() - 22..];
+        assert_eq!(&end[..4], &0x0605_4b50u32.to_le_bytes());
+        assert_eq!(u16::from_le_bytes([end[10], end[11]]), 2);
+
+        assert_eq!(&bytes[..4], &0x0403_4b50u32.to_le_bytes());
+        let compressed = u32::from_le_bytes(bytes[18..22].try_into().unwrap()) as usize;
+        let name_len = u16::from_le_bytes([bytes[26], bytes[27]]) as usize;
+        assert_eq!(&bytes[30..30 + name_len], b"a.txt");
+        let data = &bytes[30 + name_len..30 + name_len + compressed];
+        let mut inflated = String::new();
+        flate2::read::DeflateDecoder::new(data)
+            .read_to_string(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, "hello hello hello");
+        assert_eq!(
+            u32::from_le_bytes(bytes[14..18].try_into().unwrap()),
+            crc32fast::hash(b"hello hello hello")
+        );
+    }
+
+    #[test]
+    fn test_recent_transcripts_keeps_last_streams_and_redacts() {
+        let text = [
+            r#"{"ts":"t","stream_id":"cli-1-0","delta":"old"}"#,
+            r#"{"ts":"t","stream_id":"cli-2-0","delta":"key sk-abcdefghijklmnop1234"}"#,
+            "not json",
+            r#"{"ts":"t","stream_id":"cli-3-0","delta":"Hel"}"#,
+            r#"{"ts":"t","stream_id":"cli-3-0","delta":"lo"}"#,
+        ]
+        .join("\n");
+        let recent = recent_transcripts(&text, 2);
+        assert_eq!(
+            recent,
+            vec![
+                ("cli-2-0".to_string(), "key [SECRET]".to_string()),
+                ("cli-3-0".to_string(), "Hello".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_redacted_settings_hides_keys() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        db::insert_provider(
+            &conn,
+            "p",
+            "openai",
+            "https://x",
+            "sk-secret",
+            "gpt-4o",
+            None,
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO app_config (key, value) VALUES ('webhook_token', 'abc'), ('note', 'mail a@b.com')",
+            [],
+        )
+        .unwrap();
+        let settings = redacted_settings(&conn).unwrap();
+        let text = settings.to_string();
+        assert!(!text.contains("sk-secret"));
+        assert_eq!(settings["config"]["webhook_token"], "[REDACTED]");
+        assert_eq!(settings["config"]["note"], "mail [EMAIL]");
+        assert_eq!(settings["providers"][0]["has_api_key"], true);
+        assert!(db::integrity_check(&conn).unwrap().ok);
+    }
+}
@@ -0,0 +1,529 @@
+use std::{
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+
+use crate::{client::SecretStore, db, llm, models::Provider, server, telemetry};
+
+/**
+ * \brief 单项检查结论。
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl CheckStatus {
+    pub fn label(self) -> &'static str {
+        match self {
+            CheckStatus::Pass => "pass",
+            CheckStatus::Warn => "warn",
+            CheckStatus::Fail => "fail",
+        }
+    }
+}
+
+/**
+ * \brief 单项检查结果；`hint` 给出可直接执行的修复建议。
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    fn new(name: &str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+/**
+ * \brief 诊断报告。
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct DoctorReport {
+    pub checks: Vec<CheckResult>,
+}
+
+impl DoctorReport {
+    /** \brief 最严重的结论，没有检查项时为 `Pass`。 */
+    pub fn worst(&self) -> CheckStatus {
+        self.checks
+            .iter()
+            .map(|c| c.status)
+            .max()
+            .unwrap_or(CheckStatus::Pass)
+    }
+}
+
+/**
+ * \brief 诊断参数；CLI 与桌面端按各自环境开启不同检查项。
+ */
+pub struct DoctorOptions<'a> {
+    pub db_path: PathBuf,
+    /** \brief 检查能否监听该地址，为空时跳过端口检查。 */
+    pub addr: Option<String>,
+    /** \brief 是否检查 Web 静态资源目录（桌面端资源随安装包分发，无需检查）。 */
+    pub check_ui: bool,
+    /** \brief 安全存储；为空表示当前环境不可用（如 CLI）。 */
+    pub secret_store: Option<&'a dyn SecretStore>,
+    /** \brief 是否请求各 Provider 的模型列表检查连通性。 */
+    pub probe_providers: bool,
+}
+
+impl Default for DoctorOptions<'_> {
+    fn default() -> Self {
+        Self {
+            db_path: crate::paths::db_path().to_path_buf(),
+            addr: None,
+            check_ui: false,
+            secret_store: None,
+            probe_providers: true,
+        }
+    }
+}
+
+/**
+ * \brief 依次执行全部检查；只读，不会创建或迁移数据库。
+ */
+pub async fn run(options: &DoctorOptions<'_>) -> DoctorReport {
+    let mut checks = Vec::new();
+    let conn = check_database(&options.db_path, &mut checks);
+    let providers = match conn.as_ref() {
+        Some(conn) => load_providers(conn, options.secret_store, &mut checks),
+        None => Vec::new(),
+    };
+    drop(conn);
+    if options.probe_providers {
+        checks.extend(check_providers(&providers).await);
+    }
+    if options.check_ui {
+        checks.push(check_ui_assets());
+    }
+    checks.push(check_log_dir(&telemetry::log_path()));
+    if let Some(addr) = options.addr.as_deref() {
+        checks.push(check_port(addr));
+    }
+    DoctorReport { checks }
+}
+
+/**
+ * \brief 数据库可打开、结构与当前版本一致且完整性检查通过；返回只读连接供后续检查使用。
+ */
+fn check_database(path: &Path, checks: &mut Vec<CheckResult>) -> Option<Connection> {
+    const NAME: &str = "database";
+    if !path.exists() {
+        checks.push(
+            CheckResult::new(
+                NAME,
+                CheckStatus::Warn,
+                format!("{} does not exist yet", path.display()),
+            )
+            .hint("run `dreamquill init` (or start the app) to create it"),
+        );
+        return None;
+    }
+    let conn = match Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY) {
+        Ok(conn) => conn,
+        Err(e) => {
+            checks.push(
+                CheckResult::new(
+                    NAME,
+                    CheckStatus::Fail,
+                    format!("cannot open {}: {}", path.display(), e),
+                )
+                .hint("check file permissions and that no other tool holds an exclusive lock"),
+            );
+            return None;
+        }
+    };
+    if std::fs::metadata(path)
+        .map(|m| m.permissions().readonly())
+        .unwrap_or(false)
+    {
+        checks.push(
+            CheckResult::new(
+                NAME,
+                CheckStatus::Fail,
+                format!("{} is read-only", path.display()),
+            )
+            .hint("make the file writable by the current user"),
+        );
+        return Some(conn);
+    }
+    checks.push(match missing_schema(&conn) {
+        Ok(missing) if missing.is_empty() => match db::integrity_check(&conn) {
+            Ok(report) if report.ok => CheckResult::new(
+                NAME,
+                CheckStatus::Pass,
+                format!("{} schema up to date, integrity ok", path.display()),
+            ),
+            Ok(report) => CheckResult::new(
+                NAME,
+                CheckStatus::Fail,
+                format!(
+                    "integrity check failed: {} (foreign key violations: {})",
+                    report.integrity_check.join("; "),
+                    report.foreign_key_violations
+                ),
+            )
+            .hint("restore from a backup or export chats and recreate the database"),
+            Err(e) => CheckResult::new(NAME, CheckStatus::Fail, e.to_string()),
+        },
+        Ok(missing) => CheckResult::new(
+            NAME,
+            CheckStatus::Warn,
+            format!(
+                "schema is behind this version, missing {}",
+                missing.join(", ")
+            ),
+        )
+        .hint("run any dreamquill command (or start the app) to apply migrations"),
+        Err(e) => CheckResult::new(
+            NAME,
+            CheckStatus::Fail,
+            format!("cannot read schema: {}", e),
+        )
+        .hint("the file may not be a DreamQuill database"),
+    });
+    Some(conn)
+}
+
+/**
+ * \brief 与当前版本迁移后的结构对比，列出缺失的表与列（`表` 或 `表.列`）。
+ */
+fn missing_schema(conn: &Connection) -> Result<Vec<String>> {
+    let expected = {
+        let mem = Connection::open_in_memory()?;
+        db::migrate(&mem)?;
+        db::schema_layout(&mem)?
+    };
+    let actual = db::schema_layout(conn)?;
+    let mut missing = Vec::new();
+    for (table, columns) in expected {
+        match actual.iter().find(|(t, _)| *t == table) {
+            None => missing.push(table),
+            Some((_, have)) => missing.extend(
+                columns
+                    .iter()
+                    .filter(|c| !have.contains(c))
+                    .map(|c| format!("{}.{}", table, c)),
+            ),
+        }
+    }
+    Ok(missing)
+}
+
+/**
+ * \brief 读取 Provider 并从安全存储补齐密钥，同时给出安全存储检查结果。
+ */
+fn load_providers(
+    conn: &Connection,
+    store: Option<&dyn SecretStore>,
+    checks: &mut Vec<CheckResult>,
+) -> Vec<Provider> {
+    const NAME: &str = "secure-storage";
+    let mut providers = match db::list_providers(conn) {
+        Ok(providers) => providers,
+        Err(e) => {
+            checks.push(CheckResult::new(
+                "providers",
+                CheckStatus::Fail,
+                format!("cannot load providers: {}", e),
+            ));
+            return Vec::new();
+        }
+    };
+    let unreadable: Vec<&str> = providers
+        .iter()
+        .filter(|p| p.api_key_unreadable)
+        .map(|p| p.name.as_str())
+        .collect();
+    if !unreadable.is_empty() {
+        checks.push(
+            CheckResult::new(
+                "master-key",
+                CheckStatus::Fail,
+                format!(
+                    "API key of {} cannot be decrypted with the current master key",
+                    unreadable.join(", ")
+                ),
+            )
+            .hint("restore the original master key, run `dreamquill provider rekey` with DREAMQUILL_OLD_MASTER_KEY set, or re-enter the key"),
+        );
+    }
+    let aliased: Vec<usize> = providers
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| p.secret_alias.is_some() && p.api_key.is_empty())
+        .map(|(i, _)| i)
+        .collect();
+    if aliased.is_empty() {
+        checks.push(CheckResult::new(
+            NAME,
+            CheckStatus::Pass,
+            "no provider depends on secure storage",
+        ));
+        return providers;
+    }
+    let Some(store) = store else {
+        checks.push(
+            CheckResult::new(
+                NAME,
+                CheckStatus::Warn,
+                format!(
+                    "{} provider(s) keep their key in secure storage, which is only available in the desktop app",
+                    aliased.len()
+                ),
+            )
+            .hint("run the desktop app's diagnostics, or re-run `dreamquill init` with --api-key"),
+        );
+        return providers;
+    };
+    let mut missing = Vec::new();
+    for i in aliased {
+        let provider = &mut providers[i];
+        let alias = provider.secret_alias.clone().unwrap_or_default();
+        match store.get(&alias) {
+            Ok(Some(key)) if !key.is_empty() => provider.api_key = key,
+            Ok(_) => missing.push(provider.name.clone()),
+            Err(e) => {
+                checks.push(
+                    CheckResult::new(
+                        NAME,
+                        CheckStatus::Fail,
+                        format!("secure storage unavailable: {}", e),
+                    )
+                    .hint("unlock the system keychain / credential manager and retry"),
+                );
+                return providers;
+            }
+        }
+    }
+    checks.push(if missing.is_empty() {
+        CheckResult::new(NAME, CheckStatus::Pass, "all provider keys readable")
+    } else {
+        CheckResult::new(
+            NAME,
+            CheckStatus::Fail,
+            format!("key missing for {}", missing.join(", ")),
+        )
+        .hint("re-enter the API key in provider settings")
+    });
+    providers
+}
+
+/**
+ * \brief 并发请求各 Provider 的模型列表，检查网络、鉴权与默认模型。
+ */
+async fn check_providers(providers: &[Provider]) -> Vec<CheckResult> {
+    if providers.is_empty() {
+        return vec![
+            CheckResult::new("providers", CheckStatus::Warn, "no provider configured")
+                .hint("run `dreamquill init --api-base ... --api-key ... --model ...`"),
+        ];
+    }
+    // 密钥未能读取的 Provider 已在安全存储或主密钥检查中报告，不再发起必然失败的请求
+    let (probed, skipped): (Vec<&Provider>, Vec<&Provider>) = providers.iter().partition(|p| {
+        !(p.api_key_unreadable || (p.api_key.is_empty() && p.secret_alias.is_some()))
+    });
+    let results =
+        futures_util::future::join_all(probed.into_iter().map(llm::check_provider_health)).await;
+    let mut checks: Vec<CheckResult> = results.iter().map(provider_result).collect();
+    checks.extend(skipped.into_iter().map(|p| {
+        CheckResult::new(
+            &format!("provider:{}", p.name),
+            CheckStatus::Warn,
+            "skipped, API key not available here",
+        )
+    }));
+    checks
+}
+
+fn provider_result(health: &llm::ProviderHealth) -> CheckResult {
+    let name = format!("provider:{}", health.name);
+    if health.is_healthy() {
+        return CheckResult::new(
+            &name,
+            CheckStatus::Pass,
+            format!("{} reachable in {}ms", health.model, health.latency_ms),
+        );
+    }
+    match (health.auth_ok, health.model_available) {
+        (Some(false), _) => CheckResult::new(
+            &name,
+            CheckStatus::Fail,
+            health.error.clone().unwrap_or_default(),
+        )
+        .hint("the API key was rejected, update it in provider settings"),
+        (Some(true), Some(false)) => CheckResult::new(
+            &name,
+            CheckStatus::Warn,
+            format!("model {} not in the provider's model list", health.model),
+        )
+        .hint("pick an available model in provider settings"),
+        _ => CheckResult::new(
+            &name,
+            CheckStatus::Fail,
+            health.error.clone().unwrap_or_default(),
+        )
+        .hint("check api_base, network access and proxy settings"),
+    }
+}
+
+/**
+ * \brief Web 界面静态资源是否已构建。
+ */
+fn check_ui_assets() -> CheckResult {
+    const NAME: &str = "ui-assets";
+    let (ui_root, fallback_root) = server::ui_roots();
+    if ui_root.join("index.html").exists() {
+        CheckResult::new(NAME, CheckStatus::Pass, ui_root.display().to_string())
+    } else if fallback_root.join("index.html").exists() {
+        CheckResult::new(
+            NAME,
+            CheckStatus::Warn,
+            format!(
+                "{} not built, serving fallback {}",
+                ui_root.display(),
+                fallback_root.display()
+            ),
+        )
+        .hint("run `npm run build:ui` or set DREAMQUILL_UI_DIR")
+    } else {
+        CheckResult::new(
+            NAME,
+            CheckStatus::Warn,
+            format!(
+                "no index.html in {} or {}, the web UI will 404",
+                ui_root.display(),
+                fallback_root.display()
+            ),
+        )
+        .hint("run `npm run build:ui` or set DREAMQUILL_UI_DIR")
+    }
+}
+
+/**
+ * \brief 日志目录可创建且日志文件可追加写入。
+ */
+fn check_log_dir(log_path: &Path) -> CheckResult {
+    const NAME: &str = "log-dir";
+    let writable = log_path
+        .parent()
+        .map(std::fs::create_dir_all)
+        .transpose()
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(log_path));
+    match writable {
+        Ok(_) => CheckResult::new(NAME, CheckStatus::Pass, log_path.display().to_string()),
+        Err(e) => CheckResult::new(
+            NAME,
+            CheckStatus::Fail,
+            format!("cannot write {}: {}", log_path.display(), e),
+        )
+        .hint("run from a writable working directory or fix the logs/ permissions"),
+    }
+}
+
+/**
+ * \brief 监听地址可用。
+ */
+fn check_port(addr: &str) -> CheckResult {
+    const NAME: &str = "port";
+    match std::net::TcpListener::bind(addr) {
+        Ok(_) => CheckResult::new(NAME, CheckStatus::Pass, format!("{} is free", addr)),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => CheckResult::new(
+            NAME,
+            CheckStatus::Fail,
+            format!("{} is already in use", addr),
+        )
+        .hint("stop the other process or pass `--addr` with a different port"),
+        Err(e) => CheckResult::new(
+            NAME,
+            CheckStatus::Fail,
+            format!("cannot bind {}: {}", addr, e),
+        )
+        .hint("use a local address such as 127.0.0.1:5173"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "dreamquill-doctor-{}-{}",
+            name,
+            db::process_session_id()
+        ))
+    }
+
+    #[test]
+    fn test_database_check_reports_missing_and_outdated_schema() {
+        let path = temp_path("db.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let mut checks = Vec::new();
+        assert!(check_database(&path, &mut checks).is_none());
+        assert_eq!(checks[0].status, CheckStatus::Warn);
+        assert!(!path.exists());
+
+        let conn = Connection::open(&path).unwrap();
+        db::migrate(&conn).unwrap();
+        conn.execute_batch("DROP TABLE message_revisions;").unwrap();
+        checks.clear();
+        check_database(&path, &mut checks);
+        assert_eq!(checks[0].status, CheckStatus::Warn);
+        assert!(checks[0].detail.contains("message_revisions"));
+
+        db::migrate(&conn).unwrap();
+        checks.clear();
+        let ro = check_database(&path, &mut checks).expect("connection");
+        assert_eq!(checks[0].status, CheckStatus::Pass, "{}", checks[0].detail);
+        let providers = load_providers(&ro, None, &mut checks);
+        assert!(providers.is_empty());
+        assert_eq!(checks[1].status, CheckStatus::Pass);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_port_and_log_checks() {
+        let held = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = held.local_addr().unwrap().to_string();
+        assert_eq!(check_port(&addr).status, CheckStatus::Fail);
+        drop(held);
+        assert_eq!(check_port(&addr).status, CheckStatus::Pass);
+        assert_eq!(check_port("not-an-addr").status, CheckStatus::Fail);
+
+        let log = temp_path("logs").join("dreamquill.log");
+        assert_eq!(check_log_dir(&log).status, CheckStatus::Pass);
+        let report = DoctorReport {
+            checks: vec![
+                CheckResult::new("a", CheckStatus::Pass, ""),
+                CheckResult::new("b", CheckStatus::Warn, ""),
+            ],
+        };
+        assert_eq!(report.worst(), CheckStatus::Warn);
+        let _ = std::fs::remove_dir_all(log.parent().unwrap());
+    }
+}
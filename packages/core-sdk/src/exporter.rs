@@ -0,0 +1,397 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime};
+
+use crate::{
+    db::{self, ChatSummary, StoredMessage},
+    models::Provider,
+    redact,
+};
+
+/**
+ * \brief Obsidian 导出结果统计。
+ */
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExportSummary {
+    /** \brief 写入的笔记数量。 */
+    pub notes: usize,
+    /** \brief 写入的文件路径。 */
+    pub files: Vec<PathBuf>,
+}
+
+/**
+ * \brief 将全部会话导出为 Obsidian 笔记：`<dir>/YYYY/MM/<标题> (<id>).md`。
+ * \details 文件名包含会话 ID，重复导出会覆盖同一文件，便于定期增量同步到笔记库。
+ */
+pub fn export_obsidian(conn: &Connection, dir: &Path) -> Result<ExportSummary> {
+    let providers = db::list_providers(conn)?;
+    let mut summary = ExportSummary::default();
+    for chat in db::list_chats(conn, None, true)? {
+        let provider = chat
+            .provider_id
+            .and_then(|pid| providers.iter().find(|p| p.id == pid));
+        let messages = db::load_messages_with_meta(conn, chat.id)?;
+        let path = dir.join(note_relative_path(&chat));
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("create {} failed", parent.display()))?;
+        }
+        std::fs::write(&path, render_obsidian_note(&chat, provider, &messages))
+            .with_context(|| format!("write {} failed", path.display()))?;
+        summary.notes += 1;
+        summary.files.push(path);
+    }
+    Ok(summary)
+}
+
+/**
+ * \brief 微调数据导出的会话选择：指定会话 ID 时忽略标签；两者都为空时导出全部会话。
+ */
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FinetuneSelection {
+    pub chat_ids: Vec<i64>,
+    /** \brief 会话须同时带有的标签。 */
+    pub tags: Vec<String>,
+}
+
+/**
+ * \brief 微调数据导出结果统计。
+ */
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FinetuneSummary {
+    /** \brief 参与导出的会话数。 */
+    pub chats: usize,
+    /** \brief 写出的样本（行）数。 */
+    pub examples: usize,
+}
+
+/**
+ * \brief 导出 OpenAI 微调格式的 JSONL：每个“用户提问 + 助手回复”生成一行 system/user/assistant 样本。
+ * \details 所有文本先经过个人信息脱敏；中断或取消后保存的部分回复不会作为样本。
+ */
+pub fn export_finetune(
+    conn: &Connection,
+    selection: &FinetuneSelection,
+    out: &mut dyn std::io::Write,
+) -> Result<FinetuneSummary> {
+    let chat_ids: Vec<i64> = if selection.chat_ids.is_empty() {
+        let filter = db::ChatFilter {
+            tags: selection.tags.clone(),
+            ..Default::default()
+        };
+        db::search_chats(conn, &filter)?
+            .into_iter()
+            .rev()
+            .map(|c| c.id)
+            .collect()
+    } else {
+        selection.chat_ids.clone()
+    };
+
+    let mut summary = FinetuneSummary::default();
+    for chat_id in chat_ids {
+        let messages = db::load_messages_with_meta(conn, chat_id)?;
+        let examples = finetune_examples(&messages);
+        if examples.is_empty() {
+            continue;
+        }
+        for example in &examples {
+            serde_json::to_writer(&mut *out, example)?;
+            out.write_all(b"\n")?;
+        }
+        summary.chats += 1;
+        summary.examples += examples.len();
+    }
+    out.flush()?;
+    Ok(summary)
+}
+
+/**
+ * \brief 将一个会话拆成微调样本；system 消息作为其后每个样本的系统提示。
+ */
+pub fn finetune_examples(messages: &[StoredMessage]) -> Vec<serde_json::Value> {
+    let mut examples = Vec::new();
+    let mut system: Option<String> = None;
+    let mut question: Option<String> = None;
+    for msg in messages {
+        let content = msg.content.trim();
+        match msg.role.as_str() {
+            "system" => system = Some(redact::redact_pii(content)),
+            "user" => question = Some(redact::redact_pii(content)),
+            "assistant" => {
+                let interrupted = msg
+                    .metadata
+                    .as_ref()
+                    .and_then(|m| m.get("interrupted"))
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false);
+                let user = match question.take() {
+                    Some(q) if !q.is_empty() => q,
+                    _ => continue,
+                };
+                if interrupted || msg.partial || content.is_empty() {
+                    continue;
+                }
+                let mut turn = Vec::new();
+                if let Some(sys) = system.as_ref().filter(|s| !s.is_empty()) {
+                    turn.push(json!({"role": "system", "content": sys}));
+                }
+                turn.push(json!({"role": "user", "content": user}));
+                turn.push(json!({"role": "assistant", "content": redact::redact_pii(content)}));
+                examples.push(json!({ "messages": turn }));
+            }
+            _ => {}
+        }
+    }
+    examples
+}
+
+/**
+ * \brief 计算会话笔记相对导出根目录的路径；无创建时间的旧数据归入 `undated/`。
+ */
+pub fn note_relative_path(chat: &ChatSummary) -> PathBuf {
+    let file_name = format!("{} ({}).md", sanitize_file_name(&chat.title), chat.id);
+    match chat
+        .created_at
+        .and_then(|ts| OffsetDateTime::from_unix_timestamp(ts).ok())
+    {
+        Some(dt) => PathBuf::from(format!("{:04}", dt.year()))
+            .join(format!("{:02}", u8::from(dt.month())))
+            .join(file_name),
+        None => PathBuf::from("undated").join(file_name),
+    }
+}
+
+/**
+ * \brief 渲染单个会话为带 YAML front-matter 的 Markdown 笔记。
+ */
+pub fn render_obsidian_note(
+    chat: &ChatSummary,
+    provider: Option<&Provider>,
+    messages: &[StoredMessage],
+) -> String {
+    let mut out = String::from("---\n");
+    out.push_str(&format!("title: {}\n", yaml_string(&chat.title)));
+    if let Some(date) = chat.created_at.and_then(format_rfc3339) {
+        out.push_str(&format!("date: {}\n", date));
+    }
+    if let Some(p) = provider {
+        out.push_str(&format!("provider: {}\n", yaml_string(&p.name)));
+        out.push_str(&format!("model: {}\n", yaml_string(&p.model)));
+    }
+    out.push_str(&format!("chat_id: {}\n", chat.id));
+    out.push_str("tags:\n  - dreamquill\n");
+    if let Some(p) = provider {
+        out.push_str(&format!(
+            "  - {}\n",
+            yaml_string(&tag_slug(&p.provider_type))
+        ));
+    }
+    out.push_str("---\n\n");
+    out.push_str(&format!("# {}\n", chat.title));
+
+    for msg in messages {
+        let time = msg
+            .created_at
+            .and_then(|ts| OffsetDateTime::from_unix_timestamp(ts).ok())
+            .and_then(|dt| dt.format(format_description!("[hour]:[minute]")).ok());
+        match time {
+            Some(t) => out.push_str(&format!("\n## {} · {}\n\n", role_heading(&msg.role), t)),
+            None => out.push_str(&format!("\n## {}\n\n", role_heading(&msg.role))),
+        }
+        if let Some(line) = msg.metadata.as_ref().and_then(overrides_callout) {
+            out.push_str(&line);
+        }
+        out.push_str(msg.content.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/** \brief 将单次请求的参数覆盖渲染为 Obsidian callout，便于回看回复的生成参数。 */
+fn overrides_callout(metadata: &serde_json::Value) -> Option<String> {
+    let overrides = metadata.get("overrides")?.as_object()?;
+    if overrides.is_empty() {
+        return None;
+    }
+    let parts: Vec<String> = overrides
+        .iter()
+        .map(|(k, v)| match v.as_str() {
+            Some(s) => format!("{}: {}", k, s.replace('\n', " ")),
+            None => format!("{}: {}", k, v),
+        })
+        .collect();
+    Some(format!("> [!info] overrides\n> {}\n\n", parts.join(" · ")))
+}
+
+fn role_heading(role: &str) -> &str {
+    match role {
+        "user" => "User",
+        "assistant" => "Assistant",
+        "system" => "System",
+        other => other,
+    }
+}
+
+fn format_rfc3339(ts: i64) -> Option<String> {
+    OffsetDateTime::from_unix_timestamp(ts)
+        .ok()?
+        .format(&Rfc3339)
+        .ok()
+}
+
+/** \brief 始终使用双引号字符串，避免标题中的冒号、井号等破坏 YAML。 */
+fn yaml_string(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+fn tag_slug(s: &str) -> String {
+    s.trim()
+        .chars()
+        .map(|c| if c.is_whitespace() { '-' } else { c })
+        .collect()
+}
+
+/** \brief 去掉 Obsidian 与常见文件系统不允许出现在文件名中的字符。 */
+pub(crate) fn sanitize_file_name(title: &str) -> String {
+    let cleaned: String = title
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '#' | '^' | '[' | ']' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let trimmed = cleaned.trim().trim_matches('.');
+    if trimmed.is_empty() {
+        "untitled".to_string()
+    } else {
+        trimmed.chars().take(80).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_chat(created_at: Option<i64>) -> ChatSummary {
+        ChatSummary {
+            id: 7,
+            title: "Plan: v2/launch?".to_string(),
+            provider_id: Some(1),
+            created_at,
+            last_read_message_id: None,
+            updated_at: None,
+            pinned: false,
+            archived: false,
+            model: None,
+        }
+    }
+
+    #[test]
+    fn test_note_relative_path_groups_by_month() {
+        let path = note_relative_path(&sample_chat(Some(1709294400)));
+        assert_eq!(path, PathBuf::from("2024/03/Plan_ v2_launch_ (7).md"));
+        let undated = note_relative_path(&sample_chat(None));
+        assert!(undated.starts_with("undated"));
+    }
+
+    fn stored(role: &str, content: &str, metadata: Option<serde_json::Value>) -> StoredMessage {
+        StoredMessage {
+            id: 0,
+            role: role.to_string(),
+            content: content.to_string(),
+            created_at: None,
+            first_token_ms: None,
+            duration_ms: None,
+            metadata,
+            partial: false,
+        }
+    }
+
+    #[test]
+    fn test_finetune_examples_pairs_and_redacts() {
+        let messages = vec![
+            stored("system", "be brief", None),
+            stored("user", "my mail is bob@example.com", None),
+            stored("assistant", "noted", None),
+            stored("user", "and now?", None),
+            stored(
+                "assistant",
+                "half an ans",
+                Some(serde_json::json!({"interrupted": true})),
+            ),
+            stored("assistant", "orphan", None),
+        ];
+        let examples = finetune_examples(&messages);
+        assert_eq!(examples.len(), 1);
+        assert_eq!(
+            examples[0],
+            serde_json::json!({"messages": [
+                {"role": "system", "content": "be brief"},
+                {"role": "user", "content": "my mail is [EMAIL]"},
+                {"role": "assistant", "content": "noted"}
+            ]})
+        );
+    }
+
+    #[test]
+    fn test_render_obsidian_note_front_matter() {
+        let provider = Provider {
+            id: 1,
+            name: "My \"GPT\"".to_string(),
+            api_base: String::new(),
+            api_key: String::new(),
+            model: "gpt-4o".to_string(),
+            provider_type: "openai".to_string(),
+            secret_alias: None,
+            api_prefix: None,
+            signing: None,
+            generation: Default::default(),
+            proxy_url: None,
+            api_key_unreadable: false,
+        };
+        let messages = vec![
+            StoredMessage {
+                id: 1,
+                role: "user".to_string(),
+                content: "hello\n".to_string(),
+                created_at: Some(1709294460),
+                first_token_ms: None,
+                duration_ms: None,
+                metadata: None,
+                partial: false,
+            },
+            StoredMessage {
+                id: 2,
+                role: "assistant".to_string(),
+                content: "hi".to_string(),
+                created_at: None,
+                first_token_ms: Some(10),
+                duration_ms: Some(20),
+                metadata: Some(serde_json::json!({
+                    "overrides": {"model": "gpt-4o-mini", "temperature": 0.3}
+                })),
+                partial: false,
+            },
+        ];
+        let note = render_obsidian_note(&sample_chat(Some(1709294400)), Some(&provider), &messages);
+        assert!(note.starts_with("---\ntitle: \"Plan: v2/launch?\"\n"));
+        assert!(note.contains("date: 2024-03-01T12:00:00Z\n"));
+        assert!(note.contains("provider: \"My \\\"GPT\\\"\"\n"));
+        assert!(note.contains("tags:\n  - dreamquill\n  - \"openai\"\n"));
+        assert!(note.contains("\n## User · 12:01\n\nhello\n"));
+        assert!(note.contains(
+            "\n## Assistant\n\n> [!info] overrides\n> model: gpt-4o-mini · temperature: 0.3\n\nhi\n"
+        ));
+    }
+}
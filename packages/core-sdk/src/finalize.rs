@@ -0,0 +1,141 @@
+use anyhow::Result;
+use rusqlite::Connection;
+use serde_json::{Map, Value};
+use tokio::task::JoinHandle;
+
+use crate::{
+    autotag, backfill, db,
+    models::{Message, Provider},
+    openai_compat::Usage,
+    summarize,
+};
+
+/**
+ * \brief 一次生成结束后待保存的助手回复。
+ */
+#[derive(Debug, Clone)]
+pub struct FinishedReply<'a> {
+    pub chat_id: i64,
+    pub content: &'a str,
+    /** \brief 本次发送给模型的上下文，用于估算用量。 */
+    pub prompt: &'a [Message],
+    pub timing: db::GenerationTiming,
+    /** \brief 请求级元数据（如生成参数覆盖），用量会合并进去。 */
+    pub metadata: Option<Value>,
+    /** \brief 取消生成后保存的不完整回复。 */
+    pub partial: bool,
+}
+
+/**
+ * \brief 收尾结果：已保存的消息、估算用量与是否需要生成首轮标题。
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Finalized {
+    pub message_id: i64,
+    pub usage: Usage,
+    pub title_due: bool,
+}
+
+/**
+ * \brief 在一个事务内完成生成后的记账：写入助手回复（元数据含 `usage`）、标记不完整回复、
+ * 更新会话 `updated_at`，并判断是否需要首轮标题。
+ * \details 任一步失败整体回滚，不会留下只写了一半的记录；会话与消息的数据版本（变更通知）
+ * 由触发器在同一事务内递增，随提交一起生效。后台任务由 `spawn_followups` 在提交后启动。
+ */
+pub fn finalize(conn: &Connection, reply: &FinishedReply) -> Result<Finalized> {
+    let usage = Usage::estimate(reply.prompt, reply.content);
+    let mut metadata = match &reply.metadata {
+        Some(Value::Object(map)) => map.clone(),
+        _ => Map::new(),
+    };
+    metadata.insert("usage".to_string(), serde_json::to_value(usage)?);
+    let metadata = Value::Object(metadata);
+
+    let tx = conn.unchecked_transaction()?;
+    let message_id = db::insert_assistant_message(
+        &tx,
+        reply.chat_id,
+        reply.content,
+        &reply.timing,
+        Some(&metadata),
+    )?;
+    if reply.partial {
+        db::mark_message_partial(&tx, message_id)?;
+    }
+    db::touch_chat(&tx, reply.chat_id, db::unix_now())?;
+    let title_due = backfill::first_exchange_due(&tx, reply.chat_id)?;
+    tx.commit()?;
+    Ok(Finalized {
+        message_id,
+        usage,
+        title_due,
+    })
+}
+
+/**
+ * \brief 收尾提交后启动后台任务：按需补打自动标签与滚动摘要；首轮回复后生成标题并返回其句柄。
+ */
+pub fn spawn_followups(
+    conn: &Connection,
+    provider: &Provider,
+    chat_id: i64,
+    finalized: &Finalized,
+) -> Option<JoinHandle<Option<String>>> {
+    autotag::spawn_if_due(conn, provider.clone(), chat_id);
+    summarize::spawn_if_due(conn, provider.clone(), chat_id);
+    finalized
+        .title_due
+        .then(|| backfill::spawn_title(provider.clone(), chat_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply<'a>(chat_id: i64, content: &'a str, prompt: &'a [Message]) -> FinishedReply<'a> {
+        FinishedReply {
+            chat_id,
+            content,
+            prompt,
+            timing: db::GenerationTiming::default(),
+            metadata: None,
+            partial: false,
+        }
+    }
+
+    #[test]
+    fn test_finalize_is_all_or_nothing() {
+        let conn = Connection::open_in_memory().expect("open db");
+        db::migrate(&conn).expect("migrate");
+        let chat_id = db::create_chat_at(&conn, "新会话", None, 1).expect("chat");
+        let prompt = vec![Message {
+            role: "user".to_string(),
+            content: "hello there".to_string(),
+        }];
+        db::insert_message(&conn, chat_id, "user", "hello there").expect("user");
+
+        let mut first = reply(chat_id, "hi", &prompt);
+        first.partial = true;
+        let done = finalize(&conn, &first).expect("finalize");
+        assert!(done.title_due);
+        assert_eq!(done.usage, Usage::estimate(&prompt, "hi"));
+        let chat = db::get_chat(&conn, chat_id).expect("get").expect("chat");
+        assert!(chat.updated_at.is_some());
+        let saved = db::load_messages_with_meta(&conn, chat_id).expect("messages");
+        let last = saved.last().expect("reply");
+        assert_eq!(last.id, done.message_id);
+        assert!(last.partial);
+        assert_eq!(
+            last.metadata.as_ref().and_then(|m| m.get("usage")),
+            Some(&serde_json::to_value(done.usage).unwrap())
+        );
+
+        let second = finalize(&conn, &reply(chat_id, "again", &prompt)).expect("finalize");
+        assert!(!second.title_due);
+
+        let before = db::count_messages(&conn, chat_id).expect("count");
+        assert!(finalize(&conn, &reply(chat_id + 100, "lost", &prompt)).is_err());
+        assert_eq!(db::count_messages(&conn, chat_id).expect("count"), before);
+        assert_eq!(db::count_messages(&conn, chat_id + 100).expect("count"), 0);
+    }
+}
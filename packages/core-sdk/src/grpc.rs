@@ -0,0 +1,506 @@
+// tonic 的服务接口固定以 `Status` 作为错误类型，辅助函数沿用同一类型。
+#![allow(clippy::result_large_err)]
+
+use std::{net::SocketAddr, pin::Pin};
+
+use anyhow::{Context, Result};
+use futures_util::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    base_url,
+    client::{self, DreamQuill},
+    db, llm,
+    models::Provider,
+    telemetry,
+};
+
+/**
+ * \brief 由 `proto/dreamquill.proto` 生成的消息与服务定义。
+ */
+pub mod proto {
+    tonic::include_proto!("dreamquill.v1");
+}
+
+use proto::dream_quill_server::{DreamQuill as DreamQuillApi, DreamQuillServer};
+
+/**
+ * \brief gRPC 服务实现，与 HTTP 接口共用数据库；API Key 与 HTTP 服务一样直接保存在数据库中。
+ */
+pub struct GrpcService {
+    dq: DreamQuill,
+}
+
+impl GrpcService {
+    pub fn new(dq: DreamQuill) -> Self {
+        Self { dq }
+    }
+
+    /** \brief 包装为 tonic 服务，可与其他服务一起挂到同一个 `Server` 上。 */
+    pub fn into_server(self) -> DreamQuillServer<Self> {
+        DreamQuillServer::new(self)
+    }
+
+    fn conn(&self) -> Result<rusqlite::Connection, Status> {
+        self.dq.connection().map_err(internal)
+    }
+
+    fn state(&self, conn: &rusqlite::Connection) -> Result<proto::ListProvidersReply, Status> {
+        let default_provider_id = db::get_default_provider_id(conn).map_err(internal)?;
+        let providers = db::list_providers(conn)
+            .map_err(internal)?
+            .into_iter()
+            .map(|p| provider_reply(p, default_provider_id))
+            .collect();
+        Ok(proto::ListProvidersReply {
+            providers,
+            default_provider_id,
+        })
+    }
+
+    fn reply_for(&self, conn: &rusqlite::Connection, id: i64) -> Result<proto::Provider, Status> {
+        let default_provider_id = db::get_default_provider_id(conn).map_err(internal)?;
+        db::get_provider_by_id(conn, id)
+            .map_err(internal)?
+            .map(|p| provider_reply(p, default_provider_id))
+            .ok_or_else(|| Status::not_found(format!("provider id {} not found", id)))
+    }
+}
+
+/**
+ * \brief 在 `addr` 上启动 gRPC 服务，直到收到 Ctrl-C。
+ */
+pub async fn serve(addr: &str, dq: DreamQuill) -> Result<()> {
+    let addr: SocketAddr = addr
+        .parse()
+        .with_context(|| format!("invalid grpc address {}", addr))?;
+    telemetry::log_event("grpc", &format!("listening on {}", addr));
+    tonic::transport::Server::builder()
+        .add_service(GrpcService::new(dq).into_server())
+        .serve_with_shutdown(addr, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+        .context("grpc server failed")
+}
+
+fn internal(e: anyhow::Error) -> Status {
+    telemetry::log_error("grpc", &format!("{:#}", e));
+    Status::internal(e.to_string())
+}
+
+fn provider_reply(p: Provider, default_provider_id: Option<i64>) -> proto::Provider {
+    proto::Provider {
+        id: p.id,
+        is_default: default_provider_id == Some(p.id),
+        has_api_key: !p.api_key.is_empty() || p.secret_alias.is_some(),
+        name: p.name,
+        provider: p.provider_type,
+        api_base: p.api_base,
+        model: p.model,
+        proxy_url: p.proxy_url.unwrap_or_default(),
+    }
+}
+
+/** \brief 校验表单必填项、api_base 与代理地址。 */
+fn validate_input(input: &proto::ProviderInput) -> Result<(), Status> {
+    for (field, value) in [
+        ("name", &input.name),
+        ("provider", &input.provider),
+        ("api_base", &input.api_base),
+        ("model", &input.model),
+    ] {
+        if value.trim().is_empty() {
+            return Err(Status::invalid_argument(format!("{} is required", field)));
+        }
+    }
+    base_url::normalize(&input.api_base).map_err(|e| Status::invalid_argument(e.to_string()))?;
+    if !input.proxy_url.trim().is_empty() {
+        llm::parse_proxy_url(&input.proxy_url)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+    }
+    Ok(())
+}
+
+fn audit<T>(
+    conn: &rusqlite::Connection,
+    request: &Request<T>,
+    action: &str,
+    id: i64,
+    changes: serde_json::Value,
+) {
+    let actor = match request.remote_addr() {
+        Some(addr) => format!("grpc@{}", addr.ip()),
+        None => "grpc".to_string(),
+    };
+    let target = format!("provider:{}", id);
+    if let Err(e) = db::insert_audit_log(conn, &actor, action, Some(&target), &changes) {
+        telemetry::log_error("grpc.audit", &format!("audit write failed: {}", e));
+    }
+}
+
+type ChatEventStream = Pin<Box<dyn Stream<Item = Result<proto::ChatEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl DreamQuillApi for GrpcService {
+    async fn health(
+        &self,
+        request: Request<proto::HealthRequest>,
+    ) -> Result<Response<proto::HealthReply>, Status> {
+        let provider = self
+            .dq
+            .provider(request.into_inner().provider_id)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        let mut reply = proto::HealthReply {
+            ok: false,
+            provider_id: provider.id,
+            provider: provider.provider_type.clone(),
+            model: provider.model.clone(),
+            models: 0,
+            error: String::new(),
+        };
+        match llm::list_models(&provider).await {
+            Ok(models) => {
+                reply.ok = true;
+                reply.models = models.len() as u32;
+            }
+            Err(e) => reply.error = e.to_string(),
+        }
+        Ok(Response::new(reply))
+    }
+
+    async fn list_providers(
+        &self,
+        _request: Request<proto::ListProvidersRequest>,
+    ) -> Result<Response<proto::ListProvidersReply>, Status> {
+        let conn = self.conn()?;
+        Ok(Response::new(self.state(&conn)?))
+    }
+
+    async fn create_provider(
+        &self,
+        request: Request<proto::ProviderInput>,
+    ) -> Result<Response<proto::Provider>, Status> {
+        let input = request.get_ref();
+        validate_input(input)?;
+        let conn = self.conn()?;
+        let insert = if input.set_default {
+            db::upsert_default_provider
+        } else {
+            db::insert_provider
+        };
+        let id = insert(
+            &conn,
+            input.name.trim(),
+            input.provider.trim(),
+            input.api_base.trim(),
+            &input.api_key,
+            input.model.trim(),
+            None,
+        )
+        .map_err(internal)?;
+        db::set_provider_proxy(&conn, id, Some(&input.proxy_url)).map_err(internal)?;
+        let created = db::get_provider_by_id(&conn, id).map_err(internal)?;
+        audit(
+            &conn,
+            &request,
+            "provider.create",
+            id,
+            db::provider_changes(None, created.as_ref()),
+        );
+        telemetry::log_event("grpc.provider", &format!("create id={}", id));
+        Ok(Response::new(self.reply_for(&conn, id)?))
+    }
+
+    async fn update_provider(
+        &self,
+        request: Request<proto::UpdateProviderRequest>,
+    ) -> Result<Response<proto::Provider>, Status> {
+        let id = request.get_ref().id;
+        let input = request
+            .get_ref()
+            .provider
+            .as_ref()
+            .ok_or_else(|| Status::invalid_argument("provider is required"))?;
+        validate_input(input)?;
+        let conn = self.conn()?;
+        let before = db::get_provider_by_id(&conn, id)
+            .map_err(internal)?
+            .ok_or_else(|| Status::not_found(format!("provider id {} not found", id)))?;
+        // 留空的密钥沿用原值（含安全存储别名）
+        let (api_key, alias) = if input.api_key.trim().is_empty() {
+            (before.api_key.clone(), before.secret_alias.clone())
+        } else {
+            (input.api_key.clone(), None)
+        };
+        db::update_provider(
+            &conn,
+            id,
+            input.name.trim(),
+            input.provider.trim(),
+            input.api_base.trim(),
+            &api_key,
+            input.model.trim(),
+            alias.as_deref(),
+        )
+        .map_err(internal)?;
+        db::set_provider_proxy(&conn, id, Some(&input.proxy_url)).map_err(internal)?;
+        if input.set_default {
+            db::set_default_provider_id(&conn, id).map_err(internal)?;
+        }
+        let after = db::get_provider_by_id(&conn, id).map_err(internal)?;
+        audit(
+            &conn,
+            &request,
+            "provider.update",
+            id,
+            db::provider_changes(Some(&before), after.as_ref()),
+        );
+        telemetry::log_event("grpc.provider", &format!("update id={}", id));
+        Ok(Response::new(self.reply_for(&conn, id)?))
+    }
+
+    async fn delete_provider(
+        &self,
+        request: Request<proto::DeleteProviderRequest>,
+    ) -> Result<Response<proto::ListProvidersReply>, Status> {
+        let id = request.get_ref().id;
+        let conn = self.conn()?;
+        let before = db::get_provider_by_id(&conn, id)
+            .map_err(internal)?
+            .ok_or_else(|| Status::not_found(format!("provider id {} not found", id)))?;
+        db::delete_provider(&conn, id).map_err(internal)?;
+        audit(
+            &conn,
+            &request,
+            "provider.delete",
+            id,
+            db::provider_changes(Some(&before), None),
+        );
+        telemetry::log_event("grpc.provider", &format!("delete id={}", id));
+        Ok(Response::new(self.state(&conn)?))
+    }
+
+    type ChatStream = ChatEventStream;
+
+    async fn chat(
+        &self,
+        request: Request<proto::ChatRequest>,
+    ) -> Result<Response<Self::ChatStream>, Status> {
+        let req = request.into_inner();
+        if req.prompt.trim().is_empty() {
+            return Err(Status::invalid_argument("prompt must not be empty"));
+        }
+        let chat_id = match req.chat_id {
+            Some(id) => {
+                db::get_chat(&self.conn()?, id)
+                    .map_err(internal)?
+                    .ok_or_else(|| Status::not_found(format!("chat id {} not found", id)))?;
+                id
+            }
+            None => {
+                let provider = self
+                    .dq
+                    .provider(req.provider_id)
+                    .map_err(|e| Status::failed_precondition(e.to_string()))?;
+                self.dq
+                    .create_chat(&format!("{} 会话", provider.name), Some(provider.id))
+                    .map_err(internal)?
+            }
+        };
+        let mut send = self
+            .dq
+            .chat(chat_id)
+            .send(req.prompt)
+            .overrides(llm::RequestOverrides {
+                model: req.model,
+                temperature: req.temperature,
+                max_tokens: req.max_tokens,
+                system_instruction: req.system,
+            });
+        if let Some(pid) = req.provider_id {
+            send = send.provider(pid);
+        }
+        let events = send
+            .stream()
+            .await
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        telemetry::log_event("grpc.chat", &format!("chat_id={}", chat_id));
+
+        let first = futures_util::stream::once(async move {
+            Ok(proto::ChatEvent {
+                event: Some(proto::chat_event::Event::ChatId(chat_id)),
+            })
+        });
+        let rest = events.map(move |event| {
+            let event = match event.map_err(internal)? {
+                client::ChatEvent::Delta(text) => proto::chat_event::Event::Delta(text),
+                client::ChatEvent::Done(reply) => proto::chat_event::Event::Done(proto::ChatDone {
+                    chat_id,
+                    message_id: reply.message_id,
+                    content: reply.content,
+                }),
+            };
+            Ok(proto::ChatEvent { event: Some(event) })
+        });
+        Ok(Response::new(Box::pin(first.chain(rest))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::dream_quill_client::DreamQuillClient;
+    use std::io::{Read, Write};
+
+    /** \brief 假的 OpenAI 上游：GET 返回模型列表，POST 返回两段 SSE 增量。 */
+    fn fake_upstream() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.expect("accept");
+                let mut buf = [0u8; 8192];
+                let mut seen = Vec::new();
+                while !seen.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).expect("read");
+                    if n == 0 {
+                        break;
+                    }
+                    seen.extend_from_slice(&buf[..n]);
+                }
+                let (content_type, body) = if seen.starts_with(b"GET") {
+                    ("application/json", r#"{"data":[{"id":"m"}]}"#.to_string())
+                } else {
+                    (
+                        "text/event-stream",
+                        [
+                            r#"data: {"choices":[{"delta":{"content":"Hel"}}]}"#,
+                            r#"data: {"choices":[{"delta":{"content":"lo"}}]}"#,
+                            "data: [DONE]",
+                        ]
+                        .map(|line| format!("{}\n\n", line))
+                        .concat(),
+                    )
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    content_type,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_grpc_provider_crud_and_streaming_chat() {
+        let path =
+            std::env::temp_dir().join(format!("dreamquill-grpc-{}.db", db::process_session_id()));
+        let _ = std::fs::remove_file(&path);
+        let dq = DreamQuill::builder().db_path(&path).build().expect("build");
+        let upstream = fake_upstream();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .expect("free port");
+
+        let rt = tokio::runtime::Runtime::new().expect("runtime");
+        rt.block_on(async {
+            tokio::spawn(
+                tonic::transport::Server::builder()
+                    .add_service(GrpcService::new(dq).into_server())
+                    .serve(addr),
+            );
+            let mut client = loop {
+                match DreamQuillClient::connect(format!("http://{}", addr)).await {
+                    Ok(c) => break c,
+                    Err(_) => tokio::time::sleep(std::time::Duration::from_millis(20)).await,
+                }
+            };
+
+            let input = proto::ProviderInput {
+                name: "fake".to_string(),
+                provider: "openai".to_string(),
+                api_base: format!("http://{}", upstream),
+                api_key: "sk".to_string(),
+                model: "m".to_string(),
+                set_default: true,
+                proxy_url: String::new(),
+            };
+            let bad = proto::ProviderInput {
+                proxy_url: "ftp://proxy".to_string(),
+                ..input.clone()
+            };
+            let status = client.create_provider(bad).await.expect_err("bad proxy");
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+            let created = client
+                .create_provider(input.clone())
+                .await
+                .expect("create")
+                .into_inner();
+            assert!(created.is_default && created.has_api_key);
+            let updated = client
+                .update_provider(proto::UpdateProviderRequest {
+                    id: created.id,
+                    provider: Some(proto::ProviderInput {
+                        name: "renamed".to_string(),
+                        api_key: String::new(),
+                        ..input
+                    }),
+                })
+                .await
+                .expect("update")
+                .into_inner();
+            assert_eq!(updated.name, "renamed");
+            assert!(updated.has_api_key, "empty key keeps the stored one");
+
+            let health = client
+                .health(proto::HealthRequest { provider_id: None })
+                .await
+                .expect("health")
+                .into_inner();
+            assert!(health.ok, "{}", health.error);
+            assert_eq!(health.models, 1);
+
+            let mut events = client
+                .chat(proto::ChatRequest {
+                    prompt: "hi".to_string(),
+                    ..Default::default()
+                })
+                .await
+                .expect("chat")
+                .into_inner();
+            let mut chat_id = None;
+            let mut text = String::new();
+            let mut done = None;
+            while let Some(event) = events.message().await.expect("event") {
+                match event.event.expect("event body") {
+                    proto::chat_event::Event::ChatId(id) => chat_id = Some(id),
+                    proto::chat_event::Event::Delta(delta) => text.push_str(&delta),
+                    proto::chat_event::Event::Done(d) => done = Some(d),
+                }
+            }
+            let done = done.expect("done event");
+            assert_eq!(text, "Hello");
+            assert_eq!(done.content, "Hello");
+            assert_eq!(Some(done.chat_id), chat_id);
+
+            let empty = client
+                .chat(proto::ChatRequest::default())
+                .await
+                .expect_err("empty prompt");
+            assert_eq!(empty.code(), tonic::Code::InvalidArgument);
+
+            let remaining = client
+                .delete_provider(proto::DeleteProviderRequest { id: created.id })
+                .await
+                .expect("delete")
+                .into_inner();
+            assert!(remaining.providers.is_empty());
+        });
+        let _ = std::fs::remove_file(&path);
+    }
+}
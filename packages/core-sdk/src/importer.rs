@@ -0,0 +1,359 @@
+use anyhow::{anyhow, Context, Result};
+use rusqlite::Connection;
+use serde::Serialize;
+use serde_json::Value;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::db;
+
+/**
+ * \brief 从第三方导出文件解析出的会话。
+ */
+#[derive(Debug, Clone)]
+pub struct ImportedChat {
+    /** \brief 会话标题。 */
+    pub title: String,
+    /** \brief 创建时间（Unix 秒）。 */
+    pub created_at: Option<i64>,
+    /** \brief 按时间顺序排列的消息。 */
+    pub messages: Vec<ImportedMessage>,
+}
+
+/**
+ * \brief 从第三方导出文件解析出的消息。
+ */
+#[derive(Debug, Clone)]
+pub struct ImportedMessage {
+    /** \brief 角色：system/user/assistant。 */
+    pub role: String,
+    /** \brief 正文。 */
+    pub content: String,
+    /** \brief 创建时间（Unix 秒）。 */
+    pub created_at: Option<i64>,
+}
+
+/**
+ * \brief 导入结果统计。
+ */
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportSummary {
+    /** \brief 新建会话数。 */
+    pub chats: usize,
+    /** \brief 写入消息数。 */
+    pub messages: usize,
+    /** \brief 跳过的空会话数。 */
+    pub skipped: usize,
+    /** \brief 新建会话的 ID 列表。 */
+    pub chat_ids: Vec<i64>,
+}
+
+/**
+ * \brief 支持的导出来源。
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /** \brief ChatGPT 数据导出。 */
+    ChatGpt,
+    /** \brief Claude 数据导出。 */
+    Claude,
+}
+
+/**
+ * \brief 按来源解析原始导出 JSON 文本。
+ */
+pub fn parse_export(format: ImportFormat, raw: &str) -> Result<Vec<ImportedChat>> {
+    let v: Value = serde_json::from_str(raw).context("parse export json failed")?;
+    match format {
+        ImportFormat::ChatGpt => parse_chatgpt_export(&v),
+        ImportFormat::Claude => parse_claude_export(&v),
+    }
+}
+
+/**
+ * \brief 解析 ChatGPT 导出的 `conversations.json`。
+ * \details 每个会话沿 `current_node` 向上回溯得到当前分支，忽略工具消息与非文本片段。
+ */
+pub fn parse_chatgpt_export(v: &Value) -> Result<Vec<ImportedChat>> {
+    let conversations = v
+        .as_array()
+        .ok_or_else(|| anyhow!("chatgpt export must be a JSON array"))?;
+    let mut chats = Vec::new();
+    for conv in conversations {
+        let mapping = match conv.get("mapping").and_then(|m| m.as_object()) {
+            Some(m) => m,
+            None => continue,
+        };
+
+        let mut node_id = conv
+            .get("current_node")
+            .and_then(|n| n.as_str())
+            .map(|s| s.to_string());
+        let mut chain = Vec::new();
+        while let Some(id) = node_id {
+            let node = match mapping.get(&id) {
+                Some(n) => n,
+                None => break,
+            };
+            chain.push(node);
+            node_id = node
+                .get("parent")
+                .and_then(|p| p.as_str())
+                .map(|s| s.to_string());
+            if chain.len() > mapping.len() {
+                break;
+            }
+        }
+        chain.reverse();
+
+        let messages = chain
+            .into_iter()
+            .filter_map(|node| node.get("message"))
+            .filter_map(|msg| {
+                let role = msg.get("author")?.get("role")?.as_str()?;
+                if !matches!(role, "system" | "user" | "assistant") {
+                    return None;
+                }
+                let content = msg
+                    .get("content")?
+                    .get("parts")?
+                    .as_array()?
+                    .iter()
+                    .filter_map(|p| p.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                if content.trim().is_empty() {
+                    return None;
+                }
+                Some(ImportedMessage {
+                    role: role.to_string(),
+                    content,
+                    created_at: msg
+                        .get("create_time")
+                        .and_then(|t| t.as_f64())
+                        .map(|t| t as i64),
+                })
+            })
+            .collect();
+
+        chats.push(ImportedChat {
+            title: conv
+                .get("title")
+                .and_then(|t| t.as_str())
+                .filter(|t| !t.trim().is_empty())
+                .unwrap_or("ChatGPT 导入会话")
+                .to_string(),
+            created_at: conv
+                .get("create_time")
+                .and_then(|t| t.as_f64())
+                .map(|t| t as i64),
+            messages,
+        });
+    }
+    Ok(chats)
+}
+
+/**
+ * \brief 解析 Claude（Anthropic）数据导出的 `conversations.json`。
+ */
+pub fn parse_claude_export(v: &Value) -> Result<Vec<ImportedChat>> {
+    let conversations = v
+        .as_array()
+        .ok_or_else(|| anyhow!("claude export must be a JSON array"))?;
+    let mut chats = Vec::new();
+    for conv in conversations {
+        let messages = conv
+            .get("chat_messages")
+            .and_then(|m| m.as_array())
+            .map(|arr| {
+                arr.iter()
+                    .filter_map(|msg| {
+                        let role = match msg.get("sender")?.as_str()? {
+                            "human" | "user" => "user",
+                            "assistant" => "assistant",
+                            _ => return None,
+                        };
+                        let content = claude_message_text(msg);
+                        if content.trim().is_empty() {
+                            return None;
+                        }
+                        Some(ImportedMessage {
+                            role: role.to_string(),
+                            content,
+                            created_at: msg
+                                .get("created_at")
+                                .and_then(|t| t.as_str())
+                                .and_then(parse_timestamp),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        chats.push(ImportedChat {
+            title: conv
+                .get("name")
+                .and_then(|t| t.as_str())
+                .filter(|t| !t.trim().is_empty())
+                .unwrap_or("Claude 导入会话")
+                .to_string(),
+            created_at: conv
+                .get("created_at")
+                .and_then(|t| t.as_str())
+                .and_then(parse_timestamp),
+            messages,
+        });
+    }
+    Ok(chats)
+}
+
+fn claude_message_text(msg: &Value) -> String {
+    if let Some(parts) = msg.get("content").and_then(|c| c.as_array()) {
+        let text = parts
+            .iter()
+            .filter(|p| p.get("type").and_then(|t| t.as_str()) == Some("text"))
+            .filter_map(|p| p.get("text").and_then(|t| t.as_str()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if !text.is_empty() {
+            return text;
+        }
+    }
+    msg.get("text")
+        .and_then(|t| t.as_str())
+        .unwrap_or("")
+        .to_string()
+}
+
+fn parse_timestamp(s: &str) -> Option<i64> {
+    OffsetDateTime::parse(s, &Rfc3339)
+        .ok()
+        .map(|t| t.unix_timestamp())
+}
+
+/**
+ * \brief 在单个事务中写入解析后的会话，保留原始时间戳；空会话会被跳过。
+ */
+pub fn import_chats(
+    conn: &Connection,
+    chats: &[ImportedChat],
+    provider_id: Option<i64>,
+) -> Result<ImportSummary> {
+    let tx = conn.unchecked_transaction()?;
+    let mut summary = ImportSummary::default();
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    for chat in chats {
+        if chat.messages.is_empty() {
+            summary.skipped += 1;
+            continue;
+        }
+        let chat_created = chat
+            .created_at
+            .or_else(|| chat.messages.first().and_then(|m| m.created_at))
+            .unwrap_or(now);
+        let chat_id = db::create_chat_at(&tx, &chat.title, provider_id, chat_created)?;
+        for msg in &chat.messages {
+            db::insert_message_at(
+                &tx,
+                chat_id,
+                &msg.role,
+                &msg.content,
+                msg.created_at.unwrap_or(chat_created),
+            )?;
+            summary.messages += 1;
+        }
+        summary.chats += 1;
+        summary.chat_ids.push(chat_id);
+    }
+    tx.commit()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_chatgpt_export_follows_current_branch() {
+        let export = json!([{
+            "title": "Rust 问答",
+            "create_time": 1700000000.5,
+            "current_node": "c",
+            "mapping": {
+                "root": {"id": "root", "message": null, "parent": null},
+                "a": {"id": "a", "parent": "root", "message": {
+                    "author": {"role": "user"}, "create_time": 1700000001.0,
+                    "content": {"content_type": "text", "parts": ["hello"]}}},
+                "b": {"id": "b", "parent": "a", "message": {
+                    "author": {"role": "assistant"}, "create_time": 1700000002.0,
+                    "content": {"content_type": "text", "parts": ["old branch"]}}},
+                "c": {"id": "c", "parent": "a", "message": {
+                    "author": {"role": "assistant"}, "create_time": 1700000003.0,
+                    "content": {"content_type": "text", "parts": ["hi there"]}}}
+            }
+        }]);
+        let chats = parse_chatgpt_export(&export).expect("parse");
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].title, "Rust 问答");
+        assert_eq!(chats[0].created_at, Some(1700000000));
+        let contents: Vec<_> = chats[0]
+            .messages
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["hello", "hi there"]);
+        assert_eq!(chats[0].messages[1].created_at, Some(1700000003));
+    }
+
+    #[test]
+    fn test_parse_claude_export_maps_roles_and_timestamps() {
+        let export = json!([{
+            "uuid": "x",
+            "name": "",
+            "created_at": "2024-03-01T12:00:00.000000Z",
+            "chat_messages": [
+                {"sender": "human", "text": "ping", "created_at": "2024-03-01T12:00:01Z"},
+                {"sender": "assistant", "text": "",
+                 "content": [{"type": "text", "text": "pong"}],
+                 "created_at": "2024-03-01T12:00:02Z"}
+            ]
+        }]);
+        let chats = parse_claude_export(&export).expect("parse");
+        assert_eq!(chats[0].title, "Claude 导入会话");
+        assert_eq!(chats[0].created_at, Some(1709294400));
+        assert_eq!(chats[0].messages[0].role, "user");
+        assert_eq!(chats[0].messages[1].content, "pong");
+        assert_eq!(chats[0].messages[1].created_at, Some(1709294402));
+    }
+
+    #[test]
+    fn test_import_chats_preserves_timestamps_and_skips_empty() {
+        let conn = Connection::open_in_memory().expect("open db");
+        db::migrate(&conn).expect("migrate");
+        let chats = vec![
+            ImportedChat {
+                title: "one".to_string(),
+                created_at: Some(100),
+                messages: vec![ImportedMessage {
+                    role: "user".to_string(),
+                    content: "hi".to_string(),
+                    created_at: Some(101),
+                }],
+            },
+            ImportedChat {
+                title: "empty".to_string(),
+                created_at: None,
+                messages: vec![],
+            },
+        ];
+        let summary = import_chats(&conn, &chats, None).expect("import");
+        assert_eq!(summary.chats, 1);
+        assert_eq!(summary.skipped, 1);
+        let chat = db::get_chat(&conn, summary.chat_ids[0])
+            .expect("get chat")
+            .unwrap();
+        assert_eq!(chat.created_at, Some(100));
+        let messages = db::load_messages_with_meta(&conn, chat.id).expect("load");
+        assert_eq!(messages[0].created_at, Some(101));
+    }
+}
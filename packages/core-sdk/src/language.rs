@@ -0,0 +1,335 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    db::{self, MessageVariant},
+    llm,
+    models::{Provider, ReplyLanguage},
+    rerun, telemetry,
+};
+
+/** \brief 可检测的语言：(代码, 名称)。拉丁字母语言靠常用虚词区分。 */
+const LANGUAGES: &[(&str, &str)] = &[
+    ("zh", "简体中文 (Chinese)"),
+    ("ja", "日本語 (Japanese)"),
+    ("ko", "한국어 (Korean)"),
+    ("ru", "Русский (Russian)"),
+    ("en", "English"),
+    ("fr", "Français (French)"),
+    ("de", "Deutsch (German)"),
+    ("es", "Español (Spanish)"),
+];
+
+/** \brief 去掉代码后字母数少于该值时不做判断，避免短回复（如“OK”）误判。 */
+const MIN_LETTERS: usize = 12;
+
+/** \brief 各拉丁字母语言的常用虚词（小写）。 */
+const STOPWORDS: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "the", "and", "is", "are", "of", "to", "in", "that", "it", "you", "for", "with",
+            "this", "be", "not", "can",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "les", "et", "est", "des", "une", "un", "pour", "dans", "que", "vous",
+            "pas", "avec", "ce", "sont",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "das", "und", "ist", "nicht", "ein", "eine", "mit", "sie", "zu", "den",
+            "auf", "für", "sind", "auch",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "los", "las", "y", "es", "del", "una", "por", "para", "con", "que", "no", "se",
+            "como", "está", "son",
+        ],
+    ),
+];
+
+/**
+ * \brief 回复语言与会话设置不符时的检测结果。
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LanguageMismatch {
+    pub expected: String,
+    pub detected: String,
+}
+
+/**
+ * \brief 归一化语言代码：小写并去掉地区后缀（`zh-CN` → `zh`）。
+ */
+pub fn normalize_code(code: &str) -> String {
+    code.trim()
+        .split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+/**
+ * \brief 语言代码对应的名称，用于提示词；未知代码原样返回。
+ */
+pub fn language_name(code: &str) -> String {
+    let code = normalize_code(code);
+    LANGUAGES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or(code)
+}
+
+/**
+ * \brief 启发式检测文本语言：先按文字系统判断，拉丁字母再按常用虚词区分；
+ * 代码块、行内代码与链接不参与判断，无法判断时返回 None。
+ */
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let prose = strip_code(text);
+    let (mut han, mut kana, mut hangul, mut cyrillic, mut latin) = (0usize, 0, 0, 0, 0);
+    for ch in prose.chars() {
+        match ch as u32 {
+            0x3040..=0x30FF => kana += 1,
+            0xAC00..=0xD7AF => hangul += 1,
+            0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF => han += 1,
+            0x0400..=0x04FF => cyrillic += 1,
+            _ if ch.is_alphabetic() => latin += 1,
+            _ => {}
+        }
+    }
+    let total = han + kana + hangul + cyrillic + latin;
+    if total < MIN_LETTERS {
+        return None;
+    }
+    // 汉字一个字约等于拉丁字母一个词，占两成以上即视为东亚语言
+    let cjk = han + kana + hangul;
+    if cjk * 5 >= total {
+        return Some(if hangul * 2 >= cjk {
+            "ko"
+        } else if kana * 10 >= cjk {
+            "ja"
+        } else {
+            "zh"
+        });
+    }
+    if cyrillic * 2 >= total {
+        return Some("ru");
+    }
+    let words: Vec<String> = prose
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    STOPWORDS
+        .iter()
+        .map(|(code, list)| {
+            let hits = words.iter().filter(|w| list.contains(&w.as_str())).count();
+            (*code, hits)
+        })
+        .filter(|(_, hits)| *hits > 0)
+        // 平局时取靠前的语言（英语优先）
+        .fold(None, |best: Option<(&str, usize)>, cur| match best {
+            Some(b) if b.1 >= cur.1 => Some(b),
+            _ => Some(cur),
+        })
+        .map(|(code, _)| code)
+}
+
+/** \brief 去掉围栏代码块、行内代码与链接。 */
+fn strip_code(text: &str) -> String {
+    let mut out = String::new();
+    for (i, part) in text.split("```").enumerate() {
+        if i % 2 == 0 {
+            for (j, seg) in part.split('`').enumerate() {
+                if j % 2 == 0 {
+                    out.push_str(seg);
+                    out.push(' ');
+                }
+            }
+        }
+    }
+    out.split_whitespace()
+        .filter(|w| !w.contains("://"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/**
+ * \brief 比较回复语言与设置；设置语言不在可检测范围或无法判断时视为相符。
+ */
+pub fn check(setting: &ReplyLanguage, content: &str) -> Option<LanguageMismatch> {
+    let expected = normalize_code(&setting.language);
+    if !LANGUAGES.iter().any(|(c, _)| *c == expected) {
+        return None;
+    }
+    let detected = detect_language(content)?;
+    (detected != expected).then(|| LanguageMismatch {
+        expected,
+        detected: detected.to_string(),
+    })
+}
+
+/**
+ * \brief 会话开启语言约束且回复语言不符时返回检测结果，调用方据此调用 `enforce`。
+ */
+pub fn mismatch_for(
+    conn: &rusqlite::Connection,
+    chat_id: i64,
+    content: &str,
+) -> Option<LanguageMismatch> {
+    match db::get_chat_reply_language(conn, chat_id) {
+        Ok(Some(setting)) if setting.enforce => check(&setting, content),
+        Ok(_) => None,
+        Err(e) => {
+            telemetry::log_error("language", &format!("load reply language failed: {}", e));
+            None
+        }
+    }
+}
+
+/**
+ * \brief 重问时追加到系统指令末尾的强约束。
+ */
+pub fn enforcement_instruction(expected: &str) -> String {
+    let name = language_name(expected);
+    format!(
+        "IMPORTANT: Write your entire reply in {name}, regardless of the language used in the question or earlier messages. \
+Translate explanations into {name}; keep code, identifiers, commands and quoted text unchanged."
+    )
+}
+
+/**
+ * \brief 以更强的语言指令重问一次，结果保存为对应用户消息的备选回复，
+ * 并在原助手消息元数据的 `language_enforcement` 字段记录处理过程。
+ * \details 数据库连接不跨越 await 持有，可在后台任务中调用。
+ */
+pub async fn enforce(
+    provider: &Provider,
+    assistant_message_id: i64,
+    mismatch: &LanguageMismatch,
+) -> Result<MessageVariant> {
+    let (user_message_id, system) = db::run(move |conn| {
+        let user_message_id = db::user_message_before(conn, assistant_message_id)?
+            .ok_or_else(|| anyhow!("no user message before {}", assistant_message_id))?;
+        let system = db::message_context(conn, user_message_id)?
+            .and_then(|(_, history)| history.into_iter().find(|m| m.role == "system"))
+            .map(|m| m.content);
+        Ok((user_message_id, system))
+    })
+    .await?;
+    let nudge = enforcement_instruction(&mismatch.expected);
+    let overrides = llm::RequestOverrides {
+        system_instruction: Some(match system {
+            Some(sys) => format!("{}\n\n{}", sys.trim_end(), nudge),
+            None => nudge,
+        }),
+        ..Default::default()
+    };
+    let result = rerun::rerun_message(provider, user_message_id, &overrides).await;
+    let record = match &result {
+        Ok(variant) => {
+            let variant_language = detect_language(&variant.content);
+            json!({
+                "expected": mismatch.expected,
+                "detected": mismatch.detected,
+                "action": "reasked",
+                "variant_id": variant.id,
+                "variant_language": variant_language,
+                "resolved": variant_language.is_none_or(|l| l == mismatch.expected),
+            })
+        }
+        Err(e) => json!({
+            "expected": mismatch.expected,
+            "detected": mismatch.detected,
+            "action": "reask_failed",
+            "error": e.to_string(),
+        }),
+    };
+    let saved = record.clone();
+    db::run(move |conn| {
+        db::merge_message_metadata(conn, assistant_message_id, "language_enforcement", &saved)
+    })
+    .await?;
+    telemetry::log_event(
+        "language",
+        &format!(
+            "enforced message_id={} provider={} {}",
+            assistant_message_id, provider.name, record
+        ),
+    );
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_language_by_script_and_stopwords() {
+        assert_eq!(
+            detect_language("这是一个使用 Rust 编写的命令行工具，支持多种模型。"),
+            Some("zh")
+        );
+        assert_eq!(
+            detect_language("これは Rust で書かれたコマンドラインツールです。"),
+            Some("ja")
+        );
+        assert_eq!(
+            detect_language("이것은 러스트로 작성된 명령줄 도구입니다."),
+            Some("ko")
+        );
+        assert_eq!(
+            detect_language("Это инструмент командной строки на Rust."),
+            Some("ru")
+        );
+        assert_eq!(
+            detect_language("This is a command line tool and it is written in Rust."),
+            Some("en")
+        );
+        assert_eq!(
+            detect_language("Das ist ein Werkzeug und es ist nicht schwer zu benutzen."),
+            Some("de")
+        );
+        assert_eq!(
+            detect_language("C'est un outil pour la ligne de commande et il est simple."),
+            Some("fr")
+        );
+        assert_eq!(detect_language("OK"), None);
+        // 代码块内的英文不影响判断
+        assert_eq!(
+            detect_language("用法如下，先安装依赖再编译：\n```rust\nfn main() { println!(\"this is the code\"); }\n```\n编译完成后直接运行即可。"),
+            Some("zh")
+        );
+    }
+
+    #[test]
+    fn test_check_against_setting() {
+        let setting = ReplyLanguage {
+            language: "zh-CN".to_string(),
+            enforce: true,
+        };
+        assert_eq!(
+            check(&setting, "Sure, here is the answer you asked for."),
+            Some(LanguageMismatch {
+                expected: "zh".to_string(),
+                detected: "en".to_string(),
+            })
+        );
+        assert!(check(&setting, "好的，下面是你要的答案。").is_none());
+        assert!(check(&setting, "`cargo build`").is_none());
+        let unknown = ReplyLanguage {
+            language: "it".to_string(),
+            enforce: true,
+        };
+        assert!(check(&unknown, "This is clearly English text.").is_none());
+        assert!(enforcement_instruction("zh").contains("简体中文"));
+    }
+}
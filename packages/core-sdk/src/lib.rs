@@ -1,16 +1,78 @@
+pub mod archive;
+pub mod autotag;
+pub mod backfill;
+pub mod base_url;
+pub mod client;
+pub mod commands;
+pub mod context;
+pub mod crypto;
 pub mod db;
+pub mod debug_bundle;
+pub mod doctor;
+pub mod exporter;
+pub mod finalize;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod importer;
+pub mod language;
 pub mod llm;
+pub mod markdown;
+pub mod model_cache;
 pub mod models;
+pub mod openai_compat;
+pub mod paths;
+pub mod pipeline;
+pub mod provider_sync;
+pub mod rate_limit;
+pub mod redact;
+pub mod refusal;
+pub mod rerun;
 pub mod server;
+pub mod sql_console;
+pub mod sse;
+pub mod summarize;
 pub mod telemetry;
+pub mod transcript;
+
+pub use client::DreamQuill;
 
 /**
  * \brief SDK 预导入集合，方便外部引用常用模块。
  */
 pub mod prelude {
+    pub use crate::archive;
+    pub use crate::autotag;
+    pub use crate::backfill;
+    pub use crate::base_url;
+    pub use crate::client;
+    pub use crate::commands;
+    pub use crate::context;
+    pub use crate::crypto;
     pub use crate::db;
+    pub use crate::debug_bundle;
+    pub use crate::doctor;
+    pub use crate::exporter;
+    pub use crate::finalize;
+    #[cfg(feature = "grpc")]
+    pub use crate::grpc;
+    pub use crate::importer;
+    pub use crate::language;
     pub use crate::llm;
+    pub use crate::markdown;
+    pub use crate::model_cache;
     pub use crate::models;
+    pub use crate::openai_compat;
+    pub use crate::paths;
+    pub use crate::pipeline;
+    pub use crate::provider_sync;
+    pub use crate::rate_limit;
+    pub use crate::redact;
+    pub use crate::refusal;
+    pub use crate::rerun;
     pub use crate::server;
+    pub use crate::sql_console;
+    pub use crate::sse;
+    pub use crate::summarize;
     pub use crate::telemetry;
+    pub use crate::transcript;
 }
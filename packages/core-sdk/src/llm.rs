@@ -1,15 +1,34 @@
 use anyhow::{anyhow, Result};
 use async_stream::try_stream;
-use futures_util::Stream;
+use futures_util::{Stream, StreamExt};
+use once_cell::sync::Lazy;
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::pin::Pin;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
-use crate::models::{Message, Provider};
+use crate::{
+    base_url, db,
+    models::{HostPolicy, Message, Provider, RequestSigning, RetryPolicy},
+    sse::SseDecoder,
+};
 
 const ANTHROPIC_VERSION: &str = "2023-06-01";
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 enum ProviderKind {
     OpenAI,
     OpenAIResponse,
@@ -26,75 +45,1493 @@ fn provider_kind(provider: &Provider) -> ProviderKind {
     }
 }
 
+/** \brief 已探测的 API 路径前缀，按 (Provider 类型, api_base) 缓存，覆盖环境变量与未保存的 Provider。 */
+static API_PREFIX_CACHE: Lazy<Mutex<HashMap<(ProviderKind, String), String>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/** \brief 路径探测请求的超时。 */
+const API_PREFIX_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/** \brief Provider 代理支持的协议。 */
+const PROXY_SCHEMES: &[&str] = &["http", "https", "socks5", "socks5h"];
+
+/**
+ * \brief 校验代理地址：须带协议（http/https/socks5/socks5h）与主机，可含 `user:pass@` 认证信息。
+ */
+pub fn parse_proxy_url(raw: &str) -> Result<reqwest::Proxy> {
+    let raw = raw.trim();
+    let url =
+        reqwest::Url::parse(raw).map_err(|e| anyhow!("invalid proxy url {:?}: {}", raw, e))?;
+    if !PROXY_SCHEMES.contains(&url.scheme()) {
+        return Err(anyhow!(
+            "unsupported proxy scheme {:?}, expected one of {}",
+            url.scheme(),
+            PROXY_SCHEMES.join("/")
+        ));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err(anyhow!("proxy url {:?} has no host", raw));
+    }
+    Ok(reqwest::Proxy::all(url)?)
+}
+
+static HOST_POLICY: Lazy<Mutex<HostPolicy>> = Lazy::new(|| Mutex::new(HostPolicy::default()));
+
+/** \brief 当前进程使用的 api_base 主机名白名单/黑名单。 */
+pub fn host_policy() -> HostPolicy {
+    HOST_POLICY
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/** \brief 替换当前进程使用的主机名策略，通常在启动时从 `db::get_host_policy` 读取后设置。 */
+pub fn set_host_policy(policy: HostPolicy) {
+    *HOST_POLICY.lock().unwrap_or_else(|e| e.into_inner()) = policy.normalized();
+}
+
+/** \brief 单次请求最多跟随的重定向次数，与 reqwest 默认策略一致。 */
+const MAX_REDIRECTS: usize = 10;
+
+/**
+ * \brief 按 Provider 配置创建请求构建器：设置了 `proxy_url` 时全部请求经该代理，否则沿用系统代理环境变量。
+ * \details 所有请求都经此创建客户端，api_base 不符合 `host_policy` 或密钥无法解密时在发出请求前报错；
+ * 上游返回的重定向逐跳按当前策略校验，指向不允许的主机时请求失败。
+ */
+fn http_client_builder(provider: &Provider) -> Result<reqwest::ClientBuilder> {
+    if provider.api_key_unreadable {
+        return Err(anyhow!(
+            "the API key of provider {} cannot be decrypted with the current master key; re-enter it or run `dreamquill provider rekey`",
+            provider.name
+        ));
+    }
+    base_url::check_host(&provider.api_base, &host_policy())?;
+    let builder =
+        reqwest::Client::builder().redirect(reqwest::redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error(anyhow!("too many redirects"));
+            }
+            match base_url::check_redirect(attempt.url(), &host_policy()) {
+                Ok(()) => attempt.follow(),
+                Err(e) => attempt.error(e),
+            }
+        }));
+    Ok(
+        match provider
+            .proxy_url
+            .as_deref()
+            .filter(|u| !u.trim().is_empty())
+        {
+            Some(url) => builder.proxy(parse_proxy_url(url)?),
+            None => builder,
+        },
+    )
+}
+
+fn http_client(provider: &Provider) -> Result<reqwest::Client> {
+    Ok(http_client_builder(provider)?.build()?)
+}
+
+/**
+ * \brief 请求使用的 api_base：经 `base_url::normalize` 归一化，覆盖旧数据与环境变量中未归一化的地址；
+ * 无法归一化时只去掉末尾斜杠，交由请求本身报错。
+ */
+fn clean_base(api_base: &str) -> String {
+    base_url::normalize(api_base)
+        .unwrap_or_else(|_| api_base.trim().trim_end_matches('/').to_string())
+}
+
+/**
+ * \brief 候选 API 路径前缀：api_base 已以版本段（如 `/v1`）结尾时优先原样使用，否则优先补 `/v1`。
+ */
+pub fn api_prefix_candidates(api_base: &str) -> Vec<String> {
+    let base = clean_base(api_base);
+    let with_v1 = format!("{}/v1", base);
+    let has_version = base
+        .rsplit('/')
+        .next()
+        .and_then(|seg| seg.strip_prefix('v'))
+        .is_some_and(|rest| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit()));
+    if has_version {
+        vec![base, with_v1]
+    } else {
+        vec![with_v1, base]
+    }
+}
+
+/**
+ * \brief 解析 OpenAI/Claude 类 Provider 实际可用的路径前缀。
+ * \details 依次使用 Provider 行上缓存的结果、进程内缓存；都没有时以 `GET {prefix}/models` 依次探测候选，
+ * 非 404 的响应即视为路径正确（鉴权失败也说明路径存在），并写回缓存与数据库。网络错误时不缓存。
+ */
+pub async fn resolve_api_prefix(provider: &Provider) -> String {
+    if let Some(prefix) = provider.api_prefix.as_deref().filter(|p| !p.is_empty()) {
+        return prefix.to_string();
+    }
+    let kind = provider_kind(provider);
+    let key = (kind, clean_base(&provider.api_base));
+    if let Some(prefix) = API_PREFIX_CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&key)
+    {
+        return prefix.clone();
+    }
+
+    let candidates = api_prefix_candidates(&provider.api_base);
+    let client = match http_client_builder(provider)
+        .and_then(|b| Ok(b.timeout(API_PREFIX_PROBE_TIMEOUT).build()?))
+    {
+        Ok(c) => c,
+        Err(_) => return candidates[0].clone(),
+    };
+    for prefix in &candidates {
+        let url = format!("{}/models", prefix);
+        let mut req = client.get(&url);
+        req = match kind {
+            ProviderKind::Claude => req
+                .header("x-api-key", &provider.api_key)
+                .header("anthropic-version", ANTHROPIC_VERSION),
+            _ => req.header(AUTHORIZATION, format!("Bearer {}", provider.api_key)),
+        };
+        let Ok(req) = signed(req, provider, "GET", &url, None) else {
+            break;
+        };
+        match req.send().await {
+            Ok(resp) if resp.status() == reqwest::StatusCode::NOT_FOUND => continue,
+            Ok(_) => {
+                remember_api_prefix(provider, key, prefix);
+                return prefix.clone();
+            }
+            Err(_) => break,
+        }
+    }
+    candidates[0].clone()
+}
+
+fn remember_api_prefix(provider: &Provider, key: (ProviderKind, String), prefix: &str) {
+    API_PREFIX_CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(key, prefix.to_string());
+    if provider.id > 0 {
+        let (id, api_base, prefix) = (provider.id, provider.api_base.clone(), prefix.to_string());
+        crate::db::spawn_write("llm.api_prefix", "cache prefix failed", move |conn| {
+            crate::db::set_provider_api_prefix(conn, id, &api_base, &prefix)
+        });
+    }
+}
+
+/**
+ * \brief 单次请求的参数覆盖（模型、温度、输出上限、系统指令），优先于 Provider 配置。
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RequestOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /** \brief 输出 token 上限，优先于按类型的默认值。 */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /** \brief 替换会话中已有 system 消息的系统指令。 */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<String>,
+}
+
+impl RequestOverrides {
+    /** \brief 去掉空白模型名与系统指令，避免把空字符串当作覆盖。 */
+    pub fn normalized(self) -> Self {
+        Self {
+            model: self
+                .model
+                .map(|m| m.trim().to_string())
+                .filter(|m| !m.is_empty()),
+            temperature: self.temperature,
+            max_tokens: self.max_tokens.filter(|n| *n > 0),
+            system_instruction: self.system_instruction.filter(|s| !s.trim().is_empty()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.model.is_none()
+            && self.temperature.is_none()
+            && self.max_tokens.is_none()
+            && self.system_instruction.is_none()
+    }
+
+    /** \brief 实际使用的模型名。 */
+    pub fn model_for<'p>(&'p self, provider: &'p Provider) -> &'p str {
+        self.model.as_deref().unwrap_or(&provider.model)
+    }
+
+    /** \brief 应用系统指令覆盖后的消息列表。 */
+    pub fn messages_for(&self, messages: &[Message]) -> Vec<Message> {
+        match &self.system_instruction {
+            Some(sys) => std::iter::once(Message {
+                role: "system".to_string(),
+                content: sys.clone(),
+            })
+            .chain(messages.iter().filter(|m| m.role != "system").cloned())
+            .collect(),
+            None => messages.to_vec(),
+        }
+    }
+
+    /** \brief 写入消息元数据的覆盖记录；无覆盖时返回 None。 */
+    pub fn to_metadata(&self) -> Option<Value> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(json!({ "overrides": self }))
+        }
+    }
+}
+
+/**
+ * \brief 实际发送的生成参数：单次覆盖、Provider 配置与按类型的默认值依次合并后的结果。
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GenerationParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+}
+
+/** \brief 按类型的默认输出上限。 */
+#[derive(Debug, Clone, Copy)]
+struct KindDefaults {
+    max_tokens: Option<u32>,
+}
+
+const fn max_tokens(limit: u32) -> KindDefaults {
+    KindDefaults {
+        max_tokens: Some(limit),
+    }
+}
+
+/**
+ * \brief 生成参数默认值表，按 (Provider 类型, 模型名前缀) 顺序匹配，首条命中生效；未命中时全部交给服务端决定。
+ * \details Claude 接口必须给出 max_tokens，按模型代际取输出上限。
+ */
+const GENERATION_DEFAULTS: &[(ProviderKind, &str, KindDefaults)] = &[
+    (ProviderKind::Claude, "claude-opus-4", max_tokens(8192)),
+    (ProviderKind::Claude, "claude-sonnet-4", max_tokens(8192)),
+    (ProviderKind::Claude, "claude-3-7", max_tokens(8192)),
+    (ProviderKind::Claude, "claude-3-5", max_tokens(8192)),
+    (ProviderKind::Claude, "", max_tokens(4096)),
+];
+
+/**
+ * \brief 合并单次覆盖、Provider 配置与类型默认值，得到本次请求实际使用的生成参数。
+ */
+pub fn generation_params(provider: &Provider, overrides: &RequestOverrides) -> GenerationParams {
+    let kind = provider_kind(provider);
+    let model = overrides.model_for(provider).to_ascii_lowercase();
+    let defaults = GENERATION_DEFAULTS
+        .iter()
+        .find(|(k, prefix, _)| *k == kind && model.starts_with(prefix))
+        .map(|(_, _, params)| params.max_tokens)
+        .unwrap_or_default();
+    let configured = &provider.generation;
+    GenerationParams {
+        temperature: overrides.temperature.or(configured.temperature),
+        top_p: configured.top_p,
+        max_tokens: overrides
+            .max_tokens
+            .or(configured.max_tokens.filter(|n| *n > 0))
+            .or(defaults),
+        stop: configured
+            .stop
+            .iter()
+            .filter(|s| !s.is_empty())
+            .cloned()
+            .collect(),
+    }
+}
+
+/**
+ * \brief 已弃用或更名的模型提示：配置的模型命中登记表时给出替代建议。
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelDeprecation {
+    pub model: String,
+    pub replacement: String,
+    pub message: String,
+}
+
+/**
+ * \brief 已知弃用/更名模型登记表：(Provider 类型, 旧模型名, 建议替代)。
+ * \details 按模型名精确匹配（忽略大小写与 Gemini 的 `models/` 前缀）；OpenAI Responses 与 OpenAI 共用条目。
+ */
+const DEPRECATED_MODELS: &[(ProviderKind, &str, &str)] = &[
+    (ProviderKind::OpenAI, "gpt-4-32k", "gpt-4o"),
+    (ProviderKind::OpenAI, "gpt-4-vision-preview", "gpt-4o"),
+    (ProviderKind::OpenAI, "gpt-4-1106-preview", "gpt-4o"),
+    (ProviderKind::OpenAI, "gpt-3.5-turbo-0301", "gpt-4o-mini"),
+    (ProviderKind::OpenAI, "gpt-3.5-turbo-0613", "gpt-4o-mini"),
+    (ProviderKind::OpenAI, "text-davinci-003", "gpt-4o-mini"),
+    (
+        ProviderKind::Claude,
+        "claude-2.0",
+        "claude-3-5-sonnet-latest",
+    ),
+    (
+        ProviderKind::Claude,
+        "claude-2.1",
+        "claude-3-5-sonnet-latest",
+    ),
+    (
+        ProviderKind::Claude,
+        "claude-instant-1.2",
+        "claude-3-5-haiku-latest",
+    ),
+    (
+        ProviderKind::Claude,
+        "claude-3-sonnet-20240229",
+        "claude-3-5-sonnet-latest",
+    ),
+    (ProviderKind::Gemini, "gemini-pro", "gemini-1.5-pro"),
+    (ProviderKind::Gemini, "gemini-1.0-pro", "gemini-1.5-flash"),
+    (
+        ProviderKind::Gemini,
+        "gemini-pro-vision",
+        "gemini-1.5-flash",
+    ),
+];
+
+/**
+ * \brief 查询模型是否在弃用登记表中。
+ */
+pub fn model_deprecation(provider: &Provider, model: &str) -> Option<ModelDeprecation> {
+    let kind = match provider_kind(provider) {
+        ProviderKind::OpenAIResponse => ProviderKind::OpenAI,
+        kind => kind,
+    };
+    let name = model.trim().trim_start_matches("models/");
+    DEPRECATED_MODELS
+        .iter()
+        .find(|(k, old, _)| *k == kind && old.eq_ignore_ascii_case(name))
+        .map(|(_, _, replacement)| ModelDeprecation {
+            model: model.trim().to_string(),
+            replacement: replacement.to_string(),
+            message: format!(
+                "model {} is deprecated, consider switching to {}",
+                model.trim(),
+                replacement
+            ),
+        })
+}
+
+/**
+ * \brief 以统一接口返回各类 Provider 的流式增量。
+ */
+pub async fn stream_chat<'a>(
+    provider: &'a Provider,
+    messages: &'a [Message],
+) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + Send + 'a>>> {
+    stream_chat_with(provider, messages, &RequestOverrides::default()).await
+}
+
+/**
+ * \brief 带单次参数覆盖的流式调用。
+ */
+pub async fn stream_chat_with<'a>(
+    provider: &'a Provider,
+    messages: &'a [Message],
+    overrides: &RequestOverrides,
+) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + Send + 'a>>> {
+    let span = request_span(provider, overrides, "stream");
+    let mut meter = RequestMeter::start(provider, overrides, true);
+    let opened: Result<(Option<Result<String>>, _)> = async {
+        let policy = retry_policy();
+        let mut retry = 0;
+        loop {
+            let mut stream = open_stream(provider, messages, overrides).await?;
+            // 尚未收到任何增量就中断时整条请求重发；已有输出后的中断交给调用方续写。
+            let first = stream.next().await;
+            match first {
+                Some(Err(e))
+                    if retry + 1 < policy.max_attempts && is_retryable_error(&e.to_string()) =>
+                {
+                    retry += 1;
+                    let delay = policy.backoff(retry);
+                    note_retry("stream_first_token", retry, "stream", delay);
+                    tokio::time::sleep(delay).await;
+                }
+                first => return Ok((first, stream)),
+            }
+        }
+    }
+    .instrument(span.clone())
+    .await;
+    let ok = matches!(opened, Ok((Some(Ok(_)) | None, _)));
+    finish_request_span(&span, meter.started, ok);
+    let (first, stream) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            meter.finish(false);
+            return Err(e);
+        }
+    };
+    match &first {
+        Some(Ok(delta)) => meter.observe(delta),
+        Some(Err(_)) => meter.ok = false,
+        None => {}
+    }
+    let metered = MeteredStream {
+        inner: stream,
+        meter: Some(meter),
+    };
+    Ok(Box::pin(futures_util::stream::iter(first).chain(metered)))
+}
+
+/**
+ * \brief 单次模型请求的计量：记录首 token 时间与输出量，结束时写入 `request_metrics`。
+ */
+struct RequestMeter {
+    provider_id: i64,
+    provider_name: String,
+    model: String,
+    streaming: bool,
+    started: Instant,
+    first_token_ms: Option<i64>,
+    cjk_chars: usize,
+    other_chars: usize,
+    ok: bool,
+}
+
+impl RequestMeter {
+    fn start(provider: &Provider, overrides: &RequestOverrides, streaming: bool) -> Self {
+        Self {
+            provider_id: provider.id,
+            provider_name: provider.name.clone(),
+            model: overrides.model_for(provider).to_string(),
+            streaming,
+            started: Instant::now(),
+            first_token_ms: None,
+            cjk_chars: 0,
+            other_chars: 0,
+            ok: true,
+        }
+    }
+
+    /** \brief 记录一段输出；按字符累计，结果与 `estimate_tokens` 对整段回复的估算一致。 */
+    fn observe(&mut self, delta: &str) {
+        if delta.is_empty() {
+            return;
+        }
+        self.first_token_ms
+            .get_or_insert_with(|| self.started.elapsed().as_millis() as i64);
+        for ch in delta.chars() {
+            if is_cjk(ch) {
+                self.cjk_chars += 1;
+            } else {
+                self.other_chars += 1;
+            }
+        }
+    }
+
+    fn finish(self, ok: bool) {
+        db::record_request_metrics(&db::RequestMetrics {
+            provider_id: Some(self.provider_id),
+            provider_name: self.provider_name,
+            model: self.model,
+            streaming: self.streaming,
+            ok: ok && self.ok,
+            first_token_ms: self.first_token_ms,
+            total_ms: self.started.elapsed().as_millis() as i64,
+            output_tokens: (self.cjk_chars + self.other_chars.div_ceil(4)) as i64,
+        });
+    }
+}
+
+/**
+ * \brief 流式输出的计量包装：流结束或被提前丢弃（如取消）时写入指标。
+ */
+struct MeteredStream<'a> {
+    inner: Pin<Box<dyn Stream<Item = Result<String>> + Send + 'a>>,
+    meter: Option<RequestMeter>,
+}
+
+impl Stream for MeteredStream<'_> {
+    type Item = Result<String>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let polled = this.inner.as_mut().poll_next(cx);
+        match &polled {
+            std::task::Poll::Ready(Some(Ok(delta))) => {
+                if let Some(meter) = this.meter.as_mut() {
+                    meter.observe(delta);
+                }
+            }
+            std::task::Poll::Ready(Some(Err(_))) => {
+                if let Some(meter) = this.meter.as_mut() {
+                    meter.ok = false;
+                }
+            }
+            std::task::Poll::Ready(None) => {
+                if let Some(meter) = this.meter.take() {
+                    meter.finish(true);
+                }
+            }
+            std::task::Poll::Pending => {}
+        }
+        polled
+    }
+}
+
+impl Drop for MeteredStream<'_> {
+    fn drop(&mut self) {
+        if let Some(meter) = self.meter.take() {
+            meter.finish(true);
+        }
+    }
+}
+
+/**
+ * \brief 单次模型请求的 span，结束时记录 `latency_ms`（流式为首个增量到达的耗时）。
+ */
+fn request_span(
+    provider: &Provider,
+    overrides: &RequestOverrides,
+    kind: &'static str,
+) -> tracing::Span {
+    tracing::info_span!(
+        "llm.request",
+        kind,
+        provider = provider.name.as_str(),
+        provider_type = provider.provider_type.as_str(),
+        model = overrides.model_for(provider),
+        latency_ms = tracing::field::Empty
+    )
+}
+
+fn finish_request_span(span: &tracing::Span, started: Instant, ok: bool) {
+    let latency_ms = started.elapsed().as_millis() as u64;
+    span.record("latency_ms", latency_ms);
+    tracing::info!(parent: span, category = "llm", latency_ms, ok, "request finished");
+}
+
+async fn open_stream<'a>(
+    provider: &'a Provider,
+    messages: &'a [Message],
+    overrides: &RequestOverrides,
+) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + Send + 'a>>> {
+    match provider_kind(provider) {
+        ProviderKind::OpenAI | ProviderKind::OpenAIResponse => {
+            stream_openai(provider, messages, overrides).await
+        }
+        ProviderKind::Claude => stream_claude(provider, messages, overrides).await,
+        ProviderKind::Gemini => stream_gemini(provider, messages, overrides).await,
+    }
+}
+
+/**
+ * \brief 非流式调用，返回完整回复。
+ */
+pub async fn chat_once(provider: &Provider, messages: &[Message]) -> Result<String> {
+    chat_once_with(provider, messages, &RequestOverrides::default()).await
+}
+
+/**
+ * \brief 带单次参数覆盖的非流式调用。
+ */
+pub async fn chat_once_with(
+    provider: &Provider,
+    messages: &[Message],
+    overrides: &RequestOverrides,
+) -> Result<String> {
+    let span = request_span(provider, overrides, "once");
+    let mut meter = RequestMeter::start(provider, overrides, false);
+    let reply = match provider_kind(provider) {
+        ProviderKind::OpenAI | ProviderKind::OpenAIResponse => {
+            chat_once_openai(provider, messages, overrides)
+                .instrument(span.clone())
+                .await
+        }
+        ProviderKind::Claude => {
+            chat_once_claude(provider, messages, overrides)
+                .instrument(span.clone())
+                .await
+        }
+        ProviderKind::Gemini => {
+            chat_once_gemini(provider, messages, overrides)
+                .instrument(span.clone())
+                .await
+        }
+    };
+    finish_request_span(&span, meter.started, reply.is_ok());
+    if let Ok(text) = &reply {
+        meter.observe(text);
+    }
+    meter.finish(reply.is_ok());
+    reply
+}
+
+/**
+ * \brief 请求被取消令牌中止时返回的错误，可用 `err.is::<Cancelled>()` 判断。
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("request cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/**
+ * \brief 可取消的流式调用。
+ * \details 令牌在等待响应头期间触发时直接返回 `Cancelled`；流式输出期间触发时流立即结束。
+ * 两种情况下进行中的 HTTP 请求都随之被丢弃，连接关闭，上游不再继续生成。
+ */
+pub async fn stream_chat_cancellable<'a>(
+    provider: &'a Provider,
+    messages: &'a [Message],
+    overrides: &RequestOverrides,
+    cancel: &CancellationToken,
+) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + Send + 'a>>> {
+    let stream = tokio::select! {
+        biased;
+        _ = cancel.cancelled() => return Err(Cancelled.into()),
+        stream = stream_chat_with(provider, messages, overrides) => stream?,
+    };
+    Ok(Box::pin(
+        stream.take_until(cancel.clone().cancelled_owned()),
+    ))
+}
+
+/**
+ * \brief 可取消的非流式调用，令牌触发时丢弃进行中的请求并返回 `Cancelled`。
+ */
+pub async fn chat_once_cancellable(
+    provider: &Provider,
+    messages: &[Message],
+    overrides: &RequestOverrides,
+    cancel: &CancellationToken,
+) -> Result<String> {
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => Err(Cancelled.into()),
+        reply = chat_once_with(provider, messages, overrides) => reply,
+    }
+}
+
+/**
+ * \brief 各类型 Provider 的默认向量模型；Claude 没有向量接口，返回 `None`。
+ */
+pub fn default_embedding_model(provider: &Provider) -> Option<&'static str> {
+    match provider_kind(provider) {
+        ProviderKind::OpenAI | ProviderKind::OpenAIResponse => Some("text-embedding-3-small"),
+        ProviderKind::Gemini => Some("text-embedding-004"),
+        ProviderKind::Claude => None,
+    }
+}
+
+/**
+ * \brief 批量计算文本向量，结果与输入一一对应。
+ */
+pub async fn embed_texts(
+    provider: &Provider,
+    model: &str,
+    texts: &[String],
+) -> Result<Vec<Vec<f32>>> {
+    if texts.is_empty() {
+        return Ok(Vec::new());
+    }
+    let vectors = match provider_kind(provider) {
+        ProviderKind::OpenAI | ProviderKind::OpenAIResponse => {
+            embed_openai(provider, model, texts).await?
+        }
+        ProviderKind::Gemini => embed_gemini(provider, model, texts).await?,
+        ProviderKind::Claude => {
+            return Err(anyhow!(
+                "provider type {} has no embeddings API",
+                provider.provider_type
+            ))
+        }
+    };
+    if vectors.len() != texts.len() {
+        return Err(anyhow!(
+            "embeddings response has {} vectors for {} inputs",
+            vectors.len(),
+            texts.len()
+        ));
+    }
+    Ok(vectors)
+}
+
+async fn embed_openai(provider: &Provider, model: &str, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+    let url = format!("{}/embeddings", resolve_api_prefix(provider).await);
+    let body = json!({ "model": model, "input": texts });
+    let req = http_client(provider)?
+        .post(&url)
+        .header(AUTHORIZATION, format!("Bearer {}", provider.api_key));
+    let resp = send_with_retry(
+        signed(req, provider, "POST", &url, Some(&body))?,
+        "embed_openai",
+    )
+    .await?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        return Err(anyhow!("embeddings failed: {} -> {}", status, text));
+    }
+    parse_openai_embeddings(&resp.json().await?)
+}
+
+async fn embed_gemini(provider: &Provider, model: &str, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+    let model = model.trim_start_matches("models/");
+    let url = format!(
+        "{}/models/{}:batchEmbedContents",
+        normalize_gemini_base(&provider.api_base),
+        model
+    );
+    let requests: Vec<Value> = texts
+        .iter()
+        .map(|t| json!({ "model": format!("models/{}", model), "content": { "parts": [{ "text": t }] } }))
+        .collect();
+    let body = json!({ "requests": requests });
+    let req = http_client(provider)?
+        .post(&url)
+        .query(&[("key", provider.api_key.as_str())]);
+    let resp = send_with_retry(
+        signed(req, provider, "POST", &url, Some(&body))?,
+        "embed_gemini",
+    )
+    .await?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        return Err(anyhow!("gemini embeddings failed: {} -> {}", status, text));
+    }
+    parse_gemini_embeddings(&resp.json().await?)
+}
+
+fn float_array(v: &Value) -> Option<Vec<f32>> {
+    v.as_array()?
+        .iter()
+        .map(|x| x.as_f64().map(|f| f as f32))
+        .collect()
+}
+
+/** \brief 解析 OpenAI `data[].embedding`，按 `index` 排序。 */
+fn parse_openai_embeddings(v: &Value) -> Result<Vec<Vec<f32>>> {
+    let data = v
+        .get("data")
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow!("embeddings response missing data"))?;
+    let mut rows = data
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let index = item
+                .get("index")
+                .and_then(Value::as_u64)
+                .unwrap_or(i as u64);
+            item.get("embedding")
+                .and_then(float_array)
+                .map(|vec| (index, vec))
+                .ok_or_else(|| anyhow!("embeddings response item {} malformed", i))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    rows.sort_by_key(|(index, _)| *index);
+    Ok(rows.into_iter().map(|(_, vec)| vec).collect())
+}
+
+/** \brief 解析 Gemini `embeddings[].values`。 */
+fn parse_gemini_embeddings(v: &Value) -> Result<Vec<Vec<f32>>> {
+    v.get("embeddings")
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow!("gemini embeddings response missing embeddings"))?
+        .iter()
+        .map(|item| {
+            item.get("values")
+                .and_then(float_array)
+                .ok_or_else(|| anyhow!("gemini embeddings item malformed"))
+        })
+        .collect()
+}
+
+/**
+ * \brief 列出当前 Provider 可用模型列表。
+ */
+pub async fn list_models(provider: &Provider) -> Result<Vec<String>> {
+    match provider_kind(provider) {
+        ProviderKind::OpenAI | ProviderKind::OpenAIResponse => list_models_openai(provider).await,
+        ProviderKind::Claude => list_models_claude(provider).await,
+        ProviderKind::Gemini => list_models_gemini(provider).await,
+    }
+}
+
+/**
+ * \brief DreamQuill 对某个 Provider 可提供的功能矩阵，供界面按会话禁用不支持的操作。
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ProviderCapabilities {
+    /** \brief 逐增量流式输出；不支持时退化为一次性返回整段回复。 */
+    pub streaming: bool,
+    /** \brief 工具/函数调用。 */
+    pub tools: bool,
+    /** \brief 图片输入。 */
+    pub vision: bool,
+    pub embeddings: bool,
+    /** \brief 文本转语音。 */
+    pub tts: bool,
+    /** \brief 提示词缓存。 */
+    pub caching: bool,
+}
+
+/**
+ * \brief 根据 Provider 类型与模型名推断能力；`probed_models` 为探测到的模型列表时，
+ * 嵌入与语音以列表中是否存在对应模型为准。
+ */
+pub fn detect_capabilities(
+    provider: &Provider,
+    probed_models: Option<&[String]>,
+) -> ProviderCapabilities {
+    let kind = provider_kind(provider);
+    let model = provider.model.to_ascii_lowercase();
+    let has_model = |needle: &str| {
+        probed_models.map(|models| {
+            models
+                .iter()
+                .any(|m| m.to_ascii_lowercase().contains(needle))
+        })
+    };
+    let openai_like = matches!(kind, ProviderKind::OpenAI | ProviderKind::OpenAIResponse);
+
+    let vision = match kind {
+        ProviderKind::Claude => !model.starts_with("claude-2") && !model.contains("instant"),
+        ProviderKind::Gemini => true,
+        ProviderKind::OpenAI | ProviderKind::OpenAIResponse => {
+            [
+                "gpt-4o",
+                "gpt-4.1",
+                "gpt-4-turbo",
+                "gpt-5",
+                "vision",
+                "-vl",
+                "llava",
+            ]
+            .iter()
+            .any(|m| model.contains(m))
+                || ["o1", "o3", "o4"].iter().any(|m| model.starts_with(m))
+        }
+    };
+    let embeddings = match kind {
+        ProviderKind::Claude => false,
+        _ => has_model("embed").unwrap_or(true),
+    };
+    let tts = openai_like && has_model("tts").unwrap_or(true);
+
+    ProviderCapabilities {
+        streaming: openai_like,
+        tools: true,
+        vision,
+        embeddings,
+        tts,
+        caching: true,
+    }
+}
+
+/**
+ * \brief 拉取模型列表后推断能力；探测失败时退回按类型推断并返回错误信息。
+ */
+pub async fn probe_capabilities(provider: &Provider) -> (ProviderCapabilities, Option<String>) {
+    match list_models(provider).await {
+        Ok(models) => (detect_capabilities(provider, Some(&models)), None),
+        Err(e) => (detect_capabilities(provider, None), Some(e.to_string())),
+    }
+}
+
+/** \brief 签名配置未指定时间戳请求头时使用的名称。 */
+const DEFAULT_SIGNING_TIMESTAMP_HEADER: &str = "X-Timestamp";
+
+/**
+ * \brief 计算 HMAC-SHA256 请求签名（小写十六进制），原文为 `METHOD\nPATH\nhex(sha256(body))\nTIMESTAMP`。
+ */
+pub fn sign_request(secret: &str, method: &str, path: &str, body: &[u8], timestamp: i64) -> String {
+    let body_hash = hex::encode(ring::digest::digest(&ring::digest::SHA256, body));
+    let canonical = format!(
+        "{}\n{}\n{}\n{}",
+        method.to_ascii_uppercase(),
+        path,
+        body_hash,
+        timestamp
+    );
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    hex::encode(ring::hmac::sign(&key, canonical.as_bytes()))
+}
+
+/**
+ * \brief 设置 JSON 请求体，并在 Provider 配置了签名时附加签名与时间戳请求头。
+ */
+fn signed(
+    req: reqwest::RequestBuilder,
+    provider: &Provider,
+    method: &str,
+    url: &str,
+    body: Option<&Value>,
+) -> Result<reqwest::RequestBuilder> {
+    let bytes = body
+        .map(serde_json::to_vec)
+        .transpose()?
+        .unwrap_or_default();
+    let mut req = req;
+    if body.is_some() {
+        req = req
+            .header(CONTENT_TYPE, "application/json")
+            .body(bytes.clone());
+    }
+    let signing = provider
+        .signing
+        .as_ref()
+        .filter(|s| !s.header.trim().is_empty() && !s.secret.is_empty());
+    let Some(RequestSigning {
+        header,
+        timestamp_header,
+        secret,
+    }) = signing
+    else {
+        return Ok(req);
+    };
+    let path = reqwest::Url::parse(url)?.path().to_string();
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    Ok(req
+        .header(
+            header.trim(),
+            sign_request(secret, method, &path, &bytes, timestamp),
+        )
+        .header(
+            timestamp_header
+                .as_deref()
+                .map(str::trim)
+                .filter(|h| !h.is_empty())
+                .unwrap_or(DEFAULT_SIGNING_TIMESTAMP_HEADER),
+            timestamp.to_string(),
+        ))
+}
+
+/** \brief 单个 Provider 健康检查的超时。 */
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/**
+ * \brief Provider 健康检查结果：鉴权、默认模型是否可用与模型列表延迟。
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderHealth {
+    pub provider_id: i64,
+    pub name: String,
+    pub provider_type: String,
+    pub model: String,
+    /** \brief 鉴权是否通过；请求未到达服务端（网络错误、超时）时为空。 */
+    pub auth_ok: Option<bool>,
+    /** \brief 默认模型是否出现在模型列表中；列表拉取失败时为空。 */
+    pub model_available: Option<bool>,
+    /** \brief 模型列表请求耗时（毫秒）。 */
+    pub latency_ms: u64,
+    pub error: Option<String>,
+    /** \brief 默认模型已弃用时的替代建议，不影响健康判定。 */
+    pub deprecation: Option<ModelDeprecation>,
+}
+
+impl ProviderHealth {
+    /** \brief 鉴权通过且默认模型可用。 */
+    pub fn is_healthy(&self) -> bool {
+        self.auth_ok == Some(true) && self.model_available == Some(true)
+    }
+}
+
+/**
+ * \brief 通过拉取模型列表检查 Provider 的鉴权、默认模型与延迟。
+ */
+pub async fn check_provider_health(provider: &Provider) -> ProviderHealth {
+    let started = std::time::Instant::now();
+    let result = tokio::time::timeout(HEALTH_CHECK_TIMEOUT, list_models(provider)).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let (auth_ok, model_available, error) = match result {
+        Ok(Ok(models)) => (
+            Some(true),
+            Some(model_listed(&models, &provider.model)),
+            None,
+        ),
+        Ok(Err(e)) => {
+            let msg = e.to_string();
+            let auth = is_auth_failure(&msg).then_some(false);
+            (auth, None, Some(msg))
+        }
+        Err(_) => (
+            None,
+            None,
+            Some(format!(
+                "timed out after {}s",
+                HEALTH_CHECK_TIMEOUT.as_secs()
+            )),
+        ),
+    };
+    ProviderHealth {
+        provider_id: provider.id,
+        name: provider.name.clone(),
+        provider_type: provider.provider_type.clone(),
+        model: provider.model.clone(),
+        auth_ok,
+        model_available,
+        latency_ms,
+        error,
+        deprecation: model_deprecation(provider, &provider.model),
+    }
+}
+
+/** \brief 模型列表中是否包含指定模型（兼容 Gemini 的 `models/` 前缀）。 */
+fn model_listed(models: &[String], model: &str) -> bool {
+    let model = model.trim_start_matches("models/");
+    models
+        .iter()
+        .any(|m| m.trim_start_matches("models/") == model)
+}
+
+/** \brief 模型列表错误是否来自 401/403 响应。 */
+fn is_auth_failure(message: &str) -> bool {
+    message.contains(": 401 ") || message.contains(": 403 ")
+}
+
+/** \brief 流中断后自动续写的最大重试次数。 */
+pub const STREAM_RETRY_LIMIT: u32 = 2;
+
+/** \brief 续写请求追加的用户提示：要求模型从中断处接着写。 */
+const CONTINUE_PROMPT: &str = "Your previous reply was cut off by a connection error. \
+                               Continue exactly where it stopped, without repeating any text or adding commentary.";
+
+/**
+ * \brief 错误是否可能是暂时性的（网络中断、超时、限流、上游 5xx/过载），值得自动重试。
+ */
+pub fn is_retryable_error(message: &str) -> bool {
+    const HINTS: &[&str] = &[
+        "timed out",
+        "timeout",
+        "connection reset",
+        "connection closed",
+        "broken pipe",
+        "unexpected eof",
+        "incomplete message",
+        "error decoding response body",
+        "error sending request",
+        "overloaded",
+        ": 429 ",
+        ": 500 ",
+        ": 502 ",
+        ": 503 ",
+        ": 504 ",
+        ": 529 ",
+    ];
+    let lower = message.to_ascii_lowercase();
+    HINTS.iter().any(|h| lower.contains(h))
+}
+
+/**
+ * \brief 续写请求的消息列表：已输出的部分作为助手消息，再追加续写提示；尚无输出时原样重发。
+ */
+pub fn continuation_messages(messages: &[Message], partial: &str) -> Vec<Message> {
+    let mut out = messages.to_vec();
+    if partial.is_empty() {
+        return out;
+    }
+    out.push(Message {
+        role: "assistant".to_string(),
+        content: partial.to_string(),
+    });
+    out.push(Message {
+        role: "user".to_string(),
+        content: CONTINUE_PROMPT.to_string(),
+    });
+    out
+}
+
+/** \brief 第 `attempt` 次重试前的等待时间。 */
+pub fn stream_retry_delay(attempt: u32) -> std::time::Duration {
+    std::time::Duration::from_secs(attempt as u64)
+}
+
+static RETRY_POLICY: Lazy<Mutex<RetryPolicy>> = Lazy::new(|| Mutex::new(RetryPolicy::default()));
+
+/** \brief 当前进程使用的重试策略。 */
+pub fn retry_policy() -> RetryPolicy {
+    *RETRY_POLICY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/** \brief 替换当前进程使用的重试策略，通常在启动时从 `db::get_retry_policy` 读取后设置。 */
+pub fn set_retry_policy(policy: RetryPolicy) {
+    *RETRY_POLICY.lock().unwrap_or_else(|e| e.into_inner()) = policy.normalized();
+}
+
+tokio::task_local! {
+    static RETRY_COUNTER: Arc<AtomicU32>;
+}
+
+/**
+ * \brief 执行 `fut` 并统计其间发生的 HTTP 重试次数，用于调试日志。
+ * \details 只统计在 `fut` 内部发生的重试；返回的流在之后被消费时不再计数。
+ */
+pub async fn count_retries<F: Future>(fut: F) -> (F::Output, u32) {
+    let counter = Arc::new(AtomicU32::new(0));
+    let out = RETRY_COUNTER.scope(counter.clone(), fut).await;
+    (out, counter.load(Ordering::Relaxed))
+}
+
+fn note_retry(label: &str, retry: u32, reason: &str, delay: Duration) {
+    let _ = RETRY_COUNTER.try_with(|c| c.fetch_add(1, Ordering::Relaxed));
+    crate::telemetry::log_event(
+        "llm.retry",
+        &format!(
+            "{} retry={} reason={} delay_ms={}",
+            label,
+            retry,
+            reason,
+            delay.as_millis()
+        ),
+    );
+}
+
+fn retryable_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/** \brief 解析 `Retry-After`：秒数或 HTTP 日期。 */
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at =
+        time::OffsetDateTime::parse(value, &time::format_description::well_known::Rfc2822).ok()?;
+    let wait = at - time::OffsetDateTime::now_utc();
+    Some(Duration::from_secs(wait.whole_seconds().max(0) as u64))
+}
+
+/**
+ * \brief 按当前重试策略发送请求：429、5xx 与连接/超时错误会等待后重发，其余结果原样返回。
+ * \details 请求体无法复制（流式上传）时只发送一次。
+ */
+async fn send_with_retry(req: reqwest::RequestBuilder, label: &str) -> Result<reqwest::Response> {
+    let policy = retry_policy();
+    let mut retry = 0;
+    loop {
+        let attempt = if retry + 1 < policy.max_attempts {
+            req.try_clone()
+        } else {
+            None
+        };
+        let Some(attempt) = attempt else {
+            return Ok(req.send().await?);
+        };
+        retry += 1;
+        let (reason, delay) = match attempt.send().await {
+            Ok(resp) if retryable_status(resp.status()) => {
+                let delay = resp
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(parse_retry_after)
+                    .map(|d| policy.cap(d))
+                    .unwrap_or_else(|| policy.backoff(retry));
+                (resp.status().as_u16().to_string(), delay)
+            }
+            Ok(resp) => return Ok(resp),
+            Err(e) if !e.is_builder() && (e.is_connect() || e.is_timeout() || e.is_request()) => {
+                ("network".to_string(), policy.backoff(retry))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        note_retry(label, retry, &reason, delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
 /**
- * \brief 以统一接口返回流式增量；对于不支持流式的 Provider，会退化为一次性结果。
+ * \brief 客户端侧停止串裁剪器：在流式增量中检测用户配置的停止串，命中后丢弃其后的内容。
+ * \details 可能构成停止串前缀的尾部会暂存到下一个增量再判断，避免把停止串的前半段先输出。
+ * 设置输出上限后，累计输出达到上限的字符数时同样截断并停止。
  */
-pub async fn stream_chat<'a>(
-    provider: &'a Provider,
-    messages: &'a [Message],
-) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + Send + 'a>>> {
-    match provider_kind(provider) {
-        ProviderKind::OpenAI | ProviderKind::OpenAIResponse => {
-            stream_openai(provider, messages).await
+#[derive(Debug, Clone, Default)]
+pub struct StopTrimmer {
+    stops: Vec<String>,
+    pending: String,
+    stopped: bool,
+    remainder: String,
+    max_chars: Option<usize>,
+    emitted_chars: usize,
+    limit_reached: bool,
+}
+
+impl StopTrimmer {
+    pub fn new(stops: &[String]) -> Self {
+        Self {
+            stops: stops.iter().filter(|s| !s.is_empty()).cloned().collect(),
+            ..Default::default()
         }
-        _ => {
-            let full = chat_once(provider, messages).await?;
-            let s = try_stream! {
-                if !full.is_empty() {
-                    yield full;
-                }
-            };
-            Ok(Box::pin(s))
+    }
+
+    /** \brief 设置输出字符数上限，None 表示不限制。 */
+    pub fn with_max_chars(mut self, max_chars: Option<usize>) -> Self {
+        self.max_chars = max_chars;
+        self
+    }
+
+    /** \brief 是否已命中停止串或输出上限；命中后调用方应停止消费上游流（即取消上游请求）。 */
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /** \brief 是否因达到输出上限而截断。 */
+    pub fn limit_reached(&self) -> bool {
+        self.limit_reached
+    }
+
+    /** \brief 截断原因：`max_output`（达到输出上限）或 `stop`（命中停止串），未截断时为 None。 */
+    pub fn truncation(&self) -> Option<&'static str> {
+        if self.limit_reached {
+            Some("max_output")
+        } else if self.stopped {
+            Some("stop")
+        } else {
+            None
+        }
+    }
+
+    /** \brief 截断时在回复元数据中记录 `truncated` 字段，供断线续传与历史查看。 */
+    pub fn annotate(&self, metadata: Option<Value>) -> Option<Value> {
+        let Some(reason) = self.truncation() else {
+            return metadata;
+        };
+        let mut metadata = metadata.unwrap_or_else(|| json!({}));
+        if let Some(map) = metadata.as_object_mut() {
+            map.insert("truncated".to_string(), json!(reason));
+        }
+        Some(metadata)
+    }
+
+    /** \brief 命中停止串后被裁掉的内容（含停止串本身）。 */
+    pub fn remainder(&self) -> &str {
+        &self.remainder
+    }
+
+    /** \brief 处理一个增量，返回可以立即输出的文本。 */
+    pub fn push(&mut self, delta: &str) -> String {
+        if self.stopped {
+            self.remainder.push_str(delta);
+            return String::new();
+        }
+        if self.stops.is_empty() {
+            return self.limit(delta.to_string());
+        }
+        self.pending.push_str(delta);
+
+        if let Some(pos) = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min()
+        {
+            self.remainder = self.pending.split_off(pos);
+            self.stopped = true;
+            let out = std::mem::take(&mut self.pending);
+            return self.limit(out);
         }
+
+        let cut = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                let tail = &self.pending[i..];
+                self.stops.iter().any(|stop| stop.starts_with(tail))
+            })
+            .unwrap_or(self.pending.len());
+        let held = self.pending.split_off(cut);
+        let out = std::mem::replace(&mut self.pending, held);
+        self.limit(out)
+    }
+
+    /** \brief 上游结束时输出暂存的尾部。 */
+    pub fn finish(&mut self) -> String {
+        let out = std::mem::take(&mut self.pending);
+        self.limit(out)
+    }
+
+    /** \brief 按输出上限截断即将输出的文本，超出部分连同暂存尾部并入被裁掉的内容。 */
+    fn limit(&mut self, mut out: String) -> String {
+        let Some(max_chars) = self.max_chars else {
+            return out;
+        };
+        let room = max_chars.saturating_sub(self.emitted_chars);
+        if let Some((cut, _)) = out.char_indices().nth(room) {
+            let mut cut_off = out.split_off(cut);
+            cut_off.push_str(&std::mem::take(&mut self.pending));
+            cut_off.push_str(&self.remainder);
+            self.remainder = cut_off;
+            self.stopped = true;
+            self.limit_reached = true;
+        }
+        self.emitted_chars += out.chars().count();
+        out
+    }
+
+    /** \brief 对完整文本（非流式结果）应用停止串，返回保留部分。 */
+    pub fn trim_full(&mut self, text: &str) -> String {
+        let mut out = self.push(text);
+        out.push_str(&self.finish());
+        out
     }
 }
 
 /**
- * \brief 非流式调用，返回完整回复。
+ * \brief 流式增量的进度信息，供前端绘制进度条与估算剩余时间。
  */
-pub async fn chat_once(provider: &Provider, messages: &[Message]) -> Result<String> {
-    match provider_kind(provider) {
-        ProviderKind::OpenAI | ProviderKind::OpenAIResponse => {
-            chat_once_openai(provider, messages).await
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ChunkProgress {
+    /** \brief 截至本增量（含）已输出的字符数。 */
+    pub cumulative_chars: usize,
+    /** \brief 增量序号，从 0 开始。 */
+    pub chunk_index: u64,
+    /** \brief 距流开始的毫秒数。 */
+    pub elapsed_ms: u64,
+}
+
+/**
+ * \brief 开启进度字段时的增量事件载荷。
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkPayload {
+    pub delta: String,
+    #[serde(flatten)]
+    pub progress: ChunkProgress,
+}
+
+/**
+ * \brief 为一次流式输出的各个增量计数，生成 `ChunkProgress`。
+ */
+#[derive(Debug, Clone)]
+pub struct ChunkCounter {
+    started: Instant,
+    chars: usize,
+    next_index: u64,
+}
+
+impl Default for ChunkCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkCounter {
+    pub fn new() -> Self {
+        Self::resumed(0)
+    }
+
+    /** \brief 断线续传时以客户端已收到的字符数为起点；序号与耗时从续传开始重新计算。 */
+    pub fn resumed(delivered_chars: usize) -> Self {
+        Self {
+            started: Instant::now(),
+            chars: delivered_chars,
+            next_index: 0,
         }
-        ProviderKind::Claude => chat_once_claude(provider, messages).await,
-        ProviderKind::Gemini => chat_once_gemini(provider, messages).await,
+    }
+
+    /** \brief 记录一个增量并返回其进度。 */
+    pub fn next(&mut self, delta: &str) -> ChunkProgress {
+        self.chars += delta.chars().count();
+        let progress = ChunkProgress {
+            cumulative_chars: self.chars,
+            chunk_index: self.next_index,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+        };
+        self.next_index += 1;
+        progress
+    }
+
+    /** \brief 记录一个增量并包装为事件载荷。 */
+    pub fn payload(&mut self, delta: String) -> ChunkPayload {
+        let progress = self.next(&delta);
+        ChunkPayload { delta, progress }
     }
 }
 
 /**
- * \brief 列出当前 Provider 可用模型列表。
+ * \brief 粗略估算文本 token 数：CJK 字符按 1 token 计，其余按每 4 个字符 1 token 计。
+ * \details 仅用于进度显示与预算估计，不保证与各 Provider 的计费口径一致。
  */
-pub async fn list_models(provider: &Provider) -> Result<Vec<String>> {
-    match provider_kind(provider) {
-        ProviderKind::OpenAI | ProviderKind::OpenAIResponse => list_models_openai(provider).await,
-        ProviderKind::Claude => list_models_claude(provider).await,
-        ProviderKind::Gemini => list_models_gemini(provider).await,
+pub fn estimate_tokens(text: &str) -> usize {
+    let mut cjk = 0usize;
+    let mut other = 0usize;
+    for ch in text.chars() {
+        if is_cjk(ch) {
+            cjk += 1;
+        } else {
+            other += 1;
+        }
+    }
+    cjk + other.div_ceil(4)
+}
+
+pub(crate) fn is_cjk(ch: char) -> bool {
+    matches!(ch as u32,
+        0x3040..=0x30FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xAC00..=0xD7AF | 0xF900..=0xFAFF)
+}
+
+/** \brief 把生成参数写入 OpenAI chat/completions 请求体。 */
+fn apply_openai_params(body: &mut Value, params: &GenerationParams) {
+    if let Some(t) = params.temperature {
+        body["temperature"] = json!(t);
+    }
+    if let Some(p) = params.top_p {
+        body["top_p"] = json!(p);
+    }
+    if let Some(n) = params.max_tokens {
+        body["max_tokens"] = json!(n);
+    }
+    if !params.stop.is_empty() {
+        body["stop"] = json!(params.stop);
     }
 }
 
 async fn stream_openai<'a>(
     provider: &'a Provider,
     messages: &'a [Message],
+    overrides: &RequestOverrides,
 ) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + Send + 'a>>> {
-    let url = format!(
-        "{}/v1/chat/completions",
-        provider.api_base.trim_end_matches('/')
-    );
-    let client = reqwest::Client::builder().build()?;
-    let body = json!({
-        "model": provider.model,
-        "messages": messages,
+    let url = format!("{}/chat/completions", resolve_api_prefix(provider).await);
+    let client = http_client(provider)?;
+    let mut body = json!({
+        "model": overrides.model_for(provider),
+        "messages": overrides.messages_for(messages),
         "stream": true
     });
+    apply_openai_params(&mut body, &generation_params(provider, overrides));
 
-    let resp = client
-        .post(url)
-        .header(CONTENT_TYPE, "application/json")
-        .header(AUTHORIZATION, format!("Bearer {}", provider.api_key))
-        .json(&body)
-        .send()
-        .await?;
+    let req = client
+        .post(&url)
+        .header(AUTHORIZATION, format!("Bearer {}", provider.api_key));
+    let resp = send_with_retry(
+        signed(req, provider, "POST", &url, Some(&body))?,
+        "stream_openai",
+    )
+    .await?;
 
     if !resp.status().is_success() {
         let status = resp.status();
@@ -103,35 +1540,25 @@ async fn stream_openai<'a>(
     }
 
     let mut stream = resp.bytes_stream();
-    let mut buf = Vec::<u8>::new();
+    let mut decoder = SseDecoder::new();
 
     let out = try_stream! {
         use futures_util::StreamExt;
         while let Some(chunk) = stream.next().await {
-            let chunk = chunk?;
-            buf.extend_from_slice(&chunk);
-            loop {
-                if let Some(pos) = find_double_newline(&buf) {
-                    let block = buf.drain(..pos + 2).collect::<Vec<u8>>();
-                    if let Some(line) = extract_data_line(&block) {
-                        if line.trim() == "[DONE]" {
-                            break;
-                        }
-                        if let Some(delta) = parse_openai_delta(&line) {
-                            yield delta;
-                        }
-                    }
-                } else {
+            decoder.push(&chunk?);
+            while let Some(data) = decoder.next_data() {
+                if data == "[DONE]" {
                     break;
                 }
+                if let Some(delta) = parse_openai_delta(&data) {
+                    yield delta;
+                }
             }
         }
-        if !buf.is_empty() {
-            if let Some(line) = extract_data_line(&buf) {
-                if line.trim() != "[DONE]" {
-                    if let Some(delta) = parse_openai_delta(&line) {
-                        yield delta;
-                    }
+        if let Some(data) = decoder.finish() {
+            if data != "[DONE]" {
+                if let Some(delta) = parse_openai_delta(&data) {
+                    yield delta;
                 }
             }
         }
@@ -140,25 +1567,28 @@ async fn stream_openai<'a>(
     Ok(Box::pin(out))
 }
 
-async fn chat_once_openai(provider: &Provider, messages: &[Message]) -> Result<String> {
-    let url = format!(
-        "{}/v1/chat/completions",
-        provider.api_base.trim_end_matches('/')
-    );
-    let client = reqwest::Client::builder().build()?;
-    let body = json!({
-        "model": provider.model,
-        "messages": messages,
+async fn chat_once_openai(
+    provider: &Provider,
+    messages: &[Message],
+    overrides: &RequestOverrides,
+) -> Result<String> {
+    let url = format!("{}/chat/completions", resolve_api_prefix(provider).await);
+    let client = http_client(provider)?;
+    let mut body = json!({
+        "model": overrides.model_for(provider),
+        "messages": overrides.messages_for(messages),
         "stream": false
     });
+    apply_openai_params(&mut body, &generation_params(provider, overrides));
 
-    let resp = client
-        .post(url)
-        .header(CONTENT_TYPE, "application/json")
-        .header(AUTHORIZATION, format!("Bearer {}", provider.api_key))
-        .json(&body)
-        .send()
-        .await?;
+    let req = client
+        .post(&url)
+        .header(AUTHORIZATION, format!("Bearer {}", provider.api_key));
+    let resp = send_with_retry(
+        signed(req, provider, "POST", &url, Some(&body))?,
+        "chat_once_openai",
+    )
+    .await?;
 
     if !resp.status().is_success() {
         let status = resp.status();
@@ -170,13 +1600,16 @@ async fn chat_once_openai(provider: &Provider, messages: &[Message]) -> Result<S
 }
 
 async fn list_models_openai(provider: &Provider) -> Result<Vec<String>> {
-    let url = format!("{}/v1/models", provider.api_base.trim_end_matches('/'));
-    let client = reqwest::Client::new();
-    let resp = client
-        .get(url)
-        .header(AUTHORIZATION, format!("Bearer {}", provider.api_key))
-        .send()
-        .await?;
+    let url = format!("{}/models", resolve_api_prefix(provider).await);
+    let client = http_client(provider)?;
+    let req = client
+        .get(&url)
+        .header(AUTHORIZATION, format!("Bearer {}", provider.api_key));
+    let resp = send_with_retry(
+        signed(req, provider, "GET", &url, None)?,
+        "list_models_openai",
+    )
+    .await?;
     if !resp.status().is_success() {
         let status = resp.status();
         let text = resp.text().await.unwrap_or_default();
@@ -185,29 +1618,101 @@ async fn list_models_openai(provider: &Provider) -> Result<Vec<String>> {
     parse_model_list(resp.json().await?)
 }
 
-async fn chat_once_claude(provider: &Provider, messages: &[Message]) -> Result<String> {
-    let url = format!("{}/v1/messages", provider.api_base.trim_end_matches('/'));
-    let client = reqwest::Client::new();
-    let (system_prompt, payload_messages) = anthropic_payload(messages);
-
+/** \brief Claude messages 请求体，流式与一次性调用共用。 */
+fn claude_body(provider: &Provider, messages: &[Message], overrides: &RequestOverrides) -> Value {
+    let (system_prompt, payload_messages) = anthropic_payload(&overrides.messages_for(messages));
+    let params = generation_params(provider, overrides);
     let mut body = json!({
-        "model": provider.model,
-        "max_tokens": 1024,
+        "model": overrides.model_for(provider),
+        "max_tokens": params.max_tokens.unwrap_or(4096),
         "messages": payload_messages,
     });
+    if let Some(t) = params.temperature {
+        body["temperature"] = json!(t);
+    }
+    if let Some(p) = params.top_p {
+        body["top_p"] = json!(p);
+    }
+    if !params.stop.is_empty() {
+        body["stop_sequences"] = json!(params.stop);
+    }
     if let Some(sys) = system_prompt {
         body["system"] = json!(sys);
     }
+    body
+}
 
+fn claude_headers(provider: &Provider) -> Result<HeaderMap> {
     let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
     headers.insert("x-api-key", HeaderValue::from_str(&provider.api_key)?);
     headers.insert(
         "anthropic-version",
         HeaderValue::from_static(ANTHROPIC_VERSION),
     );
+    Ok(headers)
+}
+
+async fn stream_claude<'a>(
+    provider: &'a Provider,
+    messages: &'a [Message],
+    overrides: &RequestOverrides,
+) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + Send + 'a>>> {
+    let url = format!("{}/messages", resolve_api_prefix(provider).await);
+    let client = http_client(provider)?;
+    let mut body = claude_body(provider, messages, overrides);
+    body["stream"] = json!(true);
+
+    let req = client.post(&url).headers(claude_headers(provider)?);
+    let resp = send_with_retry(
+        signed(req, provider, "POST", &url, Some(&body))?,
+        "stream_claude",
+    )
+    .await?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        return Err(anyhow!("claude request failed: {} -> {}", status, text));
+    }
+
+    let mut stream = resp.bytes_stream();
+    let mut decoder = SseDecoder::new();
+
+    let out = try_stream! {
+        use futures_util::StreamExt;
+        while let Some(chunk) = stream.next().await {
+            decoder.push(&chunk?);
+            while let Some(data) = decoder.next_data() {
+                if let Some(delta) = parse_anthropic_delta(&data)? {
+                    yield delta;
+                }
+            }
+        }
+        if let Some(data) = decoder.finish() {
+            if let Some(delta) = parse_anthropic_delta(&data)? {
+                yield delta;
+            }
+        }
+    };
+
+    Ok(Box::pin(out))
+}
 
-    let resp = client.post(url).headers(headers).json(&body).send().await?;
+async fn chat_once_claude(
+    provider: &Provider,
+    messages: &[Message],
+    overrides: &RequestOverrides,
+) -> Result<String> {
+    let url = format!("{}/messages", resolve_api_prefix(provider).await);
+    let client = http_client(provider)?;
+    let body = claude_body(provider, messages, overrides);
+
+    let req = client.post(&url).headers(claude_headers(provider)?);
+    let resp = send_with_retry(
+        signed(req, provider, "POST", &url, Some(&body))?,
+        "chat_once_claude",
+    )
+    .await?;
 
     if !resp.status().is_success() {
         let status = resp.status();
@@ -219,15 +1724,14 @@ async fn chat_once_claude(provider: &Provider, messages: &[Message]) -> Result<S
 }
 
 async fn list_models_claude(provider: &Provider) -> Result<Vec<String>> {
-    let url = format!("{}/v1/models", provider.api_base.trim_end_matches('/'));
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert("x-api-key", HeaderValue::from_str(&provider.api_key)?);
-    headers.insert(
-        "anthropic-version",
-        HeaderValue::from_static(ANTHROPIC_VERSION),
-    );
-    let resp = client.get(url).headers(headers).send().await?;
+    let url = format!("{}/models", resolve_api_prefix(provider).await);
+    let client = http_client(provider)?;
+    let req = client.get(&url).headers(claude_headers(provider)?);
+    let resp = send_with_retry(
+        signed(req, provider, "GET", &url, None)?,
+        "list_models_claude",
+    )
+    .await?;
     if !resp.status().is_success() {
         let status = resp.status();
         let text = resp.text().await.unwrap_or_default();
@@ -236,27 +1740,113 @@ async fn list_models_claude(provider: &Provider) -> Result<Vec<String>> {
     parse_model_list(resp.json().await?)
 }
 
-async fn chat_once_gemini(provider: &Provider, messages: &[Message]) -> Result<String> {
-    let base = normalize_gemini_base(&provider.api_base);
-    let url = format!("{}/models/{}:generateContent", base, provider.model);
-    let client = reqwest::Client::new();
-    let (system_prompt, contents) = gemini_payload(messages);
+/** \brief Gemini generateContent 请求体，流式与一次性调用共用。 */
+fn gemini_body(provider: &Provider, messages: &[Message], overrides: &RequestOverrides) -> Value {
+    let (system_prompt, contents) = gemini_payload(&overrides.messages_for(messages));
 
     let mut body = json!({
         "contents": contents,
     });
+    let params = generation_params(provider, overrides);
+    let mut config = serde_json::Map::new();
+    if let Some(t) = params.temperature {
+        config.insert("temperature".to_string(), json!(t));
+    }
+    if let Some(p) = params.top_p {
+        config.insert("topP".to_string(), json!(p));
+    }
+    if let Some(n) = params.max_tokens {
+        config.insert("maxOutputTokens".to_string(), json!(n));
+    }
+    if !params.stop.is_empty() {
+        config.insert("stopSequences".to_string(), json!(params.stop));
+    }
+    if !config.is_empty() {
+        body["generationConfig"] = Value::Object(config);
+    }
     if let Some(sys) = system_prompt {
         body["system_instruction"] = json!({
             "parts": [{"text": sys}]
         });
     }
+    body
+}
+
+async fn stream_gemini<'a>(
+    provider: &'a Provider,
+    messages: &'a [Message],
+    overrides: &RequestOverrides,
+) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + Send + 'a>>> {
+    let base = normalize_gemini_base(&provider.api_base);
+    let url = format!(
+        "{}/models/{}:streamGenerateContent",
+        base,
+        overrides.model_for(provider)
+    );
+    let client = http_client(provider)?;
+    let body = gemini_body(provider, messages, overrides);
+
+    let req = client
+        .post(&url)
+        .query(&[("alt", "sse"), ("key", provider.api_key.as_str())]);
+    let resp = send_with_retry(
+        signed(req, provider, "POST", &url, Some(&body))?,
+        "stream_gemini",
+    )
+    .await?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        return Err(anyhow!("gemini request failed: {} -> {}", status, text));
+    }
+
+    let mut stream = resp.bytes_stream();
+    // Gemini 以 CRLF 分隔事件，解码器按行去掉 \r
+    let mut decoder = SseDecoder::new();
+
+    let out = try_stream! {
+        use futures_util::StreamExt;
+        while let Some(chunk) = stream.next().await {
+            decoder.push(&chunk?);
+            while let Some(data) = decoder.next_data() {
+                if let Some(delta) = parse_gemini_delta(&data)? {
+                    yield delta;
+                }
+            }
+        }
+        if let Some(data) = decoder.finish() {
+            if let Some(delta) = parse_gemini_delta(&data)? {
+                yield delta;
+            }
+        }
+    };
 
-    let resp = client
-        .post(url)
-        .query(&[("key", provider.api_key.as_str())])
-        .json(&body)
-        .send()
-        .await?;
+    Ok(Box::pin(out))
+}
+
+async fn chat_once_gemini(
+    provider: &Provider,
+    messages: &[Message],
+    overrides: &RequestOverrides,
+) -> Result<String> {
+    let base = normalize_gemini_base(&provider.api_base);
+    let url = format!(
+        "{}/models/{}:generateContent",
+        base,
+        overrides.model_for(provider)
+    );
+    let client = http_client(provider)?;
+    let body = gemini_body(provider, messages, overrides);
+
+    let req = client
+        .post(&url)
+        .query(&[("key", provider.api_key.as_str())]);
+    let resp = send_with_retry(
+        signed(req, provider, "POST", &url, Some(&body))?,
+        "chat_once_gemini",
+    )
+    .await?;
 
     if !resp.status().is_success() {
         let status = resp.status();
@@ -270,12 +1860,15 @@ async fn chat_once_gemini(provider: &Provider, messages: &[Message]) -> Result<S
 async fn list_models_gemini(provider: &Provider) -> Result<Vec<String>> {
     let base = normalize_gemini_base(&provider.api_base);
     let url = format!("{}/models", base);
-    let client = reqwest::Client::new();
-    let resp = client
-        .get(url)
-        .query(&[("key", provider.api_key.as_str())])
-        .send()
-        .await?;
+    let client = http_client(provider)?;
+    let req = client
+        .get(&url)
+        .query(&[("key", provider.api_key.as_str())]);
+    let resp = send_with_retry(
+        signed(req, provider, "GET", &url, None)?,
+        "list_models_gemini",
+    )
+    .await?;
     if !resp.status().is_success() {
         let status = resp.status();
         let text = resp.text().await.unwrap_or_default();
@@ -284,29 +1877,83 @@ async fn list_models_gemini(provider: &Provider) -> Result<Vec<String>> {
     parse_gemini_model_list(resp.json().await?)
 }
 
-fn find_double_newline(buf: &[u8]) -> Option<usize> {
-    buf.windows(2).position(|w| w == b"\n\n")
+/** \brief OpenAI 流式分片中用到的字段，其余字段解析时直接跳过。 */
+#[derive(Deserialize)]
+struct OpenAiStreamChunk<'a> {
+    #[serde(borrow, default)]
+    choices: Vec<OpenAiStreamChoice<'a>>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiStreamChoice<'a> {
+    #[serde(borrow, default)]
+    delta: Option<OpenAiStreamDelta<'a>>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiStreamDelta<'a> {
+    #[serde(borrow, default)]
+    content: Option<Cow<'a, str>>,
+}
+
+/**
+ * \brief 解析 OpenAI 流式分片，取首个候选的文本增量；无文本或无法解析时返回 None。
+ * \details 只反序列化所需字段而不构建完整的 JSON 树，每个增量的分配次数与分片大小无关。
+ */
+pub fn parse_openai_delta(data: &str) -> Option<String> {
+    let chunk: OpenAiStreamChunk = serde_json::from_str(data).ok()?;
+    chunk
+        .choices
+        .into_iter()
+        .next()?
+        .delta?
+        .content
+        .map(Cow::into_owned)
 }
 
-fn extract_data_line(block: &[u8]) -> Option<String> {
-    let text = String::from_utf8_lossy(block);
-    for line in text.lines() {
-        let line = line.trim_start();
-        if line.starts_with("data:") {
-            return Some(line[5..].trim().to_string());
+/**
+ * \brief 解析 Claude 流式事件：`content_block_delta` 的文本增量返回 Some，`error` 事件转为错误，其余事件忽略。
+ */
+fn parse_anthropic_delta(line: &str) -> Result<Option<String>> {
+    let v: Value = match serde_json::from_str(line) {
+        Ok(v) => v,
+        Err(_) => return Ok(None),
+    };
+    match v.get("type").and_then(|t| t.as_str()) {
+        Some("content_block_delta") => Ok(v
+            .get("delta")
+            .filter(|d| d.get("type").and_then(|t| t.as_str()) == Some("text_delta"))
+            .and_then(|d| d.get("text"))
+            .and_then(|t| t.as_str())
+            .map(|t| t.to_string())),
+        Some("error") => {
+            let message = v
+                .pointer("/error/message")
+                .and_then(|m| m.as_str())
+                .unwrap_or("unknown error");
+            Err(anyhow!("claude stream error: {}", message))
         }
+        _ => Ok(None),
     }
-    None
 }
 
-fn parse_openai_delta(line: &str) -> Option<String> {
-    let v: Value = serde_json::from_str(line).ok()?;
-    v.get("choices")?
-        .get(0)?
-        .get("delta")?
-        .get("content")?
-        .as_str()
-        .map(|s| s.to_string())
+/**
+ * \brief 解析 Gemini 流式分片：取首个候选的文本部分，空分片返回 None，`error` 字段转为错误。
+ */
+fn parse_gemini_delta(line: &str) -> Result<Option<String>> {
+    let v: Value = match serde_json::from_str(line) {
+        Ok(v) => v,
+        Err(_) => return Ok(None),
+    };
+    if let Some(err) = v.get("error") {
+        let message = err
+            .get("message")
+            .and_then(|m| m.as_str())
+            .unwrap_or("unknown error");
+        return Err(anyhow!("gemini stream error: {}", message));
+    }
+    let text = extract_gemini_content(&v);
+    Ok((!text.is_empty()).then_some(text))
 }
 
 fn extract_openai_content(v: &Value) -> String {
@@ -425,7 +2072,8 @@ fn parse_model_list(v: Value) -> Result<Vec<String>> {
 }
 
 fn normalize_gemini_base(api_base: &str) -> String {
-    let trimmed = api_base.trim_end_matches('/');
+    let cleaned = clean_base(api_base);
+    let trimmed = cleaned.as_str();
     if trimmed.ends_with("/v1")
         || trimmed.ends_with("/v1beta")
         || trimmed.contains("/v1/")
@@ -452,3 +2100,618 @@ fn parse_gemini_model_list(v: Value) -> Result<Vec<String>> {
         Err(anyhow!("unexpected gemini models payload: {}", v))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_trimmer_across_chunks() {
+        let mut t = StopTrimmer::new(&["</end>".to_string()]);
+        let mut out = String::new();
+        for delta in ["Hello </", "e", "nd> ignored", " more"] {
+            out.push_str(&t.push(delta));
+        }
+        out.push_str(&t.finish());
+        assert_eq!(out, "Hello ");
+        assert!(t.stopped());
+        assert_eq!(t.remainder(), "</end> ignored more");
+
+        let mut partial = StopTrimmer::new(&["STOP".to_string()]);
+        assert_eq!(partial.push("abc ST"), "abc ");
+        assert_eq!(partial.push("AR"), "STAR");
+        assert_eq!(partial.finish(), "");
+        assert!(!partial.stopped());
+
+        let mut none = StopTrimmer::new(&[]);
+        assert_eq!(none.trim_full("anything"), "anything");
+    }
+
+    #[test]
+    fn test_stop_trimmer_max_chars() {
+        let mut t = StopTrimmer::new(&[]).with_max_chars(Some(5));
+        assert_eq!(t.push("你好"), "你好");
+        assert!(!t.stopped());
+        assert_eq!(t.push("，世界！"), "，世界");
+        assert!(t.stopped() && t.limit_reached());
+        assert_eq!(t.push("more"), "");
+        assert_eq!(t.remainder(), "！more");
+
+        let mut both = StopTrimmer::new(&["END".to_string()]).with_max_chars(Some(4));
+        assert_eq!(both.push("ab E"), "ab ");
+        assert_eq!(both.push("xyz END"), "E");
+        assert!(both.limit_reached());
+        assert_eq!(both.remainder(), "xyz END");
+
+        let mut exact = StopTrimmer::new(&[]).with_max_chars(Some(3));
+        assert_eq!(exact.trim_full("abc"), "abc");
+        assert_eq!(exact.truncation(), None);
+        assert_eq!(both.truncation(), Some("max_output"));
+        assert_eq!(
+            both.annotate(Some(json!({ "overrides": {} }))),
+            Some(json!({ "overrides": {}, "truncated": "max_output" }))
+        );
+    }
+
+    #[test]
+    fn test_chunk_counter_payload() {
+        let mut counter = ChunkCounter::new();
+        let first = counter.next("你好");
+        assert_eq!((first.cumulative_chars, first.chunk_index), (2, 0));
+        let payload = serde_json::to_value(counter.payload(", world".to_string())).unwrap();
+        assert_eq!(payload["delta"], ", world");
+        assert_eq!(payload["cumulative_chars"], 9);
+        assert_eq!(payload["chunk_index"], 1);
+        assert!(payload["elapsed_ms"].is_u64());
+
+        let mut resumed = ChunkCounter::resumed(9);
+        assert_eq!(resumed.next("!").cumulative_chars, 10);
+    }
+
+    #[test]
+    fn test_detect_capabilities_by_kind_and_probe() {
+        let provider = |provider_type: &str, model: &str| Provider {
+            id: 1,
+            name: "p".to_string(),
+            api_base: String::new(),
+            api_key: String::new(),
+            model: model.to_string(),
+            provider_type: provider_type.to_string(),
+            secret_alias: None,
+            api_prefix: None,
+            signing: None,
+            generation: Default::default(),
+            proxy_url: None,
+            api_key_unreadable: false,
+        };
+
+        let claude = detect_capabilities(&provider("claude", "claude-3-5-sonnet-latest"), None);
+        assert!(!claude.streaming);
+        assert!(claude.vision);
+        assert!(!claude.embeddings);
+        assert!(!claude.tts);
+
+        let local = provider("openai", "llama3");
+        let guessed = detect_capabilities(&local, None);
+        assert!(guessed.streaming);
+        assert!(!guessed.vision);
+        assert!(guessed.embeddings);
+
+        let probed = detect_capabilities(&local, Some(&["nomic-embed-text".to_string()]));
+        assert!(probed.embeddings);
+        assert!(!probed.tts);
+        assert!(detect_capabilities(&provider("openai", "gpt-4o-mini"), None).vision);
+    }
+
+    #[test]
+    fn test_parse_openai_delta_chunks() {
+        let chunk =
+            r#"{"id":"c1","choices":[{"index":0,"delta":{"role":"assistant","content":"Hel"}}]}"#;
+        assert_eq!(parse_openai_delta(chunk).as_deref(), Some("Hel"));
+        let escaped = r#"{"choices":[{"delta":{"content":"a\n\"b\" 你"}}]}"#;
+        assert_eq!(parse_openai_delta(escaped).as_deref(), Some("a\n\"b\" 你"));
+        assert!(parse_openai_delta(r#"{"choices":[{"delta":{"content":null}}]}"#).is_none());
+        assert!(parse_openai_delta(r#"{"choices":[]}"#).is_none());
+        assert!(parse_openai_delta(r#"{"choices":[{"finish_reason":"stop"}]}"#).is_none());
+        assert!(parse_openai_delta("not json").is_none());
+    }
+
+    #[test]
+    fn test_parse_anthropic_delta_events() {
+        let delta = r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hel"}}"#;
+        assert_eq!(
+            parse_anthropic_delta(delta).unwrap().as_deref(),
+            Some("Hel")
+        );
+        let json_delta = r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{"}}"#;
+        assert!(parse_anthropic_delta(json_delta).unwrap().is_none());
+        assert!(parse_anthropic_delta(r#"{"type":"message_stop"}"#)
+            .unwrap()
+            .is_none());
+        assert!(parse_anthropic_delta(r#"{"type":"ping"}"#)
+            .unwrap()
+            .is_none());
+        let err = parse_anthropic_delta(
+            r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Overloaded"));
+    }
+
+    #[test]
+    fn test_parse_gemini_delta_chunks() {
+        let chunk = r#"{"candidates":[{"content":{"parts":[{"text":"你"},{"text":"好"}],"role":"model"},"index":0}]}"#;
+        assert_eq!(parse_gemini_delta(chunk).unwrap().as_deref(), Some("你好"));
+        let tail = r#"{"candidates":[{"content":{"parts":[{"text":""}]},"finishReason":"STOP"}],"usageMetadata":{}}"#;
+        assert!(parse_gemini_delta(tail).unwrap().is_none());
+        let err = parse_gemini_delta(r#"{"error":{"code":429,"message":"Resource exhausted"}}"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("Resource exhausted"));
+    }
+
+    #[test]
+    fn test_health_helpers_match_models_and_auth_errors() {
+        let models = vec!["models/gemini-1.5-pro".to_string(), "gpt-4o".to_string()];
+        assert!(model_listed(&models, "gemini-1.5-pro"));
+        assert!(model_listed(&models, "gpt-4o"));
+        assert!(!model_listed(&models, "gpt-4o-mini"));
+        assert!(is_auth_failure(
+            "list models failed: 401 Unauthorized -> {\"error\":\"bad key\"}"
+        ));
+        assert!(!is_auth_failure("list models failed: 404 Not Found -> "));
+    }
+
+    #[test]
+    fn test_model_deprecation_matches_registry_by_kind() {
+        let provider = |provider_type: &str| Provider {
+            id: 1,
+            name: "p".to_string(),
+            api_base: String::new(),
+            api_key: String::new(),
+            model: String::new(),
+            provider_type: provider_type.to_string(),
+            secret_alias: None,
+            api_prefix: None,
+            signing: None,
+            generation: Default::default(),
+            proxy_url: None,
+            api_key_unreadable: false,
+        };
+        let hit = model_deprecation(&provider("openai-response"), " GPT-4-32k ").expect("hit");
+        assert_eq!(hit.model, "GPT-4-32k");
+        assert_eq!(hit.replacement, "gpt-4o");
+        assert_eq!(
+            model_deprecation(&provider("gemini"), "models/gemini-pro").map(|d| d.replacement),
+            Some("gemini-1.5-pro".to_string())
+        );
+        assert!(model_deprecation(&provider("claude"), "gpt-4-32k").is_none());
+        assert!(model_deprecation(&provider("openai"), "gpt-4o").is_none());
+    }
+
+    #[test]
+    fn test_generation_params_merge_kind_defaults() {
+        let provider = |provider_type: &str, model: &str| Provider {
+            id: 1,
+            name: "p".to_string(),
+            api_base: String::new(),
+            api_key: String::new(),
+            model: model.to_string(),
+            provider_type: provider_type.to_string(),
+            secret_alias: None,
+            api_prefix: None,
+            signing: None,
+            generation: Default::default(),
+            proxy_url: None,
+            api_key_unreadable: false,
+        };
+        let none = RequestOverrides::default();
+        let sonnet = provider("claude", "claude-3-5-sonnet-latest");
+        assert_eq!(generation_params(&sonnet, &none).max_tokens, Some(8192));
+        let claude = provider("claude", "claude-3-haiku-20240307");
+        assert_eq!(generation_params(&claude, &none).max_tokens, Some(4096));
+
+        let hot = RequestOverrides {
+            temperature: Some(0.9),
+            model: Some("claude-opus-4-1".to_string()),
+            ..Default::default()
+        };
+        let params = generation_params(&claude, &hot);
+        assert_eq!(params.temperature, Some(0.9));
+        assert_eq!(params.max_tokens, Some(8192));
+        assert_eq!(
+            generation_params(&provider("openai", "gpt-4o"), &none),
+            GenerationParams::default()
+        );
+        let capped = RequestOverrides {
+            max_tokens: Some(256),
+            ..Default::default()
+        };
+        assert_eq!(generation_params(&sonnet, &capped).max_tokens, Some(256));
+    }
+
+    #[test]
+    fn test_provider_generation_settings_reach_request_bodies() {
+        let mut claude = Provider {
+            id: 1,
+            name: "p".to_string(),
+            api_base: String::new(),
+            api_key: String::new(),
+            model: "claude-3-5-sonnet-latest".to_string(),
+            provider_type: "claude".to_string(),
+            secret_alias: None,
+            api_prefix: None,
+            signing: None,
+            generation: crate::models::GenerationSettings {
+                temperature: Some(0.3),
+                top_p: Some(0.9),
+                max_tokens: Some(2048),
+                stop: vec!["END".to_string()],
+            },
+            proxy_url: None,
+            api_key_unreadable: false,
+        };
+        let none = RequestOverrides::default();
+        let body = claude_body(&claude, &[], &none);
+        assert_eq!(body["max_tokens"], json!(2048));
+        assert_eq!(body["temperature"], json!(0.3));
+        assert_eq!(body["top_p"], json!(0.9));
+        assert_eq!(body["stop_sequences"], json!(["END"]));
+
+        let hot = RequestOverrides {
+            temperature: Some(1.1),
+            max_tokens: Some(64),
+            ..Default::default()
+        };
+        let body = claude_body(&claude, &[], &hot);
+        assert_eq!(body["temperature"], json!(1.1));
+        assert_eq!(body["max_tokens"], json!(64));
+
+        claude.provider_type = "gemini".to_string();
+        let config = &gemini_body(&claude, &[], &none)["generationConfig"];
+        assert_eq!(config["topP"], json!(0.9));
+        assert_eq!(config["maxOutputTokens"], json!(2048));
+        assert_eq!(config["stopSequences"], json!(["END"]));
+
+        let mut body = json!({});
+        apply_openai_params(&mut body, &generation_params(&claude, &none));
+        assert_eq!(body["stop"], json!(["END"]));
+        assert_eq!(body["top_p"], json!(0.9));
+    }
+
+    #[test]
+    fn test_stream_retry_helpers() {
+        assert!(is_retryable_error(
+            "request failed: 503 Service Unavailable -> upstream"
+        ));
+        assert!(is_retryable_error("error decoding response body"));
+        assert!(is_retryable_error(
+            "claude stream error: overloaded_error: Overloaded"
+        ));
+        assert!(!is_retryable_error(
+            "request failed: 401 Unauthorized -> bad key"
+        ));
+        assert!(!is_retryable_error(
+            "request failed: 400 Bad Request -> context too long"
+        ));
+
+        let history = vec![Message {
+            role: "user".to_string(),
+            content: "hi".to_string(),
+        }];
+        assert_eq!(continuation_messages(&history, "").len(), 1);
+        let resumed = continuation_messages(&history, "Hello, wor");
+        assert_eq!(resumed.len(), 3);
+        assert_eq!(resumed[1].role, "assistant");
+        assert_eq!(resumed[1].content, "Hello, wor");
+        assert_eq!(resumed[2].role, "user");
+    }
+
+    #[test]
+    fn test_parse_embeddings_responses() {
+        let openai = json!({"data": [
+            {"index": 1, "embedding": [0.5, 0.25]},
+            {"index": 0, "embedding": [1.0, 0.0]}
+        ]});
+        assert_eq!(
+            parse_openai_embeddings(&openai).unwrap(),
+            vec![vec![1.0, 0.0], vec![0.5, 0.25]]
+        );
+        let gemini = json!({"embeddings": [{"values": [0.1, 0.2]}]});
+        assert_eq!(
+            parse_gemini_embeddings(&gemini).unwrap(),
+            vec![vec![0.1, 0.2]]
+        );
+        assert!(parse_openai_embeddings(&json!({"data": [{"embedding": "x"}]})).is_err());
+    }
+
+    #[test]
+    fn test_cancel_aborts_pending_request() {
+        // 只接受连接、从不响应的上游：请求会一直停在等待响应头阶段
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        std::thread::spawn(move || {
+            let _held: Vec<_> = listener.incoming().take(2).collect();
+            std::thread::sleep(Duration::from_secs(30));
+        });
+        let provider = Provider {
+            id: 1,
+            name: "hang".to_string(),
+            api_base: format!("http://{}", addr),
+            api_key: "sk".to_string(),
+            model: "m".to_string(),
+            provider_type: "openai".to_string(),
+            secret_alias: None,
+            api_prefix: None,
+            signing: None,
+            generation: Default::default(),
+            proxy_url: None,
+            api_key_unreadable: false,
+        };
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: "hi".to_string(),
+        }];
+        let rt = tokio::runtime::Runtime::new().expect("runtime");
+        rt.block_on(async {
+            let cancel = CancellationToken::new();
+            let trigger = cancel.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                trigger.cancel();
+            });
+            let started = std::time::Instant::now();
+            let err = match stream_chat_cancellable(
+                &provider,
+                &messages,
+                &RequestOverrides::default(),
+                &cancel,
+            )
+            .await
+            {
+                Ok(_) => panic!("stream should be cancelled"),
+                Err(e) => e,
+            };
+            assert!(err.is::<Cancelled>());
+            assert!(started.elapsed() < Duration::from_secs(5));
+
+            let err =
+                chat_once_cancellable(&provider, &messages, &RequestOverrides::default(), &cancel)
+                    .await
+                    .expect_err("already cancelled");
+            assert!(err.is::<Cancelled>());
+        });
+    }
+
+    #[test]
+    fn test_retry_policy_backoff_and_retry_after() {
+        let policy = RetryPolicy {
+            max_attempts: 50,
+            base_delay_ms: 500,
+            max_delay_ms: 3_000,
+        }
+        .normalized();
+        assert_eq!(policy.max_attempts, 10);
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(2), Duration::from_millis(1_000));
+        assert_eq!(policy.backoff(5), Duration::from_millis(3_000));
+        assert_eq!(parse_retry_after(" 7 "), Some(Duration::from_secs(7)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn test_send_retries_after_429() {
+        use std::io::{Read, Write};
+        // 第一次返回 429 + Retry-After，第二次返回模型列表
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        std::thread::spawn(move || {
+            let responses = [
+                "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                {
+                    let body = r#"{"data":[{"id":"m"}]}"#;
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                },
+            ];
+            for (stream, response) in listener.incoming().zip(responses) {
+                let mut stream = stream.expect("accept");
+                let mut buf = [0u8; 4096];
+                let mut seen = Vec::new();
+                while !seen.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).expect("read");
+                    if n == 0 {
+                        break;
+                    }
+                    seen.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(response.as_bytes()).expect("write");
+            }
+        });
+        let provider = Provider {
+            id: 0,
+            name: "limited".to_string(),
+            api_base: format!("http://{}", addr),
+            api_key: "sk".to_string(),
+            model: "m".to_string(),
+            provider_type: "openai".to_string(),
+            secret_alias: None,
+            api_prefix: Some(format!("http://{}/v1", addr)),
+            signing: None,
+            generation: Default::default(),
+            proxy_url: None,
+            api_key_unreadable: false,
+        };
+        let rt = tokio::runtime::Runtime::new().expect("runtime");
+        let (models, retries) = rt.block_on(count_retries(list_models(&provider)));
+        assert_eq!(models.expect("models"), vec!["m".to_string()]);
+        assert_eq!(retries, 1);
+    }
+
+    #[test]
+    fn test_requests_go_through_provider_proxy() {
+        use std::io::{Read, Write};
+        assert!(parse_proxy_url("socks5h://user:pw@127.0.0.1:1080").is_ok());
+        assert!(parse_proxy_url("http://proxy.corp:3128").is_ok());
+        assert!(parse_proxy_url("ftp://proxy.corp").is_err());
+        assert!(parse_proxy_url("proxy.corp:3128").is_err());
+
+        // 充当 HTTP 代理：记录请求行并返回模型列表，上游地址本身不可达
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("accept");
+            let mut buf = [0u8; 4096];
+            let mut seen = Vec::new();
+            while !seen.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).expect("read");
+                if n == 0 {
+                    break;
+                }
+                seen.extend_from_slice(&buf[..n]);
+            }
+            let body = r#"{"data":[{"id":"m"}]}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).expect("write");
+            String::from_utf8_lossy(&seen)
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        });
+        let provider = Provider {
+            id: 0,
+            name: "proxied".to_string(),
+            api_base: "http://api.example.invalid".to_string(),
+            api_key: "sk".to_string(),
+            model: "m".to_string(),
+            provider_type: "openai".to_string(),
+            secret_alias: None,
+            api_prefix: Some("http://api.example.invalid/v1".to_string()),
+            signing: None,
+            generation: Default::default(),
+            proxy_url: Some(format!("http://{}", addr)),
+            api_key_unreadable: false,
+        };
+        let rt = tokio::runtime::Runtime::new().expect("runtime");
+        let models = rt.block_on(list_models(&provider)).expect("models");
+        assert_eq!(models, vec!["m".to_string()]);
+        assert_eq!(
+            handle.join().expect("proxy thread"),
+            "GET http://api.example.invalid/v1/models HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn test_sign_request_matches_reference_hmac() {
+        let sig = sign_request("key", "post", "/v1/chat/completions", b"{}", 1_700_000_000);
+        assert_eq!(
+            sig,
+            "a3a188b50eec1c99c34baa4f23518961273041200af08994aca70c002f0820eb"
+        );
+        assert_ne!(
+            sig,
+            sign_request("key", "POST", "/v1/chat/completions", b"{ }", 1_700_000_000)
+        );
+        assert_ne!(
+            sig,
+            sign_request(
+                "other",
+                "POST",
+                "/v1/chat/completions",
+                b"{}",
+                1_700_000_000
+            )
+        );
+    }
+
+    #[test]
+    fn test_api_prefix_candidates_prefer_existing_version() {
+        assert_eq!(
+            api_prefix_candidates("https://api.openai.com/"),
+            vec!["https://api.openai.com/v1", "https://api.openai.com"]
+        );
+        assert_eq!(
+            api_prefix_candidates("https://api.openai.com/v1"),
+            vec!["https://api.openai.com/v1", "https://api.openai.com/v1/v1"]
+        );
+        assert_eq!(
+            api_prefix_candidates("http://localhost:8080/openai/v2")[0],
+            "http://localhost:8080/openai/v2"
+        );
+        assert_eq!(
+            api_prefix_candidates("api.openai.com/v1/chat/completions")[0],
+            "https://api.openai.com/v1"
+        );
+    }
+
+    #[test]
+    fn test_request_overrides_replace_system_and_model() {
+        let provider = Provider {
+            id: 1,
+            name: "p".to_string(),
+            api_base: String::new(),
+            api_key: String::new(),
+            model: "base-model".to_string(),
+            provider_type: "openai".to_string(),
+            secret_alias: None,
+            api_prefix: None,
+            signing: None,
+            generation: Default::default(),
+            proxy_url: None,
+            api_key_unreadable: false,
+        };
+        let messages = vec![
+            Message {
+                role: "system".to_string(),
+                content: "old".to_string(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: "hi".to_string(),
+            },
+        ];
+        let empty = RequestOverrides {
+            model: Some("  ".to_string()),
+            ..Default::default()
+        }
+        .normalized();
+        assert!(empty.is_empty());
+        assert_eq!(empty.model_for(&provider), "base-model");
+        assert!(empty.to_metadata().is_none());
+
+        let o = RequestOverrides {
+            model: Some("other".to_string()),
+            temperature: Some(0.2),
+            max_tokens: None,
+            system_instruction: Some("new".to_string()),
+        };
+        assert_eq!(o.model_for(&provider), "other");
+        let applied = o.messages_for(&messages);
+        assert_eq!(applied.len(), 2);
+        assert_eq!(applied[0].content, "new");
+        assert_eq!(
+            o.to_metadata().unwrap()["overrides"]["temperature"],
+            json!(0.2)
+        );
+    }
+
+    #[test]
+    fn test_estimate_tokens_mixes_cjk_and_ascii() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("abcde"), 2);
+        assert_eq!(estimate_tokens("你好"), 2);
+        assert_eq!(estimate_tokens("你好 abc"), 3);
+    }
+}
@@ -0,0 +1,272 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::models::QuotedMessage;
+
+/**
+ * \brief 回复中的围栏代码块。
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CodeBlock {
+    /** \brief 围栏上声明的语言（小写），未声明时为空。 */
+    pub lang: Option<String>,
+    pub content: String,
+}
+
+/**
+ * \brief 回复内容特征，客户端据此按条加载代码高亮、公式与图表渲染器。
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ContentFeatures {
+    /** \brief 代码块数量（不含 math/mermaid 块）。 */
+    pub code_blocks: usize,
+    /** \brief 代码块语言，去重并保持出现顺序。 */
+    pub languages: Vec<String>,
+    /** \brief 含 LaTeX 公式（`$...$`、`$$...$$`、`\(...\)`、`\[...\]` 或 math 代码块）。 */
+    pub math: bool,
+    /** \brief 含 mermaid 图表代码块。 */
+    pub mermaid: bool,
+}
+
+impl ContentFeatures {
+    pub fn is_empty(&self) -> bool {
+        self.code_blocks == 0 && !self.math && !self.mermaid
+    }
+}
+
+/**
+ * \brief 提取全部围栏代码块（``` 或 ~~~），未闭合的围栏视为延续到文末。
+ */
+pub fn extract_code_blocks(text: &str) -> Vec<CodeBlock> {
+    split_fences(text).0
+}
+
+/**
+ * \brief 检测回复中的代码块、公式与 mermaid 图表。
+ */
+pub fn detect_features(text: &str) -> ContentFeatures {
+    let (blocks, prose) = split_fences(text);
+    let mut features = ContentFeatures::default();
+    for block in blocks {
+        match block.lang.as_deref() {
+            Some("mermaid") => features.mermaid = true,
+            Some("math" | "latex" | "tex" | "katex") => features.math = true,
+            lang => {
+                features.code_blocks += 1;
+                if let Some(lang) = lang {
+                    if !features.languages.iter().any(|l| l == lang) {
+                        features.languages.push(lang.to_string());
+                    }
+                }
+            }
+        }
+    }
+    features.math = features.math || has_math(&prose);
+    features
+}
+
+/**
+ * \brief 将内容特征合并进消息元数据的 `content` 字段；没有任何特征时原样返回。
+ */
+pub fn merge_into_metadata(metadata: Option<&Value>, content: &str) -> Option<Value> {
+    let features = detect_features(content);
+    if features.is_empty() {
+        return metadata.cloned();
+    }
+    let mut merged = match metadata {
+        Some(Value::Object(map)) => map.clone(),
+        _ => serde_json::Map::new(),
+    };
+    merged.insert(
+        "content".to_string(),
+        serde_json::to_value(features).unwrap_or_default(),
+    );
+    Some(Value::Object(merged))
+}
+
+/**
+ * \brief 把引用的消息以 Markdown 引用块放在提示之前，作为发送给模型的显式上下文。
+ */
+pub fn quote_prompt(quotes: &[QuotedMessage], prompt: &str) -> String {
+    let mut out = String::new();
+    for quote in quotes {
+        out.push_str(&format!("> [#{} {}]\n", quote.message_id, quote.role));
+        for line in quote.content.lines() {
+            out.push_str("> ");
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    out.push_str(prompt);
+    out
+}
+
+/**
+ * \brief 按消息元数据中的 `quotes` 展开引用；没有引用时原样返回。
+ */
+pub fn expand_quotes(content: &str, metadata: Option<&Value>) -> String {
+    let quotes: Vec<QuotedMessage> = metadata
+        .and_then(|m| m.get("quotes"))
+        .and_then(|q| serde_json::from_value(q.clone()).ok())
+        .unwrap_or_default();
+    if quotes.is_empty() {
+        content.to_string()
+    } else {
+        quote_prompt(&quotes, content)
+    }
+}
+
+/** \brief 拆分出代码块与围栏之外的正文（正文中的行内代码已去除）。 */
+fn split_fences(text: &str) -> (Vec<CodeBlock>, String) {
+    let mut blocks = Vec::new();
+    let mut prose = String::new();
+    let mut open: Option<(char, usize, Option<String>, Vec<&str>)> = None;
+
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        let fence = fence_marker(trimmed);
+        match open.as_mut() {
+            Some((ch, len, _, body)) => {
+                let closes = fence.is_some_and(|(c, l)| {
+                    c == *ch && l >= *len && trimmed[l * c.len_utf8()..].trim().is_empty()
+                });
+                if closes {
+                    let (_, _, lang, body) = open.take().unwrap_or_default();
+                    blocks.push(CodeBlock {
+                        lang,
+                        content: body.join("\n"),
+                    });
+                } else {
+                    body.push(line);
+                }
+            }
+            None => match fence {
+                Some((ch, len)) => {
+                    let lang = trimmed[len * ch.len_utf8()..]
+                        .split_whitespace()
+                        .next()
+                        .map(|l| {
+                            l.trim_matches(|c| c == '{' || c == '}')
+                                .to_ascii_lowercase()
+                        })
+                        .filter(|l| !l.is_empty());
+                    open = Some((ch, len, lang, Vec::new()));
+                }
+                None => {
+                    prose.push_str(&strip_inline_code(line));
+                    prose.push('\n');
+                }
+            },
+        }
+    }
+    if let Some((_, _, lang, body)) = open {
+        blocks.push(CodeBlock {
+            lang,
+            content: body.join("\n"),
+        });
+    }
+    (blocks, prose)
+}
+
+fn fence_marker(line: &str) -> Option<(char, usize)> {
+    let ch = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.chars().take_while(|c| *c == ch).count();
+    (len >= 3).then_some((ch, len))
+}
+
+fn strip_inline_code(line: &str) -> String {
+    let mut out = String::new();
+    let mut in_code = false;
+    for c in line.chars() {
+        if c == '`' {
+            in_code = !in_code;
+        } else if !in_code {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/**
+ * \brief 判断正文是否含公式；行内 `$...$` 采用 pandoc 规则：开头 `$` 后、结尾 `$` 前不能是空白，
+ * 结尾 `$` 后不能紧跟数字，从而排除 “$5 and $10” 这类金额。
+ */
+fn has_math(prose: &str) -> bool {
+    if prose.contains("$$") || (prose.contains("\\[") && prose.contains("\\]")) {
+        return true;
+    }
+    if prose.contains("\\(") && prose.contains("\\)") {
+        return true;
+    }
+    let chars: Vec<char> = prose.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '$' && (i == 0 || chars[i - 1] != '\\') {
+            let starts_ok = chars.get(i + 1).is_some_and(|c| !c.is_whitespace());
+            if starts_ok {
+                let mut j = i + 1;
+                while j < chars.len() && chars[j] != '\n' {
+                    if chars[j] == '$' && chars[j - 1] != '\\' {
+                        let ends_ok = !chars[j - 1].is_whitespace()
+                            && !chars.get(j + 1).is_some_and(|c| c.is_ascii_digit());
+                        if ends_ok {
+                            return true;
+                        }
+                        break;
+                    }
+                    j += 1;
+                }
+            }
+        }
+        i += 1;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_features_code_math_and_mermaid() {
+        let reply = "Energy is $E = mc^2$.\n\n```Rust\nfn main() {}\n```\n\n~~~mermaid\ngraph TD; A-->B\n~~~\n\n```\nplain\n```";
+        let features = detect_features(reply);
+        assert_eq!(features.code_blocks, 2);
+        assert_eq!(features.languages, vec!["rust".to_string()]);
+        assert!(features.math);
+        assert!(features.mermaid);
+        assert_eq!(extract_code_blocks(reply)[0].content, "fn main() {}");
+
+        let prices = detect_features("It costs $5 and $10, see `$HOME/bin` and `a$b$`.");
+        assert!(prices.is_empty());
+        assert!(detect_features("$$\\int_0^1 x\\,dx$$").math);
+        assert!(!detect_features("```sh\necho $PATH $x$\n```").math);
+    }
+
+    #[test]
+    fn test_merge_into_metadata_keeps_existing_keys() {
+        let base = serde_json::json!({"overrides": {"model": "m"}});
+        let merged = merge_into_metadata(Some(&base), "```mermaid\ngraph\n```").expect("merged");
+        assert_eq!(merged["overrides"]["model"], "m");
+        assert_eq!(merged["content"]["mermaid"], true);
+        assert_eq!(merge_into_metadata(Some(&base), "plain text"), Some(base));
+        assert_eq!(merge_into_metadata(None, "plain"), None);
+    }
+
+    #[test]
+    fn test_expand_quotes_prefixes_blockquotes() {
+        let metadata = serde_json::json!({"quotes": [
+            {"message_id": 3, "role": "assistant", "content": "line one\nline two"}
+        ]});
+        assert_eq!(
+            expand_quotes("why?", Some(&metadata)),
+            "> [#3 assistant]\n> line one\n> line two\n\nwhy?"
+        );
+        assert_eq!(expand_quotes("plain", None), "plain");
+        assert_eq!(
+            expand_quotes("plain", Some(&serde_json::json!({"content": {}}))),
+            "plain"
+        );
+    }
+}
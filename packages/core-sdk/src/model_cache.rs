@@ -0,0 +1,228 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{db, llm, models::Provider, telemetry};
+
+/** \brief 模型列表缓存有效期。 */
+pub const MODEL_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/** \brief 预热时同时请求的 Provider 数，避免启动瞬间并发打满上游配额。 */
+const WARMUP_CONCURRENCY: usize = 3;
+
+/** \brief 除默认 Provider 外，预热最近使用的 Provider 个数。 */
+pub const WARMUP_RECENT: usize = 4;
+
+struct CachedModels {
+    fingerprint: u64,
+    models: Vec<String>,
+    fetched_at: Instant,
+}
+
+static CACHE: Lazy<Mutex<HashMap<i64, CachedModels>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/**
+ * \brief 影响模型列表结果的连接参数摘要；Provider 改了地址或密钥后缓存自动失效。
+ */
+fn fingerprint(provider: &Provider) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    provider.provider_type.hash(&mut hasher);
+    provider.api_base.hash(&mut hasher);
+    provider.api_key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/**
+ * \brief 缓存中未过期的模型列表。
+ */
+pub fn cached_models(provider: &Provider) -> Option<Vec<String>> {
+    let cache = CACHE.lock().ok()?;
+    cache
+        .get(&provider.id)
+        .filter(|c| {
+            c.fingerprint == fingerprint(provider) && c.fetched_at.elapsed() < MODEL_CACHE_TTL
+        })
+        .map(|c| c.models.clone())
+}
+
+/**
+ * \brief 拉取模型列表并写入缓存。
+ */
+pub async fn refresh_models(provider: &Provider) -> Result<Vec<String>> {
+    let models = llm::list_models(provider).await?;
+    if let Ok(mut cache) = CACHE.lock() {
+        cache.insert(
+            provider.id,
+            CachedModels {
+                fingerprint: fingerprint(provider),
+                models: models.clone(),
+                fetched_at: Instant::now(),
+            },
+        );
+    }
+    Ok(models)
+}
+
+/**
+ * \brief 优先返回缓存的模型列表，缺失或过期时重新拉取。
+ */
+pub async fn list_models_cached(provider: &Provider) -> Result<Vec<String>> {
+    match cached_models(provider) {
+        Some(models) => Ok(models),
+        None => refresh_models(provider).await,
+    }
+}
+
+/**
+ * \brief 启动预热进度，随就绪探针返回。
+ */
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WarmupStatus {
+    pub running: bool,
+    pub total: usize,
+    pub warmed: usize,
+    pub failed: usize,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+}
+
+static WARMUP: Lazy<Mutex<WarmupStatus>> = Lazy::new(|| Mutex::new(WarmupStatus::default()));
+
+fn update(f: impl FnOnce(&mut WarmupStatus)) {
+    if let Ok(mut guard) = WARMUP.lock() {
+        f(&mut guard);
+    }
+}
+
+/**
+ * \brief 当前（或最近一次）预热进度。
+ */
+pub fn warmup_status() -> WarmupStatus {
+    WARMUP.lock().map(|g| g.clone()).unwrap_or_default()
+}
+
+/**
+ * \brief 预热目标：默认 Provider 加最近使用的若干 Provider（按 ID 去重，保持优先顺序）。
+ */
+pub fn warmup_targets(conn: &rusqlite::Connection) -> Result<Vec<i64>> {
+    let mut ids = Vec::new();
+    if let Some(id) = db::get_default_provider_id(conn)? {
+        ids.push(id);
+    }
+    for id in db::recent_provider_ids(conn, WARMUP_RECENT)? {
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+/**
+ * \brief 在后台并发预热给定 Provider 的模型列表；已有新鲜缓存的跳过。
+ * \details 至多 `WARMUP_CONCURRENCY` 个请求同时进行，每个 Provider 只请求一次；失败只记录日志。
+ */
+pub fn start_warmup(providers: Vec<Provider>) -> WarmupStatus {
+    let providers: Vec<Provider> = providers
+        .into_iter()
+        .filter(|p| cached_models(p).is_none())
+        .collect();
+    update(|s| {
+        *s = WarmupStatus {
+            running: !providers.is_empty(),
+            total: providers.len(),
+            started_at: Some(db::unix_now()),
+            finished_at: providers.is_empty().then(db::unix_now),
+            ..Default::default()
+        }
+    });
+    if providers.is_empty() {
+        return warmup_status();
+    }
+
+    tokio::spawn(async move {
+        futures_util::stream::iter(providers)
+            .for_each_concurrent(WARMUP_CONCURRENCY, |provider| async move {
+                match refresh_models(&provider).await {
+                    Ok(models) => {
+                        telemetry::log_event(
+                            "model_cache",
+                            &format!("warmed provider={} models={}", provider.name, models.len()),
+                        );
+                        update(|s| s.warmed += 1);
+                    }
+                    Err(e) => {
+                        telemetry::log_error(
+                            "model_cache",
+                            &format!("warmup provider={} err={}", provider.name, e),
+                        );
+                        update(|s| s.failed += 1);
+                    }
+                }
+            })
+            .await;
+        update(|s| {
+            s.running = false;
+            s.finished_at = Some(db::unix_now());
+        });
+    });
+    warmup_status()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(id: i64, key: &str) -> Provider {
+        Provider {
+            id,
+            name: "p".to_string(),
+            api_base: "https://api.example.com".to_string(),
+            api_key: key.to_string(),
+            model: "gpt-4o".to_string(),
+            provider_type: "openai".to_string(),
+            secret_alias: None,
+            api_prefix: None,
+            signing: None,
+            generation: Default::default(),
+            proxy_url: None,
+            api_key_unreadable: false,
+        }
+    }
+
+    #[test]
+    fn test_cache_invalidates_on_credential_change() {
+        let p = provider(9001, "sk-1");
+        CACHE.lock().unwrap().insert(
+            p.id,
+            CachedModels {
+                fingerprint: fingerprint(&p),
+                models: vec!["gpt-4o".to_string()],
+                fetched_at: Instant::now(),
+            },
+        );
+        assert_eq!(cached_models(&p), Some(vec!["gpt-4o".to_string()]));
+        assert!(cached_models(&provider(9001, "sk-2")).is_none());
+        assert!(cached_models(&provider(9002, "sk-1")).is_none());
+    }
+
+    #[test]
+    fn test_warmup_targets_put_default_first() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        let a = db::insert_provider(&conn, "a", "openai", "x", "", "m", None).unwrap();
+        let b = db::insert_provider(&conn, "b", "openai", "x", "", "m", None).unwrap();
+        db::insert_provider(&conn, "unused", "openai", "x", "", "m", None).unwrap();
+        db::create_chat(&conn, "one", a).unwrap();
+        db::create_chat(&conn, "two", b).unwrap();
+        db::set_default_provider_id(&conn, a).unwrap();
+        assert_eq!(warmup_targets(&conn).unwrap(), vec![a, b]);
+    }
+}
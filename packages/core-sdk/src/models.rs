@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 /**
  * \brief Provider 配置模型。
@@ -19,6 +20,246 @@ pub struct Provider {
     pub provider_type: String,
     /** \brief 关联安全存储的别名（若存在）。 */
     pub secret_alias: Option<String>,
+    /** \brief 探测得到的可用 API 路径前缀（是否需要补 `/v1`），为空时首次请求前探测。 */
+    #[serde(default)]
+    pub api_prefix: Option<String>,
+    /** \brief 企业网关要求的 HMAC 请求签名，未配置时不签名。 */
+    #[serde(default)]
+    pub signing: Option<RequestSigning>,
+    /** \brief Provider 级生成参数，未设置的项沿用按类型的默认值。 */
+    #[serde(default)]
+    pub generation: GenerationSettings,
+    /** \brief 访问该 Provider 使用的代理（`http://`、`https://`、`socks5://`、`socks5h://`），为空时直连或沿用系统代理环境变量。 */
+    #[serde(default)]
+    pub proxy_url: Option<String>,
+    /** \brief 已保存的密钥无法用当前主密钥解密（主密钥丢失或与加密时不同），此时 `api_key` 为空，需要重新填写或执行 `provider rekey`。 */
+    #[serde(default)]
+    pub api_key_unreadable: bool,
+}
+
+/**
+ * \brief Provider 级生成参数：温度、top_p、输出上限与停止序列。
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /** \brief 停止序列，空列表表示不设置。 */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+}
+
+impl GenerationSettings {
+    /** \brief 去掉非正的输出上限与空白停止序列。 */
+    pub fn normalized(self) -> Self {
+        Self {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens.filter(|n| *n > 0),
+            stop: self.stop.into_iter().filter(|s| !s.is_empty()).collect(),
+        }
+    }
+}
+
+/**
+ * \brief 会话的上下文选择策略：`full` 发送全部历史，`relevance` 按与新提示的向量相似度挑选历史。
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ContextStrategy {
+    #[default]
+    Full,
+    Relevance(RelevanceSettings),
+}
+
+/**
+ * \brief 相关性上下文参数；最近 `keep_recent` 条与 system 消息总会保留，其余按相似度在 token 预算内挑选。
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RelevanceSettings {
+    /** \brief 早先历史可占用的 token 预算（估算值）。 */
+    #[serde(default = "default_context_max_tokens")]
+    pub max_tokens: usize,
+    #[serde(default = "default_context_keep_recent")]
+    pub keep_recent: usize,
+    /** \brief 余弦相似度低于该值的历史不入选。 */
+    #[serde(default = "default_context_min_score")]
+    pub min_score: f32,
+    /** \brief 向量模型，为空时使用 Provider 类型的默认模型。 */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding_model: Option<String>,
+}
+
+fn default_context_max_tokens() -> usize {
+    3000
+}
+
+fn default_context_keep_recent() -> usize {
+    4
+}
+
+fn default_context_min_score() -> f32 {
+    0.2
+}
+
+impl Default for RelevanceSettings {
+    fn default() -> Self {
+        Self {
+            max_tokens: default_context_max_tokens(),
+            keep_recent: default_context_keep_recent(),
+            min_score: default_context_min_score(),
+            embedding_model: None,
+        }
+    }
+}
+
+/**
+ * \brief 会话的回复语言设置：`language` 为语言代码（如 `zh`、`en`、`ja`），
+ * `enforce` 开启时生成后检测回复语言，不符则以更强的指令重问一次。
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplyLanguage {
+    pub language: String,
+    #[serde(default = "default_reply_language_enforce")]
+    pub enforce: bool,
+}
+
+fn default_reply_language_enforce() -> bool {
+    true
+}
+
+/**
+ * \brief HTTP 请求的重试策略：429、5xx 与连接类错误按指数退避重试，响应带 `Retry-After` 时以其为准。
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /** \brief 总尝试次数（含首次），1 表示不重试。 */
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+    /** \brief 首次重试前的等待，之后每次翻倍。 */
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub base_delay_ms: u64,
+    /** \brief 单次等待上限，`Retry-After` 同样受此限制。 */
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    500
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    10_000
+}
+
+/** \brief 总尝试次数上限，避免误配置导致请求长时间挂起。 */
+const RETRY_MAX_ATTEMPTS_LIMIT: u32 = 10;
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_retry_max_attempts(),
+            base_delay_ms: default_retry_base_delay_ms(),
+            max_delay_ms: default_retry_max_delay_ms(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /** \brief 尝试次数限制在 1..=10，首次等待不超过等待上限。 */
+    pub fn normalized(self) -> Self {
+        Self {
+            max_attempts: self.max_attempts.clamp(1, RETRY_MAX_ATTEMPTS_LIMIT),
+            base_delay_ms: self.base_delay_ms.min(self.max_delay_ms),
+            max_delay_ms: self.max_delay_ms,
+        }
+    }
+
+    /** \brief 第 `retry` 次重试（从 1 开始）前的退避时间。 */
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u64 << retry.saturating_sub(1).min(20);
+        Duration::from_millis(
+            self.base_delay_ms
+                .saturating_mul(factor)
+                .min(self.max_delay_ms),
+        )
+    }
+
+    pub(crate) fn cap(&self, delay: Duration) -> Duration {
+        delay.min(Duration::from_millis(self.max_delay_ms))
+    }
+}
+
+/**
+ * \brief Provider api_base 的主机名白名单/黑名单，保存 Provider 与每次 LLM 请求前校验。
+ * \details 条目为主机名（`api.openai.com`）或通配子域（`*.example.com`，不含 `example.com` 本身），
+ * 不区分大小写。命中 `deny` 一律拒绝；`allow` 非空时只放行命中的主机，为空表示不限制。
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostPolicy {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl HostPolicy {
+    /** \brief 条目去除首尾空白与末尾的点并转小写，去掉空条目与重复项。 */
+    pub fn normalized(&self) -> Self {
+        fn clean(list: &[String]) -> Vec<String> {
+            let mut out: Vec<String> = Vec::new();
+            for entry in list {
+                let entry = entry.trim().trim_end_matches('.').to_ascii_lowercase();
+                if !entry.is_empty() && !out.contains(&entry) {
+                    out.push(entry);
+                }
+            }
+            out
+        }
+        Self {
+            allow: clean(&self.allow),
+            deny: clean(&self.deny),
+        }
+    }
+
+    /** \brief 未配置任何条目。 */
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+/**
+ * \brief HMAC-SHA256 请求签名配置。
+ * \details 签名原文为 `METHOD\nPATH\nhex(sha256(body))\nTIMESTAMP`，结果以小写十六进制写入 `header`。
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestSigning {
+    /** \brief 携带签名的请求头名称。 */
+    pub header: String,
+    /** \brief 携带 Unix 秒时间戳的请求头名称，默认 `X-Timestamp`。 */
+    #[serde(default)]
+    pub timestamp_header: Option<String>,
+    /** \brief 签名密钥；桌面端存于安全存储，数据库中为空。 */
+    #[serde(default)]
+    pub secret: String,
+}
+
+/**
+ * \brief 新提示中引用的早先消息，内容为引用时的快照。
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotedMessage {
+    pub message_id: i64,
+    pub role: String,
+    pub content: String,
 }
 
 /**
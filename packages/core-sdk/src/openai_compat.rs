@@ -0,0 +1,442 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    db, llm,
+    models::{Message, Provider},
+};
+
+/** \brief 请求 `model` 为空或取该值时使用默认 Provider 及其模型。 */
+pub const DEFAULT_MODEL_ALIAS: &str = "default";
+
+/** \brief 指定追加到已有会话的请求头；响应中同名头返回本次使用的会话。 */
+pub const CHAT_ID_HEADER: &str = "x-dreamquill-chat-id";
+
+/** \brief 新会话标题取首条用户消息的字符数上限。 */
+const TITLE_MAX_CHARS: usize = 30;
+
+/**
+ * \brief OpenAI Chat Completions 请求体，只解析网关用到的字段，其余字段忽略。
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatCompletionRequest {
+    #[serde(default)]
+    pub model: String,
+    pub messages: Vec<CompatMessage>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    /** \brief 新版客户端使用的输出上限字段，优先于 `max_tokens`。 */
+    #[serde(default)]
+    pub max_completion_tokens: Option<u32>,
+    #[serde(default)]
+    pub stop: Option<StopSpec>,
+    #[serde(default)]
+    pub stream_options: Option<StreamOptions>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompatMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: Option<MessageContent>,
+}
+
+/**
+ * \brief 消息内容：纯文本或内容片段数组（仅支持 `text` 片段）。
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum StopSpec {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StreamOptions {
+    /** \brief 流结束前额外发送一个带 `usage` 的增量。 */
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+impl ChatCompletionRequest {
+    /**
+     * \brief 转换为内部消息列表：`developer` 视作 `system`，工具调用与非文本片段不支持。
+     */
+    pub fn to_messages(&self) -> Result<Vec<Message>> {
+        let mut out = Vec::with_capacity(self.messages.len());
+        for msg in &self.messages {
+            let role = match msg.role.as_str() {
+                "system" | "developer" => "system",
+                "user" => "user",
+                "assistant" => "assistant",
+                other => bail!("unsupported message role: {}", other),
+            };
+            let content = match &msg.content {
+                None => String::new(),
+                Some(MessageContent::Text(text)) => text.clone(),
+                Some(MessageContent::Parts(parts)) => {
+                    let mut texts = Vec::with_capacity(parts.len());
+                    for part in parts {
+                        if part.kind != "text" {
+                            bail!("unsupported content part type: {}", part.kind);
+                        }
+                        texts.push(part.text.clone().unwrap_or_default());
+                    }
+                    texts.join("\n")
+                }
+            };
+            out.push(Message {
+                role: role.to_string(),
+                content,
+            });
+        }
+        if !out
+            .iter()
+            .any(|m| m.role == "user" && !m.content.trim().is_empty())
+        {
+            bail!("messages must contain at least one non-empty user message");
+        }
+        Ok(out)
+    }
+
+    /** \brief 请求中的停止串，去掉空串。 */
+    pub fn stop_strings(&self) -> Vec<String> {
+        let stops = match &self.stop {
+            None => Vec::new(),
+            Some(StopSpec::One(s)) => vec![s.clone()],
+            Some(StopSpec::Many(list)) => list.clone(),
+        };
+        stops.into_iter().filter(|s| !s.is_empty()).collect()
+    }
+
+    /** \brief 单次参数覆盖，`model` 为路由后的模型名（None 表示沿用 Provider 配置）。 */
+    pub fn overrides(&self, model: Option<String>) -> llm::RequestOverrides {
+        llm::RequestOverrides {
+            model,
+            temperature: self.temperature,
+            max_tokens: self.max_completion_tokens.or(self.max_tokens),
+            system_instruction: None,
+        }
+        .normalized()
+    }
+
+    pub fn include_usage(&self) -> bool {
+        self.stream_options
+            .as_ref()
+            .is_some_and(|opts| opts.include_usage)
+    }
+}
+
+/**
+ * \brief 按请求的 `model` 选择 Provider，返回 Provider 与模型覆盖。
+ * \details 依次匹配：Provider 名称 → 该 Provider 的已配置模型；`名称/模型` → 该 Provider 的指定模型；
+ * 空串或 `default` → 默认 Provider 的已配置模型；其余视为默认 Provider 上的模型名。
+ */
+pub fn route_model(
+    providers: &[Provider],
+    default: Option<&Provider>,
+    requested: &str,
+) -> Result<(Provider, Option<String>)> {
+    let requested = requested.trim();
+    if let Some(p) = providers.iter().find(|p| p.name == requested) {
+        return Ok((p.clone(), None));
+    }
+    if let Some((name, model)) = requested.split_once('/') {
+        if let Some(p) = providers.iter().find(|p| p.name == name) {
+            return Ok((p.clone(), Some(model.to_string())));
+        }
+    }
+    let Some(default) = default else {
+        bail!("no default provider configured, run `dreamquill init` or select one first");
+    };
+    let model =
+        (!requested.is_empty() && requested != DEFAULT_MODEL_ALIAS).then(|| requested.to_string());
+    Ok((default.clone(), model))
+}
+
+/**
+ * \brief 将请求写入会话，返回会话 ID。
+ * \details 未指定会话时新建会话并写入全部消息；指定会话时只追加请求中最后一条助手消息之后的部分，
+ * 即客户端本轮新增的消息。
+ */
+pub fn persist_request(
+    conn: &Connection,
+    chat_id: Option<i64>,
+    provider: &Provider,
+    messages: &[Message],
+) -> Result<i64> {
+    let (chat_id, new_messages) = match chat_id {
+        Some(id) => {
+            if db::get_chat(conn, id)?.is_none() {
+                bail!("chat id {} not found", id);
+            }
+            let start = messages
+                .iter()
+                .rposition(|m| m.role == "assistant")
+                .map_or(0, |i| i + 1);
+            (id, &messages[start..])
+        }
+        None => (
+            db::create_chat(conn, &chat_title(messages, provider), provider.id)?,
+            messages,
+        ),
+    };
+    for msg in new_messages {
+        db::insert_message(conn, chat_id, &msg.role, &msg.content)?;
+    }
+    Ok(chat_id)
+}
+
+/** \brief 新会话标题：首条用户消息的开头，缺省为 Provider 名。 */
+fn chat_title(messages: &[Message], provider: &Provider) -> String {
+    let first = messages
+        .iter()
+        .find(|m| m.role == "user")
+        .map(|m| m.content.split_whitespace().collect::<Vec<_>>().join(" "))
+        .unwrap_or_default();
+    if first.is_empty() {
+        return format!("{} 会话", provider.name);
+    }
+    let mut title: String = first.chars().take(TITLE_MAX_CHARS).collect();
+    if first.chars().count() > TITLE_MAX_CHARS {
+        title.push('…');
+    }
+    title
+}
+
+/**
+ * \brief 估算的 token 用量；上游流式接口不统一返回用量，按字符估算。
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl Usage {
+    pub fn estimate(messages: &[Message], reply: &str) -> Self {
+        let prompt_tokens = messages
+            .iter()
+            .map(|m| llm::estimate_tokens(&m.content))
+            .sum();
+        let completion_tokens = llm::estimate_tokens(reply);
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+/**
+ * \brief 一次补全的响应标识：ID、模型名与创建时间，流式增量与最终响应共用。
+ */
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub id: String,
+    pub model: String,
+    pub created: i64,
+}
+
+impl Completion {
+    pub fn new(chat_id: i64, model: &str) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            id: format!("chatcmpl-dq{}-{}", chat_id, now.as_millis()),
+            model: model.to_string(),
+            created: now.as_secs() as i64,
+        }
+    }
+
+    /** \brief 非流式响应体（`chat.completion`）。 */
+    pub fn response(&self, content: &str, usage: Usage) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": content},
+                "finish_reason": "stop",
+            }],
+            "usage": usage,
+        })
+    }
+
+    /** \brief 流式增量（`chat.completion.chunk`）；`delta` 为空对象且带 `finish_reason` 时表示结束。 */
+    pub fn chunk(&self, delta: Value, finish_reason: Option<&str>) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        })
+    }
+
+    /** \brief `stream_options.include_usage` 要求的末尾用量增量，`choices` 为空。 */
+    pub fn usage_chunk(&self, usage: Usage) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [],
+            "usage": usage,
+        })
+    }
+}
+
+/**
+ * \brief `GET /v1/models` 响应：`default` 加上每个 Provider 的名称（去重）。
+ */
+pub fn model_list(providers: &[Provider]) -> Value {
+    let mut ids = vec![DEFAULT_MODEL_ALIAS];
+    for p in providers {
+        if !ids.contains(&p.name.as_str()) {
+            ids.push(&p.name);
+        }
+    }
+    let data: Vec<Value> = ids
+        .into_iter()
+        .map(|id| json!({"id": id, "object": "model", "created": 0, "owned_by": "dreamquill"}))
+        .collect();
+    json!({"object": "list", "data": data})
+}
+
+/** \brief OpenAI 格式的错误体。 */
+pub fn error_body(message: &str, kind: &str) -> Value {
+    json!({"error": {"message": message, "type": kind, "param": null, "code": null}})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(id: i64, name: &str, model: &str) -> Provider {
+        Provider {
+            id,
+            name: name.to_string(),
+            api_base: "https://api.example.com".to_string(),
+            api_key: "k".to_string(),
+            model: model.to_string(),
+            provider_type: "openai".to_string(),
+            secret_alias: None,
+            api_prefix: None,
+            signing: None,
+            generation: Default::default(),
+            proxy_url: None,
+            api_key_unreadable: false,
+        }
+    }
+
+    #[test]
+    fn test_request_parses_parts_roles_and_stops() {
+        let req: ChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [
+                {"role": "developer", "content": "be brief"},
+                {"role": "user", "content": [{"type": "text", "text": "hi"}, {"type": "text", "text": "there"}]},
+            ],
+            "max_tokens": 50,
+            "max_completion_tokens": 80,
+            "stop": "END",
+            "stream_options": {"include_usage": true},
+        }))
+        .unwrap();
+        let messages = req.to_messages().unwrap();
+        assert_eq!(messages[0].role, "system");
+        assert_eq!(messages[1].content, "hi\nthere");
+        assert_eq!(req.stop_strings(), vec!["END"]);
+        assert_eq!(req.overrides(None).max_tokens, Some(80));
+        assert!(req.include_usage());
+
+        let tool: ChatCompletionRequest = serde_json::from_value(json!({
+            "messages": [{"role": "tool", "content": "x"}],
+        }))
+        .unwrap();
+        assert!(tool.to_messages().is_err());
+        let image: ChatCompletionRequest = serde_json::from_value(json!({
+            "messages": [{"role": "user", "content": [{"type": "image_url"}]}],
+        }))
+        .unwrap();
+        assert!(image.to_messages().is_err());
+    }
+
+    #[test]
+    fn test_route_model_matches_names_prefixes_and_default() {
+        let providers = vec![
+            provider(1, "openai", "gpt-4o"),
+            provider(2, "local", "llama3"),
+        ];
+        let default = providers[0].clone();
+        let route = |m: &str| {
+            let (p, model) = route_model(&providers, Some(&default), m).unwrap();
+            (p.id, model)
+        };
+        assert_eq!(route(""), (1, None));
+        assert_eq!(route("default"), (1, None));
+        assert_eq!(route("local"), (2, None));
+        assert_eq!(route("local/qwen2"), (2, Some("qwen2".to_string())));
+        assert_eq!(route("gpt-4o-mini"), (1, Some("gpt-4o-mini".to_string())));
+        assert!(route_model(&providers, None, "gpt-4o-mini").is_err());
+    }
+
+    #[test]
+    fn test_persist_request_creates_then_appends() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        let pid = db::insert_provider(&conn, "p", "openai", "http://x", "k", "m", None).unwrap();
+        let p = db::get_provider_by_id(&conn, pid).unwrap().unwrap();
+        let msg = |role: &str, content: &str| Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        };
+        let first = vec![msg("system", "be brief"), msg("user", "hello   world")];
+        let chat_id = persist_request(&conn, None, &p, &first).unwrap();
+        assert_eq!(
+            db::get_chat(&conn, chat_id).unwrap().unwrap().title,
+            "hello world"
+        );
+        db::insert_message(&conn, chat_id, "assistant", "hi").unwrap();
+
+        let second = vec![
+            msg("system", "be brief"),
+            msg("user", "hello world"),
+            msg("assistant", "hi"),
+            msg("user", "again"),
+        ];
+        persist_request(&conn, Some(chat_id), &p, &second).unwrap();
+        let stored = db::load_messages(&conn, chat_id).unwrap();
+        let roles: Vec<&str> = stored.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+        assert!(persist_request(&conn, Some(chat_id + 100), &p, &second).is_err());
+    }
+}
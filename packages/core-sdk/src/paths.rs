@@ -0,0 +1,227 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use rusqlite::Connection;
+
+/** \brief 覆盖数据目录的环境变量。 */
+pub const DATA_DIR_ENV: &str = "DREAMQUILL_DATA_DIR";
+
+/** \brief 数据目录下的数据库文件名；旧版本把同名文件写在当前工作目录。 */
+pub const DB_FILE_NAME: &str = "dreamquill.db";
+
+/** \brief 平台数据目录下的应用子目录名。 */
+const APP_DIR_NAME: &str = "DreamQuill";
+
+/** \brief WAL 模式的附属文件，迁移时随主文件一起移动。 */
+const DB_SIDECARS: [&str; 2] = ["-wal", "-shm"];
+
+static DATA_DIR: Lazy<PathBuf> =
+    Lazy::new(|| resolve_data_dir(std::env::var_os(DATA_DIR_ENV), dirs::data_dir()));
+
+static DB_PATH: Lazy<PathBuf> = Lazy::new(|| {
+    let target = DATA_DIR.join(DB_FILE_NAME);
+    let legacy = PathBuf::from(DB_FILE_NAME);
+    if target.exists() || !legacy.is_file() {
+        return target;
+    }
+    match relocate_db(&legacy, &target) {
+        Ok(()) => {
+            eprintln!(
+                "moved database {} to {}",
+                legacy.display(),
+                target.display()
+            );
+            target
+        }
+        Err(e) => {
+            eprintln!(
+                "Warning: keep using {}, moving it to {} failed: {:#}",
+                legacy.display(),
+                target.display(),
+                e
+            );
+            legacy
+        }
+    }
+});
+
+/**
+ * \brief 应用数据目录：`DREAMQUILL_DATA_DIR` 优先，否则为平台数据目录下的 `DreamQuill`
+ * （Linux `~/.local/share`、macOS `~/Library/Application Support`、Windows `%APPDATA%`）。
+ * \details 进程内首次调用时确定，之后修改环境变量不再生效。
+ */
+pub fn data_dir() -> &'static Path {
+    &DATA_DIR
+}
+
+/**
+ * \brief 默认数据库路径，CLI、桌面端与服务端共用。
+ * \details 首次调用时若数据目录中还没有数据库、而当前工作目录存在旧版 `dreamquill.db`，
+ * 会先把它（连同 WAL 文件）移入数据目录；移动失败时继续使用旧文件并打印警告。
+ */
+pub fn db_path() -> &'static Path {
+    &DB_PATH
+}
+
+/** \brief 遥测日志目录。 */
+pub fn log_dir() -> PathBuf {
+    data_dir().join("logs")
+}
+
+/** \brief 未指定位置时数据库备份的存放目录。 */
+pub fn backup_dir() -> PathBuf {
+    data_dir().join("backups")
+}
+
+fn resolve_data_dir(env: Option<OsString>, platform: Option<PathBuf>) -> PathBuf {
+    match env.filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => platform.map_or_else(|| PathBuf::from("."), |dir| dir.join(APP_DIR_NAME)),
+    }
+}
+
+/**
+ * \brief 把数据库移到新位置：先合并 WAL 再移动文件，跨文件系统时退回 `copy_then_rename`。
+ * \details 附属文件先于主文件移动，中途失败时目标主文件尚不存在，下次启动会重新尝试。
+ */
+fn relocate_db(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("create {} failed", parent.display()))?;
+    }
+    {
+        let conn = Connection::open(from)?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    }
+    for suffix in DB_SIDECARS {
+        let sidecar = with_suffix(from, suffix);
+        if sidecar.exists() {
+            move_file(&sidecar, &with_suffix(to, suffix))?;
+        }
+    }
+    move_file(from, to)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_then_rename(from, to)
+}
+
+/**
+ * \brief 跨文件系统移动：复制到目标目录下的临时名并落盘，再改名为目标文件，确认到位后才删除原文件。
+ * \details 中途失败（如磁盘写满、进程被杀）时目标路径要么不存在、要么是完整文件，原文件始终保留。
+ */
+fn copy_then_rename(from: &Path, to: &Path) -> Result<()> {
+    let staging = with_suffix(to, ".partial");
+    let staged = (|| -> Result<()> {
+        std::fs::copy(from, &staging)
+            .with_context(|| format!("copy {} to {} failed", from.display(), staging.display()))?;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&staging)
+            .and_then(|file| file.sync_all())
+            .with_context(|| format!("sync {} failed", staging.display()))?;
+        std::fs::rename(&staging, to)
+            .with_context(|| format!("rename {} to {} failed", staging.display(), to.display()))
+    })();
+    if let Err(e) = staged {
+        let _ = std::fs::remove_file(&staging);
+        return Err(e);
+    }
+    sync_parent_dir(to)?;
+    std::fs::remove_file(from).with_context(|| format!("remove {} failed", from.display()))
+}
+
+/** \brief 落盘目录项，使改名在断电后仍然可见；Windows 不支持打开目录同步，直接跳过。 */
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::File::open(parent)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("sync {} failed", parent.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_overrides_platform_dir() {
+        let platform = Some(PathBuf::from("/home/u/.local/share"));
+        assert_eq!(
+            resolve_data_dir(Some("/srv/dq".into()), platform.clone()),
+            PathBuf::from("/srv/dq")
+        );
+        assert_eq!(
+            resolve_data_dir(Some("".into()), platform.clone()),
+            PathBuf::from("/home/u/.local/share/DreamQuill")
+        );
+        assert_eq!(resolve_data_dir(None, None), PathBuf::from("."));
+    }
+
+    #[test]
+    fn test_relocate_keeps_data() {
+        let root = std::env::temp_dir().join(format!(
+            "dreamquill-paths-{}",
+            crate::db::process_session_id()
+        ));
+        let legacy = root.join(DB_FILE_NAME);
+        let target = root.join("data").join(DB_FILE_NAME);
+        std::fs::create_dir_all(&root).expect("mkdir");
+        {
+            let conn = Connection::open(&legacy).expect("open legacy");
+            crate::db::migrate(&conn).expect("migrate");
+            crate::db::create_chat_at(&conn, "old chat", None, 1).expect("chat");
+        }
+
+        relocate_db(&legacy, &target).expect("relocate");
+        assert!(!legacy.exists());
+        assert!(!with_suffix(&legacy, "-wal").exists());
+        let conn = Connection::open(&target).expect("open target");
+        let chats = crate::db::list_chats(&conn, None, true).expect("list");
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].title, "old chat");
+        drop(conn);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_copy_fallback_keeps_source_until_target_is_complete() {
+        let root = std::env::temp_dir().join(format!(
+            "dreamquill-paths-copy-{}",
+            crate::db::process_session_id()
+        ));
+        std::fs::create_dir_all(root.join("data")).expect("mkdir");
+        let from = root.join("legacy.db");
+        let to = root.join("data").join("moved.db");
+        std::fs::write(&from, b"payload").expect("write");
+
+        copy_then_rename(&from, &to).expect("copy");
+        assert_eq!(std::fs::read(&to).expect("read"), b"payload");
+        assert!(!from.exists());
+        assert!(!with_suffix(&to, ".partial").exists());
+
+        // 目标目录不存在时复制失败，原文件原样保留
+        std::fs::write(&from, b"payload").expect("write");
+        let missing = root.join("missing").join("moved.db");
+        assert!(copy_then_rename(&from, &missing).is_err());
+        assert!(from.exists());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,515 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rusqlite::Connection;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime};
+
+use crate::{
+    db::{self, ExportPipeline, PipelineDefinition, PipelineFormat},
+    exporter,
+};
+
+/** \brief Markdown/纯文本格式未提供模板时使用的默认模板。 */
+pub const DEFAULT_MARKDOWN_TEMPLATE: &str = "# {{pipeline}} ({{date}})\n\
+{{#each chats}}\n## {{title}}\n{{#each messages}}\n**{{role}}** · {{created_date}}\n\n{{content}}\n{{/each}}{{/each}}";
+
+/** \brief HTML 格式未提供模板时使用的默认模板。 */
+pub const DEFAULT_HTML_TEMPLATE: &str = "<h1>{{pipeline}} ({{date}})</h1>\n\
+{{#each chats}}<h2>{{title}}</h2>\n{{#each messages}}<p><b>{{role}}</b> {{created_date}}</p>\n<pre>{{content}}</pre>\n{{/each}}{{/each}}";
+
+/**
+ * \brief 渲染得到的单个输出文件。
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderedFile {
+    pub file_name: String,
+    pub content: String,
+}
+
+/**
+ * \brief 流水线运行结果。
+ */
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PipelineRun {
+    pub chats: usize,
+    pub messages: usize,
+    pub files: Vec<RenderedFile>,
+}
+
+/**
+ * \brief 检查正文与文件名模板能否解析，保存流水线前调用。
+ */
+pub fn validate(definition: &PipelineDefinition) -> Result<()> {
+    parse_template(&definition.template).context("invalid pipeline template")?;
+    parse_template(&definition.output.file_name).context("invalid file name template")?;
+    Ok(())
+}
+
+/**
+ * \brief 按定义筛选会话与消息并渲染输出，不写文件。
+ */
+pub fn render(
+    conn: &Connection,
+    name: &str,
+    definition: &PipelineDefinition,
+    now: OffsetDateTime,
+) -> Result<PipelineRun> {
+    let format = definition.output.format;
+    let body = parse_template(match definition.template.trim() {
+        "" => default_template(format),
+        _ => &definition.template,
+    })
+    .context("invalid pipeline template")?;
+    let file_name = parse_template(match definition.output.file_name.trim() {
+        "" if definition.output.per_chat => "{{pipeline}}-{{chat.id}}",
+        "" => "{{pipeline}}-{{date}}",
+        other => other,
+    })
+    .context("invalid file name template")?;
+
+    let chats = collect_chats(conn, definition, now)?;
+    let messages = chats
+        .iter()
+        .map(|c| c["messages"].as_array().map_or(0, Vec::len))
+        .sum();
+    let mut base = Map::new();
+    base.insert("pipeline".to_string(), json!(name));
+    base.insert("date".to_string(), json!(format_date(now)));
+    base.insert(
+        "generated_at".to_string(),
+        json!(now.format(&Rfc3339).unwrap_or_default()),
+    );
+    base.insert("chat_count".to_string(), json!(chats.len()));
+    base.insert("message_count".to_string(), json!(messages));
+
+    let scopes: Vec<Value> = if definition.output.per_chat {
+        chats
+            .iter()
+            .map(|chat| {
+                let mut scope = base.clone();
+                scope.insert("chat".to_string(), chat.clone());
+                scope.insert("chats".to_string(), json!([chat]));
+                Value::Object(scope)
+            })
+            .collect()
+    } else {
+        let mut scope = base;
+        scope.insert("chats".to_string(), Value::Array(chats.clone()));
+        vec![Value::Object(scope)]
+    };
+
+    let files = scopes
+        .iter()
+        .map(|scope| {
+            let content = if format == PipelineFormat::Json && definition.template.trim().is_empty()
+            {
+                serde_json::to_string_pretty(scope)?
+            } else {
+                render_nodes(&body, &mut vec![scope], format == PipelineFormat::Html)
+            };
+            Ok(RenderedFile {
+                file_name: output_file_name(
+                    &render_nodes(&file_name, &mut vec![scope], false),
+                    format,
+                ),
+                content,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(PipelineRun {
+        chats: chats.len(),
+        messages,
+        files,
+    })
+}
+
+/**
+ * \brief 以当前时间渲染已保存的流水线，不写文件。
+ */
+pub fn preview(conn: &Connection, pipeline: &ExportPipeline) -> Result<PipelineRun> {
+    render(
+        conn,
+        &pipeline.name,
+        &pipeline.definition,
+        OffsetDateTime::now_utc(),
+    )
+}
+
+/**
+ * \brief 运行已保存的流水线并把结果写入 `dir`，同名文件会被覆盖。
+ */
+pub fn run(
+    conn: &Connection,
+    pipeline: &ExportPipeline,
+    dir: &Path,
+) -> Result<(PipelineRun, Vec<PathBuf>)> {
+    let run = preview(conn, pipeline)?;
+    std::fs::create_dir_all(dir).with_context(|| format!("create {} failed", dir.display()))?;
+    let mut paths = Vec::new();
+    for file in &run.files {
+        let path = dir.join(&file.file_name);
+        std::fs::write(&path, &file.content)
+            .with_context(|| format!("write {} failed", path.display()))?;
+        paths.push(path);
+    }
+    Ok((run, paths))
+}
+
+fn default_template(format: PipelineFormat) -> &'static str {
+    match format {
+        PipelineFormat::Html => DEFAULT_HTML_TEMPLATE,
+        _ => DEFAULT_MARKDOWN_TEMPLATE,
+    }
+}
+
+fn extension(format: PipelineFormat) -> &'static str {
+    match format {
+        PipelineFormat::Markdown => "md",
+        PipelineFormat::Text => "txt",
+        PipelineFormat::Html => "html",
+        PipelineFormat::Json => "json",
+    }
+}
+
+/** \brief 去掉文件名中的非法字符；没有扩展名时按格式补上。 */
+fn output_file_name(rendered: &str, format: PipelineFormat) -> String {
+    let name = exporter::sanitize_file_name(rendered);
+    if Path::new(&name).extension().is_some() {
+        name
+    } else {
+        format!("{}.{}", name, extension(format))
+    }
+}
+
+fn format_date(at: OffsetDateTime) -> String {
+    at.format(format_description!("[year]-[month]-[day]"))
+        .unwrap_or_default()
+}
+
+fn date_of(ts: Option<i64>) -> Value {
+    ts.and_then(|ts| OffsetDateTime::from_unix_timestamp(ts).ok())
+        .map(|at| json!(format_date(at)))
+        .unwrap_or(Value::Null)
+}
+
+/**
+ * \brief 模板上下文中的会话列表：按创建时间正序，消息按来源条件过滤。
+ */
+fn collect_chats(
+    conn: &Connection,
+    definition: &PipelineDefinition,
+    now: OffsetDateTime,
+) -> Result<Vec<Value>> {
+    let source = &definition.source;
+    let since = source
+        .since_days
+        .filter(|d| *d > 0)
+        .map(|d| now.unix_timestamp() - d * 86_400);
+    let providers = db::list_providers(conn)?;
+    let mut chats = Vec::new();
+    for chat in db::search_chats(conn, &source.filter)?.into_iter().rev() {
+        let messages: Vec<Value> = db::load_messages_with_meta(conn, chat.id)?
+            .into_iter()
+            .filter(|m| source.roles.is_empty() || source.roles.iter().any(|r| r == &m.role))
+            .filter(|m| since.is_none_or(|since| m.created_at.is_some_and(|t| t >= since)))
+            .map(|m| {
+                json!({
+                    "id": m.id,
+                    "role": m.role,
+                    "content": m.content,
+                    "created_at": m.created_at,
+                    "created_date": date_of(m.created_at),
+                })
+            })
+            .collect();
+        if messages.is_empty() {
+            continue;
+        }
+        let tags: Vec<String> = db::list_chat_tags(conn, chat.id)?
+            .into_iter()
+            .map(|t| t.tag)
+            .collect();
+        let provider = chat
+            .provider_id
+            .and_then(|pid| providers.iter().find(|p| p.id == pid))
+            .map(|p| p.name.clone());
+        chats.push(json!({
+            "id": chat.id,
+            "title": chat.title,
+            "created_at": chat.created_at,
+            "created_date": date_of(chat.created_at),
+            "provider": provider,
+            "tags": tags,
+            "message_count": messages.len(),
+            "messages": messages,
+        }));
+    }
+    Ok(chats)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    /** \brief 变量输出，第二项为是否原样输出（`{{{ }}}`）。 */
+    Var(String, bool),
+    Each(String, Vec<Node>),
+    If(String, Vec<Node>, Vec<Node>),
+}
+
+/** \brief 解析栈上尚未闭合的块。 */
+struct Frame {
+    kind: &'static str,
+    arg: String,
+    body: Vec<Node>,
+    alt: Vec<Node>,
+    in_else: bool,
+}
+
+impl Frame {
+    fn new(kind: &'static str, arg: &str) -> Self {
+        Self {
+            kind,
+            arg: arg.trim().to_string(),
+            body: Vec::new(),
+            alt: Vec::new(),
+            in_else: false,
+        }
+    }
+}
+
+/**
+ * \brief 解析模板，块标签不配对时报错。
+ */
+fn parse_template(template: &str) -> Result<Vec<Node>> {
+    let mut rest = template;
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut root = Vec::new();
+    fn push(root: &mut Vec<Node>, stack: &mut [Frame], node: Node) {
+        match stack.last_mut() {
+            Some(frame) if frame.in_else => frame.alt.push(node),
+            Some(frame) => frame.body.push(node),
+            None => root.push(node),
+        }
+    }
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            push(&mut root, &mut stack, Node::Text(rest[..start].to_string()));
+        }
+        let raw = rest[start..].starts_with("{{{");
+        let (open, close) = if raw { (3, "}}}") } else { (2, "}}") };
+        let end = rest[start + open..].find(close).ok_or_else(|| {
+            anyhow!(
+                "unclosed tag at offset {}",
+                template.len() - rest.len() + start
+            )
+        })?;
+        let tag = rest[start + open..start + open + end].trim();
+        rest = &rest[start + open + end + close.len()..];
+        if let Some(arg) = tag.strip_prefix("#each ") {
+            stack.push(Frame::new("each", arg));
+        } else if let Some(arg) = tag.strip_prefix("#if ") {
+            stack.push(Frame::new("if", arg));
+        } else if tag == "else" {
+            match stack.last_mut() {
+                Some(frame) if frame.kind == "if" && !frame.in_else => frame.in_else = true,
+                _ => bail!("unexpected {{{{else}}}}"),
+            }
+        } else if let Some(kind) = tag.strip_prefix('/') {
+            let Some(Frame {
+                kind: open_kind,
+                arg,
+                body,
+                alt,
+                ..
+            }) = stack.pop()
+            else {
+                bail!("unexpected {{{{/{}}}}}", kind);
+            };
+            if open_kind != kind.trim() {
+                bail!(
+                    "{{{{/{}}}}} does not close {{{{#{}}}}}",
+                    kind.trim(),
+                    open_kind
+                );
+            }
+            let node = match open_kind {
+                "each" => Node::Each(arg, body),
+                _ => Node::If(arg, body, alt),
+            };
+            push(&mut root, &mut stack, node);
+        } else if tag.starts_with('#') {
+            bail!("unknown block {{{{{}}}}}", tag);
+        } else {
+            push(&mut root, &mut stack, Node::Var(tag.to_string(), raw));
+        }
+    }
+    if !rest.is_empty() {
+        push(&mut root, &mut stack, Node::Text(rest.to_string()));
+    }
+    if let Some(frame) = stack.last() {
+        bail!("unclosed {{{{#{} {}}}}}", frame.kind, frame.arg);
+    }
+    Ok(root)
+}
+
+/** \brief 由内向外查找变量；`this` 指当前项，支持 `a.b` 形式的路径。 */
+fn lookup<'a>(scopes: &[&'a Value], path: &str) -> Option<&'a Value> {
+    if path == "this" {
+        return scopes.last().copied();
+    }
+    let path = path.strip_prefix("this.").unwrap_or(path);
+    scopes.iter().rev().find_map(|scope| {
+        path.split('.').try_fold(*scope, |v, key| match v {
+            Value::Object(map) => map.get(key),
+            Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        })
+    })
+}
+
+fn truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) | Some(Value::Bool(false)) => false,
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(items)) => !items.is_empty(),
+        Some(Value::Number(n)) => n.as_f64() != Some(0.0),
+        Some(_) => true,
+    }
+}
+
+fn display(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|v| display(Some(v)))
+            .collect::<Vec<_>>()
+            .join(", "),
+        Some(other) => other.to_string(),
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render_nodes(nodes: &[Node], scopes: &mut Vec<&Value>, escape: bool) -> String {
+    let mut out = String::new();
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var(path, raw) => {
+                let text = display(lookup(scopes, path));
+                if escape && !raw {
+                    out.push_str(&escape_html(&text));
+                } else {
+                    out.push_str(&text);
+                }
+            }
+            Node::Each(path, body) => {
+                if let Some(Value::Array(items)) = lookup(scopes, path) {
+                    for item in items {
+                        scopes.push(item);
+                        out.push_str(&render_nodes(body, scopes, escape));
+                        scopes.pop();
+                    }
+                }
+            }
+            Node::If(path, body, alt) => {
+                let branch = if truthy(lookup(scopes, path)) {
+                    body
+                } else {
+                    alt
+                };
+                out.push_str(&render_nodes(branch, scopes, escape));
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::{ChatFilter, PipelineOutput, PipelineSource};
+
+    fn mem_conn() -> Connection {
+        let conn = Connection::open_in_memory().expect("open");
+        db::migrate(&conn).expect("migrate");
+        conn
+    }
+
+    #[test]
+    fn test_template_blocks_and_errors() {
+        let nodes = parse_template(
+            "{{title}}:{{#each items}}[{{name}}{{#if done}}✓{{else}}…{{/if}}]{{/each}}{{{raw}}}",
+        )
+        .unwrap();
+        let ctx = json!({
+            "title": "T",
+            "raw": "<b>",
+            "items": [{"name": "a", "done": true}, {"name": "<b>", "done": false}],
+        });
+        assert_eq!(
+            render_nodes(&nodes, &mut vec![&ctx], false),
+            "T:[a✓][<b>…]<b>"
+        );
+        assert_eq!(
+            render_nodes(&nodes, &mut vec![&ctx], true),
+            "T:[a✓][&lt;b&gt;…]<b>"
+        );
+        assert!(parse_template("{{#each items}}x").is_err());
+        assert!(parse_template("{{#each items}}{{/if}}").is_err());
+        assert!(parse_template("{{name").is_err());
+    }
+
+    #[test]
+    fn test_render_filters_messages_and_names_files() {
+        let conn = mem_conn();
+        let pid = db::insert_provider(&conn, "p", "openai", "x", "", "m", None).unwrap();
+        let tagged = db::create_chat(&conn, "Project A", pid).unwrap();
+        db::insert_message(&conn, tagged, "user", "question").unwrap();
+        db::insert_message(&conn, tagged, "assistant", "answer").unwrap();
+        db::replace_auto_tags(&conn, tagged, &[("work".to_string(), 0.9)]).unwrap();
+        let other = db::create_chat(&conn, "Other", pid).unwrap();
+        db::insert_message(&conn, other, "assistant", "unrelated").unwrap();
+
+        let definition = PipelineDefinition {
+            source: PipelineSource {
+                filter: ChatFilter {
+                    tags: vec!["work".to_string()],
+                    ..Default::default()
+                },
+                since_days: Some(7),
+                roles: vec!["assistant".to_string()],
+            },
+            template: "{{#each chats}}{{title}} [{{tags}}]: {{#each messages}}{{content}}{{/each}}{{/each}}"
+                .to_string(),
+            output: PipelineOutput {
+                format: PipelineFormat::Markdown,
+                file_name: "{{pipeline}}/{{chat.title}}".to_string(),
+                per_chat: true,
+            },
+        };
+        let run = render(&conn, "digest", &definition, OffsetDateTime::now_utc()).unwrap();
+        assert_eq!((run.chats, run.messages), (1, 1));
+        assert_eq!(run.files.len(), 1);
+        assert_eq!(run.files[0].file_name, "digest_Project A.md");
+        assert_eq!(run.files[0].content, "Project A [work]: answer");
+
+        let old = render(
+            &conn,
+            "digest",
+            &definition,
+            OffsetDateTime::now_utc() + time::Duration::days(30),
+        )
+        .unwrap();
+        assert!(old.files.is_empty());
+    }
+}
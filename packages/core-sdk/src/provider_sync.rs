@@ -0,0 +1,513 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, bail, Context, Result};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    base_url, db, llm,
+    models::{GenerationSettings, Provider},
+};
+
+/**
+ * \brief providers.yaml 中的一条 Provider 声明。
+ * \details 密钥不写入文件，只记录环境变量名 `api_key_env`，同步时再读取。
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProviderSpec {
+    pub name: String,
+    /** \brief Provider 类型，未填写时为 `openai`。 */
+    pub provider_type: String,
+    pub api_base: String,
+    pub model: String,
+    pub api_key_env: Option<String>,
+    /** \brief 是否设为默认 Provider，文件中至多一条。 */
+    pub default: bool,
+    /** \brief Provider 级生成参数，未填写的项保持为空。 */
+    pub generation: GenerationSettings,
+    pub proxy_url: Option<String>,
+}
+
+/**
+ * \brief providers.yaml 中单条声明的原始形式，未知字段直接报错。
+ */
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawProviderSpec {
+    #[serde(default)]
+    name: String,
+    #[serde(default, alias = "type")]
+    provider: Option<String>,
+    #[serde(default)]
+    api_base: String,
+    #[serde(default)]
+    model: String,
+    #[serde(default)]
+    api_key_env: Option<String>,
+    /** \brief 仅用于给出明确的报错，不允许在文件中写明文密钥。 */
+    #[serde(default)]
+    api_key: Option<serde_yaml::Value>,
+    #[serde(default)]
+    default: bool,
+    #[serde(default)]
+    generation: GenerationSettings,
+    #[serde(default)]
+    proxy_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProvidersFile {
+    #[serde(default)]
+    providers: Vec<RawProviderSpec>,
+}
+
+/**
+ * \brief 解析 providers.yaml。
+ * \details 顶层可以是 `providers:` 键下的列表，也可以直接是列表；空文件视为没有 Provider。
+ */
+pub fn parse_providers_yaml(text: &str) -> Result<Vec<ProviderSpec>> {
+    let value: serde_yaml::Value = serde_yaml::from_str(text).context("invalid providers.yaml")?;
+    let items = match value {
+        serde_yaml::Value::Null => Vec::new(),
+        serde_yaml::Value::Sequence(_) => {
+            serde_yaml::from_value(value).context("invalid providers.yaml")?
+        }
+        _ => {
+            serde_yaml::from_value::<ProvidersFile>(value)
+                .context("invalid providers.yaml")?
+                .providers
+        }
+    };
+
+    let mut specs = Vec::new();
+    let mut names = HashSet::new();
+    for (index, raw) in items.into_iter().enumerate() {
+        if raw.api_key.is_some() {
+            bail!(
+                "provider #{}: literal api_key is not allowed, reference an environment variable with api_key_env",
+                index + 1
+            );
+        }
+        for (field, value) in [
+            ("name", &raw.name),
+            ("api_base", &raw.api_base),
+            ("model", &raw.model),
+        ] {
+            if value.trim().is_empty() {
+                bail!("provider #{} is missing `{}`", index + 1, field);
+            }
+        }
+        let api_base = base_url::normalize(&raw.api_base)
+            .map_err(|e| anyhow!("provider #{}: {}", index + 1, e))?;
+        let proxy_url = raw
+            .proxy_url
+            .map(|u| u.trim().to_string())
+            .filter(|u| !u.is_empty());
+        if let Some(url) = &proxy_url {
+            llm::parse_proxy_url(url).map_err(|e| anyhow!("provider #{}: {}", index + 1, e))?;
+        }
+        if !names.insert(raw.name.clone()) {
+            bail!("duplicate provider name `{}`", raw.name);
+        }
+        specs.push(ProviderSpec {
+            name: raw.name,
+            provider_type: raw
+                .provider
+                .filter(|p| !p.is_empty())
+                .unwrap_or_else(|| "openai".to_string()),
+            api_base,
+            model: raw.model,
+            api_key_env: raw.api_key_env.filter(|v| !v.is_empty()),
+            default: raw.default,
+            generation: raw.generation.normalized(),
+            proxy_url,
+        });
+    }
+    if specs.iter().filter(|s| s.default).count() > 1 {
+        bail!("at most one provider may set `default: true`");
+    }
+    Ok(specs)
+}
+
+/**
+ * \brief 同步计划中的单个动作，按名称匹配文件与数据库中的 Provider。
+ */
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum SyncAction {
+    Create {
+        name: String,
+    },
+    Update {
+        id: i64,
+        name: String,
+        /** \brief 发生变化的字段名（密钥只记为 `api_key`）。 */
+        fields: Vec<&'static str>,
+    },
+    Delete {
+        id: i64,
+        name: String,
+        /** \brief 删除后将失去 Provider 关联的会话数。 */
+        chats: i64,
+    },
+    Unchanged {
+        id: i64,
+        name: String,
+    },
+}
+
+/**
+ * \brief 同步计划：动作列表以及需要切换的默认 Provider。
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncPlan {
+    pub actions: Vec<SyncAction>,
+    /** \brief 需要设为默认的 Provider 名称；已是默认时为空。 */
+    pub set_default: Option<String>,
+    #[serde(skip)]
+    resolved: Vec<(ProviderSpec, String)>,
+}
+
+impl SyncPlan {
+    /** \brief 计划是否会修改数据库。 */
+    pub fn has_changes(&self) -> bool {
+        self.set_default.is_some()
+            || self
+                .actions
+                .iter()
+                .any(|a| !matches!(a, SyncAction::Unchanged { .. }))
+    }
+}
+
+/**
+ * \brief 对比文件声明与数据库现状生成同步计划；缺失的环境变量直接报错，dry-run 也能发现。
+ */
+pub fn plan_sync(
+    conn: &Connection,
+    specs: &[ProviderSpec],
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<SyncPlan> {
+    let mut resolved = Vec::new();
+    let mut missing = Vec::new();
+    for spec in specs {
+        let key = match &spec.api_key_env {
+            Some(var) => match env(var) {
+                Some(value) => value,
+                None => {
+                    missing.push(format!("{} (provider {})", var, spec.name));
+                    continue;
+                }
+            },
+            None => String::new(),
+        };
+        resolved.push((spec.clone(), key));
+    }
+    if !missing.is_empty() {
+        bail!("missing environment variables: {}", missing.join(", "));
+    }
+
+    let existing = db::list_providers(conn)?;
+    let default_id = db::get_default_provider_id(conn)?;
+    let mut actions = Vec::new();
+    let mut set_default = None;
+
+    for (spec, key) in &resolved {
+        match existing.iter().find(|p| p.name == spec.name) {
+            Some(current) => {
+                let mut fields = Vec::new();
+                if current.provider_type != spec.provider_type {
+                    fields.push("provider_type");
+                }
+                if current.api_base != spec.api_base {
+                    fields.push("api_base");
+                }
+                if current.model != spec.model {
+                    fields.push("model");
+                }
+                if current.secret_alias.is_none() && &current.api_key != key {
+                    fields.push("api_key");
+                }
+                if current.generation != spec.generation {
+                    fields.push("generation");
+                }
+                if current.proxy_url != spec.proxy_url {
+                    fields.push("proxy_url");
+                }
+                if spec.default && default_id != Some(current.id) {
+                    set_default = Some(spec.name.clone());
+                }
+                actions.push(if fields.is_empty() {
+                    SyncAction::Unchanged {
+                        id: current.id,
+                        name: current.name.clone(),
+                    }
+                } else {
+                    SyncAction::Update {
+                        id: current.id,
+                        name: current.name.clone(),
+                        fields,
+                    }
+                });
+            }
+            None => {
+                if spec.default {
+                    set_default = Some(spec.name.clone());
+                }
+                actions.push(SyncAction::Create {
+                    name: spec.name.clone(),
+                });
+            }
+        }
+    }
+    for provider in existing
+        .iter()
+        .filter(|p| !specs.iter().any(|s| s.name == p.name))
+    {
+        actions.push(SyncAction::Delete {
+            id: provider.id,
+            name: provider.name.clone(),
+            chats: db::count_chats_for_provider(conn, provider.id)?,
+        });
+    }
+
+    Ok(SyncPlan {
+        actions,
+        set_default,
+        resolved,
+    })
+}
+
+fn spec_for<'a>(plan: &'a SyncPlan, name: &str) -> Result<&'a (ProviderSpec, String)> {
+    plan.resolved
+        .iter()
+        .find(|(s, _)| s.name == name)
+        .ok_or_else(|| anyhow!("provider {} missing from plan", name))
+}
+
+/**
+ * \brief 执行同步计划，每个变更写入一条审计日志。
+ * \details 全部变更在同一事务中完成，任一步失败都不会留下半同步的状态；
+ * 已绑定安全存储别名的 Provider 保留原有密钥，不被环境变量覆盖。
+ */
+pub fn apply_sync(conn: &Connection, plan: &SyncPlan, actor: &str) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let conn = &*tx;
+    for action in &plan.actions {
+        match action {
+            SyncAction::Create { name } => {
+                let (spec, key) = spec_for(plan, name)?;
+                let id = db::insert_provider(
+                    conn,
+                    &spec.name,
+                    &spec.provider_type,
+                    &spec.api_base,
+                    key,
+                    &spec.model,
+                    None,
+                )?;
+                db::set_provider_generation(conn, id, &spec.generation)?;
+                db::set_provider_proxy(conn, id, spec.proxy_url.as_deref())?;
+                let created = db::get_provider_by_id(conn, id)?;
+                db::insert_audit_log(
+                    conn,
+                    actor,
+                    "provider.create",
+                    Some(&format!("provider:{}", id)),
+                    &db::provider_changes(None, created.as_ref()),
+                )?;
+            }
+            SyncAction::Update { id, name, .. } => {
+                let (spec, key) = spec_for(plan, name)?;
+                let before = db::get_provider_by_id(conn, *id)?
+                    .ok_or_else(|| anyhow!("provider id {} not found", id))?;
+                let key = if before.secret_alias.is_some() {
+                    before.api_key.as_str()
+                } else {
+                    key.as_str()
+                };
+                db::update_provider(
+                    conn,
+                    *id,
+                    &spec.name,
+                    &spec.provider_type,
+                    &spec.api_base,
+                    key,
+                    &spec.model,
+                    before.secret_alias.as_deref(),
+                )?;
+                db::set_provider_generation(conn, *id, &spec.generation)?;
+                db::set_provider_proxy(conn, *id, spec.proxy_url.as_deref())?;
+                let after = db::get_provider_by_id(conn, *id)?;
+                db::insert_audit_log(
+                    conn,
+                    actor,
+                    "provider.update",
+                    Some(&format!("provider:{}", id)),
+                    &db::provider_changes(Some(&before), after.as_ref()),
+                )?;
+            }
+            SyncAction::Delete { id, .. } => {
+                let before: Option<Provider> = db::get_provider_by_id(conn, *id)?;
+                db::delete_provider(conn, *id)?;
+                db::insert_audit_log(
+                    conn,
+                    actor,
+                    "provider.delete",
+                    Some(&format!("provider:{}", id)),
+                    &db::provider_changes(before.as_ref(), None),
+                )?;
+            }
+            SyncAction::Unchanged { .. } => {}
+        }
+    }
+
+    if let Some(name) = &plan.set_default {
+        let provider = db::list_providers(conn)?
+            .into_iter()
+            .find(|p| &p.name == name)
+            .ok_or_else(|| anyhow!("provider {} not found after sync", name))?;
+        db::set_default_provider_id(conn, provider.id)?;
+        db::insert_audit_log(
+            conn,
+            actor,
+            "provider.default",
+            Some(&format!("provider:{}", provider.id)),
+            &serde_json::json!({ "default": true }),
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"
+# 团队共享的 Provider 列表
+providers:
+  - name: openai
+    api_base: https://api.openai.com/v1
+    model: gpt-4o-mini
+    api_key_env: OPENAI_API_KEY
+    default: true
+    generation:
+      temperature: 0.2
+      stop: ["END"]
+    proxy_url: socks5://127.0.0.1:1080
+  - name: "local #1"   # 本地模型无需密钥
+    provider: openai
+    api_base: 'http://localhost:11434/v1'
+    model: llama3
+"#;
+
+    fn mem_conn() -> Connection {
+        let conn = Connection::open_in_memory().expect("open in-memory db");
+        db::migrate(&conn).expect("migrate");
+        conn
+    }
+
+    fn env(var: &str) -> Option<String> {
+        (var == "OPENAI_API_KEY").then(|| "sk-test".to_string())
+    }
+
+    #[test]
+    fn test_parse_providers_yaml() {
+        let specs = parse_providers_yaml(SAMPLE).expect("parse");
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].api_key_env.as_deref(), Some("OPENAI_API_KEY"));
+        assert!(specs[0].default);
+        assert_eq!(specs[0].generation.temperature, Some(0.2));
+        assert_eq!(specs[0].generation.stop, vec!["END".to_string()]);
+        assert_eq!(
+            specs[0].proxy_url.as_deref(),
+            Some("socks5://127.0.0.1:1080")
+        );
+        assert_eq!(specs[1].name, "local #1");
+        assert_eq!(specs[1].provider_type, "openai");
+        assert_eq!(specs[1].api_base, "http://localhost:11434/v1");
+        assert!(specs[1].api_key_env.is_none());
+        assert_eq!(specs[1].generation, GenerationSettings::default());
+
+        let bare = parse_providers_yaml(
+            "- name: a\n  type: ollama\n  api_base: http://h/v1\n  model: m\n",
+        )
+        .expect("bare list");
+        assert_eq!(bare[0].provider_type, "ollama");
+        assert!(parse_providers_yaml("").unwrap().is_empty());
+
+        let err =
+            parse_providers_yaml("- name: a\n  api_key: sk-1\n  api_base: http://h\n  model: m\n")
+                .unwrap_err();
+        assert!(err.to_string().contains("api_key_env"));
+        assert!(parse_providers_yaml("- name: a\n  api_base: x\n").is_err());
+        assert!(parse_providers_yaml(
+            "- name: a\n  api_base: http://h\n  model: m\n  colour: red\n"
+        )
+        .is_err());
+        assert!(parse_providers_yaml(
+            "- name: a\n  api_base: http://h\n  model: m\n  proxy_url: ftp://p\n"
+        )
+        .is_err());
+        assert!(parse_providers_yaml("name: a\n").is_err());
+    }
+
+    #[test]
+    fn test_sync_creates_updates_and_deletes_by_name() {
+        let conn = mem_conn();
+        let stale =
+            db::insert_provider(&conn, "stale", "openai", "http://old", "k", "m", None).unwrap();
+        db::insert_provider(
+            &conn,
+            "local #1",
+            "openai",
+            "http://old",
+            "",
+            "llama3",
+            None,
+        )
+        .unwrap();
+        let specs = parse_providers_yaml(SAMPLE).unwrap();
+
+        assert!(plan_sync(&conn, &specs, |_| None).is_err());
+        let plan = plan_sync(&conn, &specs, env).expect("plan");
+        assert!(plan.has_changes());
+        assert!(matches!(&plan.actions[0], SyncAction::Create { name } if name == "openai"));
+        assert!(
+            matches!(&plan.actions[1], SyncAction::Update { fields, .. } if fields == &vec!["api_base"])
+        );
+        assert!(matches!(&plan.actions[2], SyncAction::Delete { id, .. } if *id == stale));
+        assert_eq!(plan.set_default.as_deref(), Some("openai"));
+
+        apply_sync(&conn, &plan, "test").expect("apply");
+        let providers = db::list_providers(&conn).unwrap();
+        assert_eq!(providers.len(), 2);
+        let openai = providers.iter().find(|p| p.name == "openai").unwrap();
+        assert_eq!(openai.api_key, "sk-test");
+        assert_eq!(db::get_default_provider_id(&conn).unwrap(), Some(openai.id));
+
+        assert_eq!(openai.generation.temperature, Some(0.2));
+        assert_eq!(openai.proxy_url.as_deref(), Some("socks5://127.0.0.1:1080"));
+
+        let again = plan_sync(&conn, &specs, env).expect("replan");
+        assert!(!again.has_changes());
+
+        let tweaked = parse_providers_yaml(&SAMPLE.replace("0.2", "0.7")).unwrap();
+        let plan = plan_sync(&conn, &tweaked, env).expect("plan generation change");
+        assert!(
+            matches!(&plan.actions[0], SyncAction::Update { fields, .. } if fields == &vec!["generation"])
+        );
+    }
+
+    #[test]
+    fn test_apply_sync_rolls_back_on_failure() {
+        let conn = mem_conn();
+        let specs = parse_providers_yaml(SAMPLE).unwrap();
+        let mut plan = plan_sync(&conn, &specs, env).expect("plan");
+        plan.set_default = Some("missing".to_string());
+
+        assert!(apply_sync(&conn, &plan, "test").is_err());
+        assert!(db::list_providers(&conn).unwrap().is_empty());
+    }
+}
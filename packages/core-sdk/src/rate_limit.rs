@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::http::Method;
+
+/** \brief 最多保留的桶数量；新客户端到来而桶已满时淘汰最久未出现的桶，避免内存随客户端数量无限增长。 */
+const MAX_BUCKETS: usize = 4096;
+
+/** \brief 后台清理空闲桶的间隔。 */
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/**
+ * \brief 请求计入的限额类别。
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitClass {
+    /** \brief 会向上游模型发请求的接口，计入聊天限额。 */
+    Chat,
+    /** \brief 其余写操作。 */
+    Mutation,
+}
+
+/**
+ * \brief 按方法、路径与查询串归类请求；返回 None 表示不限流。
+ * \details 聊天之外会调用模型的接口（温度对比、重跑、自动标签、补全、健康检查、模型列表、能力探测）
+ * 同样计入聊天限额；按流取消不发起生成，按写操作计数。
+ */
+pub fn classify(method: &Method, path: &str, query: Option<&str>) -> Option<LimitClass> {
+    let is_get = method == Method::GET;
+    let calls_model = if path.starts_with("/api/chat/cancel/") {
+        false
+    } else if path.starts_with("/api/chat/")
+        || path == "/api/chat"
+        || path == "/v1/chat/completions"
+    {
+        true
+    } else if path.starts_with("/api/chats/") {
+        !is_get && (path.ends_with("/temperature-preview") || path.ends_with("/autotag"))
+    } else if path.starts_with("/api/messages/") {
+        !is_get && path.ends_with("/rerun")
+    } else {
+        match path {
+            "/api/health" | "/api/models" => is_get,
+            "/api/health/preview" | "/api/maintenance/backfill" => method == Method::POST,
+            "/api/capabilities" => {
+                is_get && query.is_some_and(|q| q.split('&').any(|kv| kv == "probe=true"))
+            }
+            _ => false,
+        }
+    };
+    if calls_model {
+        Some(LimitClass::Chat)
+    } else if path.starts_with("/api/") && !is_get {
+        Some(LimitClass::Mutation)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/**
+ * \brief 按客户端键（IP 或令牌）隔离的令牌桶限流器。
+ */
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /**
+     * \brief 创建每分钟允许 `limit` 次请求的限流器；`limit` 为 0 时返回 None 表示不限流。
+     */
+    pub fn per_minute(limit: u32) -> Option<Self> {
+        if limit == 0 {
+            return None;
+        }
+        Some(Self {
+            capacity: limit as f64,
+            refill_per_sec: limit as f64 / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /**
+     * \brief 消耗一个令牌；超限时返回建议的重试等待时间。
+     */
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    /**
+     * \brief 移除空闲到足以回满的桶（与从未出现的客户端等价），由服务端按 `SWEEP_INTERVAL` 定时调用。
+     */
+    pub fn sweep(&self) {
+        self.sweep_at(Instant::now());
+    }
+
+    fn sweep_at(&self, now: Instant) {
+        let idle = Duration::from_secs_f64(self.capacity / self.refill_per_sec);
+        let mut guard = self.buckets.lock().expect("lock rate limiter");
+        guard.retain(|_, b| now.saturating_duration_since(b.updated) < idle);
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut guard = self.buckets.lock().expect("lock rate limiter");
+        if guard.len() >= MAX_BUCKETS && !guard.contains_key(key) {
+            // 只在桶已满且出现新客户端时扫描，常规请求不遍历
+            let oldest = guard
+                .iter()
+                .min_by_key(|(_, b)| b.updated)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                guard.remove(&oldest);
+            }
+        }
+        let bucket = guard.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / self.refill_per_sec;
+            Err(Duration::from_secs_f64(wait.max(0.0)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_counts_model_calls_as_chat() {
+        let chat = Some(LimitClass::Chat);
+        let mutation = Some(LimitClass::Mutation);
+        assert_eq!(classify(&Method::GET, "/api/chat/sse", None), chat);
+        assert_eq!(classify(&Method::POST, "/v1/chat/completions", None), chat);
+        assert_eq!(
+            classify(&Method::POST, "/api/chat/cancel/s1", None),
+            mutation
+        );
+        assert_eq!(
+            classify(&Method::POST, "/api/chats/3/temperature-preview", None),
+            chat
+        );
+        assert_eq!(classify(&Method::POST, "/api/chats/3/autotag", None), chat);
+        assert_eq!(classify(&Method::POST, "/api/messages/9/rerun", None), chat);
+        assert_eq!(
+            classify(&Method::POST, "/api/maintenance/backfill", None),
+            chat
+        );
+        assert_eq!(
+            classify(&Method::GET, "/api/maintenance/backfill", None),
+            None
+        );
+        assert_eq!(
+            classify(&Method::DELETE, "/api/maintenance/backfill", None),
+            mutation
+        );
+        assert_eq!(classify(&Method::GET, "/api/health", None), chat);
+        assert_eq!(classify(&Method::POST, "/api/health/preview", None), chat);
+        assert_eq!(classify(&Method::GET, "/api/models", None), chat);
+        assert_eq!(
+            classify(&Method::POST, "/api/models/migrate", None),
+            mutation
+        );
+        assert_eq!(
+            classify(&Method::GET, "/api/capabilities", Some("probe=true")),
+            chat
+        );
+        assert_eq!(classify(&Method::GET, "/api/capabilities", None), None);
+        assert_eq!(classify(&Method::GET, "/api/chats/3/tags", None), None);
+        assert_eq!(classify(&Method::PUT, "/api/chats/3", None), mutation);
+        assert_eq!(classify(&Method::GET, "/healthz", None), None);
+    }
+
+    #[test]
+    fn test_zero_limit_disables_limiter() {
+        assert!(RateLimiter::per_minute(0).is_none());
+    }
+
+    #[test]
+    fn test_bucket_exhausts_and_refills() {
+        let limiter = RateLimiter::per_minute(2).expect("limiter");
+        let start = Instant::now();
+        assert!(limiter.check_at("a", start).is_ok());
+        assert!(limiter.check_at("a", start).is_ok());
+        let wait = limiter
+            .check_at("a", start)
+            .expect_err("third call limited");
+        assert!(wait.as_secs_f64() > 29.0 && wait.as_secs_f64() <= 30.0);
+
+        assert!(limiter.check_at("b", start).is_ok());
+
+        let later = start + Duration::from_secs(31);
+        assert!(limiter.check_at("a", later).is_ok());
+    }
+
+    #[test]
+    fn test_full_map_evicts_least_recently_seen() {
+        let limiter = RateLimiter::per_minute(1).expect("limiter");
+        let start = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            let at = start + Duration::from_millis(i as u64);
+            assert!(limiter.check_at(&format!("ip:{}", i), at).is_ok());
+        }
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check_at("ip:new", later).is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_BUCKETS);
+        assert!(!buckets.contains_key("ip:0"));
+        assert!(buckets.contains_key("ip:1"));
+    }
+
+    #[test]
+    fn test_sweep_drops_idle_buckets_only() {
+        let limiter = RateLimiter::per_minute(2).expect("limiter");
+        let start = Instant::now();
+        assert!(limiter.check_at("idle", start).is_ok());
+        let later = start + Duration::from_secs(61);
+        assert!(limiter.check_at("busy", later).is_ok());
+        limiter.sweep_at(later);
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.contains_key("idle"));
+        assert!(buckets.contains_key("busy"));
+    }
+}
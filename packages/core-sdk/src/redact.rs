@@ -0,0 +1,60 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/**
+ * \brief 按顺序应用的脱敏规则：先匹配密钥与证件号等长串，再匹配电话，避免被拆开替换。
+ */
+static RULES: Lazy<Vec<(Regex, &'static str)>> = Lazy::new(|| {
+    [
+        (
+            r"(?i)\b(?:sk|pk|rk)-[a-z0-9_-]{16,}|\bAKIA[0-9A-Z]{16}\b|\bgh[pousr]_[A-Za-z0-9]{36,}\b|\bBearer\s+[A-Za-z0-9._~+/-]{16,}=*",
+            "[SECRET]",
+        ),
+        (
+            r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}",
+            "[EMAIL]",
+        ),
+        (r"\b\d{17}[\dXx]\b", "[ID]"),
+        (r"\b(?:\d{1,3}\.){3}\d{1,3}\b", "[IP]"),
+        (
+            r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{2,4}\)[\s.-]?|\b\d{2,4}[\s.-])?\b\d{3,4}[\s.-]?\d{4}\b|\b\d{7,15}\b",
+            "[PHONE]",
+        ),
+    ]
+    .into_iter()
+    .map(|(pattern, label)| (Regex::new(pattern).expect("valid redaction pattern"), label))
+    .collect()
+});
+
+/**
+ * \brief 将文本中的常见个人信息（邮箱、电话、身份证号、IP）与 API 密钥替换为占位符。
+ * \details 规则偏保守，宁可误伤长数字串也不漏掉电话号码。
+ */
+pub fn redact_pii(text: &str) -> String {
+    let mut out = text.to_string();
+    for (re, label) in RULES.iter() {
+        if re.is_match(&out) {
+            out = re.replace_all(&out, *label).into_owned();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_pii_replaces_common_identifiers() {
+        let text = "mail a.b+c@example.com, call +1 415-555-0100 or 13800138000, \
+                    key sk-abcdefghijklmnop1234, host 192.168.1.20, id 11010519491231002X";
+        assert_eq!(
+            redact_pii(text),
+            "mail [EMAIL], call [PHONE] or [PHONE], key [SECRET], host [IP], id [ID]"
+        );
+        assert_eq!(
+            redact_pii("version 2.0 shipped in 2024"),
+            "version 2.0 shipped in 2024"
+        );
+    }
+}
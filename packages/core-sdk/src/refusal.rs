@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+
+use crate::{
+    db::{self, MessageVariant, RefusalPolicy},
+    llm,
+    models::Provider,
+    rerun, telemetry,
+};
+
+/** \brief 只在回复开头这么多字符内匹配拒答措辞，避免把正文中的引用误判为拒答。 */
+const REFUSAL_SCAN_CHARS: usize = 240;
+
+/** \brief 常见拒答措辞（小写）。 */
+const REFUSAL_PATTERNS: &[&str] = &[
+    "i'm sorry, but i can't",
+    "i'm sorry, but i cannot",
+    "i’m sorry, but i can’t",
+    "sorry, i can't help with",
+    "i can't help with that",
+    "i cannot help with that",
+    "i can't assist with",
+    "i cannot assist with",
+    "i'm unable to help with",
+    "i am unable to help with",
+    "i won't be able to help with",
+    "as an ai language model, i cannot",
+    "抱歉，我无法",
+    "抱歉，我不能",
+    "我无法协助",
+    "我不能提供这",
+    "作为一个ai",
+    "作为一个人工智能",
+];
+
+/**
+ * \brief 检测回复是否为拒答，返回命中的措辞。
+ */
+pub fn detect_refusal(content: &str) -> Option<&'static str> {
+    let head: String = content
+        .trim_start()
+        .chars()
+        .take(REFUSAL_SCAN_CHARS)
+        .collect::<String>()
+        .to_lowercase();
+    REFUSAL_PATTERNS.iter().find(|p| head.contains(*p)).copied()
+}
+
+/**
+ * \brief 检测到拒答时在消息元数据中写入 `refusal` 字段；未命中时原样返回。
+ */
+pub fn merge_into_metadata(metadata: Option<&Value>, content: &str) -> Option<Value> {
+    let pattern = match detect_refusal(content) {
+        Some(p) => p,
+        None => return metadata.cloned(),
+    };
+    let mut merged = match metadata {
+        Some(Value::Object(map)) => map.clone(),
+        _ => serde_json::Map::new(),
+    };
+    merged.insert("refusal".to_string(), json!({ "pattern": pattern }));
+    Some(Value::Object(merged))
+}
+
+/**
+ * \brief 回复为拒答且策略开启自动重试时返回策略，调用方据此解析重试 Provider 并调用 `retry`。
+ */
+pub fn retry_policy_for(conn: &rusqlite::Connection, content: &str) -> Option<RefusalPolicy> {
+    detect_refusal(content)?;
+    match db::get_refusal_policy(conn) {
+        Ok(policy) if policy.retry => Some(policy),
+        Ok(_) => None,
+        Err(e) => {
+            telemetry::log_error("refusal", &format!("load policy failed: {}", e));
+            None
+        }
+    }
+}
+
+/**
+ * \brief 对被拒答的助手消息重试一次：在系统指令末尾追加提示，结果保存为对应用户消息的备选回复。
+ * \details 数据库连接不跨越 await 持有，可在后台任务中调用。
+ */
+pub async fn retry(
+    provider: &Provider,
+    assistant_message_id: i64,
+    policy: &RefusalPolicy,
+) -> Result<MessageVariant> {
+    let (user_message_id, system) = db::run(move |conn| {
+        let user_message_id = db::user_message_before(conn, assistant_message_id)?
+            .ok_or_else(|| anyhow!("no user message before {}", assistant_message_id))?;
+        let system = db::message_context(conn, user_message_id)?
+            .and_then(|(_, history)| history.into_iter().find(|m| m.role == "system"))
+            .map(|m| m.content);
+        Ok((user_message_id, system))
+    })
+    .await?;
+    let nudge = policy.nudge.trim();
+    let overrides = llm::RequestOverrides {
+        system_instruction: Some(match system {
+            Some(sys) => format!("{}\n\n{}", sys.trim_end(), nudge),
+            None => nudge.to_string(),
+        }),
+        ..Default::default()
+    };
+    let variant = rerun::rerun_message(provider, user_message_id, &overrides).await?;
+    telemetry::log_event(
+        "refusal",
+        &format!(
+            "retried message_id={} variant_id={} provider={} refused_again={}",
+            assistant_message_id,
+            variant.id,
+            provider.name,
+            detect_refusal(&variant.content).is_some()
+        ),
+    );
+    Ok(variant)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_refusal_matches_opening_only() {
+        assert_eq!(
+            detect_refusal("  I'm sorry, but I can't help with that request."),
+            Some("i'm sorry, but i can't")
+        );
+        assert_eq!(
+            detect_refusal("很抱歉，我无法提供该信息。"),
+            Some("抱歉，我无法")
+        );
+        assert!(detect_refusal("Here is the code you asked for.").is_none());
+        let late = format!("{} I can't help with that", "x".repeat(REFUSAL_SCAN_CHARS));
+        assert!(detect_refusal(&late).is_none());
+    }
+
+    #[test]
+    fn test_merge_into_metadata_keeps_existing_fields() {
+        let base = json!({ "overrides": { "temperature": 0.2 } });
+        let merged = merge_into_metadata(Some(&base), "I cannot assist with that.").unwrap();
+        assert_eq!(merged["overrides"]["temperature"], json!(0.2));
+        assert_eq!(merged["refusal"]["pattern"], json!("i cannot assist with"));
+        assert_eq!(merge_into_metadata(Some(&base), "Sure!"), Some(base));
+        assert!(merge_into_metadata(None, "Sure!").is_none());
+    }
+}
@@ -0,0 +1,193 @@
+use anyhow::{anyhow, bail, Result};
+use futures_util::future::join_all;
+use serde::Serialize;
+
+use crate::{
+    db::{self, MessageVariant},
+    llm,
+    models::{Message, Provider},
+    telemetry,
+};
+
+/** \brief 温度预览每个变体默认的输出 token 上限。 */
+pub const PREVIEW_MAX_TOKENS: u32 = 256;
+
+/** \brief 温度预览允许的输出 token 上限。 */
+pub const PREVIEW_MAX_TOKENS_LIMIT: u32 = 1024;
+
+/** \brief 单次温度预览最多并发的变体数。 */
+pub const PREVIEW_MAX_VARIANTS: usize = 6;
+
+/**
+ * \brief 用指定 Provider/模型重新回答一条用户消息，结果保存为该消息的备选回复。
+ * \details 只使用截至该消息的历史，不修改会话本身；数据库连接不跨越 await 持有。
+ */
+pub async fn rerun_message(
+    provider: &Provider,
+    message_id: i64,
+    overrides: &llm::RequestOverrides,
+) -> Result<MessageVariant> {
+    let (chat_id, history, stops) = db::run(move |conn| {
+        let (chat_id, history) = db::message_context(conn, message_id)?
+            .ok_or_else(|| anyhow!("message {} not found", message_id))?;
+        Ok((chat_id, history, db::get_stop_strings(conn)?))
+    })
+    .await?;
+
+    let started = std::time::Instant::now();
+    let reply = llm::chat_once_with(provider, &history, overrides).await?;
+    let reply = llm::StopTrimmer::new(&stops).trim_full(&reply);
+    if reply.is_empty() {
+        bail!("model returned an empty reply");
+    }
+    let elapsed = started.elapsed().as_millis() as i64;
+    let timing = db::GenerationTiming {
+        provider_id: Some(provider.id),
+        first_token_ms: Some(elapsed),
+        duration_ms: Some(elapsed),
+    };
+
+    let model = overrides.model_for(provider).to_string();
+    let variant_model = model.clone();
+    let variant = db::run(move |conn| {
+        let id = db::insert_message_variant(conn, message_id, &variant_model, &reply, &timing)?;
+        db::list_message_variants(conn, message_id)?
+            .into_iter()
+            .find(|v| v.id == id)
+            .ok_or_else(|| anyhow!("variant {} vanished after insert", id))
+    })
+    .await?;
+    telemetry::log_event(
+        "rerun",
+        &format!(
+            "chat_id={} message_id={} provider={}({}) model={}",
+            chat_id, message_id, provider.name, provider.provider_type, model
+        ),
+    );
+    Ok(variant)
+}
+
+/**
+ * \brief 温度预览中的一个变体；失败时 `content` 为空并带错误信息。
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct TemperatureVariant {
+    pub temperature: f64,
+    pub content: Option<String>,
+    pub duration_ms: i64,
+    pub error: Option<String>,
+}
+
+/**
+ * \brief 校验并整理温度列表：去重、按升序排列，每项须在 0~2 之间。
+ */
+pub fn normalize_temperatures(raw: &[f64]) -> Result<Vec<f64>> {
+    let mut temps: Vec<f64> = Vec::new();
+    for &t in raw {
+        if !(0.0..=2.0).contains(&t) {
+            bail!("temperature {} out of range 0~2", t);
+        }
+        if !temps.iter().any(|x| (x - t).abs() < f64::EPSILON) {
+            temps.push(t);
+        }
+    }
+    if temps.is_empty() {
+        bail!("at least one temperature is required");
+    }
+    if temps.len() > PREVIEW_MAX_VARIANTS {
+        bail!("at most {} temperatures per preview", PREVIEW_MAX_VARIANTS);
+    }
+    temps.sort_by(|a, b| a.total_cmp(b));
+    Ok(temps)
+}
+
+/**
+ * \brief 用一组温度并发生成同一提示的简短回复，便于比较后选定会话参数。
+ * \details 有会话时以其历史为上下文；结果不写入数据库。每个变体按 `overrides.max_tokens`
+ * （默认 `PREVIEW_MAX_TOKENS`，不超过 `PREVIEW_MAX_TOKENS_LIMIT`）截断。
+ */
+pub async fn preview_temperatures(
+    provider: &Provider,
+    chat_id: Option<i64>,
+    prompt: &str,
+    temperatures: &[f64],
+    overrides: &llm::RequestOverrides,
+) -> Result<Vec<TemperatureVariant>> {
+    let temperatures = normalize_temperatures(temperatures)?;
+    if prompt.trim().is_empty() {
+        bail!("prompt is empty");
+    }
+    let (mut history, stops) = db::run(move |conn| {
+        let history = match chat_id {
+            Some(id) => db::load_messages(conn, id)?,
+            None => Vec::new(),
+        };
+        Ok((history, db::get_stop_strings(conn)?))
+    })
+    .await?;
+    history.push(Message {
+        role: "user".to_string(),
+        content: prompt.trim().to_string(),
+    });
+    let max_tokens = overrides
+        .max_tokens
+        .unwrap_or(PREVIEW_MAX_TOKENS)
+        .min(PREVIEW_MAX_TOKENS_LIMIT);
+
+    let history = &history;
+    let stops = &stops;
+    let variants = join_all(temperatures.iter().map(|&temperature| async move {
+        let overrides = llm::RequestOverrides {
+            temperature: Some(temperature),
+            max_tokens: Some(max_tokens),
+            ..overrides.clone()
+        };
+        let started = std::time::Instant::now();
+        let result = llm::chat_once_with(provider, history, &overrides).await;
+        let duration_ms = started.elapsed().as_millis() as i64;
+        match result {
+            Ok(reply) => TemperatureVariant {
+                temperature,
+                content: Some(llm::StopTrimmer::new(stops).trim_full(&reply)),
+                duration_ms,
+                error: None,
+            },
+            Err(e) => TemperatureVariant {
+                temperature,
+                content: None,
+                duration_ms,
+                error: Some(e.to_string()),
+            },
+        }
+    }))
+    .await;
+
+    telemetry::log_event(
+        "rerun",
+        &format!(
+            "temperature preview chat_id={:?} provider={}({}) variants={} failed={}",
+            chat_id,
+            provider.name,
+            provider.provider_type,
+            variants.len(),
+            variants.iter().filter(|v| v.error.is_some()).count()
+        ),
+    );
+    Ok(variants)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_temperatures_dedupes_sorts_and_bounds() {
+        assert_eq!(
+            normalize_temperatures(&[1.0, 0.2, 1.0, 0.7]).unwrap(),
+            vec![0.2, 0.7, 1.0]
+        );
+        assert!(normalize_temperatures(&[]).is_err());
+        assert!(normalize_temperatures(&[2.5]).is_err());
+        assert!(normalize_temperatures(&[0.0, 0.2, 0.4, 0.6, 0.8, 1.0, 1.2]).is_err());
+    }
+}
@@ -1,413 +1,3482 @@
-use std::convert::Infallible;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use axum::{
-    extract::{Path, Query},
-    response::sse::{Event, KeepAlive, Sse},
-    routing::{delete, get, get_service, post, put},
+    extract::{
+        rejection::JsonRejection, ConnectInfo, DefaultBodyLimit, Extension, Path, Query, Request,
+        State,
+    },
+    http::{header, Method, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{delete, get, get_service, patch, post, put},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
-use tower_http::services::ServeDir;
+use tokio_util::sync::CancellationToken;
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    services::ServeDir,
+};
+use tracing::Instrument;
+
+use crate::{
+    autotag, backfill, base_url, context, db, exporter, finalize,
+    importer::{self, ImportFormat},
+    language, llm, markdown, model_cache,
+    models::{
+        ContextStrategy, GenerationSettings, HostPolicy, Message, Provider, ReplyLanguage,
+        RequestSigning, RetryPolicy,
+    },
+    openai_compat, paths, pipeline,
+    rate_limit::{LimitClass, RateLimiter, SWEEP_INTERVAL},
+    refusal, rerun, sql_console, summarize, telemetry,
+    transcript::{self, TranscriptTee},
+};
 
-use crate::{db, llm, telemetry, models::Provider};
+/** \brief 写操作接口默认每分钟限额。 */
+const DEFAULT_MUTATION_RATE_LIMIT: u32 = 120;
+/** \brief 聊天接口默认每分钟限额。 */
+const DEFAULT_CHAT_RATE_LIMIT: u32 = 30;
+/** \brief SSE 断线后建议客户端等待的重连间隔（毫秒）。 */
+const DEFAULT_SSE_RETRY_MS: u32 = 3000;
 
 /**
- * \brief 启动本地 HTTP 服务，提供静态前端与 API。
- * \param addr 监听地址，如 "127.0.0.1:5173"
+ * \brief HTTP 服务运行参数。
  */
-pub async fn run(addr: &str) -> Result<()> {
-    let ui_root =
-        std::env::var("DREAMQUILL_UI_DIR").unwrap_or_else(|_| "packages/ui/dist".to_string());
-    let fallback_root =
-        std::env::var("DREAMQUILL_UI_FALLBACK").unwrap_or_else(|_| "web".to_string());
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /** \brief 每个客户端每分钟可调用的写操作次数，0 表示不限流。 */
+    pub mutation_rate_limit: u32,
+    /** \brief 每个客户端每分钟可发起的聊天次数，0 表示不限流。 */
+    pub chat_rate_limit: u32,
+    /** \brief 由环境变量提供的内存 Provider，存在时作为默认 Provider，不写入数据库。 */
+    pub env_provider: Option<Provider>,
+    /** \brief 删除类接口需两步确认：首次调用只返回影响说明与确认令牌。 */
+    pub confirm_deletes: bool,
+    /** \brief 聊天 SSE 流中 `retry:` 字段下发的重连间隔（毫秒）。 */
+    pub sse_retry_ms: u32,
+    /** \brief 流式增量转录文件，设置后每个原始增量都带时间戳与流 ID 追加写入。 */
+    pub transcript_path: Option<PathBuf>,
+    /** \brief `/api` 与 `/v1` 接口要求的 Bearer 令牌；为空时沿用数据库中保存的令牌，都没有则不鉴权。 */
+    pub auth_token: Option<String>,
+    /** \brief 允许跨域访问的来源（如 `http://localhost:3000`，`*` 表示任意来源）；为空时不启用 CORS。 */
+    pub cors_origins: Vec<String>,
+    /** \brief 跨域请求允许的方法；为空时使用 `DEFAULT_CORS_METHODS`。 */
+    pub cors_methods: Vec<String>,
+}
 
-    let static_handler = if std::path::Path::new(&ui_root).exists() {
-        ServeDir::new(ui_root)
-    } else {
-        ServeDir::new(fallback_root)
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            mutation_rate_limit: DEFAULT_MUTATION_RATE_LIMIT,
+            chat_rate_limit: DEFAULT_CHAT_RATE_LIMIT,
+            env_provider: None,
+            confirm_deletes: false,
+            sse_retry_ms: DEFAULT_SSE_RETRY_MS,
+            transcript_path: None,
+            auth_token: None,
+            cors_origins: Vec::new(),
+            cors_methods: Vec::new(),
+        }
     }
-    .append_index_html_on_directories(true);
+}
 
-    let static_service = get_service(static_handler);
+impl ServerOptions {
+    /**
+     * \brief 从环境变量读取参数，未设置的项使用默认值。
+     * \details 支持 `DREAMQUILL_RATE_LIMIT` 与 `DREAMQUILL_CHAT_RATE_LIMIT`（每分钟次数）、
+     * `DREAMQUILL_CONFIRM_DELETES`（`1`/`true` 开启删除确认）、`DREAMQUILL_SSE_RETRY_MS`（SSE 重连间隔），
+     * `DREAMQUILL_TRANSCRIPT`（流式转录文件路径）、`DREAMQUILL_AUTH_TOKEN`（接口访问令牌），
+     * `DREAMQUILL_CORS_ORIGINS` 与 `DREAMQUILL_CORS_METHODS`（逗号分隔的跨域来源与方法），
+     * 以及 `DREAMQUILL_PROVIDER_*` 系列 Provider 配置（见 `env_provider_from_env`）。
+     */
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            mutation_rate_limit: env_u32("DREAMQUILL_RATE_LIMIT")
+                .unwrap_or(defaults.mutation_rate_limit),
+            chat_rate_limit: env_u32("DREAMQUILL_CHAT_RATE_LIMIT")
+                .unwrap_or(defaults.chat_rate_limit),
+            env_provider: env_provider_from_env(),
+            confirm_deletes: std::env::var("DREAMQUILL_CONFIRM_DELETES")
+                .map(|v| matches!(v.trim(), "1" | "true" | "yes"))
+                .unwrap_or(defaults.confirm_deletes),
+            sse_retry_ms: env_u32("DREAMQUILL_SSE_RETRY_MS").unwrap_or(defaults.sse_retry_ms),
+            transcript_path: std::env::var("DREAMQUILL_TRANSCRIPT")
+                .ok()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from),
+            auth_token: std::env::var("DREAMQUILL_AUTH_TOKEN")
+                .ok()
+                .filter(|v| !v.trim().is_empty()),
+            cors_origins: env_list("DREAMQUILL_CORS_ORIGINS"),
+            cors_methods: env_list("DREAMQUILL_CORS_METHODS"),
+        }
+    }
+}
 
-    let app = Router::new()
-        .route("/api/config", get(get_config).post(set_config))
-        .route("/api/providers", get(get_providers).post(create_provider))
-        .route(
-            "/api/providers/{id}",
-            put(update_provider).delete(delete_provider),
-        )
-        .route("/api/providers/{id}/select", post(select_provider))
-        .route("/api/chats", get(list_chats))
-        .route("/api/chats/{id}/messages", get(get_chat_messages))
-        .route("/api/chats/{id}", delete(remove_chat).put(rename_chat))
-        .route("/api/chats/{id}/branch", post(branch_chat))
-        .route("/api/models", get(list_models))
-        .route("/api/health", get(health_check))
-        .route("/api/health/preview", post(health_check_preview))
-        .route("/api/chat/sse", get(chat_sse))
-        .fallback_service(static_service);
+/** \brief 环境变量 Provider 使用的保留 ID，数据库自增主键从 1 开始，不会冲突。 */
+pub const ENV_PROVIDER_ID: i64 = 0;
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    println!("Server listening on http://{}", addr);
-    axum::serve(listener, app).await?;
-    Ok(())
+/** \brief 运行期的环境变量 Provider 覆盖层。 */
+static ENV_PROVIDER: OnceLock<Provider> = OnceLock::new();
+
+/**
+ * \brief 从 `DREAMQUILL_PROVIDER_TYPE/BASE/KEY/MODEL`（可选 `DREAMQUILL_PROVIDER_NAME`、`DREAMQUILL_PROVIDER_PROXY`）构造 Provider。
+ * \details BASE 与 MODEL 必填；TYPE 缺省为 openai。
+ */
+pub fn env_provider_from_env() -> Option<Provider> {
+    let var = |key: &str| {
+        std::env::var(key)
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let api_base = var("DREAMQUILL_PROVIDER_BASE")?;
+    let model = var("DREAMQUILL_PROVIDER_MODEL")?;
+    Some(Provider {
+        id: ENV_PROVIDER_ID,
+        name: var("DREAMQUILL_PROVIDER_NAME").unwrap_or_else(|| "env".to_string()),
+        api_base,
+        api_key: var("DREAMQUILL_PROVIDER_KEY").unwrap_or_default(),
+        model,
+        provider_type: var("DREAMQUILL_PROVIDER_TYPE").unwrap_or_else(|| "openai".to_string()),
+        secret_alias: None,
+        api_prefix: None,
+        signing: None,
+        generation: Default::default(),
+        proxy_url: var("DREAMQUILL_PROVIDER_PROXY"),
+        api_key_unreadable: false,
+    })
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ProviderInput {
-    /** \brief Provider 名称 */
-    #[serde(default)]
-    name: Option<String>,
-    /** \brief Provider 类型 */
-    provider: String,
-    /** \brief API 基地址 */
-    api_base: String,
-    /** \brief API 密钥 */
-    api_key: String,
-    /** \brief 模型名 */
-    model: String,
-    #[serde(default)]
-    telemetry_enabled: Option<bool>,
-    #[serde(default)]
-    set_default: Option<bool>,
+fn env_provider() -> Option<&'static Provider> {
+    ENV_PROVIDER.get()
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ProviderRequest {
-    name: String,
-    provider: String,
-    api_base: String,
-    api_key: String,
-    model: String,
-    #[serde(default)]
-    telemetry_enabled: Option<bool>,
-    #[serde(default)]
-    set_default: Option<bool>,
+/**
+ * \brief 按 ID 解析 Provider，保留 ID 指向环境变量覆盖层。
+ */
+fn resolve_provider_by_id(conn: &rusqlite::Connection, id: i64) -> Result<Option<Provider>> {
+    if id == ENV_PROVIDER_ID {
+        return Ok(env_provider().cloned());
+    }
+    db::get_provider_by_id(conn, id)
 }
 
-#[derive(Serialize, Debug)]
-struct ProviderItem {
-    id: i64,
-    name: String,
-    provider: String,
-    api_base: String,
-    api_key: String,
-    model: String,
-    is_default: bool,
+/**
+ * \brief 默认 Provider：环境变量覆盖层优先，其次为数据库中的默认项。
+ */
+fn resolve_default_provider(conn: &rusqlite::Connection) -> Result<Option<Provider>> {
+    match env_provider() {
+        Some(p) => Ok(Some(p.clone())),
+        None => db::get_default_provider(conn),
+    }
 }
 
-#[derive(Serialize, Debug)]
-struct ProvidersState {
-    providers: Vec<ProviderItem>,
-    default_provider_id: Option<i64>,
-    telemetry_enabled: bool,
+/**
+ * \brief 后台预热默认与最近使用的 Provider 的模型列表，进度见 `/readyz` 的 `model_warmup`。
+ */
+fn start_model_warmup() {
+    let mut providers: Vec<Provider> = env_provider().cloned().into_iter().collect();
+    match db::open_default_db().and_then(|conn| {
+        let ids = model_cache::warmup_targets(&conn)?;
+        ids.into_iter()
+            .map(|id| db::get_provider_by_id(&conn, id))
+            .collect::<Result<Vec<_>>>()
+    }) {
+        Ok(found) => providers.extend(found.into_iter().flatten()),
+        Err(e) => telemetry::log_error("server", &format!("model warmup targets: {}", e)),
+    }
+    let status = model_cache::start_warmup(providers);
+    if status.total > 0 {
+        println!("Warming model lists for {} provider(s)", status.total);
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct ModelQuery {
-    provider_id: Option<i64>,
+/**
+ * \brief 会话绑定的 Provider，兼容绑定到环境变量覆盖层的会话。
+ */
+fn resolve_provider_for_chat(
+    conn: &rusqlite::Connection,
+    chat_id: i64,
+) -> Result<Option<Provider>> {
+    if let Some(p) = db::get_provider_for_chat(conn, chat_id)? {
+        return Ok(Some(p));
+    }
+    match db::get_chat(conn, chat_id)?.and_then(|c| c.provider_id) {
+        Some(ENV_PROVIDER_ID) => Ok(env_provider().cloned()),
+        _ => Ok(None),
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct ChatListQuery {
-    provider_id: Option<i64>,
+/** \brief 是否开启删除两步确认，启动时由 `ServerOptions` 设置。 */
+static CONFIRM_DELETES: OnceLock<bool> = OnceLock::new();
+
+/** \brief SSE 重连间隔，启动时由 `ServerOptions` 设置。 */
+static SSE_RETRY: OnceLock<Duration> = OnceLock::new();
+
+/** \brief 流式增量转录，启动时按 `ServerOptions::transcript_path` 打开。 */
+static TRANSCRIPT: OnceLock<TranscriptTee> = OnceLock::new();
+
+/**
+ * \brief ETag 中的进程标识：数据库重建后版本号可能重复，环境变量 Provider 也只在重启时变化，重启即令旧 ETag 失效。
+ */
+static ETAG_EPOCH: OnceLock<String> = OnceLock::new();
+
+/**
+ * \brief 按数据版本生成弱 ETag，无需读取列表本身；`variant` 区分同一资源的不同查询参数。
+ */
+fn revision_etag(conn: &rusqlite::Connection, scopes: &[&str], variant: &str) -> Result<String> {
+    let epoch = ETAG_EPOCH.get_or_init(|| {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        format!("{:x}", nanos)
+    });
+    let mut tag = format!("W/\"{}-{}", epoch, variant);
+    for scope in scopes {
+        tag.push_str(&format!("-{}", db::data_revision(conn, scope)?));
+    }
+    tag.push('"');
+    Ok(tag)
 }
 
-#[derive(Serialize, Debug)]
-struct ChatSummaryDto {
-    id: i64,
-    title: String,
-    provider_id: Option<i64>,
+/** \brief 请求的 `If-None-Match` 命中当前 ETag 时返回 304（弱比较）。 */
+fn not_modified(headers: &axum::http::HeaderMap, etag: &str) -> Option<Response> {
+    let opaque = etag.trim_start_matches("W/");
+    let matched = headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == opaque);
+    matched.then(|| (StatusCode::NOT_MODIFIED, etag_headers(etag)).into_response())
 }
 
-#[derive(Serialize, Debug)]
-struct ChatListResponse {
-    chats: Vec<ChatSummaryDto>,
+/** \brief 带 ETag 的 JSON 响应；`no-cache` 让浏览器每次携带 `If-None-Match` 重新验证。 */
+fn with_etag<T: Serialize>(body: T, etag: &str) -> Response {
+    (etag_headers(etag), Json(body)).into_response()
 }
 
-#[derive(Serialize, Debug)]
-struct ChatMessageDto {
-    id: i64,
-    role: String,
-    content: String,
+fn etag_headers(etag: &str) -> [(header::HeaderName, String); 2] {
+    [
+        (header::ETAG, etag.to_string()),
+        (header::CACHE_CONTROL, "no-cache".to_string()),
+    ]
 }
 
-#[derive(Serialize, Debug)]
-struct ChatMessagesResponse {
+/**
+ * \brief 进行中的聊天生成的取消令牌，按会话 ID 索引；值为 `(登记序号, 令牌)`。
+ * \details 客户端断线不会取消生成（重连后可续传），只有显式调用取消接口才会中止上游请求。
+ */
+type ActiveGenerations = HashMap<i64, Vec<(u64, CancellationToken)>>;
+static ACTIVE_GENERATIONS: OnceLock<Mutex<ActiveGenerations>> = OnceLock::new();
+static NEXT_GENERATION_SEQ: AtomicU64 = AtomicU64::new(1);
+
+fn active_generations() -> &'static Mutex<ActiveGenerations> {
+    ACTIVE_GENERATIONS.get_or_init(Default::default)
+}
+
+/**
+ * \brief 生成任务登记，离开作用域时自动注销。
+ */
+struct GenerationGuard {
     chat_id: i64,
-    provider_id: Option<i64>,
-    messages: Vec<ChatMessageDto>,
+    seq: u64,
 }
 
-#[derive(Deserialize, Debug)]
-struct BranchRequest {
-    /** \brief 新聊天标题，可选。 */
-    title: Option<String>,
-    /** \brief 截断到的消息 ID（包含该消息）。 */
-    until_message_id: Option<i64>,
+impl Drop for GenerationGuard {
+    fn drop(&mut self) {
+        if let Ok(mut map) = active_generations().lock() {
+            if let Some(entries) = map.get_mut(&self.chat_id) {
+                entries.retain(|(seq, _)| *seq != self.seq);
+                if entries.is_empty() {
+                    map.remove(&self.chat_id);
+                }
+            }
+        }
+    }
 }
 
-#[derive(Serialize, Debug)]
-struct BranchResponse {
-    chat_id: i64,
-    title: String,
+fn register_generation(chat_id: i64) -> (CancellationToken, GenerationGuard) {
+    let token = CancellationToken::new();
+    let guard = track_generation(chat_id, token.clone());
+    (token, guard)
 }
 
-#[derive(Deserialize, Debug)]
-struct RenameChatRequest {
-    /** \brief 新的会话标题。 */
-    title: String,
+/** \brief 以已有的取消令牌登记生成（如按 stream_id 登记的流），会话级取消同样生效。 */
+fn track_generation(chat_id: i64, token: CancellationToken) -> GenerationGuard {
+    let seq = NEXT_GENERATION_SEQ.fetch_add(1, Ordering::Relaxed);
+    if let Ok(mut map) = active_generations().lock() {
+        map.entry(chat_id).or_default().push((seq, token));
+    }
+    GenerationGuard { chat_id, seq }
 }
 
-#[derive(Deserialize, Debug)]
-struct HealthPreviewRequest {
-    /** \brief 可选的显示名称。 */
-    #[serde(default)]
-    name: Option<String>,
-    /** \brief Provider 类型。 */
-    provider: String,
-    /** \brief API 基地址。 */
-    api_base: String,
-    /** \brief API 密钥。 */
-    api_key: String,
-    /** \brief 默认模型名称。 */
-    model: String,
+/** \brief 取消会话所有进行中的生成，返回取消的个数。 */
+fn cancel_generations(chat_id: i64) -> usize {
+    active_generations()
+        .lock()
+        .map(|map| {
+            map.get(&chat_id)
+                .map(|entries| {
+                    entries.iter().for_each(|(_, token)| token.cancel());
+                    entries.len()
+                })
+                .unwrap_or(0)
+        })
+        .unwrap_or(0)
 }
 
-fn build_provider_state(conn: &rusqlite::Connection) -> Result<ProvidersState, anyhow::Error> {
-    let providers = db::list_providers(conn)?;
-    let default_id = db::get_default_provider_id(conn)?;
-    let telemetry_enabled = db::get_telemetry_enabled(conn)?;
-    let items = providers
-        .into_iter()
-        .map(|p| ProviderItem {
-            id: p.id,
-            name: p.name,
-            provider: p.provider_type,
-            api_base: p.api_base,
-            api_key: if p.secret_alias.is_some() {
-                String::new()
-            } else {
-                p.api_key
-            },
-            model: p.model,
-            is_default: default_id.map(|d| d == p.id).unwrap_or(false),
+/** \brief 取消所有进行中的生成，返回取消的个数；已收到的部分照常保存。 */
+fn cancel_all_generations() -> usize {
+    active_generations()
+        .lock()
+        .map(|map| {
+            let tokens: Vec<_> = map.values().flatten().map(|(_, token)| token).collect();
+            tokens.iter().for_each(|token| token.cancel());
+            tokens.len()
         })
-        .collect();
-    telemetry::set_enabled(telemetry_enabled);
-    Ok(ProvidersState {
-        providers: items,
-        default_provider_id: default_id,
-        telemetry_enabled,
-    })
+        .unwrap_or(0)
 }
 
-/**
- * \brief 获取当前默认 Provider 配置。
- */
-async fn get_config() -> Result<Json<ProvidersState>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    let state = build_provider_state(&conn).map_err(internal_err)?;
-    Ok(Json(state))
+fn active_generation_count() -> usize {
+    active_generations()
+        .lock()
+        .map(|map| map.values().map(Vec::len).sum())
+        .unwrap_or(0)
 }
 
+/** \brief 收到退出信号后，进行中的生成自然结束的宽限时间，超时后取消并保存已生成的部分。 */
+const SHUTDOWN_GENERATION_GRACE: Duration = Duration::from_secs(10);
+/** \brief 取消后等待生成任务写完回复的最长时间。 */
+const SHUTDOWN_PERSIST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/** \brief 已收到退出信号；就绪探针据此返回 503，让负载均衡停止派发新请求。 */
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN_SIGNALLED_AT: OnceLock<Instant> = OnceLock::new();
+
 /**
- * \brief 设置默认 Provider 配置。
+ * \brief 退出前等待生成任务结束：宽限时间内任其完成，之后取消剩余任务并等待其保存部分回复。
+ * \details 客户端已断开的生成不占用连接，`axum::serve` 返回时可能仍在运行，须在此等待。
  */
-async fn set_config(
-    Json(input): Json<ProviderInput>,
-) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    let set_default = input.set_default.unwrap_or(true);
-    let name = input.name.unwrap_or_else(|| "default".to_string());
-    let id = if set_default {
-        db::upsert_default_provider(
-            &conn,
-            &name,
-            &input.provider,
-            &input.api_base,
-            &input.api_key,
-            &input.model,
-            None,
-        )
-        .map_err(internal_err)?
-    } else {
-        db::insert_provider(
-            &conn,
-            &name,
-            &input.provider,
-            &input.api_base,
-            &input.api_key,
-            &input.model,
-            None,
-        )
-        .map_err(internal_err)?
-    };
-    if let Some(enabled) = input.telemetry_enabled {
-        db::set_telemetry_enabled(&conn, enabled).map_err(internal_err)?;
-        telemetry::set_enabled(enabled);
+async fn drain_generations(signalled_at: Instant) {
+    let cancel_at = signalled_at + SHUTDOWN_GENERATION_GRACE;
+    let deadline = cancel_at + SHUTDOWN_PERSIST_TIMEOUT;
+    let mut cancelled = false;
+    loop {
+        let active = active_generation_count();
+        if active == 0 {
+            return;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            telemetry::log_error(
+                "server",
+                &format!("shutdown with {} generation(s) still running", active),
+            );
+            return;
+        }
+        if now >= cancel_at && !cancelled {
+            cancelled = true;
+            cancel_all_generations();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
     }
-    Ok(Json(serde_json::json!({"id": id})))
 }
 
 /**
- * \brief 获取 Provider 列表。
+ * \brief 进行中的生成流，按 `stream_id` 索引；其他客户端可经 `GET /api/streams/{id}/subscribe` 中途接入。
  */
-async fn get_providers() -> Result<Json<ProvidersState>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    let state = build_provider_state(&conn).map_err(internal_err)?;
-    Ok(Json(state))
+type ActiveStreams = HashMap<String, Arc<Mutex<StreamBroadcast>>>;
+static ACTIVE_STREAMS: OnceLock<Mutex<ActiveStreams>> = OnceLock::new();
+
+fn active_streams() -> &'static Mutex<ActiveStreams> {
+    ACTIVE_STREAMS.get_or_init(Default::default)
 }
 
 /**
- * \brief 新增 Provider。
+ * \brief 一次生成的广播状态：保存 meta 与已输出的回复，供中途接入的订阅者先补发前缀再接收实时输出。
  */
-async fn create_provider(
-    Json(payload): Json<ProviderRequest>,
-) -> Result<Json<ProvidersState>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    let set_default = payload.set_default.unwrap_or(false);
-    if let Some(enabled) = payload.telemetry_enabled {
-        db::set_telemetry_enabled(&conn, enabled).map_err(internal_err)?;
-        telemetry::set_enabled(enabled);
-    }
-    if set_default {
-        db::upsert_default_provider(
-            &conn,
-            &payload.name,
-            &payload.provider,
-            &payload.api_base,
-            &payload.api_key,
-            &payload.model,
-            None,
-        )
-        .map_err(internal_err)?;
-    } else {
-        db::insert_provider(
-            &conn,
-            &payload.name,
-            &payload.provider,
-            &payload.api_base,
-            &payload.api_key,
-            &payload.model,
-            None,
-        )
-        .map_err(internal_err)?;
+#[derive(Default)]
+struct StreamBroadcast {
+    /** \brief 生成任务的取消令牌，`POST /api/chat/cancel/{stream_id}` 据此中止生成。 */
+    cancel: CancellationToken,
+    meta: Option<ChatOutput>,
+    reply: String,
+    subscribers: Vec<mpsc::UnboundedSender<ChatOutput>>,
+}
+
+impl StreamBroadcast {
+    fn publish(&mut self, output: &ChatOutput) {
+        match output {
+            ChatOutput::Meta { .. } => self.meta = Some(output.clone()),
+            ChatOutput::Chunk { text, .. } => self.reply.push_str(text),
+            _ => {}
+        }
+        self.subscribers
+            .retain(|subscriber| subscriber.send(output.clone()).is_ok());
+        if matches!(output, ChatOutput::Done { .. }) {
+            self.subscribers.clear();
+        }
     }
-    telemetry::log_event(
-        "server.provider",
-        &format!("create name={} type={}", payload.name, payload.provider),
-    );
-    let state = build_provider_state(&conn).map_err(internal_err)?;
-    Ok(Json(state))
 }
 
 /**
- * \brief 更新 Provider。
+ * \brief 生成流登记，离开作用域时注销；已接入的订阅者在收到 `Done` 后随发送端释放而结束。
  */
-async fn update_provider(
-    Path(id): Path<i64>,
-    Json(payload): Json<ProviderRequest>,
-) -> Result<Json<ProvidersState>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    db::update_provider(
-        &conn,
-        id,
-        &payload.name,
-        &payload.provider,
-        &payload.api_base,
-        &payload.api_key,
-        &payload.model,
-        None,
-    )
-    .map_err(internal_err)?;
-    if payload.set_default.unwrap_or(false) {
-        db::set_default_provider_id(&conn, id).map_err(internal_err)?;
-    }
-    if let Some(enabled) = payload.telemetry_enabled {
-        db::set_telemetry_enabled(&conn, enabled).map_err(internal_err)?;
-        telemetry::set_enabled(enabled);
-    }
+struct StreamGuard {
+    stream_id: String,
+    /** \brief 与登记的广播状态共用的取消令牌。 */
+    cancel: CancellationToken,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if let Ok(mut map) = active_streams().lock() {
+            map.remove(&self.stream_id);
+        }
+    }
+}
+
+/** \brief 客户端指定的 stream_id 最大长度。 */
+const MAX_STREAM_ID_LEN: usize = 128;
+
+/** \brief stream_id 只允许字母、数字与 `-`、`_`、`.`，便于直接放进 URL 路径。 */
+fn validate_stream_id(stream_id: &str) -> Result<(), (StatusCode, String)> {
+    let valid = !stream_id.is_empty()
+        && stream_id.len() <= MAX_STREAM_ID_LEN
+        && stream_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "stream_id 只能包含字母、数字与 -_.，且不超过 {} 个字符",
+                MAX_STREAM_ID_LEN
+            ),
+        ))
+    }
+}
+
+/** \brief 登记成功的广播句柄与随生成结束注销登记的守卫。 */
+type RegisteredStream = (Arc<Mutex<StreamBroadcast>>, StreamGuard);
+
+fn register_stream(stream_id: &str) -> Result<RegisteredStream, (StatusCode, String)> {
+    let mut map = active_streams()
+        .lock()
+        .map_err(|_| internal_err(anyhow!("stream registry poisoned")))?;
+    if map.contains_key(stream_id) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("stream_id {} 正在生成中", stream_id),
+        ));
+    }
+    let cancel = CancellationToken::new();
+    let broadcast = Arc::new(Mutex::new(StreamBroadcast {
+        cancel: cancel.clone(),
+        ..Default::default()
+    }));
+    map.insert(stream_id.to_string(), broadcast.clone());
+    Ok((
+        broadcast,
+        StreamGuard {
+            stream_id: stream_id.to_string(),
+            cancel,
+        },
+    ))
+}
+
+/** \brief 取消指定流的生成，返回其所属会话；流不存在或已结束时返回 `None`。 */
+fn cancel_stream(stream_id: &str) -> Option<Option<i64>> {
+    let broadcast = active_streams().lock().ok()?.get(stream_id)?.clone();
+    let state = broadcast.lock().ok()?;
+    state.cancel.cancel();
+    Some(match &state.meta {
+        Some(ChatOutput::Meta { chat_id, .. }) => Some(*chat_id),
+        _ => None,
+    })
+}
+
+/**
+ * \brief 接入进行中的生成流：依次收到 meta、已输出的回复前缀（跳过前 `skip` 字节）与之后的实时输出。
+ * \details 登记与补发在同一把锁内完成，前缀与实时输出之间不会漏发或重复。
+ */
+fn subscribe_stream(
+    stream_id: &str,
+    skip: usize,
+) -> Option<(mpsc::UnboundedReceiver<ChatOutput>, Option<i64>)> {
+    let broadcast = active_streams().lock().ok()?.get(stream_id)?.clone();
+    let mut state = broadcast.lock().ok()?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut generation = None;
+    if let Some(meta) = state.meta.clone() {
+        if let ChatOutput::Meta { generation_id, .. } = &meta {
+            generation = *generation_id;
+        }
+        let _ = tx.send(meta);
+    }
+    let prefix = state.reply.get(skip..).unwrap_or(&state.reply);
+    if !prefix.is_empty() {
+        let _ = tx.send(ChatOutput::Chunk {
+            text: prefix.to_string(),
+            offset: state.reply.len(),
+        });
+    }
+    state.subscribers.push(tx);
+    Some((rx, generation))
+}
+
+/**
+ * \brief 生成输出的发送端：发给发起请求的客户端，同时广播给该流的订阅者。
+ */
+#[derive(Clone)]
+struct ChatSender {
+    tx: mpsc::UnboundedSender<ChatOutput>,
+    broadcast: Arc<Mutex<StreamBroadcast>>,
+}
+
+impl ChatSender {
+    /** \brief 发起请求的客户端断开后仍继续广播；返回值只反映发起方是否还在接收。 */
+    fn send(&self, output: ChatOutput) -> Result<(), mpsc::error::SendError<ChatOutput>> {
+        if let Ok(mut state) = self.broadcast.lock() {
+            state.publish(&output);
+        }
+        self.tx.send(output)
+    }
+}
+
+/** \brief 续传时轮询生成检查点的间隔。 */
+const RESUME_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/**
+ * \brief 静态资源目录：`DREAMQUILL_UI_DIR`（缺省 `packages/ui/dist`），不存在时回退到 `DREAMQUILL_UI_FALLBACK`（缺省 `web`）。
+ */
+pub fn ui_roots() -> (PathBuf, PathBuf) {
+    let ui_root =
+        std::env::var("DREAMQUILL_UI_DIR").unwrap_or_else(|_| "packages/ui/dist".to_string());
+    let fallback_root =
+        std::env::var("DREAMQUILL_UI_FALLBACK").unwrap_or_else(|_| "web".to_string());
+    (PathBuf::from(ui_root), PathBuf::from(fallback_root))
+}
+
+fn sse_retry() -> Duration {
+    SSE_RETRY
+        .get()
+        .copied()
+        .unwrap_or(Duration::from_millis(DEFAULT_SSE_RETRY_MS as u64))
+}
+
+/** \brief SSE 事件 ID：`{生成记录 ID}:{已发送回复的字节数}`，客户端重连时经 Last-Event-ID 带回。 */
+fn sse_event_id(generation_id: i64, offset: usize) -> String {
+    format!("{}:{}", generation_id, offset)
+}
+
+fn parse_sse_event_id(raw: &str) -> Option<(i64, usize)> {
+    let (generation, offset) = raw.trim().split_once(':')?;
+    Some((generation.parse().ok()?, offset.parse().ok()?))
+}
+
+/** \brief 删除确认令牌的有效期。 */
+const DELETE_TOKEN_TTL: Duration = Duration::from_secs(120);
+
+/**
+ * \brief 已签发、尚未使用的删除确认令牌。
+ */
+struct PendingDelete {
+    target: String,
+    expires_at: Instant,
+}
+
+fn pending_deletes() -> &'static Mutex<HashMap<String, PendingDelete>> {
+    static PENDING: OnceLock<Mutex<HashMap<String, PendingDelete>>> = OnceLock::new();
+    PENDING.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ConfirmQuery {
+    /** \brief 首次调用返回的确认令牌。 */
+    confirm: Option<String>,
+}
+
+/**
+ * \brief 删除确认关卡：未开启确认模式或令牌有效时返回 `None` 放行；
+ * 否则签发绑定到 `target` 的新令牌，返回 428 响应，正文包含影响说明。
+ */
+fn delete_confirmation(
+    target: &str,
+    confirm: Option<&str>,
+    summary: String,
+    impact: serde_json::Value,
+) -> Option<Response> {
+    if !CONFIRM_DELETES.get().copied().unwrap_or(false) {
+        return None;
+    }
+    let now = Instant::now();
+    let mut pending = pending_deletes().lock().unwrap_or_else(|e| e.into_inner());
+    pending.retain(|_, p| p.expires_at > now);
+    if let Some(token) = confirm {
+        if pending.get(token).is_some_and(|p| p.target == target) {
+            pending.remove(token);
+            return None;
+        }
+    }
+
+    let token = new_confirmation_token();
+    pending.insert(
+        token.clone(),
+        PendingDelete {
+            target: target.to_string(),
+            expires_at: now + DELETE_TOKEN_TTL,
+        },
+    );
+    Some(
+        (
+            StatusCode::PRECONDITION_REQUIRED,
+            Json(serde_json::json!({
+                "confirmation_required": true,
+                "confirm_token": token,
+                "expires_in_secs": DELETE_TOKEN_TTL.as_secs(),
+                "target": target,
+                "summary": summary,
+                "impact": impact,
+            })),
+        )
+            .into_response(),
+    )
+}
+
+/**
+ * \brief 生成随机访问令牌（32 字节，十六进制）。
+ */
+pub fn generate_auth_token() -> Result<String> {
+    use ring::rand::SecureRandom;
+    let mut bytes = [0u8; 32];
+    ring::rand::SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| anyhow!("system random source unavailable"))?;
+    Ok(hex::encode(bytes))
+}
+
+/**
+ * \brief 启动时确定访问令牌：参数（命令行或环境变量）优先，其次为数据库中保存的令牌。
+ * \details 读取数据库失败时直接报错，避免在本应鉴权时以无鉴权方式启动。
+ */
+fn resolve_auth_token(options: &ServerOptions) -> Result<Option<String>> {
+    match options.auth_token.as_deref() {
+        Some(token) => Ok(Some(db::validate_auth_token(token)?.to_string())),
+        None => db::open_default_db().and_then(|conn| db::get_server_auth_token(&conn)),
+    }
+}
+
+fn new_confirmation_token() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0),
+    );
+    format!("{:016x}", hasher.finish())
+}
+
+/** \brief 导入接口的请求体上限，导出文件通常远大于 axum 默认的 2 MiB。 */
+const IMPORT_BODY_LIMIT: usize = 64 * 1024 * 1024;
+
+fn env_u32(key: &str) -> Option<u32> {
+    std::env::var(key).ok().and_then(|v| v.trim().parse().ok())
+}
+
+/** \brief 读取逗号分隔的环境变量，去掉空白项。 */
+fn env_list(key: &str) -> Vec<String> {
+    std::env::var(key)
+        .map(|v| {
+            v.split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/** \brief 未指定时跨域请求允许的方法。 */
+const DEFAULT_CORS_METHODS: &[Method] = &[
+    Method::GET,
+    Method::POST,
+    Method::PUT,
+    Method::PATCH,
+    Method::DELETE,
+];
+
+/**
+ * \brief 按配置构造 CORS 层；未配置来源时返回 `None`。
+ * \details 来源须为 `scheme://host[:port]`（不含路径），`*` 允许任意来源且不能与具体来源混用。
+ * 允许前端需要的请求头（含 `Authorization`、`Last-Event-ID`、`If-None-Match`），并暴露 `ETag` 等响应头。
+ */
+fn cors_layer(origins: &[String], methods: &[String]) -> Result<Option<CorsLayer>> {
+    if origins.is_empty() {
+        return Ok(None);
+    }
+    let allow_origin = if origins.iter().any(|o| o == "*") {
+        if origins.len() > 1 {
+            return Err(anyhow!(
+                "CORS origin `*` cannot be combined with other origins"
+            ));
+        }
+        AllowOrigin::any()
+    } else {
+        let parsed = origins
+            .iter()
+            .map(|o| parse_cors_origin(o))
+            .collect::<Result<Vec<_>>>()?;
+        AllowOrigin::list(parsed)
+    };
+    let methods = if methods.is_empty() {
+        DEFAULT_CORS_METHODS.to_vec()
+    } else {
+        methods
+            .iter()
+            .map(|m| {
+                Method::from_bytes(m.trim().to_ascii_uppercase().as_bytes())
+                    .map_err(|_| anyhow!("invalid CORS method {:?}", m))
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(methods)
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                header::IF_NONE_MATCH,
+                header::HeaderName::from_static("last-event-id"),
+                header::HeaderName::from_static(openai_compat::CHAT_ID_HEADER),
+            ])
+            .expose_headers([
+                header::ETAG,
+                header::RETRY_AFTER,
+                header::HeaderName::from_static(openai_compat::CHAT_ID_HEADER),
+            ])
+            .max_age(Duration::from_secs(600)),
+    ))
+}
+
+/** \brief 校验单个来源并转为请求头取值，去掉末尾斜杠。 */
+fn parse_cors_origin(raw: &str) -> Result<axum::http::HeaderValue> {
+    let url = reqwest::Url::parse(raw.trim())
+        .map_err(|e| anyhow!("invalid CORS origin {:?}: {}", raw, e))?;
+    if !matches!(url.scheme(), "http" | "https")
+        || url.host_str().is_none()
+        || url.path() != "/"
+        || url.query().is_some()
+    {
+        return Err(anyhow!(
+            "invalid CORS origin {:?}, expected scheme://host[:port]",
+            raw
+        ));
+    }
+    Ok(axum::http::HeaderValue::from_str(
+        &url.origin().ascii_serialization(),
+    )?)
+}
+
+/**
+ * \brief 写操作与聊天接口各自独立的限流器。
+ */
+struct ApiRateLimits {
+    mutation: Option<RateLimiter>,
+    chat: Option<RateLimiter>,
+}
+
+/**
+ * \brief 聊天请求的限额句柄：限流中间件写入请求扩展，生成结束后的拒答重试与语言重问同样从中扣减。
+ */
+#[derive(Clone)]
+struct ChatQuota {
+    limits: Arc<ApiRateLimits>,
+    client_key: String,
+}
+
+impl ChatQuota {
+    /** \brief 再消耗一次聊天限额；超限时返回建议的等待时间。 */
+    fn acquire(&self) -> Result<(), Duration> {
+        match &self.limits.chat {
+            Some(limiter) => limiter.check(&self.client_key),
+            None => Ok(()),
+        }
+    }
+}
+
+/**
+ * \brief 启动本地 HTTP 服务，提供静态前端与 API。
+ * \param addr 监听地址，如 "127.0.0.1:5173"
+ */
+pub async fn run(addr: &str) -> Result<()> {
+    run_with_options(addr, ServerOptions::from_env()).await
+}
+
+/**
+ * \brief 以指定参数启动本地 HTTP 服务。
+ */
+pub async fn run_with_options(addr: &str, options: ServerOptions) -> Result<()> {
+    if let Some(provider) = options.env_provider.clone() {
+        println!(
+            "Using provider from environment: {} ({} | {})",
+            provider.name, provider.provider_type, provider.model
+        );
+        if ENV_PROVIDER.set(provider).is_err() {
+            telemetry::log_error("server", "env provider already initialized");
+        }
+    }
+    if CONFIRM_DELETES.set(options.confirm_deletes).is_err() {
+        telemetry::log_error("server", "delete confirmation mode already initialized");
+    }
+    if SSE_RETRY
+        .set(Duration::from_millis(options.sse_retry_ms as u64))
+        .is_err()
+    {
+        telemetry::log_error("server", "sse retry interval already initialized");
+    }
+    if let Some(path) = options.transcript_path.as_ref() {
+        let tee = TranscriptTee::open(path)?;
+        println!("Teeing streamed output to {}", tee.path().display());
+        if TRANSCRIPT.set(tee).is_err() {
+            telemetry::log_error("server", "transcript already initialized");
+        }
+    }
+    match db::open_default_db().and_then(|conn| db::get_retry_policy(&conn)) {
+        Ok(policy) => llm::set_retry_policy(policy),
+        Err(e) => telemetry::log_error("server", &format!("load retry policy failed: {}", e)),
+    }
+    // 主机策略读不出时拒绝启动，不能带着空策略放行请求
+    let host_policy = db::open_default_db()
+        .and_then(|conn| db::get_host_policy(&conn))
+        .context("load host policy failed")?;
+    llm::set_host_policy(host_policy);
+    println!("Database: {}", paths::db_path().display());
+    let auth_token = resolve_auth_token(&options)?;
+    match &auth_token {
+        Some(_) => println!("API authentication enabled: /api and /v1 require a Bearer token"),
+        None if !addr
+            .parse::<SocketAddr>()
+            .map(|a| a.ip().is_loopback())
+            .unwrap_or(true) =>
+        {
+            println!(
+                "Warning: listening on {} without authentication, set --auth-token or DREAMQUILL_AUTH_TOKEN",
+                addr
+            );
+        }
+        None => {}
+    }
+    start_model_warmup();
+    let (ui_root, fallback_root) = ui_roots();
+
+    let static_handler = if ui_root.exists() {
+        ServeDir::new(ui_root)
+    } else {
+        ServeDir::new(fallback_root)
+    }
+    .append_index_html_on_directories(true);
+
+    let static_service = get_service(static_handler);
+
+    let limits = Arc::new(ApiRateLimits {
+        mutation: RateLimiter::per_minute(options.mutation_rate_limit),
+        chat: RateLimiter::per_minute(options.chat_rate_limit),
+    });
+    {
+        let limits = limits.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                ticker.tick().await;
+                for limiter in [&limits.mutation, &limits.chat].into_iter().flatten() {
+                    limiter.sweep();
+                }
+            }
+        });
+    }
+
+    let mut api = Router::new()
+        .route("/api/config", get(get_config).post(set_config))
+        .route("/api/providers", get(get_providers).post(create_provider))
+        .route(
+            "/api/providers/{id}",
+            put(update_provider).delete(delete_provider),
+        )
+        .route("/api/providers/{id}/select", post(select_provider))
+        .route("/api/providers/{id}/signing", put(set_provider_signing))
+        .route("/api/chats", get(list_chats).post(create_chat))
+        .route(
+            "/api/chats/interrupted",
+            get(list_interrupted).post(resolve_interrupted),
+        )
+        .route("/api/chats/{id}/messages", get(get_chat_messages))
+        .route(
+            "/api/chats/{id}/messages/{mid}/variants",
+            get(list_chat_message_variants),
+        )
+        .route("/api/chats/{id}/context-preview", get(context_preview))
+        .route(
+            "/api/chats/{id}",
+            delete(remove_chat).put(rename_chat).patch(update_chat),
+        )
+        .route("/api/chats/{id}/branch", post(branch_chat))
+        .route(
+            "/api/chats/{id}/provider",
+            put(set_chat_provider).patch(set_chat_provider),
+        )
+        .route("/api/chats/{id}/model", patch(set_chat_model))
+        .route(
+            "/api/chats/{id}/stream-retry",
+            get(get_chat_stream_retry).put(set_chat_stream_retry),
+        )
+        .route(
+            "/api/chats/{id}/context-strategy",
+            get(get_chat_context_strategy).put(set_chat_context_strategy),
+        )
+        .route(
+            "/api/chats/{id}/reply-language",
+            get(get_chat_reply_language).put(set_chat_reply_language),
+        )
+        .route("/api/chats/{id}/cancel", post(cancel_chat_generation))
+        .route("/api/chats/{id}/read", patch(mark_chat_read))
+        .route("/api/chats/{id}/tags", get(get_chat_tags))
+        .route("/api/chats/{id}/summary", get(get_chat_summary))
+        .route("/api/chats/{id}/autotag", post(autotag_chat))
+        .route(
+            "/api/chats/{id}/temperature-preview",
+            post(preview_temperatures),
+        )
+        .route("/api/messages/{id}/rerun", post(rerun_message))
+        .route("/api/messages/{id}/variants", get(list_message_variants))
+        .route("/api/messages/{id}", patch(edit_message))
+        .route("/api/messages/{id}/revisions", get(list_message_revisions))
+        .route("/api/messages/{id}/revert", post(revert_message))
+        .route(
+            "/api/smart-lists",
+            get(list_smart_lists).post(create_smart_list),
+        )
+        .route(
+            "/api/smart-lists/{id}",
+            put(update_smart_list).delete(delete_smart_list),
+        )
+        .route("/api/smart-lists/{id}/chats", get(resolve_smart_list))
+        .route(
+            "/api/config/stop-strings",
+            get(get_stop_strings).put(set_stop_strings),
+        )
+        .route(
+            "/api/config/max-output",
+            get(get_max_output).put(set_max_output),
+        )
+        .route(
+            "/api/config/retry-policy",
+            get(get_retry_policy).put(set_retry_policy),
+        )
+        .route(
+            "/api/config/host-policy",
+            get(get_host_policy).put(set_host_policy),
+        )
+        .route(
+            "/api/config/autotag",
+            get(get_autotag_config).put(set_autotag_config),
+        )
+        .route(
+            "/api/config/summary",
+            get(get_summary_config).put(set_summary_config),
+        )
+        .route(
+            "/api/config/refusal-policy",
+            get(get_refusal_policy).put(set_refusal_policy),
+        )
+        .route("/api/models", get(list_models))
+        .route("/api/models/migrate", post(migrate_model))
+        .route("/api/capabilities", get(list_capabilities))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/api/ready", get(readyz))
+        .route("/api/version", get(version))
+        .route("/api/health", get(health_check))
+        .route("/api/health/preview", post(health_check_preview))
+        .route("/api/chat", post(chat_post))
+        .route("/api/chat/sse", get(chat_sse))
+        .route("/api/chat/stream", post(chat_stream_post))
+        .route("/api/chat/cancel/{stream_id}", post(cancel_chat_stream))
+        .route("/api/streams", get(list_streams))
+        .route("/api/streams/{id}/subscribe", get(subscribe_stream_sse))
+        .route("/v1/models", get(openai_models))
+        .route("/v1/chat/completions", post(openai_chat_completions))
+        .route("/api/stats", get(request_stats))
+        .route("/api/stats/providers", get(provider_stats))
+        .route(
+            "/api/maintenance/backfill",
+            get(backfill_progress)
+                .post(start_backfill)
+                .delete(cancel_backfill),
+        )
+        .route("/api/admin/audit", get(list_audit_log))
+        .route("/api/admin/audit/retention", put(set_audit_retention))
+        .route("/api/admin/backup", post(backup_database))
+        .route("/api/admin/sql", post(run_sql_query))
+        .route(
+            "/api/admin/sql/settings",
+            get(get_sql_console_settings).put(set_sql_console_settings),
+        )
+        .route("/api/export/finetune", post(export_finetune))
+        .route(
+            "/api/export/pipelines",
+            get(list_export_pipelines).post(create_export_pipeline),
+        )
+        .route(
+            "/api/export/pipelines/{id}",
+            put(update_export_pipeline).delete(delete_export_pipeline),
+        )
+        .route("/api/export/pipelines/{id}/run", post(run_export_pipeline))
+        .route(
+            "/api/import/chatgpt",
+            post(import_chatgpt).layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)),
+        )
+        .route(
+            "/api/import/claude",
+            post(import_claude).layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)),
+        )
+        .layer(middleware::from_fn_with_state(limits, rate_limit));
+    if let Some(token) = auth_token {
+        api = api.layer(middleware::from_fn_with_state(
+            Arc::new(token),
+            require_auth,
+        ));
+    }
+    let mut app = api.fallback_service(static_service);
+    // 放在最外层，预检请求不经过鉴权与限流
+    if let Some(cors) = cors_layer(&options.cors_origins, &options.cors_methods)? {
+        println!("CORS enabled for: {}", options.cors_origins.join(", "));
+        app = app.layer(cors);
+    }
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("Server listening on http://{}", addr);
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
+    drain_generations(*SHUTDOWN_SIGNALLED_AT.get().unwrap_or(&Instant::now())).await;
+    db::shutdown_write_behind();
+    println!("Server stopped");
+    Ok(())
+}
+
+/**
+ * \brief 等待 Ctrl+C 或 SIGTERM，触发优雅退出：停止接收新连接并等待进行中的请求完成。
+ */
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            telemetry::log_error("server", &format!("listen ctrl_c failed: {}", e));
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                telemetry::log_error("server", &format!("listen SIGTERM failed: {}", e));
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    let _ = SHUTDOWN_SIGNALLED_AT.set(Instant::now());
+    println!("Shutdown signal received, draining connections");
+    let active = active_generation_count();
+    if active > 0 {
+        println!(
+            "Waiting up to {}s for {} generation(s) to finish",
+            SHUTDOWN_GENERATION_GRACE.as_secs(),
+            active
+        );
+        // 流式连接要等生成结束才会关闭，宽限期后取消，部分回复照常保存
+        tokio::spawn(async {
+            tokio::time::sleep(SHUTDOWN_GENERATION_GRACE).await;
+            let cancelled = cancel_all_generations();
+            if cancelled > 0 {
+                println!(
+                    "Cancelled {} generation(s), keeping partial replies",
+                    cancelled
+                );
+            }
+        });
+    }
+}
+
+/**
+ * \brief 存活探针：进程可响应即返回 200。
+ */
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/**
+ * \brief 就绪探针（`/readyz` 与 `/api/ready`）：数据库可连接、迁移已完成且未在退出中时返回 200，否则返回 503。
+ * \details `provider_configured` 与 `model_warmup`（启动时模型列表预热进度）仅作提示，不影响就绪状态。
+ */
+async fn readyz() -> (StatusCode, Json<serde_json::Value>) {
+    let probe = db::run(|conn| {
+        let ready = db::schema_ready(conn)?;
+        let configured = ready
+            && db::list_providers(conn)
+                .map(|p| !p.is_empty())
+                .unwrap_or(false);
+        Ok((ready, configured))
+    })
+    .await;
+    let (db_ok, migrations_ok, provider_configured, error) = match probe {
+        Ok((ready, configured)) => (true, ready, env_provider().is_some() || configured, None),
+        Err(e) => (false, false, env_provider().is_some(), Some(e.to_string())),
+    };
+    let shutting_down = SHUTTING_DOWN.load(Ordering::SeqCst);
+    let status = if db_ok && migrations_ok && !shutting_down {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(serde_json::json!({
+            "ready": status == StatusCode::OK,
+            "db": db_ok,
+            "migrations": migrations_ok,
+            "shutting_down": shutting_down,
+            "provider_configured": provider_configured,
+            "model_warmup": model_cache::warmup_status(),
+            "error": error,
+        })),
+    )
+}
+
+/**
+ * \brief 版本信息：core-sdk 版本、SQLite 版本与运行平台，便于部署脚本核对。
+ */
+async fn version() -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "name": "dreamquill",
+        "version": env!("CARGO_PKG_VERSION"),
+        "sqlite_version": rusqlite::version(),
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+    }))
+}
+
+/**
+ * \brief 限流中间件：会调用模型的接口与写操作分别计数（归类见 `rate_limit::classify`），超限返回 429 与 Retry-After。
+ * \details 经 `require_auth` 校验通过的请求按令牌计数，否则按客户端 IP 计数；未校验的令牌不参与计数键。
+ * 聊天类请求另在扩展中附上 `ChatQuota`，供生成后的追加请求扣减同一限额。
+ */
+async fn rate_limit(
+    State(limits): State<Arc<ApiRateLimits>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let path = req.uri().path().to_string();
+    let class = crate::rate_limit::classify(req.method(), &path, req.uri().query());
+    let limiter = match class {
+        Some(LimitClass::Chat) => limits.chat.as_ref(),
+        Some(LimitClass::Mutation) => limits.mutation.as_ref(),
+        None => None,
+    };
+
+    if let Some(limiter) = limiter {
+        let client_key = req
+            .extensions()
+            .get::<VerifiedToken>()
+            .map(|VerifiedToken(token)| format!("token:{}", token))
+            .unwrap_or_else(|| format!("ip:{}", addr.ip()));
+        if let Err(wait) = limiter.check(&client_key) {
+            let retry_after = wait.as_secs().max(1);
+            telemetry::log_event(
+                "server.rate_limit",
+                &format!("reject client={} path={}", addr.ip(), path),
+            );
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                "请求过于频繁，请稍后再试",
+            )
+                .into_response();
+        }
+        if class == Some(LimitClass::Chat) {
+            let limits = limits.clone();
+            req.extensions_mut()
+                .insert(ChatQuota { limits, client_key });
+        }
+    }
+    next.run(req).await
+}
+
+/** \brief 请求头中的 Bearer 令牌。 */
+fn bearer_token(headers: &axum::http::HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/** \brief `require_auth` 校验通过后写入请求扩展的令牌，供内层限流按令牌计数。 */
+#[derive(Clone)]
+struct VerifiedToken(Arc<String>);
+
+/** \brief 开启鉴权后仍可匿名访问的接口（探针与版本信息）。 */
+const PUBLIC_API_PATHS: &[&str] = &["/api/ready", "/api/version"];
+
+/** \brief 按字节比较令牌，耗时与首个不同字节的位置无关。 */
+fn tokens_match(presented: &str, expected: &str) -> bool {
+    presented.len() == expected.len()
+        && presented
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/**
+ * \brief 鉴权中间件：`/api/` 与 `/v1/` 下的请求须携带 `Authorization: Bearer <令牌>`，否则返回 401。
+ * \details GET 请求也可用 `access_token` 查询参数（浏览器 EventSource 无法设置请求头）；探针、版本信息与静态页面不受影响。
+ */
+async fn require_auth(
+    State(token): State<Arc<String>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let path = req.uri().path().to_string();
+    if (!path.starts_with("/api/") && !path.starts_with("/v1/"))
+        || PUBLIC_API_PATHS.contains(&path.as_str())
+    {
+        return next.run(req).await;
+    }
+    let authorized = match bearer_token(req.headers()) {
+        Some(presented) => tokens_match(presented, &token),
+        None if req.method() == Method::GET => {
+            Query::<HashMap<String, String>>::try_from_uri(req.uri())
+                .ok()
+                .and_then(|Query(mut q)| q.remove("access_token"))
+                .is_some_and(|presented| tokens_match(&presented, &token))
+        }
+        None => false,
+    };
+    if authorized {
+        req.extensions_mut().insert(VerifiedToken(token));
+        return next.run(req).await;
+    }
     telemetry::log_event(
-        "server.provider",
-        &format!("update id={} name={}", id, payload.name),
+        "server.auth",
+        &format!("reject client={} path={}", addr.ip(), path),
+    );
+    let mut resp = if path.starts_with("/v1/") {
+        openai_error(StatusCode::UNAUTHORIZED, "missing or invalid bearer token")
+    } else {
+        (StatusCode::UNAUTHORIZED, "缺少或无效的访问令牌").into_response()
+    };
+    resp.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        axum::http::HeaderValue::from_static("Bearer"),
+    );
+    resp
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ProviderInput {
+    /** \brief Provider 名称 */
+    #[serde(default)]
+    name: Option<String>,
+    /** \brief Provider 类型 */
+    provider: String,
+    /** \brief API 基地址 */
+    api_base: String,
+    /** \brief API 密钥 */
+    api_key: String,
+    /** \brief 模型名 */
+    model: String,
+    #[serde(default)]
+    telemetry_enabled: Option<bool>,
+    #[serde(default)]
+    set_default: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ProviderRequest {
+    name: String,
+    provider: String,
+    api_base: String,
+    api_key: String,
+    model: String,
+    /** \brief 生成参数：temperature、top_p、max_tokens、stop。 */
+    #[serde(default, flatten)]
+    generation: GenerationSettings,
+    /** \brief 代理地址（http/https/socks5/socks5h），为空时直连。 */
+    #[serde(default)]
+    proxy_url: Option<String>,
+    #[serde(default)]
+    telemetry_enabled: Option<bool>,
+    #[serde(default)]
+    set_default: Option<bool>,
+}
+
+#[derive(Serialize, Debug)]
+struct ProviderItem {
+    id: i64,
+    name: String,
+    provider: String,
+    api_base: String,
+    api_key: String,
+    model: String,
+    #[serde(flatten)]
+    generation: GenerationSettings,
+    proxy_url: Option<String>,
+    /** \brief 已保存的密钥无法用当前主密钥解密，需要重新填写。 */
+    api_key_unreadable: bool,
+    is_default: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct ProvidersState {
+    providers: Vec<ProviderItem>,
+    default_provider_id: Option<i64>,
+    telemetry_enabled: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModelQuery {
+    provider_id: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CapabilitiesQuery {
+    /** \brief 为 true 时拉取各 Provider 的模型列表以细化推断。 */
+    #[serde(default)]
+    probe: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct ProviderCapabilitiesDto {
+    provider_id: i64,
+    name: String,
+    provider_type: String,
+    model: String,
+    /** \brief 是否成功探测了模型列表。 */
+    probed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    probe_error: Option<String>,
+    capabilities: llm::ProviderCapabilities,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatListQuery {
+    provider_id: Option<i64>,
+    /** \brief 同时列出已归档的会话。 */
+    #[serde(default)]
+    include_archived: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatSummaryDto {
+    id: i64,
+    title: String,
+    provider_id: Option<i64>,
+    /** \brief 已读到的最后一条消息，客户端据此计算未读标记。 */
+    last_read_message_id: Option<i64>,
+    pinned: bool,
+    archived: bool,
+    /** \brief 会话级模型覆盖，为空时使用 Provider 的默认模型。 */
+    model: Option<String>,
+}
+
+impl From<db::ChatSummary> for ChatSummaryDto {
+    fn from(c: db::ChatSummary) -> Self {
+        Self {
+            id: c.id,
+            title: c.title,
+            provider_id: c.provider_id,
+            last_read_message_id: c.last_read_message_id,
+            pinned: c.pinned,
+            archived: c.archived,
+            model: c.model,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ChatListResponse {
+    chats: Vec<ChatSummaryDto>,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatMessageDto {
+    id: i64,
+    role: String,
+    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_token_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+    /** \brief 取消生成时保存的不完整回复。 */
+    partial: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatMessagesResponse {
+    chat_id: i64,
+    provider_id: Option<i64>,
+    messages: Vec<ChatMessageDto>,
+    /** \brief 分页模式下加载更早消息的游标。 */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EditMessageRequest {
+    content: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RevertMessageRequest {
+    /** \brief 要恢复的历史版本 ID。 */
+    revision_id: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct ResolveInterruptedRequest {
+    /** \brief 中断记录 ID。 */
+    id: i64,
+    /** \brief `finalize` 保存部分回复，`discard` 丢弃。 */
+    action: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessagePageQuery {
+    /** \brief 仅返回 ID 小于该值的消息。 */
+    before: Option<i64>,
+    /** \brief 每页条数；与 `before` 均未提供时返回全部消息。 */
+    limit: Option<i64>,
+}
+
+/** \brief 分页请求未指定条数时的默认值。 */
+const DEFAULT_MESSAGE_PAGE_SIZE: i64 = 50;
+
+#[derive(Deserialize, Debug)]
+struct ContextPreviewQuery {
+    /** \brief 即将发送的用户消息（可选），计入预览末尾。 */
+    prompt: Option<String>,
+    /** \brief 单次请求覆盖的模型名。 */
+    model: Option<String>,
+    /** \brief 单次请求覆盖的系统指令，替换会话中的 system 消息。 */
+    system: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ContextItemDto {
+    /** \brief 对应的消息 ID；覆盖的系统指令与待发送消息为空。 */
+    message_id: Option<i64>,
+    role: String,
+    /** \brief 来源：`message`（会话历史）、`system_override`、`summary`（滚动摘要）或 `prompt`。 */
+    source: &'static str,
+    content: String,
+    tokens: usize,
+    /** \brief 是否随下一次请求发送；按相关性挑选时落选的历史为 `false`。 */
+    included: bool,
+    /** \brief 入选或落选原因，仅会话历史带有。 */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<context::ContextReason>,
+    /** \brief 与新提示的相似度，仅相关性策略下的早先历史带有。 */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f32>,
+}
+
+#[derive(Serialize, Debug)]
+struct ContextPreviewResponse {
+    chat_id: i64,
+    provider_id: Option<i64>,
+    model: Option<String>,
+    /** \brief 会话的上下文选择策略。 */
+    strategy: ContextStrategy,
+    /** \brief 计算相似度所用的向量模型，`full` 策略时为空。 */
+    embedding_model: Option<String>,
+    /** \brief 远端向量不可用、退回本地哈希向量。 */
+    fallback: bool,
+    items: Vec<ContextItemDto>,
+    /** \brief 入选项的 token 合计。 */
+    total_tokens: usize,
+}
+
+#[derive(Deserialize, Debug)]
+struct BranchRequest {
+    /** \brief 新聊天标题，可选。 */
+    title: Option<String>,
+    /** \brief 截断到的消息 ID（包含该消息）。 */
+    until_message_id: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+struct BranchResponse {
+    chat_id: i64,
+    title: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CreateChatRequest {
+    /** \brief 会话标题，缺省时按 Provider 名称生成。 */
+    #[serde(default)]
+    title: Option<String>,
+    /** \brief 绑定的 Provider，缺省时使用默认 Provider。 */
+    #[serde(default)]
+    provider_id: Option<i64>,
+    /** \brief 可选系统提示词，作为首条 system 消息写入。 */
+    #[serde(default)]
+    system_prompt: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ImportQuery {
+    /** \brief 导入会话绑定的 Provider，缺省不绑定。 */
+    #[serde(default)]
+    provider_id: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct StreamRetryDto {
+    /** \brief 流式输出因瞬时错误中断时是否自动重试并续写。 */
+    enabled: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatProviderRequest {
+    /** \brief 目标 Provider；为 null 时解除绑定。 */
+    provider_id: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatModelRequest {
+    /** \brief 会话使用的模型名；为 null 或空字符串时恢复 Provider 默认模型。 */
+    model: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MaxOutputDto {
+    /** \brief 单次回复的输出字符数上限；null 或 0 表示不限制。 */
+    max_chars: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct MarkReadRequest {
+    /** \brief 已读到的消息；省略时为会话最新一条。 */
+    #[serde(default)]
+    message_id: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RerunMessageRequest {
+    /** \brief 生成备选回复使用的 Provider。 */
+    provider_id: i64,
+    #[serde(flatten)]
+    overrides: llm::RequestOverrides,
+}
+
+#[derive(Deserialize, Debug)]
+struct TemperaturePreviewRequest {
+    prompt: String,
+    /** \brief 待比较的温度，最多 `rerun::PREVIEW_MAX_VARIANTS` 个。 */
+    temperatures: Vec<f64>,
+    /** \brief 生成使用的 Provider，缺省为会话绑定的 Provider 或默认 Provider。 */
+    #[serde(default)]
+    provider_id: Option<i64>,
+    /** \brief 模型、系统指令与每个变体的 max_tokens；其中的温度会被忽略。 */
+    #[serde(flatten)]
+    overrides: llm::RequestOverrides,
+}
+
+#[derive(Deserialize, Debug)]
+struct SaveSmartListRequest {
+    name: String,
+    #[serde(default)]
+    filter: db::ChatFilter,
+}
+
+#[derive(Deserialize, Debug)]
+struct SavePipelineRequest {
+    name: String,
+    #[serde(default)]
+    definition: db::PipelineDefinition,
+}
+
+#[derive(Deserialize, Debug)]
+struct RenameChatRequest {
+    /** \brief 新的会话标题。 */
+    title: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct UpdateChatRequest {
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    pinned: Option<bool>,
+    #[serde(default)]
+    archived: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AuditQuery {
+    /** \brief 返回条数上限（默认 100）。 */
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct StatsQuery {
+    /** \brief 只统计此后（Unix 秒）的请求，缺省统计全部。 */
+    since: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+struct AuditLogResponse {
+    retention_days: i64,
+    entries: Vec<db::AuditEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SqlQueryRequest {
+    sql: String,
+    /** \brief 返回行数上限，缺省 200，最大 5000。 */
+    max_rows: Option<usize>,
+    /** \brief 执行时限（毫秒），缺省 3000，最大 10000。 */
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SqlConsoleSettings {
+    enabled: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct AuditRetentionRequest {
+    /** \brief 保留天数，0 表示永久保留。 */
+    days: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModelMigrationRequest {
+    /** \brief 要替换的旧模型名（忽略大小写）。 */
+    from: String,
+    /** \brief 新模型名；省略时使用弃用登记表中的替代建议。 */
+    #[serde(default)]
+    to: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ModelMigrationDto {
+    provider_id: i64,
+    name: String,
+    from: String,
+    to: String,
+    /** \brief 绑定到该 Provider、随之切换模型的会话数。 */
+    chats: i64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct BackfillRequest {
+    /** \brief 用于生成标题/标签的 Provider，缺省使用默认 Provider。 */
+    #[serde(default)]
+    provider_id: Option<i64>,
+    /** \brief 每分钟模型调用次数上限。 */
+    #[serde(default)]
+    calls_per_minute: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct HealthPreviewRequest {
+    /** \brief 可选的显示名称。 */
+    #[serde(default)]
+    name: Option<String>,
+    /** \brief Provider 类型。 */
+    provider: String,
+    /** \brief API 基地址。 */
+    api_base: String,
+    /** \brief API 密钥。 */
+    api_key: String,
+    /** \brief 默认模型名称。 */
+    model: String,
+    /** \brief 代理地址，可选。 */
+    #[serde(default)]
+    proxy_url: Option<String>,
+}
+
+fn build_provider_state(conn: &rusqlite::Connection) -> Result<ProvidersState, anyhow::Error> {
+    let mut providers = db::list_providers(conn)?;
+    let mut default_id = db::get_default_provider_id(conn)?;
+    if let Some(env) = env_provider() {
+        providers.insert(
+            0,
+            Provider {
+                api_key: String::new(),
+                secret_alias: Some("env".to_string()),
+                ..env.clone()
+            },
+        );
+        default_id = Some(ENV_PROVIDER_ID);
+    }
+    let telemetry_enabled = db::get_telemetry_enabled(conn)?;
+    let items = providers
+        .into_iter()
+        .map(|p| ProviderItem {
+            id: p.id,
+            name: p.name,
+            provider: p.provider_type,
+            api_base: p.api_base,
+            api_key: if p.secret_alias.is_some() {
+                String::new()
+            } else {
+                p.api_key
+            },
+            model: p.model,
+            generation: p.generation,
+            proxy_url: p.proxy_url,
+            api_key_unreadable: p.api_key_unreadable,
+            is_default: default_id.map(|d| d == p.id).unwrap_or(false),
+        })
+        .collect();
+    telemetry::set_enabled(telemetry_enabled);
+    Ok(ProvidersState {
+        providers: items,
+        default_provider_id: default_id,
+        telemetry_enabled,
+    })
+}
+
+fn audit_actor(addr: &SocketAddr) -> String {
+    format!("server@{}", addr.ip())
+}
+
+/**
+ * \brief 记录审计日志；写入失败只记遥测，不影响主流程。
+ */
+fn record_audit(
+    conn: &rusqlite::Connection,
+    addr: &SocketAddr,
+    action: &str,
+    target: Option<String>,
+    changes: serde_json::Value,
+) {
+    if let Err(e) = db::insert_audit_log(
+        conn,
+        &audit_actor(addr),
+        action,
+        target.as_deref(),
+        &changes,
+    ) {
+        telemetry::log_error("server.audit", &format!("audit write failed: {}", e));
+    }
+}
+
+/**
+ * \brief 更新遥测开关并在值变化时写入审计。
+ */
+fn apply_telemetry_setting(
+    conn: &rusqlite::Connection,
+    addr: &SocketAddr,
+    enabled: bool,
+) -> Result<()> {
+    let previous = db::get_telemetry_enabled(conn)?;
+    db::set_telemetry_enabled(conn, enabled)?;
+    telemetry::set_enabled(enabled);
+    if previous != enabled {
+        record_audit(
+            conn,
+            addr,
+            "settings.update",
+            None,
+            serde_json::json!({"telemetry_enabled": {"from": previous, "to": enabled}}),
+        );
+    }
+    Ok(())
+}
+
+/**
+ * \brief 获取当前默认 Provider 配置。
+ */
+async fn get_config() -> Result<Json<ProvidersState>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let state = build_provider_state(conn).map_err(internal_err)?;
+        Ok(Json(state))
+    })
+    .await
+}
+
+/**
+ * \brief 设置默认 Provider 配置。
+ */
+async fn set_config(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(input): Json<ProviderInput>,
+) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        validate_api_base(conn, &input.api_base)?;
+        let set_default = input.set_default.unwrap_or(true);
+        let name = input.name.unwrap_or_else(|| "default".to_string());
+        let id = if set_default {
+            db::upsert_default_provider(
+                conn,
+                &name,
+                &input.provider,
+                &input.api_base,
+                &input.api_key,
+                &input.model,
+                None,
+            )
+            .map_err(internal_err)?
+        } else {
+            db::insert_provider(
+                conn,
+                &name,
+                &input.provider,
+                &input.api_base,
+                &input.api_key,
+                &input.model,
+                None,
+            )
+            .map_err(internal_err)?
+        };
+        let created = db::get_provider_by_id(conn, id).map_err(internal_err)?;
+        record_audit(
+            conn,
+            &addr,
+            "provider.create",
+            Some(format!("provider:{}", id)),
+            db::provider_changes(None, created.as_ref()),
+        );
+        if set_default {
+            record_audit(
+                conn,
+                &addr,
+                "provider.select_default",
+                Some(format!("provider:{}", id)),
+                serde_json::json!({}),
+            );
+        }
+        if let Some(enabled) = input.telemetry_enabled {
+            apply_telemetry_setting(conn, &addr, enabled).map_err(internal_err)?;
+        }
+        Ok(Json(serde_json::json!({"id": id})))
+    })
+    .await
+}
+
+/** \brief 校验表单中的 api_base，归一化后仍无法使用时返回 400，主机不符合主机名策略时返回 403。 */
+fn validate_api_base(
+    conn: &rusqlite::Connection,
+    api_base: &str,
+) -> Result<(), (axum::http::StatusCode, String)> {
+    let api_base =
+        base_url::normalize(api_base).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let policy = db::get_host_policy(conn).map_err(internal_err)?;
+    base_url::check_host(&api_base, &policy).map_err(|e| (StatusCode::FORBIDDEN, e.to_string()))
+}
+
+/** \brief 校验表单中的代理地址，空白视为未设置。 */
+fn validate_proxy(proxy_url: Option<&str>) -> Result<(), (axum::http::StatusCode, String)> {
+    match proxy_url.map(str::trim).filter(|u| !u.is_empty()) {
+        Some(url) => llm::parse_proxy_url(url)
+            .map(|_| ())
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string())),
+        None => Ok(()),
+    }
+}
+
+/**
+ * \brief 获取 Provider 列表。
+ */
+async fn get_providers(
+    headers: axum::http::HeaderMap,
+) -> Result<Response, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let etag =
+            revision_etag(conn, &[db::REVISION_PROVIDERS], "providers").map_err(internal_err)?;
+        if let Some(resp) = not_modified(&headers, &etag) {
+            return Ok(resp);
+        }
+        let state = build_provider_state(conn).map_err(internal_err)?;
+        Ok(with_etag(state, &etag))
+    })
+    .await
+}
+
+/**
+ * \brief 新增 Provider。
+ */
+async fn create_provider(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<ProviderRequest>,
+) -> Result<Json<ProvidersState>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        validate_api_base(conn, &payload.api_base)?;
+        validate_proxy(payload.proxy_url.as_deref())?;
+        let set_default = payload.set_default.unwrap_or(false);
+        if let Some(enabled) = payload.telemetry_enabled {
+            apply_telemetry_setting(conn, &addr, enabled).map_err(internal_err)?;
+        }
+        let id = if set_default {
+            db::upsert_default_provider(
+                conn,
+                &payload.name,
+                &payload.provider,
+                &payload.api_base,
+                &payload.api_key,
+                &payload.model,
+                None,
+            )
+            .map_err(internal_err)?
+        } else {
+            db::insert_provider(
+                conn,
+                &payload.name,
+                &payload.provider,
+                &payload.api_base,
+                &payload.api_key,
+                &payload.model,
+                None,
+            )
+            .map_err(internal_err)?
+        };
+        db::set_provider_generation(conn, id, &payload.generation.clone().normalized())
+            .map_err(internal_err)?;
+        db::set_provider_proxy(conn, id, payload.proxy_url.as_deref()).map_err(internal_err)?;
+        let created = db::get_provider_by_id(conn, id).map_err(internal_err)?;
+        record_audit(
+            conn,
+            &addr,
+            "provider.create",
+            Some(format!("provider:{}", id)),
+            db::provider_changes(None, created.as_ref()),
+        );
+        if set_default {
+            record_audit(
+                conn,
+                &addr,
+                "provider.select_default",
+                Some(format!("provider:{}", id)),
+                serde_json::json!({}),
+            );
+        }
+        telemetry::log_event(
+            "server.provider",
+            &format!("create name={} type={}", payload.name, payload.provider),
+        );
+        let state = build_provider_state(conn).map_err(internal_err)?;
+        Ok(Json(state))
+    })
+    .await
+}
+
+/**
+ * \brief 更新 Provider。
+ */
+async fn update_provider(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+    Json(payload): Json<ProviderRequest>,
+) -> Result<Json<ProvidersState>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        validate_api_base(conn, &payload.api_base)?;
+        validate_proxy(payload.proxy_url.as_deref())?;
+        let before = db::get_provider_by_id(conn, id).map_err(internal_err)?;
+        db::update_provider(
+            conn,
+            id,
+            &payload.name,
+            &payload.provider,
+            &payload.api_base,
+            &payload.api_key,
+            &payload.model,
+            None,
+        )
+        .map_err(internal_err)?;
+        db::set_provider_generation(conn, id, &payload.generation.clone().normalized())
+            .map_err(internal_err)?;
+        db::set_provider_proxy(conn, id, payload.proxy_url.as_deref()).map_err(internal_err)?;
+        let after = db::get_provider_by_id(conn, id).map_err(internal_err)?;
+        record_audit(
+            conn,
+            &addr,
+            "provider.update",
+            Some(format!("provider:{}", id)),
+            db::provider_changes(before.as_ref(), after.as_ref()),
+        );
+        if payload.set_default.unwrap_or(false) {
+            db::set_default_provider_id(conn, id).map_err(internal_err)?;
+            record_audit(
+                conn,
+                &addr,
+                "provider.select_default",
+                Some(format!("provider:{}", id)),
+                serde_json::json!({}),
+            );
+        }
+        if let Some(enabled) = payload.telemetry_enabled {
+            apply_telemetry_setting(conn, &addr, enabled).map_err(internal_err)?;
+        }
+        telemetry::log_event(
+            "server.provider",
+            &format!("update id={} name={}", id, payload.name),
+        );
+        let state = build_provider_state(conn).map_err(internal_err)?;
+        Ok(Json(state))
+    })
+    .await
+}
+
+/**
+ * \brief 删除 Provider。
+ */
+async fn delete_provider(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+    Query(q): Query<ConfirmQuery>,
+) -> Result<Response, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let before = db::get_provider_by_id(conn, id).map_err(internal_err)?;
+        let chats = db::count_chats_for_provider(conn, id).map_err(internal_err)?;
+        if let Some(challenge) = delete_confirmation(
+            &format!("provider:{}", id),
+            q.confirm.as_deref(),
+            format!("{} chats will lose their provider", chats),
+            serde_json::json!({
+                "provider_id": id,
+                "name": before.as_ref().map(|p| p.name.clone()),
+                "chats_losing_provider": chats,
+                "was_default": db::get_default_provider_id(conn).map_err(internal_err)? == Some(id),
+            }),
+        ) {
+            return Ok(challenge);
+        }
+        db::delete_provider(conn, id).map_err(internal_err)?;
+        record_audit(
+            conn,
+            &addr,
+            "provider.delete",
+            Some(format!("provider:{}", id)),
+            db::provider_changes(before.as_ref(), None),
+        );
+        telemetry::log_event("server.provider", &format!("delete id={}", id));
+        let state = build_provider_state(conn).map_err(internal_err)?;
+        Ok(Json(state).into_response())
+    })
+    .await
+}
+
+/**
+ * \brief 设置默认 Provider。
+ */
+async fn select_provider(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+) -> Result<Json<ProvidersState>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let previous = db::get_default_provider_id(conn).map_err(internal_err)?;
+        db::set_default_provider_id(conn, id).map_err(internal_err)?;
+        record_audit(
+            conn,
+            &addr,
+            "provider.select_default",
+            Some(format!("provider:{}", id)),
+            serde_json::json!({"default_provider_id": {"from": previous, "to": id}}),
+        );
+        telemetry::log_event("server.provider", &format!("select-default id={}", id));
+        let state = build_provider_state(conn).map_err(internal_err)?;
+        Ok(Json(state))
+    })
+    .await
+}
+
+/**
+ * \brief 设置或清除 Provider 的请求签名：PUT /api/providers/{id}/signing，请求体为签名配置或 null。
+ */
+async fn set_provider_signing(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+    Json(signing): Json<Option<RequestSigning>>,
+) -> Result<Json<ProvidersState>, (axum::http::StatusCode, String)> {
+    if id == ENV_PROVIDER_ID {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "环境变量提供的模型服务不支持配置签名".to_string(),
+        ));
+    }
+    if let Some(signing) = &signing {
+        if signing.header.trim().is_empty() || signing.secret.is_empty() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "签名请求头与密钥不能为空".to_string(),
+            ));
+        }
+    }
+    with_db(move |conn| {
+        let before = db::get_provider_by_id(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "指定的模型服务不存在".to_string()))?;
+        db::set_provider_signing(conn, id, signing.as_ref()).map_err(internal_err)?;
+        let header_of = |s: Option<&RequestSigning>| s.map(|s| s.header.trim().to_string());
+        record_audit(
+            conn,
+            &addr,
+            "provider.signing",
+            Some(format!("provider:{}", id)),
+            serde_json::json!({"signing_header": {
+                "from": header_of(before.signing.as_ref()),
+                "to": header_of(signing.as_ref()),
+            }}),
+        );
+        let state = build_provider_state(conn).map_err(internal_err)?;
+        Ok(Json(state))
+    })
+    .await
+}
+
+/**
+ * \brief 批量迁移模型：POST /api/models/migrate，将默认模型为 `from` 的 Provider（及绑定其上的会话）切换到新模型。
+ */
+async fn migrate_model(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<ModelMigrationRequest>,
+) -> Result<Json<Vec<ModelMigrationDto>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let from = payload.from.trim();
+        if from.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, "旧模型名不能为空".to_string()));
+        }
+        let to = payload
+            .to
+            .as_deref()
+            .map(str::trim)
+            .filter(|t| !t.is_empty());
+        let mut plan = Vec::new();
+        for provider in db::list_providers(conn).map_err(internal_err)? {
+            if !provider.model.trim().eq_ignore_ascii_case(from) {
+                continue;
+            }
+            let target = match to {
+                Some(t) => t.to_string(),
+                None => llm::model_deprecation(&provider, &provider.model)
+                    .map(|d| d.replacement)
+                    .ok_or_else(|| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            format!("模型 {} 不在弃用登记表中，请指定新模型", from),
+                        )
+                    })?,
+            };
+            plan.push((provider, target));
+        }
+
+        let mut migrated = Vec::new();
+        for (before, target) in plan {
+            db::set_provider_model(conn, before.id, &target).map_err(internal_err)?;
+            let after = db::get_provider_by_id(conn, before.id).map_err(internal_err)?;
+            record_audit(
+                conn,
+                &addr,
+                "provider.update",
+                Some(format!("provider:{}", before.id)),
+                db::provider_changes(Some(&before), after.as_ref()),
+            );
+            migrated.push(ModelMigrationDto {
+                provider_id: before.id,
+                chats: db::count_chats_for_provider(conn, before.id).map_err(internal_err)?,
+                name: before.name,
+                from: before.model,
+                to: target,
+            });
+        }
+        telemetry::log_event(
+            "server.provider",
+            &format!("migrate model from={} providers={}", from, migrated.len()),
+        );
+        Ok(Json(migrated))
+    })
+    .await
+}
+
+/**
+ * \brief 查看审计日志：GET /api/admin/audit?limit=...
+ */
+async fn list_audit_log(
+    Query(q): Query<AuditQuery>,
+) -> Result<Json<AuditLogResponse>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let limit = q.limit.unwrap_or(100).clamp(1, 1000);
+        let entries = db::list_audit_log(conn, limit).map_err(internal_err)?;
+        let retention_days = db::get_audit_retention_days(conn).map_err(internal_err)?;
+        Ok(Json(AuditLogResponse {
+            retention_days,
+            entries,
+        }))
+    })
+    .await
+}
+
+/**
+ * \brief 更新审计日志保留天数。
+ */
+async fn set_audit_retention(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<AuditRetentionRequest>,
+) -> Result<Json<AuditLogResponse>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let previous = db::get_audit_retention_days(conn).map_err(internal_err)?;
+        db::set_audit_retention_days(conn, payload.days).map_err(internal_err)?;
+        record_audit(
+            conn,
+            &addr,
+            "settings.update",
+            None,
+            serde_json::json!({"audit_retention_days": {"from": previous, "to": payload.days}}),
+        );
+        let entries = db::list_audit_log(conn, 100).map_err(internal_err)?;
+        Ok(Json(AuditLogResponse {
+            retention_days: payload.days,
+            entries,
+        }))
+    })
+    .await
+}
+
+/**
+ * \brief 备份数据库：POST /api/admin/backup，在数据目录的 `backups/` 下生成按时间命名的副本。
+ * \details 备份位置由服务端决定，不接受客户端指定的路径。
+ */
+async fn backup_database(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Result<Json<db::BackupInfo>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let info = db::backup_to_dir(conn, &paths::backup_dir()).map_err(internal_err)?;
+        record_audit(
+            conn,
+            &addr,
+            "admin.backup",
+            Some(info.path.clone()),
+            serde_json::json!({"bytes": info.bytes}),
+        );
+        Ok(Json(info))
+    })
+    .await
+}
+
+/**
+ * \brief 只读 SQL 控制台：POST /api/admin/sql；未开启时返回 403，查询被拒绝或超时返回 400。
+ */
+async fn run_sql_query(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<SqlQueryRequest>,
+) -> Result<Json<sql_console::QueryResult>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let limits = sql_console::QueryLimits::new(payload.max_rows, payload.timeout_ms);
+        sql_console::execute(conn, &audit_actor(&addr), &payload.sql, limits)
+            .map(Json)
+            .map_err(|e| {
+                if e.is::<sql_console::ConsoleDisabled>() {
+                    (StatusCode::FORBIDDEN, e.to_string())
+                } else {
+                    (StatusCode::BAD_REQUEST, format!("{:#}", e))
+                }
+            })
+    })
+    .await
+}
+
+/**
+ * \brief 读取 SQL 控制台开关。
+ */
+async fn get_sql_console_settings(
+) -> Result<Json<SqlConsoleSettings>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let enabled = db::get_sql_console_enabled(conn).map_err(internal_err)?;
+        Ok(Json(SqlConsoleSettings { enabled }))
+    })
+    .await
+}
+
+/**
+ * \brief 开启或关闭 SQL 控制台。
+ */
+async fn set_sql_console_settings(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<SqlConsoleSettings>,
+) -> Result<Json<SqlConsoleSettings>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let previous = db::get_sql_console_enabled(conn).map_err(internal_err)?;
+        db::set_sql_console_enabled(conn, payload.enabled).map_err(internal_err)?;
+        record_audit(
+            conn,
+            &addr,
+            "settings.update",
+            None,
+            serde_json::json!({"sql_console_enabled": {"from": previous, "to": payload.enabled}}),
+        );
+        Ok(Json(payload))
+    })
+    .await
+}
+
+/**
+ * \brief 列出历史会话。
+ */
+async fn list_chats(
+    headers: axum::http::HeaderMap,
+    Query(q): Query<ChatListQuery>,
+) -> Result<Response, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let mut variant = match q.provider_id {
+            Some(pid) => format!("chats.p{}", pid),
+            None => "chats".to_string(),
+        };
+        if q.include_archived {
+            variant.push_str(".all");
+        }
+        let etag = revision_etag(conn, &[db::REVISION_CHATS], &variant).map_err(internal_err)?;
+        if let Some(resp) = not_modified(&headers, &etag) {
+            return Ok(resp);
+        }
+        let chats =
+            db::list_chats(conn, q.provider_id, q.include_archived).map_err(internal_err)?;
+        let items = chats.into_iter().map(ChatSummaryDto::from).collect();
+        Ok(with_etag(ChatListResponse { chats: items }, &etag))
+    })
+    .await
+}
+
+/**
+ * \brief 创建空会话：POST /api/chats，不发送任何消息。
+ */
+async fn create_chat(
+    Json(payload): Json<CreateChatRequest>,
+) -> Result<Json<ChatSummaryDto>, (axum::http::StatusCode, String)> {
+    if backfill::progress().running {
+        return Err((StatusCode::CONFLICT, "补全任务正在运行".to_string()));
+    }
+    with_db(move |conn| {
+        let provider = match payload.provider_id {
+            Some(pid) => Some(
+                resolve_provider_by_id(conn, pid)
+                    .map_err(internal_err)?
+                    .ok_or_else(|| (StatusCode::NOT_FOUND, "指定的模型服务不存在".to_string()))?,
+            ),
+            None => resolve_default_provider(conn).map_err(internal_err)?,
+        };
+        let title = payload
+            .title
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .unwrap_or_else(|| match &provider {
+                Some(p) => format!("{} 会话", p.name),
+                None => "新会话".to_string(),
+            });
+        let provider_id = provider.as_ref().map(|p| p.id);
+        // 环境变量 Provider 不在 providers 表中，先建空绑定会话再写入保留 ID
+        let stored_provider_id = provider_id.filter(|id| *id != ENV_PROVIDER_ID);
+        let chat_id = db::create_empty_chat(
+            conn,
+            &title,
+            stored_provider_id,
+            payload.system_prompt.as_deref(),
+        )
+        .map_err(internal_err)?;
+        if provider_id != stored_provider_id {
+            db::set_chat_provider(conn, chat_id, provider_id).map_err(internal_err)?;
+        }
+        telemetry::log_event(
+            "server.chat",
+            &format!("create chat id={} provider={:?}", chat_id, provider_id),
+        );
+        Ok(Json(ChatSummaryDto {
+            id: chat_id,
+            title,
+            provider_id,
+            last_read_message_id: None,
+            pinned: false,
+            archived: false,
+            model: None,
+        }))
+    })
+    .await
+}
+
+/**
+ * \brief 列出导出流水线：GET /api/export/pipelines。
+ */
+async fn list_export_pipelines(
+) -> Result<Json<Vec<db::ExportPipeline>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::list_export_pipelines(conn)
+            .map(Json)
+            .map_err(internal_err)
+    })
+    .await
+}
+
+/**
+ * \brief 新建导出流水线：POST /api/export/pipelines，请求体 `{ "name": "...", "definition": {...} }`。
+ */
+async fn create_export_pipeline(
+    Json(payload): Json<SavePipelineRequest>,
+) -> Result<Json<db::ExportPipeline>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| save_export_pipeline(conn, None, payload)).await
+}
+
+/**
+ * \brief 修改导出流水线：PUT /api/export/pipelines/{id}。
+ */
+async fn update_export_pipeline(
+    Path(id): Path<i64>,
+    Json(payload): Json<SavePipelineRequest>,
+) -> Result<Json<db::ExportPipeline>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| save_export_pipeline(conn, Some(id), payload)).await
+}
+
+fn save_export_pipeline(
+    conn: &rusqlite::Connection,
+    id: Option<i64>,
+    payload: SavePipelineRequest,
+) -> Result<Json<db::ExportPipeline>, (axum::http::StatusCode, String)> {
+    if let Some(id) = id {
+        db::get_export_pipeline(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "导出流水线不存在".to_string()))?;
+    }
+    pipeline::validate(&payload.definition)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?;
+    let id = db::save_export_pipeline(conn, id, &payload.name, &payload.definition)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    telemetry::log_event("server.export", &format!("save pipeline id={}", id));
+    db::get_export_pipeline(conn, id)
+        .map_err(internal_err)?
+        .map(Json)
+        .ok_or_else(|| internal_err(anyhow!("pipeline {} vanished after save", id)))
+}
+
+/**
+ * \brief 删除导出流水线：DELETE /api/export/pipelines/{id}，返回剩余流水线。
+ */
+async fn delete_export_pipeline(
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<db::ExportPipeline>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::delete_export_pipeline(conn, id).map_err(internal_err)?;
+        db::list_export_pipelines(conn)
+            .map(Json)
+            .map_err(internal_err)
+    })
+    .await
+}
+
+/**
+ * \brief 运行导出流水线：POST /api/export/pipelines/{id}/run，返回渲染好的文件名与内容，不写磁盘。
+ */
+async fn run_export_pipeline(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+) -> Result<Json<pipeline::PipelineRun>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let saved = db::get_export_pipeline(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "导出流水线不存在".to_string()))?;
+        let run = pipeline::preview(conn, &saved)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?;
+        record_audit(
+            conn,
+            &addr,
+            "export.pipeline",
+            Some(saved.name.clone()),
+            serde_json::json!({ "chats": run.chats, "messages": run.messages, "files": run.files.len() }),
+        );
+        Ok(Json(run))
+    })
+    .await
+}
+
+/**
+ * \brief 导出 OpenAI 微调格式 JSONL：POST /api/export/finetune，请求体 `{ "chat_ids": [], "tags": [] }`。
+ */
+async fn export_finetune(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(selection): Json<exporter::FinetuneSelection>,
+) -> Result<Response, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let mut body = Vec::new();
+        let summary =
+            exporter::export_finetune(conn, &selection, &mut body).map_err(internal_err)?;
+        record_audit(
+            conn,
+            &addr,
+            "export.finetune",
+            None,
+            serde_json::json!({ "chats": summary.chats, "examples": summary.examples }),
+        );
+        Ok((
+            [
+                (header::CONTENT_TYPE, "application/jsonl; charset=utf-8"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"dreamquill-finetune.jsonl\"",
+                ),
+            ],
+            body,
+        )
+            .into_response())
+    })
+    .await
+}
+
+/**
+ * \brief 导入 ChatGPT 导出：POST /api/import/chatgpt，请求体为 conversations.json 原文。
+ */
+async fn import_chatgpt(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<ImportQuery>,
+    body: String,
+) -> Result<Json<importer::ImportSummary>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| import_export(conn, &addr, ImportFormat::ChatGpt, query.provider_id, &body))
+        .await
+}
+
+/**
+ * \brief 导入 Claude 导出：POST /api/import/claude，请求体为 conversations.json 原文。
+ */
+async fn import_claude(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<ImportQuery>,
+    body: String,
+) -> Result<Json<importer::ImportSummary>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| import_export(conn, &addr, ImportFormat::Claude, query.provider_id, &body))
+        .await
+}
+
+fn import_export(
+    conn: &rusqlite::Connection,
+    addr: &SocketAddr,
+    format: ImportFormat,
+    provider_id: Option<i64>,
+    body: &str,
+) -> Result<Json<importer::ImportSummary>, (axum::http::StatusCode, String)> {
+    let chats = importer::parse_export(format, body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if let Some(pid) = provider_id {
+        resolve_provider_by_id(conn, pid)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "指定的模型服务不存在".to_string()))?;
+    }
+    let summary = importer::import_chats(conn, &chats, provider_id).map_err(internal_err)?;
+    record_audit(
+        conn,
+        addr,
+        "chat.import",
+        None,
+        serde_json::json!({
+            "format": format!("{:?}", format),
+            "chats": summary.chats,
+            "messages": summary.messages,
+        }),
     );
-    let state = build_provider_state(&conn).map_err(internal_err)?;
-    Ok(Json(state))
+    Ok(Json(summary))
+}
+
+/**
+ * \brief 各 Provider 的首 token 延迟与生成耗时均值，便于横向比较响应速度。
+ */
+async fn provider_stats(
+) -> Result<Json<Vec<db::ProviderLatencyStats>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::provider_latency_stats(conn)
+            .map(Json)
+            .map_err(internal_err)
+    })
+    .await
+}
+
+/**
+ * \brief 按 Provider 与模型汇总每次模型请求的首 token 延迟、总耗时与输出速度。
+ */
+async fn request_stats(
+    Query(q): Query<StatsQuery>,
+) -> Result<Json<Vec<db::RequestStats>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::request_stats(conn, q.since)
+            .map(Json)
+            .map_err(internal_err)
+    })
+    .await
+}
+
+/**
+ * \brief 获取会话标签（含自动分类置信度）。
+ */
+async fn get_chat_tags(
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<db::ChatTag>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| db::list_chat_tags(conn, id).map(Json).map_err(internal_err)).await
+}
+
+/**
+ * \brief 会话的滚动摘要：GET /api/chats/{id}/summary；会话不存在或尚未生成摘要时返回 404。
+ */
+async fn get_chat_summary(
+    Path(id): Path<i64>,
+) -> Result<Json<db::ConversationSummary>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        db::get_conversation_summary(conn, id)
+            .map_err(internal_err)?
+            .map(Json)
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "该会话尚无摘要".to_string()))
+    })
+    .await
+}
+
+/**
+ * \brief 手动触发会话自动打标签：POST /api/chats/{id}/autotag。
+ */
+async fn autotag_chat(
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<db::ChatTag>>, (axum::http::StatusCode, String)> {
+    let provider = with_db(move |conn| {
+        db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        match resolve_provider_for_chat(conn, id).map_err(internal_err)? {
+            Some(p) => Ok(p),
+            None => resolve_default_provider(conn)
+                .map_err(internal_err)?
+                .ok_or_else(|| internal_err(anyhow!("尚未设置可用的模型服务"))),
+        }
+    })
+    .await?;
+    autotag::autotag_chat(&provider, id)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/**
+ * \brief 启动标题与标签补全：POST /api/maintenance/backfill，后台逐个处理占位标题的会话。
+ */
+async fn start_backfill(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<BackfillRequest>,
+) -> Result<Json<backfill::BackfillProgress>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let provider = match payload.provider_id {
+            Some(pid) => resolve_provider_by_id(conn, pid)
+                .map_err(internal_err)?
+                .ok_or_else(|| (StatusCode::NOT_FOUND, "指定的模型服务不存在".to_string()))?,
+            None => resolve_default_provider(conn)
+                .map_err(internal_err)?
+                .ok_or_else(|| internal_err(anyhow!("尚未设置可用的模型服务")))?,
+        };
+        let provider_id = provider.id;
+        let per_minute = payload
+            .calls_per_minute
+            .unwrap_or(backfill::DEFAULT_CALLS_PER_MINUTE);
+        let progress = backfill::start(provider, per_minute).map_err(internal_err)?;
+        record_audit(
+            conn,
+            &addr,
+            "maintenance.backfill",
+            Some(format!("provider:{}", provider_id)),
+            serde_json::json!({
+                "chats": progress.total,
+                "calls_per_minute": progress.calls_per_minute,
+            }),
+        );
+        Ok(Json(progress))
+    })
+    .await
+}
+
+/**
+ * \brief 查看补全进度：GET /api/maintenance/backfill。
+ */
+async fn backfill_progress() -> Json<backfill::BackfillProgress> {
+    Json(backfill::progress())
+}
+
+/**
+ * \brief 停止补全：DELETE /api/maintenance/backfill，当前调用完成后退出。
+ */
+async fn cancel_backfill() -> Json<backfill::BackfillProgress> {
+    Json(backfill::cancel())
+}
+
+/**
+ * \brief 用其他 Provider/模型重新回答一条用户消息：POST /api/messages/{id}/rerun。
+ * \details 结果保存为备选回复，不改变会话消息，也不创建分支。
+ */
+async fn rerun_message(
+    Path(id): Path<i64>,
+    Json(payload): Json<RerunMessageRequest>,
+) -> Result<Json<db::MessageVariant>, (axum::http::StatusCode, String)> {
+    let provider_id = payload.provider_id;
+    let provider = with_db(move |conn| {
+        db::message_context(conn, id)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "消息不存在".to_string()))?;
+        resolve_provider_by_id(conn, provider_id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "指定的模型服务不存在".to_string()))
+    })
+    .await?;
+    rerun::rerun_message(&provider, id, &payload.overrides.normalized())
+        .await
+        .map(Json)
+        .map_err(internal_err)
+}
+
+/**
+ * \brief 温度效果预览：POST /api/chats/{id}/temperature-preview，以会话历史为上下文并发生成简短回复，按温度标注返回。
+ * \details 结果不写入会话，便于用户比较后再选定会话参数。
+ */
+async fn preview_temperatures(
+    Path(id): Path<i64>,
+    Json(payload): Json<TemperaturePreviewRequest>,
+) -> Result<Json<Vec<rerun::TemperatureVariant>>, (axum::http::StatusCode, String)> {
+    let temperatures = rerun::normalize_temperatures(&payload.temperatures)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if payload.prompt.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "发送内容不能为空".to_string()));
+    }
+    let provider_id = payload.provider_id;
+    let provider = with_db(move |conn| {
+        if db::get_chat(conn, id).map_err(internal_err)?.is_none() {
+            return Err((StatusCode::NOT_FOUND, "会话不存在".to_string()));
+        }
+        let provider = match provider_id {
+            Some(pid) => resolve_provider_by_id(conn, pid).map_err(internal_err)?,
+            None => match resolve_provider_for_chat(conn, id).map_err(internal_err)? {
+                Some(p) => Some(p),
+                None => resolve_default_provider(conn).map_err(internal_err)?,
+            },
+        };
+        provider.ok_or_else(|| (StatusCode::NOT_FOUND, "指定的模型服务不存在".to_string()))
+    })
+    .await?;
+    rerun::preview_temperatures(
+        &provider,
+        Some(id),
+        &payload.prompt,
+        &temperatures,
+        &payload.overrides.normalized(),
+    )
+    .await
+    .map(Json)
+    .map_err(internal_err)
+}
+
+/**
+ * \brief 列出用户消息的备选回复：GET /api/messages/{id}/variants。
+ */
+async fn list_message_variants(
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<db::MessageVariant>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::list_message_variants(conn, id)
+            .map(Json)
+            .map_err(internal_err)
+    })
+    .await
+}
+
+/**
+ * \brief 列出会话中某条消息的备选回复（含重新生成前的旧回复）：
+ * GET /api/chats/{id}/messages/{mid}/variants，`mid` 可为用户消息或其后的助手消息。
+ */
+async fn list_chat_message_variants(
+    Path((chat_id, message_id)): Path<(i64, i64)>,
+) -> Result<Json<Vec<db::MessageVariant>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::list_chat_message_variants(conn, chat_id, message_id)
+            .map_err(internal_err)?
+            .map(Json)
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "消息不存在".to_string()))
+    })
+    .await
+}
+
+/**
+ * \brief 编辑消息正文：PATCH /api/messages/{id}，旧内容保存为历史版本。
+ */
+async fn edit_message(
+    Path(id): Path<i64>,
+    Json(payload): Json<EditMessageRequest>,
+) -> Result<Json<ChatMessageDto>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let content = payload.content.trim();
+        if content.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, "消息内容不能为空".to_string()));
+        }
+        let message = db::edit_message(conn, id, content)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "消息不存在".to_string()))?;
+        telemetry::log_event("server.message", &format!("edit message id={}", id));
+        Ok(Json(message_dto(message)))
+    })
+    .await
+}
+
+/**
+ * \brief 列出消息的历史版本：GET /api/messages/{id}/revisions。
+ */
+async fn list_message_revisions(
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<db::MessageRevision>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::get_stored_message(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "消息不存在".to_string()))?;
+        db::list_message_revisions(conn, id)
+            .map(Json)
+            .map_err(internal_err)
+    })
+    .await
+}
+
+/**
+ * \brief 将消息回退到历史版本：POST /api/messages/{id}/revert，回退前的内容同样保留为版本。
+ */
+async fn revert_message(
+    Path(id): Path<i64>,
+    Json(payload): Json<RevertMessageRequest>,
+) -> Result<Json<ChatMessageDto>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let message = db::revert_message(conn, id, payload.revision_id)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "消息不存在".to_string()))?;
+        telemetry::log_event(
+            "server.message",
+            &format!("revert message id={} revision={}", id, payload.revision_id),
+        );
+        Ok(Json(message_dto(message)))
+    })
+    .await
+}
+
+fn message_dto(m: db::StoredMessage) -> ChatMessageDto {
+    ChatMessageDto {
+        id: m.id,
+        role: m.role,
+        content: m.content,
+        first_token_ms: m.first_token_ms,
+        duration_ms: m.duration_ms,
+        metadata: m.metadata,
+        partial: m.partial,
+    }
+}
+
+/**
+ * \brief 列出保存的智能列表：GET /api/smart-lists。
+ */
+async fn list_smart_lists() -> Result<Json<Vec<db::SmartList>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| db::list_smart_lists(conn).map(Json).map_err(internal_err)).await
+}
+
+/**
+ * \brief 保存筛选条件为智能列表：POST /api/smart-lists。
+ */
+async fn create_smart_list(
+    Json(payload): Json<SaveSmartListRequest>,
+) -> Result<Json<db::SmartList>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| save_smart_list(conn, None, payload)).await
+}
+
+/**
+ * \brief 修改智能列表名称或筛选条件：PUT /api/smart-lists/{id}。
+ */
+async fn update_smart_list(
+    Path(id): Path<i64>,
+    Json(payload): Json<SaveSmartListRequest>,
+) -> Result<Json<db::SmartList>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| save_smart_list(conn, Some(id), payload)).await
+}
+
+fn save_smart_list(
+    conn: &rusqlite::Connection,
+    id: Option<i64>,
+    payload: SaveSmartListRequest,
+) -> Result<Json<db::SmartList>, (axum::http::StatusCode, String)> {
+    if let Some(id) = id {
+        db::get_smart_list(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "智能列表不存在".to_string()))?;
+    }
+    let id = db::save_smart_list(conn, id, &payload.name, &payload.filter)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    telemetry::log_event("server.smart_list", &format!("save smart list id={}", id));
+    db::get_smart_list(conn, id)
+        .map_err(internal_err)?
+        .map(Json)
+        .ok_or_else(|| internal_err(anyhow!("smart list {} vanished after save", id)))
+}
+
+/**
+ * \brief 删除智能列表：DELETE /api/smart-lists/{id}，返回剩余列表。
+ */
+async fn delete_smart_list(
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<db::SmartList>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::delete_smart_list(conn, id).map_err(internal_err)?;
+        db::list_smart_lists(conn).map(Json).map_err(internal_err)
+    })
+    .await
+}
+
+/**
+ * \brief 按智能列表的筛选条件解析出会话列表：GET /api/smart-lists/{id}/chats。
+ */
+async fn resolve_smart_list(
+    Path(id): Path<i64>,
+) -> Result<Json<ChatListResponse>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let list = db::get_smart_list(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "智能列表不存在".to_string()))?;
+        let chats = db::search_chats(conn, &list.filter).map_err(internal_err)?;
+        let items = chats.into_iter().map(ChatSummaryDto::from).collect();
+        Ok(Json(ChatListResponse { chats: items }))
+    })
+    .await
+}
+
+/**
+ * \brief 读取客户端侧停止串：GET /api/config/stop-strings。
+ */
+async fn get_stop_strings() -> Result<Json<Vec<String>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| db::get_stop_strings(conn).map(Json).map_err(internal_err)).await
+}
+
+/**
+ * \brief 更新客户端侧停止串：PUT /api/config/stop-strings，请求体为字符串数组。
+ */
+async fn set_stop_strings(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<Vec<String>>,
+) -> Result<Json<Vec<String>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let before = db::get_stop_strings(conn).map_err(internal_err)?;
+        let saved = db::set_stop_strings(conn, &payload).map_err(internal_err)?;
+        record_audit(
+            conn,
+            &addr,
+            "config.stop_strings",
+            None,
+            serde_json::json!({ "before": before, "after": saved }),
+        );
+        Ok(Json(saved))
+    })
+    .await
+}
+
+/**
+ * \brief 读取单次回复的输出上限：GET /api/config/max-output。
+ */
+async fn get_max_output() -> Result<Json<MaxOutputDto>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let max_chars = db::get_max_output_chars(conn).map_err(internal_err)?;
+        Ok(Json(MaxOutputDto { max_chars }))
+    })
+    .await
+}
+
+/**
+ * \brief 更新单次回复的输出上限：PUT /api/config/max-output，请求体如 `{"max_chars":8000}`。
+ * \details 流式输出累计达到上限时截断回复并取消上游请求，结束事件带 `truncated: true`。
+ */
+async fn set_max_output(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<MaxOutputDto>,
+) -> Result<Json<MaxOutputDto>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let before = db::get_max_output_chars(conn).map_err(internal_err)?;
+        let max_chars = db::set_max_output_chars(conn, payload.max_chars).map_err(internal_err)?;
+        record_audit(
+            conn,
+            &addr,
+            "config.max_output",
+            None,
+            serde_json::json!({ "before": before, "after": max_chars }),
+        );
+        Ok(Json(MaxOutputDto { max_chars }))
+    })
+    .await
+}
+
+/**
+ * \brief 读取 LLM 请求重试策略：GET /api/config/retry-policy。
+ */
+async fn get_retry_policy() -> Result<Json<RetryPolicy>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| db::get_retry_policy(conn).map(Json).map_err(internal_err)).await
+}
+
+/**
+ * \brief 更新 LLM 请求重试策略：PUT /api/config/retry-policy，立即对后续请求生效。
+ */
+async fn set_retry_policy(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<RetryPolicy>,
+) -> Result<Json<RetryPolicy>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let before = db::get_retry_policy(conn).map_err(internal_err)?;
+        let saved = db::set_retry_policy(conn, &payload).map_err(internal_err)?;
+        llm::set_retry_policy(saved);
+        record_audit(
+            conn,
+            &addr,
+            "config.retry_policy",
+            None,
+            serde_json::json!({ "before": before, "after": saved }),
+        );
+        Ok(Json(saved))
+    })
+    .await
+}
+
+/**
+ * \brief 读取 Provider api_base 的主机名白名单/黑名单：GET /api/config/host-policy。
+ */
+async fn get_host_policy() -> Result<Json<HostPolicy>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| db::get_host_policy(conn).map(Json).map_err(internal_err)).await
+}
+
+/**
+ * \brief 更新主机名白名单/黑名单：PUT /api/config/host-policy，立即对后续保存与请求生效；条目不合法时返回 400。
+ */
+async fn set_host_policy(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<HostPolicy>,
+) -> Result<Json<HostPolicy>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let before = db::get_host_policy(conn).map_err(internal_err)?;
+        let saved = db::set_host_policy(conn, &payload)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        llm::set_host_policy(saved.clone());
+        record_audit(
+            conn,
+            &addr,
+            "config.host_policy",
+            None,
+            serde_json::json!({ "before": before, "after": saved }),
+        );
+        Ok(Json(saved))
+    })
+    .await
+}
+
+async fn get_autotag_config() -> Result<Json<db::AutotagConfig>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| db::get_autotag_config(conn).map(Json).map_err(internal_err)).await
+}
+
+/**
+ * \brief 更新自动打标签配置（触发阈值与候选标签集合）。
+ */
+async fn set_autotag_config(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<db::AutotagConfig>,
+) -> Result<Json<db::AutotagConfig>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let before = db::get_autotag_config(conn).map_err(internal_err)?;
+        let saved = db::set_autotag_config(conn, &payload)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        record_audit(
+            conn,
+            &addr,
+            "config.autotag",
+            None,
+            serde_json::json!({ "before": before, "after": saved }),
+        );
+        Ok(Json(saved))
+    })
+    .await
+}
+
+async fn get_summary_config() -> Result<Json<db::SummaryConfig>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| db::get_summary_config(conn).map(Json).map_err(internal_err)).await
+}
+
+/**
+ * \brief 更新滚动摘要配置（触发阈值与保留原文的最近消息数）。
+ */
+async fn set_summary_config(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<db::SummaryConfig>,
+) -> Result<Json<db::SummaryConfig>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let before = db::get_summary_config(conn).map_err(internal_err)?;
+        let saved = db::set_summary_config(conn, &payload)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        record_audit(
+            conn,
+            &addr,
+            "config.summary",
+            None,
+            serde_json::json!({ "before": before, "after": saved }),
+        );
+        Ok(Json(saved))
+    })
+    .await
+}
+
+async fn get_refusal_policy() -> Result<Json<db::RefusalPolicy>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| db::get_refusal_policy(conn).map(Json).map_err(internal_err)).await
+}
+
+/**
+ * \brief 更新拒答处理策略（是否自动重试、重试提示与备用 Provider）。
+ */
+async fn set_refusal_policy(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<db::RefusalPolicy>,
+) -> Result<Json<db::RefusalPolicy>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let before = db::get_refusal_policy(conn).map_err(internal_err)?;
+        let saved = db::set_refusal_policy(conn, &payload)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        record_audit(
+            conn,
+            &addr,
+            "config.refusal_policy",
+            None,
+            serde_json::json!({ "before": before, "after": saved }),
+        );
+        Ok(Json(saved))
+    })
+    .await
+}
+
+/**
+ * \brief 获取指定会话的消息；带 `before`/`limit` 参数时按游标分页返回最近一页。
+ */
+async fn get_chat_messages(
+    headers: axum::http::HeaderMap,
+    Path(id): Path<i64>,
+    Query(page): Query<MessagePageQuery>,
+) -> Result<Response, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let variant = format!(
+            "messages.{}.b{}.l{}",
+            id,
+            page.before.map(|b| b.to_string()).unwrap_or_default(),
+            page.limit.map(|l| l.to_string()).unwrap_or_default()
+        );
+        let scope = db::messages_revision_scope(id);
+        let etag = revision_etag(conn, &[&scope, db::REVISION_PROVIDERS], &variant)
+            .map_err(internal_err)?;
+        if let Some(resp) = not_modified(&headers, &etag) {
+            return Ok(resp);
+        }
+        let provider = resolve_provider_for_chat(conn, id).map_err(internal_err)?;
+        let provider_id = provider.as_ref().map(|p| p.id);
+        let (messages, next_cursor) = if page.before.is_some() || page.limit.is_some() {
+            let page = db::load_messages_page(
+                conn,
+                id,
+                page.before,
+                page.limit.unwrap_or(DEFAULT_MESSAGE_PAGE_SIZE),
+            )
+            .map_err(internal_err)?;
+            (page.messages, page.next_cursor)
+        } else {
+            (
+                db::load_messages_with_meta(conn, id).map_err(internal_err)?,
+                None,
+            )
+        };
+        let payload = messages.into_iter().map(message_dto).collect();
+        Ok(with_etag(
+            ChatMessagesResponse {
+                chat_id: id,
+                provider_id,
+                messages: payload,
+                next_cursor,
+            },
+            &etag,
+        ))
+    })
+    .await
+}
+
+/**
+ * \brief 预览下一次请求将发送给模型的上下文：GET /api/chats/{id}/context-preview。
+ * \details 与聊天接口的组装方式一致：按会话的上下文策略挑选历史，系统指令覆盖会替换历史中的 system 消息；
+ * 每项附带粗略 token 估算，会话历史另附入选与否、原因及相似度。
+ */
+async fn context_preview(
+    Path(id): Path<i64>,
+    Query(q): Query<ContextPreviewQuery>,
+) -> Result<Json<ContextPreviewResponse>, (axum::http::StatusCode, String)> {
+    let (provider, plan, history) = with_db(move |conn| {
+        if db::get_chat(conn, id).map_err(internal_err)?.is_none() {
+            return Err((StatusCode::NOT_FOUND, "会话不存在".to_string()));
+        }
+        let provider = match resolve_provider_for_chat(conn, id).map_err(internal_err)? {
+            Some(p) => Some(p),
+            None => resolve_default_provider(conn).map_err(internal_err)?,
+        };
+        let plan = context::plan(conn, id, provider.as_ref()).map_err(internal_err)?;
+        let history = db::load_messages_with_meta(conn, id).map_err(internal_err)?;
+        Ok((provider, plan, history))
+    })
+    .await?;
+    let overrides = llm::RequestOverrides {
+        model: q.model,
+        temperature: None,
+        max_tokens: None,
+        system_instruction: q.system,
+    }
+    .normalized();
+
+    let prompt = q.prompt.filter(|p| !p.trim().is_empty());
+    let plan = match &prompt {
+        Some(prompt) => plan.with_pending_prompt(prompt),
+        None => plan,
+    };
+    let strategy = plan.strategy().clone();
+    let mut selection = plan.select(provider.as_ref()).await;
+    if let Err(err) = selection.persist_embeddings().await {
+        telemetry::log_event(
+            "server.chat",
+            &format!("embedding cache write failed: {}", err),
+        );
+    }
+    let notes: HashMap<i64, &context::ContextNote> =
+        selection.notes.iter().map(|n| (n.message_id, n)).collect();
+
+    let item = |message_id, role: &str, source, content: String| ContextItemDto {
+        message_id,
+        role: role.to_string(),
+        source,
+        tokens: llm::estimate_tokens(&content),
+        content,
+        included: true,
+        reason: None,
+        score: None,
+    };
+    let mut items = Vec::new();
+    if let Some(sys) = &overrides.system_instruction {
+        items.push(item(None, "system", "system_override", sys.clone()));
+    }
+    let mut summary = selection.rolling_summary.as_deref().map(|s| {
+        item(
+            None,
+            "system",
+            "summary",
+            summarize::system_message(s).content,
+        )
+    });
+    for m in history {
+        if m.role != "system" {
+            items.extend(summary.take());
+        }
+        if overrides.system_instruction.is_some() && m.role == "system" {
+            continue;
+        }
+        let content = markdown::expand_quotes(&m.content, m.metadata.as_ref());
+        let mut entry = item(Some(m.id), &m.role, "message", content);
+        if let Some(note) = notes.get(&m.id) {
+            entry.included = note.included;
+            entry.reason = Some(note.reason);
+            entry.score = note.score;
+        }
+        items.push(entry);
+    }
+    items.extend(summary);
+    if let Some(prompt) = prompt {
+        items.push(item(None, "user", "prompt", prompt));
+    }
+
+    Ok(Json(ContextPreviewResponse {
+        chat_id: id,
+        provider_id: provider.as_ref().map(|p| p.id),
+        model: provider
+            .as_ref()
+            .map(|p| overrides.model_for(p).to_string()),
+        strategy,
+        embedding_model: selection.embedding_model.clone(),
+        fallback: selection.fallback,
+        total_tokens: items.iter().filter(|i| i.included).map(|i| i.tokens).sum(),
+        items,
+    }))
+}
+
+/**
+ * \brief 查询会话的上下文选择策略：GET /api/chats/{id}/context-strategy。
+ */
+async fn get_chat_context_strategy(
+    Path(id): Path<i64>,
+) -> Result<Json<ContextStrategy>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        db::get_chat_context_strategy(conn, id)
+            .map(Json)
+            .map_err(internal_err)
+    })
+    .await
 }
 
 /**
- * \brief 删除 Provider。
+ * \brief 设置会话的上下文选择策略：PUT /api/chats/{id}/context-strategy，
+ * 请求体如 `{"mode":"relevance","max_tokens":3000,"keep_recent":4}`，`{"mode":"full"}` 恢复发送全部历史。
  */
-async fn delete_provider(
+async fn set_chat_context_strategy(
     Path(id): Path<i64>,
-) -> Result<Json<ProvidersState>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    db::delete_provider(&conn, id).map_err(internal_err)?;
-    telemetry::log_event("server.provider", &format!("delete id={}", id));
-    let state = build_provider_state(&conn).map_err(internal_err)?;
-    Ok(Json(state))
+    Json(payload): Json<ContextStrategy>,
+) -> Result<Json<ContextStrategy>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        db::set_chat_context_strategy(conn, id, &payload).map_err(internal_err)?;
+        telemetry::log_event(
+            "server.chat",
+            &format!("set chat context_strategy id={} strategy={:?}", id, payload),
+        );
+        Ok(Json(payload))
+    })
+    .await
 }
 
 /**
- * \brief 设置默认 Provider。
+ * \brief 查询会话的回复语言设置：GET /api/chats/{id}/reply-language，未设置时为 null。
  */
-async fn select_provider(
+async fn get_chat_reply_language(
     Path(id): Path<i64>,
-) -> Result<Json<ProvidersState>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    db::set_default_provider_id(&conn, id).map_err(internal_err)?;
-    telemetry::log_event("server.provider", &format!("select-default id={}", id));
-    let state = build_provider_state(&conn).map_err(internal_err)?;
-    Ok(Json(state))
+) -> Result<Json<Option<ReplyLanguage>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        db::get_chat_reply_language(conn, id)
+            .map(Json)
+            .map_err(internal_err)
+    })
+    .await
 }
 
 /**
- * \brief 列出历史会话。
+ * \brief 设置会话的回复语言：PUT /api/chats/{id}/reply-language，
+ * 请求体如 `{"language":"zh","enforce":true}`，`null` 清除设置。
  */
-async fn list_chats(
-    Query(q): Query<ChatListQuery>,
-) -> Result<Json<ChatListResponse>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    let chats = db::list_chats(&conn, q.provider_id).map_err(internal_err)?;
-    let items = chats
-        .into_iter()
-        .map(|c| ChatSummaryDto {
-            id: c.id,
-            title: c.title,
-            provider_id: c.provider_id,
-        })
-        .collect();
-    Ok(Json(ChatListResponse { chats: items }))
+async fn set_chat_reply_language(
+    Path(id): Path<i64>,
+    Json(payload): Json<Option<ReplyLanguage>>,
+) -> Result<Json<Option<ReplyLanguage>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        db::set_chat_reply_language(conn, id, payload.as_ref()).map_err(internal_err)?;
+        telemetry::log_event(
+            "server.chat",
+            &format!("set chat reply_language id={} setting={:?}", id, payload),
+        );
+        db::get_chat_reply_language(conn, id)
+            .map(Json)
+            .map_err(internal_err)
+    })
+    .await
 }
 
 /**
- * \brief 获取指定会话的消息。
+ * \brief 列出上次运行中断的生成任务：GET /api/chats/interrupted。
  */
-async fn get_chat_messages(
-    Path(id): Path<i64>,
-) -> Result<Json<ChatMessagesResponse>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    let provider = db::get_provider_for_chat(&conn, id).map_err(internal_err)?;
-    let provider_id = provider.as_ref().map(|p| p.id);
-    let messages = db::load_messages_with_meta(&conn, id).map_err(internal_err)?;
-    let payload = messages
-        .into_iter()
-        .map(|m| ChatMessageDto {
-            id: m.id,
-            role: m.role,
-            content: m.content,
-        })
-        .collect();
-    Ok(Json(ChatMessagesResponse {
-        chat_id: id,
-        provider_id,
-        messages: payload,
-    }))
+async fn list_interrupted(
+) -> Result<Json<Vec<db::InterruptedGeneration>>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::list_interrupted_generations(conn)
+            .map(Json)
+            .map_err(internal_err)
+    })
+    .await
+}
+
+/**
+ * \brief 处理中断的生成：POST /api/chats/interrupted，`finalize` 保存部分回复，`discard` 丢弃。
+ * \details 处理后剩余的中断记录会一并返回；需要继续生成时可对保存的回复发起重新生成。
+ */
+async fn resolve_interrupted(
+    Json(payload): Json<ResolveInterruptedRequest>,
+) -> Result<Json<Vec<db::InterruptedGeneration>>, (axum::http::StatusCode, String)> {
+    let keep_partial = match payload.action.as_str() {
+        "finalize" => true,
+        "discard" => false,
+        other => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("unknown action: {}", other),
+            ))
+        }
+    };
+    with_db(move |conn| {
+        db::resolve_interrupted_generation(conn, payload.id, keep_partial)
+            .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+        db::list_interrupted_generations(conn)
+            .map(Json)
+            .map_err(internal_err)
+    })
+    .await
 }
 
 /**
@@ -415,20 +3484,29 @@ async fn get_chat_messages(
  */
 async fn remove_chat(
     Path(id): Path<i64>,
-) -> Result<Json<ChatListResponse>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    db::delete_chat(&conn, id).map_err(internal_err)?;
-    telemetry::log_event("server.chat", &format!("delete chat id={}", id));
-    let chats = db::list_chats(&conn, None).map_err(internal_err)?;
-    let items = chats
-        .into_iter()
-        .map(|c| ChatSummaryDto {
-            id: c.id,
-            title: c.title,
-            provider_id: c.provider_id,
-        })
-        .collect();
-    Ok(Json(ChatListResponse { chats: items }))
+    Query(q): Query<ConfirmQuery>,
+) -> Result<Response, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let messages = db::count_messages(conn, id).map_err(internal_err)?;
+        if let Some(challenge) = delete_confirmation(
+            &format!("chat:{}", id),
+            q.confirm.as_deref(),
+            format!("{} messages will be deleted", messages),
+            serde_json::json!({
+                "chat_id": id,
+                "title": db::get_chat(conn, id).map_err(internal_err)?.map(|c| c.title),
+                "messages_deleted": messages,
+            }),
+        ) {
+            return Ok(challenge);
+        }
+        db::delete_chat(conn, id).map_err(internal_err)?;
+        telemetry::log_event("server.chat", &format!("delete chat id={}", id));
+        let chats = db::list_chats(conn, None, false).map_err(internal_err)?;
+        let items = chats.into_iter().map(ChatSummaryDto::from).collect();
+        Ok(Json(ChatListResponse { chats: items }).into_response())
+    })
+    .await
 }
 
 /**
@@ -438,159 +3516,873 @@ async fn rename_chat(
     Path(id): Path<i64>,
     Json(payload): Json<RenameChatRequest>,
 ) -> Result<Json<ChatSummaryDto>, (axum::http::StatusCode, String)> {
-    let trimmed_title = payload.title.trim();
-    if trimmed_title.is_empty() {
-        return Err(internal_err(anyhow!("会话标题不能为空")));
-    }
+    with_db(move |conn| {
+        let trimmed_title = payload.title.trim();
+        if trimmed_title.is_empty() {
+            return Err(internal_err(anyhow!("会话标题不能为空")));
+        }
+        db::update_chat_title(conn, id, trimmed_title).map_err(internal_err)?;
+        let provider = resolve_provider_for_chat(conn, id).map_err(internal_err)?;
+        let chat = db::get_chat(conn, id).map_err(internal_err)?;
+        telemetry::log_event(
+            "server.chat",
+            &format!("rename chat id={} title={}", id, trimmed_title),
+        );
+
+        Ok(Json(ChatSummaryDto {
+            id,
+            title: trimmed_title.to_string(),
+            provider_id: provider.map(|p| p.id),
+            last_read_message_id: chat.as_ref().and_then(|c| c.last_read_message_id),
+            pinned: chat.as_ref().is_some_and(|c| c.pinned),
+            archived: chat.as_ref().is_some_and(|c| c.archived),
+            model: chat.and_then(|c| c.model),
+        }))
+    })
+    .await
+}
+
+/**
+ * \brief 部分更新会话：PATCH /api/chats/{id}，可修改标题、置顶与归档状态，省略的字段保持不变。
+ */
+async fn update_chat(
+    Path(id): Path<i64>,
+    Json(payload): Json<UpdateChatRequest>,
+) -> Result<Json<ChatSummaryDto>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let title = payload.title.as_deref().map(str::trim);
+        if title == Some("") {
+            return Err((StatusCode::BAD_REQUEST, "会话标题不能为空".to_string()));
+        }
+        db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        let tx = conn.unchecked_transaction().map_err(internal_err)?;
+        if let Some(title) = title {
+            db::update_chat_title(&tx, id, title).map_err(internal_err)?;
+        }
+        if let Some(pinned) = payload.pinned {
+            db::set_chat_pinned(&tx, id, pinned).map_err(internal_err)?;
+        }
+        if let Some(archived) = payload.archived {
+            db::set_chat_archived(&tx, id, archived).map_err(internal_err)?;
+        }
+        tx.commit().map_err(internal_err)?;
+        telemetry::log_event(
+            "server.chat",
+            &format!(
+                "update chat id={} title={:?} pinned={:?} archived={:?}",
+                id, title, payload.pinned, payload.archived
+            ),
+        );
+        let chat = db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        Ok(Json(ChatSummaryDto::from(chat)))
+    })
+    .await
+}
+
+/**
+ * \brief 显式切换或解除会话绑定的模型服务：PUT（或 PATCH）/api/chats/{id}/provider，
+ * 请求体如 `{"provider_id":2}`；Provider 不存在时返回 404，成功返回更新后的会话摘要。
+ */
+async fn set_chat_provider(
+    Path(id): Path<i64>,
+    Json(payload): Json<ChatProviderRequest>,
+) -> Result<Json<ChatSummaryDto>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let chat = db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        if let Some(pid) = payload.provider_id {
+            if resolve_provider_by_id(conn, pid)
+                .map_err(internal_err)?
+                .is_none()
+            {
+                return Err((StatusCode::NOT_FOUND, "指定的模型服务不存在".to_string()));
+            }
+        }
+        db::set_chat_provider(conn, id, payload.provider_id).map_err(internal_err)?;
+        telemetry::log_event(
+            "server.chat",
+            &format!(
+                "set chat provider id={} from={:?} to={:?}",
+                id, chat.provider_id, payload.provider_id
+            ),
+        );
+        // 切换 Provider 可能清除会话级模型，重新读取以返回最新状态
+        let updated = db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        Ok(Json(ChatSummaryDto::from(updated)))
+    })
+    .await
+}
+
+/**
+ * \brief 设置会话级模型：PATCH /api/chats/{id}/model，请求体如 `{"model":"gpt-4o-mini"}`，
+ * `null` 恢复使用 Provider 的默认模型；返回更新后的会话摘要。
+ */
+async fn set_chat_model(
+    Path(id): Path<i64>,
+    Json(payload): Json<ChatModelRequest>,
+) -> Result<Json<ChatSummaryDto>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let chat = db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        db::set_chat_model(conn, id, payload.model.as_deref()).map_err(internal_err)?;
+        let updated = db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        telemetry::log_event(
+            "server.chat",
+            &format!(
+                "set chat model id={} from={:?} to={:?}",
+                id, chat.model, updated.model
+            ),
+        );
+        Ok(Json(ChatSummaryDto::from(updated)))
+    })
+    .await
+}
+
+/**
+ * \brief 查询会话的流中断自动续写开关：GET /api/chats/{id}/stream-retry。
+ */
+async fn get_chat_stream_retry(
+    Path(id): Path<i64>,
+) -> Result<Json<StreamRetryDto>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        let enabled = db::get_chat_stream_retry(conn, id).map_err(internal_err)?;
+        Ok(Json(StreamRetryDto { enabled }))
+    })
+    .await
+}
+
+/**
+ * \brief 开关会话的流中断自动续写：PUT /api/chats/{id}/stream-retry。
+ */
+async fn set_chat_stream_retry(
+    Path(id): Path<i64>,
+    Json(payload): Json<StreamRetryDto>,
+) -> Result<Json<StreamRetryDto>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        db::set_chat_stream_retry(conn, id, payload.enabled).map_err(internal_err)?;
+        telemetry::log_event(
+            "server.chat",
+            &format!(
+                "set chat stream_retry id={} enabled={}",
+                id, payload.enabled
+            ),
+        );
+        Ok(Json(payload))
+    })
+    .await
+}
 
-    let conn = db::open_default_db().map_err(internal_err)?;
-    db::update_chat_title(&conn, id, trimmed_title).map_err(internal_err)?;
-    let provider = db::get_provider_for_chat(&conn, id).map_err(internal_err)?;
+/**
+ * \brief 中止会话进行中的生成：POST /api/chats/{id}/cancel，上游请求立即断开，已生成部分照常保存。
+ */
+async fn cancel_chat_generation(Path(id): Path<i64>) -> Json<serde_json::Value> {
+    let cancelled = cancel_generations(id);
     telemetry::log_event(
         "server.chat",
-        &format!("rename chat id={} title={}", id, trimmed_title),
+        &format!("cancel generation chat_id={} cancelled={}", id, cancelled),
     );
+    Json(serde_json::json!({ "chat_id": id, "cancelled": cancelled }))
+}
 
-    Ok(Json(ChatSummaryDto {
-        id,
-        title: trimmed_title.to_string(),
-        provider_id: provider.map(|p| p.id),
-    }))
+/**
+ * \brief 按流 ID 中止生成：POST /api/chat/cancel/{stream_id}，`stream_id` 为发起聊天时传入（或 `meta` 事件返回）的值。
+ * \details 上游请求立即断开，已生成的部分照常保存为助手消息，发起方与订阅方随后收到 `done`；流不存在或已结束时返回 404。
+ */
+async fn cancel_chat_stream(
+    Path(stream_id): Path<String>,
+) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
+    let chat_id = cancel_stream(&stream_id).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("stream {} 不存在或已结束", stream_id),
+        )
+    })?;
+    telemetry::log_event(
+        "server.chat",
+        &format!(
+            "cancel stream stream_id={} chat_id={:?}",
+            stream_id, chat_id
+        ),
+    );
+    Ok(Json(serde_json::json!({
+        "stream_id": stream_id,
+        "chat_id": chat_id,
+        "cancelled": true
+    })))
+}
+
+/**
+ * \brief 标记会话已读：PATCH /api/chats/{id}/read，`message_id` 省略时标记到最新消息。
+ */
+async fn mark_chat_read(
+    Path(id): Path<i64>,
+    Json(payload): Json<MarkReadRequest>,
+) -> Result<Json<ChatSummaryDto>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let chat = db::get_chat(conn, id)
+            .map_err(internal_err)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "会话不存在".to_string()))?;
+        let last_read_message_id = db::mark_chat_read(conn, id, payload.message_id)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+            .or(chat.last_read_message_id);
+        Ok(Json(ChatSummaryDto {
+            last_read_message_id,
+            ..ChatSummaryDto::from(chat)
+        }))
+    })
+    .await
+}
+
+/**
+ * \brief 克隆聊天并可选截断至指定消息。
+ */
+async fn branch_chat(
+    Path(id): Path<i64>,
+    Json(payload): Json<BranchRequest>,
+) -> Result<Json<BranchResponse>, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let title = payload.title.unwrap_or_else(|| format!("Chat {} 分支", id));
+        let new_chat_id = db::clone_chat_until(conn, id, &title, payload.until_message_id)
+            .map_err(internal_err)?;
+        telemetry::log_event(
+            "server.chat",
+            &format!(
+                "branch chat={} -> new_chat={} until={:?}",
+                id, new_chat_id, payload.until_message_id
+            ),
+        );
+        Ok(Json(BranchResponse {
+            chat_id: new_chat_id,
+            title,
+        }))
+    })
+    .await
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatQuery {
+    /** \brief 会话ID（可选） */
+    chat_id: Option<i64>,
+    /** \brief Provider ID（可选） */
+    provider_id: Option<i64>,
+    /** \brief 用户发送的消息 */
+    prompt: String,
+    /** \brief 是否以流式返回（默认 true） */
+    stream: Option<bool>,
+    /** \brief 开启调试（默认 false），将推送 log 事件 */
+    debug: Option<bool>,
+    /** \brief 需要重新生成的消息 ID（针对助手消息）。 */
+    regen_message_id: Option<i64>,
+    /** \brief 单次请求覆盖的模型名。 */
+    model: Option<String>,
+    /** \brief 单次请求覆盖的温度。 */
+    temperature: Option<f64>,
+    /** \brief 单次请求覆盖的输出 token 上限。 */
+    max_tokens: Option<u32>,
+    /** \brief 单次请求覆盖的系统指令。 */
+    system: Option<String>,
+    /** \brief 引用的早先消息 ID，逗号分隔；作为引用块随提示发送给模型。 */
+    quote_ids: Option<String>,
+    /** \brief 增量事件改为 JSON，附带累计字符数、序号与耗时（默认 false）。 */
+    progress: Option<bool>,
+    /** \brief 提示原文只发送给模型，数据库中保存占位文本（默认 false）。 */
+    redact_prompt: Option<bool>,
+    /** \brief 客户端指定的流 ID，其他客户端可据此订阅本次生成；缺省由服务端生成。 */
+    stream_id: Option<String>,
+}
+
+/**
+ * \brief 聊天请求参数：`POST /api/chat` 与 `POST /api/chat/stream` 的 JSON 请求体，
+ * `GET /api/chat/sse` 的查询参数也转换为该结构。
+ */
+#[derive(Deserialize, Debug, Default)]
+struct ChatBody {
+    chat_id: Option<i64>,
+    provider_id: Option<i64>,
+    /** \brief 用户发送的消息；重新生成时留空。 */
+    #[serde(default)]
+    prompt: String,
+    /** \brief 是否以流式请求上游（SSE 接口默认 true，`POST /api/chat` 默认 false）。 */
+    stream: Option<bool>,
+    debug: Option<bool>,
+    regen_message_id: Option<i64>,
+    model: Option<String>,
+    temperature: Option<f64>,
+    max_tokens: Option<u32>,
+    system: Option<String>,
+    #[serde(default)]
+    quote_ids: Vec<i64>,
+    progress: Option<bool>,
+    redact_prompt: Option<bool>,
+    stream_id: Option<String>,
+}
+
+impl ChatQuery {
+    fn into_body(self) -> Result<ChatBody, (StatusCode, String)> {
+        Ok(ChatBody {
+            quote_ids: parse_id_list(self.quote_ids.as_deref())?,
+            chat_id: self.chat_id,
+            provider_id: self.provider_id,
+            prompt: self.prompt,
+            stream: self.stream,
+            debug: self.debug,
+            regen_message_id: self.regen_message_id,
+            model: self.model,
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            system: self.system,
+            progress: self.progress,
+            redact_prompt: self.redact_prompt,
+            stream_id: self.stream_id,
+        })
+    }
+}
+
+/** \brief 解析逗号分隔的消息 ID 列表。 */
+fn parse_id_list(raw: Option<&str>) -> Result<Vec<i64>, (StatusCode, String)> {
+    raw.unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse()
+                .map_err(|_| (StatusCode::BAD_REQUEST, format!("无效的消息 ID：{}", s)))
+        })
+        .collect()
+}
+
+/**
+ * \brief 聊天 SSE 流接口：GET /api/chat/sse?prompt=...&chat_id=...
+ * \details 事件带 `id` 与 `retry` 字段；浏览器断线重连时携带 `Last-Event-ID`，
+ * 此时不再发起新的生成，而是从生成检查点续传剩余回复。
+ */
+async fn chat_sse(
+    headers: axum::http::HeaderMap,
+    quota: Option<Extension<ChatQuota>>,
+    Query(q): Query<ChatQuery>,
+) -> Result<
+    Sse<impl tokio_stream::Stream<Item = Result<Event, Infallible>>>,
+    (axum::http::StatusCode, String),
+> {
+    // 续传与新生成的事件流类型不同，统一装箱
+    use futures_util::StreamExt;
+    if let Some((generation_id, offset)) = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_sse_event_id)
+    {
+        let (tx, rx) = mpsc::unbounded_channel::<Result<Event, Infallible>>();
+        tokio::spawn(resume_generation(
+            tx,
+            generation_id,
+            offset,
+            q.chat_id,
+            q.progress.unwrap_or(false),
+        ));
+        let stream = UnboundedReceiverStream::new(rx);
+        return Ok(Sse::new(stream.boxed()).keep_alive(KeepAlive::new()));
+    }
+
+    let progress = q.progress.unwrap_or(false);
+    let quota = quota.map(|Extension(quota)| quota);
+    let (rx, generation_id) = start_chat(q.into_body()?, true, quota).await?;
+    let stream = sse_events(rx, generation_id, progress);
+    Ok(Sse::new(stream.boxed()).keep_alive(KeepAlive::new()))
+}
+
+/**
+ * \brief 聊天 SSE 流接口（JSON 请求体）：POST /api/chat/stream。
+ * \details 提示放在请求体中，不进入 URL 与访问日志；事件格式与 `GET /api/chat/sse` 相同，
+ * 断线后可用 `Last-Event-ID` 向 GET 接口续传。
+ */
+async fn chat_stream_post(
+    quota: Option<Extension<ChatQuota>>,
+    Json(body): Json<ChatBody>,
+) -> Result<
+    Sse<impl tokio_stream::Stream<Item = Result<Event, Infallible>>>,
+    (axum::http::StatusCode, String),
+> {
+    let progress = body.progress.unwrap_or(false);
+    let quota = quota.map(|Extension(quota)| quota);
+    let (rx, generation_id) = start_chat(body, true, quota).await?;
+    Ok(Sse::new(sse_events(rx, generation_id, progress)).keep_alive(KeepAlive::new()))
+}
+
+/** \brief 订阅接口的查询参数。 */
+#[derive(Deserialize, Debug, Default)]
+struct SubscribeQuery {
+    /** \brief 增量事件改为 JSON，附带累计字符数、序号与耗时（默认 false）。 */
+    progress: Option<bool>,
+}
+
+/**
+ * \brief 订阅进行中的生成：GET /api/streams/{id}/subscribe，可在另一窗口或设备上接手观看。
+ * \details 先补发已输出的回复，再推送实时增量，事件格式与 `GET /api/chat/sse` 相同；
+ * 重连时携带 `Last-Event-ID` 只补发之后的部分。订阅者断开不影响生成；流不存在或已结束时返回 404。
+ */
+async fn subscribe_stream_sse(
+    headers: axum::http::HeaderMap,
+    Path(stream_id): Path<String>,
+    Query(q): Query<SubscribeQuery>,
+) -> Result<
+    Sse<impl tokio_stream::Stream<Item = Result<Event, Infallible>>>,
+    (axum::http::StatusCode, String),
+> {
+    let skip = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_sse_event_id)
+        .map_or(0, |(_, offset)| offset);
+    let (rx, generation_id) = subscribe_stream(&stream_id, skip).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("stream {} 不存在或已结束", stream_id),
+        )
+    })?;
+    let stream = sse_events(rx, generation_id, q.progress.unwrap_or(false));
+    Ok(Sse::new(stream).keep_alive(KeepAlive::new()))
+}
+
+/** \brief 进行中的生成流。 */
+#[derive(Serialize, Debug)]
+struct ActiveStream {
+    stream_id: String,
+    chat_id: Option<i64>,
+    generation_id: Option<i64>,
+    /** \brief 已输出的回复字符数。 */
+    chars: usize,
+    subscribers: usize,
+}
+
+/** \brief 列出进行中的生成流：GET /api/streams，供其他设备选择要接手的生成。 */
+async fn list_streams() -> Json<serde_json::Value> {
+    let broadcasts: Vec<_> = active_streams()
+        .lock()
+        .map(|map| {
+            map.iter()
+                .map(|(id, broadcast)| (id.clone(), broadcast.clone()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut streams: Vec<ActiveStream> = broadcasts
+        .into_iter()
+        .filter_map(|(stream_id, broadcast)| {
+            let state = broadcast.lock().ok()?;
+            let (chat_id, generation_id) = match &state.meta {
+                Some(ChatOutput::Meta {
+                    chat_id,
+                    generation_id,
+                    ..
+                }) => (Some(*chat_id), *generation_id),
+                _ => (None, None),
+            };
+            Some(ActiveStream {
+                stream_id,
+                chat_id,
+                generation_id,
+                chars: state.reply.chars().count(),
+                subscribers: state.subscribers.len(),
+            })
+        })
+        .collect();
+    streams.sort_by(|a, b| a.stream_id.cmp(&b.stream_id));
+    Json(serde_json::json!({ "streams": streams }))
+}
+
+/**
+ * \brief 非流式聊天接口：POST /api/chat，等待生成结束后返回完整回复。
+ * \details 与 SSE 接口共用 Provider 选择与消息保存逻辑；拒答重试与回复语言重问产生的备选回复放在 `variants` 中。
+ * 未生成任何内容时返回 502；中途出错但已保存部分回复时仍返回 200，并在 `error` 中说明。
+ */
+async fn chat_post(
+    quota: Option<Extension<ChatQuota>>,
+    Json(body): Json<ChatBody>,
+) -> Result<Json<ChatReply>, (axum::http::StatusCode, String)> {
+    let quota = quota.map(|Extension(quota)| quota);
+    let (mut rx, _) = start_chat(body, false, quota).await?;
+    let mut reply = ChatReply::default();
+    while let Some(output) = rx.recv().await {
+        match output {
+            ChatOutput::Meta {
+                chat_id, stream_id, ..
+            } => {
+                reply.chat_id = chat_id;
+                reply.stream_id = stream_id;
+            }
+            ChatOutput::Log(line) => reply.logs.push(line),
+            ChatOutput::Chunk { text, .. } => reply.content.push_str(&text),
+            ChatOutput::Error(error) => reply.error = Some(error),
+            ChatOutput::Variant(variant) => reply.variants.push(variant),
+            ChatOutput::Title { title, .. } => reply.title = Some(title),
+            ChatOutput::Done {
+                message_id,
+                truncated,
+                ..
+            } => {
+                reply.message_id = message_id;
+                reply.truncated = truncated;
+            }
+        }
+    }
+    if reply.message_id.is_none() {
+        if let Some(error) = reply.error {
+            return Err((StatusCode::BAD_GATEWAY, error));
+        }
+    }
+    Ok(Json(reply))
+}
+
+/**
+ * \brief 聊天生成过程中的输出：SSE 接口逐条转为事件，JSON 接口汇总为完整回复。
+ */
+#[derive(Debug, Clone)]
+enum ChatOutput {
+    Meta {
+        chat_id: i64,
+        generation_id: Option<i64>,
+        stream_id: String,
+    },
+    Log(String),
+    /** \brief 回复增量；`offset` 为追加后回复的字节长度，用作续传位置。 */
+    Chunk {
+        text: String,
+        offset: usize,
+    },
+    Error(String),
+    Variant(db::MessageVariant),
+    /** \brief 新会话首轮回复后自动生成的标题。 */
+    Title {
+        chat_id: i64,
+        title: String,
+    },
+    /** \brief 生成结束；`truncated` 表示回复被停止串或输出上限截断。 */
+    Done {
+        chat_id: i64,
+        message_id: Option<i64>,
+        truncated: bool,
+    },
+}
+
+/**
+ * \brief 把生成输出转为 SSE 事件：事件带 `id`（生成ID:已发送字节数），首个事件带 `retry`。
+ */
+fn sse_events(
+    rx: mpsc::UnboundedReceiver<ChatOutput>,
+    generation_id: Option<i64>,
+    progress: bool,
+) -> impl tokio_stream::Stream<Item = Result<Event, Infallible>> {
+    use tokio_stream::StreamExt;
+    let mut counter = progress.then(llm::ChunkCounter::new);
+    UnboundedReceiverStream::new(rx).map(move |output| {
+        Ok(match output {
+            ChatOutput::Meta {
+                chat_id,
+                generation_id,
+                stream_id,
+            } => {
+                let meta = Event::default().retry(sse_retry()).event("meta").data(
+                    serde_json::json!({
+                        "chat_id": chat_id,
+                        "generation_id": generation_id,
+                        "stream_id": stream_id,
+                    })
+                    .to_string(),
+                );
+                match generation_id {
+                    Some(g) => meta.id(sse_event_id(g, 0)),
+                    None => meta,
+                }
+            }
+            ChatOutput::Log(line) => Event::default().event("log").data(line),
+            ChatOutput::Chunk { text, offset } => chunk_event(
+                text,
+                generation_id.map(|g| sse_event_id(g, offset)),
+                counter.as_mut(),
+            ),
+            ChatOutput::Error(error) => Event::default().event("error").data(error),
+            ChatOutput::Variant(variant) => Event::default()
+                .event("variant")
+                .data(serde_json::to_string(&variant).unwrap_or_default()),
+            ChatOutput::Title { chat_id, title } => Event::default()
+                .event("title")
+                .data(serde_json::json!({ "chat_id": chat_id, "title": title }).to_string()),
+            ChatOutput::Done {
+                chat_id,
+                message_id,
+                truncated,
+            } => done_event(chat_id, message_id, truncated),
+        })
+    })
 }
 
-/**
- * \brief 克隆聊天并可选截断至指定消息。
- */
-async fn branch_chat(
-    Path(id): Path<i64>,
-    Json(payload): Json<BranchRequest>,
-) -> Result<Json<BranchResponse>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    let title = payload.title.unwrap_or_else(|| format!("Chat {} 分支", id));
-    let new_chat_id =
-        db::clone_chat_until(&conn, id, &title, payload.until_message_id).map_err(internal_err)?;
-    telemetry::log_event(
-        "server.chat",
-        &format!(
-            "branch chat={} -> new_chat={} until={:?}",
-            id, new_chat_id, payload.until_message_id
-        ),
-    );
-    Ok(Json(BranchResponse {
-        chat_id: new_chat_id,
-        title,
-    }))
+/** \brief `POST /api/chat` 的响应。 */
+#[derive(Serialize, Debug, Default)]
+struct ChatReply {
+    chat_id: i64,
+    stream_id: String,
+    /** \brief 保存的助手消息 ID；未生成任何内容时为空。 */
+    message_id: Option<i64>,
+    content: String,
+    /** \brief 拒答重试或回复语言重问产生的备选回复。 */
+    variants: Vec<db::MessageVariant>,
+    /** \brief 新会话首轮回复后自动生成的标题。 */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    /** \brief 调试信息与处理提示（如流中断续写、停止串命中）。 */
+    logs: Vec<String>,
+    error: Option<String>,
+    /** \brief 回复是否被停止串或输出上限截断。 */
+    truncated: bool,
 }
 
-#[derive(Deserialize, Debug)]
-struct ChatQuery {
-    /** \brief 会话ID（可选） */
-    chat_id: Option<i64>,
-    /** \brief Provider ID（可选） */
-    provider_id: Option<i64>,
-    /** \brief 用户发送的消息 */
-    prompt: String,
-    /** \brief 是否以流式返回（默认 true） */
-    stream: Option<bool>,
-    /** \brief 开启调试（默认 false），将推送 log 事件 */
-    debug: Option<bool>,
-    /** \brief 需要重新生成的消息 ID（针对助手消息）。 */
-    regen_message_id: Option<i64>,
+/** \brief `start_chat` 在数据库线程上准备好的生成所需状态。 */
+struct ChatSetup {
+    provider: Provider,
+    chat_id: i64,
+    context_plan: context::ContextPlan,
+    stream_retries: u32,
+    stop_trimmer: llm::StopTrimmer,
+    checkpointer: db::GenerationCheckpointer,
 }
 
 /**
- * \brief 聊天 SSE 流接口：GET /api/chat/sse?prompt=...&chat_id=...
+ * \brief 校验请求、确定 Provider 与会话、写入用户消息，并在后台生成回复；
+ * 生成过程通过返回的通道逐条输出，同时返回用于断线续传的生成 ID。
+ * \param default_stream 请求未指定 `stream` 时是否以流式请求上游
  */
-async fn chat_sse(
-    Query(q): Query<ChatQuery>,
-) -> Result<
-    Sse<impl tokio_stream::Stream<Item = Result<Event, Infallible>>>,
-    (axum::http::StatusCode, String),
-> {
+async fn start_chat(
+    q: ChatBody,
+    default_stream: bool,
+    quota: Option<ChatQuota>,
+) -> Result<(mpsc::UnboundedReceiver<ChatOutput>, Option<i64>), (axum::http::StatusCode, String)> {
     if q.regen_message_id.is_some() && !q.prompt.trim().is_empty() {
         return Err(internal_err(anyhow!(
             "prompt 与 regen_message_id 不可同时提供"
         )));
     }
+    if q.regen_message_id.is_none() && q.prompt.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "发送内容不能为空".to_string()));
+    }
 
-    let conn = db::open_default_db().map_err(internal_err)?;
-    let telemetry_enabled = db::get_telemetry_enabled(&conn).map_err(internal_err)?;
-    telemetry::set_enabled(telemetry_enabled);
-
-    let mut provider_opt = None;
-    if let Some(chat_id) = q.chat_id {
-        if let Some(existing) = db::get_provider_for_chat(&conn, chat_id).map_err(internal_err)? {
-            provider_opt = Some(existing);
-        }
+    let quote_ids = q.quote_ids;
+    if !quote_ids.is_empty() && (q.chat_id.is_none() || q.regen_message_id.is_some()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "引用消息需要现有会话，且不能用于重新生成".to_string(),
+        ));
     }
-    if provider_opt.is_none() {
-        if let Some(pid) = q.provider_id {
-            provider_opt = db::get_provider_by_id(&conn, pid).map_err(internal_err)?;
+    // 客户端指定的流 ID 先登记，冲突时不写入任何消息
+    let client_stream = match q.stream_id.filter(|id| !id.is_empty()) {
+        Some(id) => {
+            validate_stream_id(&id)?;
+            let registered = register_stream(&id)?;
+            Some((id, registered))
         }
+        None => None,
+    };
+
+    let overrides = llm::RequestOverrides {
+        model: q.model.clone(),
+        temperature: q.temperature,
+        max_tokens: q.max_tokens,
+        system_instruction: q.system.clone(),
     }
-    if provider_opt.is_none() {
-        provider_opt = db::get_default_provider(&conn).map_err(internal_err)?;
-    }
-    let provider = provider_opt
-        .ok_or_else(|| internal_err(anyhow!("尚未设置可用的模型服务，请先创建或选择模型服务")))?;
+    .normalized();
 
-    let chat_id = match q.chat_id {
-        Some(id) => {
-            let current = db::get_provider_for_chat(&conn, id).map_err(internal_err)?;
-            if current.as_ref().map(|p| p.id) != Some(provider.id) {
-                db::set_chat_provider(&conn, id, Some(provider.id)).map_err(internal_err)?;
+    let debug = q.debug.unwrap_or(false);
+    let stream_flag = q.stream.unwrap_or(default_stream);
+    let regen_flag = q.regen_message_id.is_some();
+    let prompt_len = if regen_flag { 0 } else { q.prompt.len() };
+    let redact_prompt = q.redact_prompt.unwrap_or(false);
+
+    let setup = with_db(move |conn| {
+        let telemetry_enabled = db::get_telemetry_enabled(conn).map_err(internal_err)?;
+        telemetry::set_enabled(telemetry_enabled);
+
+        let mut provider_opt = None;
+        if let Some(chat_id) = q.chat_id {
+            if let Some(existing) =
+                resolve_provider_for_chat(conn, chat_id).map_err(internal_err)?
+            {
+                provider_opt = Some(existing);
             }
-            id
         }
-        None => {
-            if q.regen_message_id.is_some() {
-                return Err(internal_err(anyhow!("重新生成需要现有会话 ID")));
+        if provider_opt.is_none() {
+            if let Some(pid) = q.provider_id {
+                provider_opt = resolve_provider_by_id(conn, pid).map_err(internal_err)?;
             }
-            db::create_chat(&conn, &format!("{} 会话", provider.name), provider.id)
-                .map_err(internal_err)?
         }
-    };
+        if provider_opt.is_none() {
+            provider_opt = resolve_default_provider(conn).map_err(internal_err)?;
+        }
+        let mut provider = provider_opt.ok_or_else(|| {
+            internal_err(anyhow!("尚未设置可用的模型服务，请先创建或选择模型服务"))
+        })?;
 
-    if let Some(message_id) = q.regen_message_id {
-        let metas = db::load_messages_with_meta(&conn, chat_id).map_err(internal_err)?;
-        let target = metas
-            .iter()
-            .find(|m| m.id == message_id)
-            .ok_or_else(|| internal_err(anyhow!("待重新生成的消息不存在")))?;
-        if target.role != "assistant" {
-            return Err(internal_err(anyhow!("仅支持对助手消息重新生成")));
+        let chat_id = match q.chat_id {
+            Some(id) => {
+                let current = resolve_provider_for_chat(conn, id).map_err(internal_err)?;
+                if current.as_ref().map(|p| p.id) != Some(provider.id) {
+                    db::set_chat_provider(conn, id, Some(provider.id)).map_err(internal_err)?;
+                }
+                id
+            }
+            None => {
+                if q.regen_message_id.is_some() {
+                    return Err(internal_err(anyhow!("重新生成需要现有会话 ID")));
+                }
+                db::create_chat(conn, &format!("{} 会话", provider.name), provider.id)
+                    .map_err(internal_err)?
+            }
+        };
+        // 会话级模型覆盖优先于 Provider 默认模型，请求参数中的 model 仍可再覆盖
+        db::apply_chat_model(conn, chat_id, &mut provider).map_err(internal_err)?;
+
+        let mut redacted_id = None;
+        if let Some(message_id) = q.regen_message_id {
+            let metas = db::load_messages_with_meta(conn, chat_id).map_err(internal_err)?;
+            let target = metas
+                .iter()
+                .find(|m| m.id == message_id)
+                .ok_or_else(|| internal_err(anyhow!("待重新生成的消息不存在")))?;
+            if target.role != "assistant" {
+                return Err(internal_err(anyhow!("仅支持对助手消息重新生成")));
+            }
+            // 旧回复保存为备选回复，重新生成后仍可切换回去
+            db::supersede_and_truncate(conn, chat_id, message_id).map_err(internal_err)?;
+        } else {
+            let quotes = db::resolve_quotes(conn, chat_id, &quote_ids)
+                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+            if redact_prompt {
+                redacted_id = Some(
+                    db::insert_redacted_user_message(conn, chat_id, &quotes)
+                        .map_err(internal_err)?,
+                );
+            } else {
+                db::insert_user_message(conn, chat_id, &q.prompt, &quotes).map_err(internal_err)?;
+            }
         }
-        db::delete_messages_from(&conn, chat_id, message_id).map_err(internal_err)?;
-    } else {
-        db::insert_message(&conn, chat_id, "user", &q.prompt).map_err(internal_err)?;
-    }
 
-    let messages = db::load_messages(&conn, chat_id).map_err(internal_err)?;
+        let mut context_plan =
+            context::plan(conn, chat_id, Some(&provider)).map_err(internal_err)?;
+        if let Some(message_id) = redacted_id {
+            context_plan = context_plan.with_redacted_prompt(message_id, &q.prompt);
+        }
 
-    let (tx, rx) = mpsc::unbounded_channel::<Result<Event, Infallible>>();
-    let _ = tx.send(Ok(Event::default()
-        .event("meta")
-        .data(serde_json::json!({ "chat_id": chat_id }).to_string())));
+        let stream_retries = if db::get_chat_stream_retry(conn, chat_id).map_err(internal_err)? {
+            llm::STREAM_RETRY_LIMIT
+        } else {
+            0
+        };
+        let stop_trimmer =
+            llm::StopTrimmer::new(&db::get_stop_strings(conn).map_err(internal_err)?)
+                .with_max_chars(db::get_max_output_chars(conn).map_err(internal_err)?);
+        let checkpoint_prompt = if redact_prompt {
+            db::REDACTED_PROMPT
+        } else {
+            q.prompt.as_str()
+        };
+        let checkpointer =
+            db::GenerationCheckpointer::begin(conn, chat_id, Some(provider.id), checkpoint_prompt);
+        Ok(ChatSetup {
+            provider,
+            chat_id,
+            context_plan,
+            stream_retries,
+            stop_trimmer,
+            checkpointer,
+        })
+    })
+    .await?;
+    let ChatSetup {
+        provider,
+        chat_id,
+        context_plan,
+        stream_retries,
+        mut stop_trimmer,
+        mut checkpointer,
+    } = setup;
+    let generation_id = checkpointer.id();
 
-    let debug = q.debug.unwrap_or(false);
-    let stream_flag = q.stream.unwrap_or(true);
-    let regen_flag = q.regen_message_id.is_some();
-    let prompt_len = if regen_flag { 0 } else { q.prompt.len() };
+    let (stream_id, (broadcast, stream_guard)) = match client_stream {
+        Some(registered) => registered,
+        None => {
+            let id = transcript::stream_id("server", chat_id);
+            let registered = register_stream(&id)?;
+            (id, registered)
+        }
+    };
+    let (tx, rx) = mpsc::unbounded_channel::<ChatOutput>();
+    let tx = ChatSender { tx, broadcast };
+    let _ = tx.send(ChatOutput::Meta {
+        chat_id,
+        generation_id,
+        stream_id: stream_id.clone(),
+    });
+    if let Some(d) = llm::model_deprecation(&provider, overrides.model_for(&provider)) {
+        telemetry::log_event("server.chat", &format!("chat_id={} {}", chat_id, d.message));
+        let _ = tx.send(ChatOutput::Log(format!("warning -> {}", d.message)));
+    }
 
-    tokio::spawn(async move {
+    let cancel = stream_guard.cancel.clone();
+    let generation_guard = track_generation(chat_id, cancel.clone());
+    let span = telemetry::chat_span(chat_id, &provider);
+    let task = async move {
+        let _generation_guard = generation_guard;
+        let _stream_guard = stream_guard;
+        let mut selection = context_plan.select(Some(&provider)).await;
+        if let Err(err) = selection.persist_embeddings().await {
+            telemetry::log_event(
+                "server.chat",
+                &format!("embedding cache write failed: {}", err),
+            );
+        }
+        if debug && selection.embedding_model.is_some() {
+            let _ = tx.send(ChatOutput::Log(format!(
+                "{} fallback={}",
+                selection.summary(),
+                selection.fallback
+            )));
+        }
+        let messages = selection.messages;
         if debug {
-            let _ = tx.send(Ok(Event::default().event("log").data(format!(
+            let _ = tx.send(ChatOutput::Log(format!(
                 "request -> provider={} type={} base={} model={} chat_id={} msgs={}",
                 provider.name,
                 provider.provider_type,
                 provider.api_base,
-                provider.model,
+                overrides.model_for(&provider),
                 chat_id,
                 messages.len()
-            ))));
+            )));
+            if !overrides.is_empty() {
+                let _ = tx.send(ChatOutput::Log(format!("overrides -> {:?}", overrides)));
+            }
+            let _ = tx.send(ChatOutput::Log(format!(
+                "params -> {:?}",
+                llm::generation_params(&provider, &overrides)
+            )));
         }
 
         let mut assistant_buf = String::new();
+        let started = Instant::now();
+        let mut first_token_ms: Option<i64> = None;
         telemetry::log_event(
             "server.chat",
             &format!(
@@ -604,76 +4396,721 @@ async fn chat_sse(
         );
 
         if stream_flag {
-            match llm::stream_chat(&provider, &messages).await {
-                Ok(mut s) => {
-                    use futures_util::StreamExt;
-                    while let Some(item) = s.as_mut().next().await {
-                        match item {
-                            Ok(delta) => {
-                                assistant_buf.push_str(&delta);
-                                let _ = tx.send(Ok(Event::default().data(delta)));
-                            }
-                            Err(e) => {
-                                telemetry::log_error(
-                                    "server.chat",
-                                    &format!("stream error: {}", e),
-                                );
-                                let _ = tx.send(Ok(Event::default()
-                                    .event("error")
-                                    .data(format!("{}", e))));
-                                break;
+            let mut attempt = 0;
+            loop {
+                let request = if attempt == 0 {
+                    messages.clone()
+                } else {
+                    llm::continuation_messages(&messages, &assistant_buf)
+                };
+                let mut failure = None;
+                let (opened, retries) = llm::count_retries(llm::stream_chat_cancellable(
+                    &provider, &request, &overrides, &cancel,
+                ))
+                .await;
+                if debug && retries > 0 {
+                    let _ = tx.send(ChatOutput::Log(format!(
+                        "retries -> {} before first token",
+                        retries
+                    )));
+                }
+                match opened {
+                    Ok(mut s) => {
+                        use futures_util::StreamExt;
+                        while let Some(item) = s.as_mut().next().await {
+                            match item {
+                                Ok(delta) => {
+                                    if first_token_ms.is_none() {
+                                        first_token_ms = Some(started.elapsed().as_millis() as i64);
+                                    }
+                                    if let Some(tee) = TRANSCRIPT.get() {
+                                        if let Err(e) = tee.append(&stream_id, &delta) {
+                                            telemetry::log_error(
+                                                "server.chat",
+                                                &format!("transcript write failed: {}", e),
+                                            );
+                                        }
+                                    }
+                                    let visible = stop_trimmer.push(&delta);
+                                    if !visible.is_empty() {
+                                        assistant_buf.push_str(&visible);
+                                        checkpointer.on_progress(&assistant_buf);
+                                        let _ = tx.send(ChatOutput::Chunk {
+                                            text: visible,
+                                            offset: assistant_buf.len(),
+                                        });
+                                    }
+                                    if stop_trimmer.stopped() {
+                                        break;
+                                    }
+                                }
+                                Err(e) => {
+                                    failure = Some(format!("{}", e));
+                                    break;
+                                }
                             }
                         }
                     }
+                    Err(e) => failure = Some(format!("stream failed: {}", e)),
                 }
-                Err(e) => {
-                    telemetry::log_error("server.chat", &format!("stream failed: {}", e));
-                    let _ = tx.send(Ok(Event::default()
-                        .event("error")
-                        .data(format!("stream failed: {}", e))));
+
+                let Some(error) = failure else { break };
+                if cancel.is_cancelled() {
+                    break;
                 }
+                if attempt < stream_retries && llm::is_retryable_error(&error) {
+                    attempt += 1;
+                    // 暂存的尾部已收到且不是停止串，先输出，续写从其后开始
+                    let held = stop_trimmer.finish();
+                    if !held.is_empty() {
+                        assistant_buf.push_str(&held);
+                        let _ = tx.send(ChatOutput::Chunk {
+                            text: held,
+                            offset: assistant_buf.len(),
+                        });
+                    }
+                    let note = format!(
+                        "stream interrupted ({}), retrying {}/{} and continuing after {} chars",
+                        error,
+                        attempt,
+                        stream_retries,
+                        assistant_buf.chars().count()
+                    );
+                    telemetry::log_event("server.chat", &format!("chat_id={} {}", chat_id, note));
+                    let _ = tx.send(ChatOutput::Log(note));
+                    tokio::time::sleep(llm::stream_retry_delay(attempt)).await;
+                    continue;
+                }
+                telemetry::log_error("server.chat", &format!("stream error: {}", error));
+                let _ = tx.send(ChatOutput::Error(error));
+                break;
+            }
+            let tail = stop_trimmer.finish();
+            if !tail.is_empty() {
+                assistant_buf.push_str(&tail);
+                let _ = tx.send(ChatOutput::Chunk {
+                    text: tail,
+                    offset: assistant_buf.len(),
+                });
             }
         } else {
-            match llm::chat_once(&provider, &messages).await {
+            let (reply, retries) = llm::count_retries(llm::chat_once_cancellable(
+                &provider, &messages, &overrides, &cancel,
+            ))
+            .await;
+            if debug && retries > 0 {
+                let _ = tx.send(ChatOutput::Log(format!("retries -> {}", retries)));
+            }
+            match reply {
                 Ok(full) => {
+                    first_token_ms = Some(started.elapsed().as_millis() as i64);
+                    let full = stop_trimmer.trim_full(&full);
                     assistant_buf.push_str(&full);
-                    let _ = tx.send(Ok(Event::default().data(full)));
+                    let _ = tx.send(ChatOutput::Chunk {
+                        text: full,
+                        offset: assistant_buf.len(),
+                    });
                 }
+                Err(_) if cancel.is_cancelled() => {}
                 Err(e) => {
                     telemetry::log_error("server.chat", &format!("chat_once failed: {}", e));
-                    let _ = tx.send(Ok(Event::default().event("error").data(format!("{}", e))));
+                    let _ = tx.send(ChatOutput::Error(format!("{}", e)));
                 }
             }
         }
 
+        if cancel.is_cancelled() {
+            telemetry::log_event(
+                "server.chat",
+                &format!(
+                    "generation cancelled chat_id={} kept_len={}",
+                    chat_id,
+                    assistant_buf.len()
+                ),
+            );
+            let _ = tx.send(ChatOutput::Log("generation cancelled".to_string()));
+        }
+
+        if let Some(reason) = stop_trimmer.truncation() {
+            telemetry::log_event(
+                "server.chat",
+                &format!(
+                    "output truncated ({}) chat_id={} trimmed_len={}",
+                    reason,
+                    chat_id,
+                    stop_trimmer.remainder().len()
+                ),
+            );
+            if debug {
+                let _ = tx.send(ChatOutput::Log(format!(
+                    "output truncated ({}), trimmed -> {:?}",
+                    reason,
+                    stop_trimmer.remainder()
+                )));
+            }
+        }
+
+        let mut message_id = None;
+        let mut first_title = None;
+        let mut refusal_retry = None;
+        let mut language_mismatch = None;
         if !assistant_buf.is_empty() {
-            if let Ok(conn2) = db::open_default_db() {
-                let _ = db::insert_message(&conn2, chat_id, "assistant", &assistant_buf);
+            let timing = db::GenerationTiming {
+                provider_id: Some(provider.id),
+                first_token_ms,
+                duration_ms: Some(started.elapsed().as_millis() as i64),
+            };
+            let cancelled = cancel.is_cancelled();
+            let content = assistant_buf.clone();
+            let prompt = messages.clone();
+            let metadata = stop_trimmer.annotate(overrides.to_metadata());
+            let reply_provider = provider.clone();
+            let saved = db::run(move |conn| {
+                let reply = finalize::FinishedReply {
+                    chat_id,
+                    content: &content,
+                    prompt: &prompt,
+                    timing,
+                    metadata,
+                    partial: cancelled,
+                };
+                let done = finalize::finalize(conn, &reply)?;
+                let id = done.message_id;
+                let title = finalize::spawn_followups(conn, &reply_provider, chat_id, &done);
+                let refusal = refusal::retry_policy_for(conn, &content)
+                    .filter(|_| !cancelled)
+                    .map(|policy| {
+                        let retry_provider = match policy.provider_id {
+                            Some(pid) => resolve_provider_by_id(conn, pid).ok().flatten(),
+                            None => None,
+                        };
+                        (id, policy, retry_provider.unwrap_or(reply_provider))
+                    });
+                let mismatch = match refusal {
+                    None if !cancelled => {
+                        language::mismatch_for(conn, chat_id, &content).map(|m| (id, m))
+                    }
+                    _ => None,
+                };
+                Ok((id, title, refusal, mismatch))
+            })
+            .await;
+            match saved {
+                Ok((id, title, refusal, mismatch)) => {
+                    message_id = Some(id);
+                    first_title = title;
+                    refusal_retry = refusal;
+                    language_mismatch = mismatch;
+                }
+                Err(e) => {
+                    telemetry::log_error("server.chat", &format!("save reply failed: {}", e));
+                    let _ = tx.send(ChatOutput::Error(format!("save reply failed: {}", e)));
+                }
             }
         }
-    });
+        checkpointer.finish();
+        // 拒答重试与语言重问各是一次额外的模型请求，同样扣减聊天限额，超限时跳过
+        let over_quota = |what: &str| match quota.as_ref().map(ChatQuota::acquire) {
+            Some(Err(wait)) => {
+                let _ = tx.send(ChatOutput::Log(format!(
+                    "{} skipped: rate limited, retry after {}s",
+                    what,
+                    wait.as_secs().max(1)
+                )));
+                true
+            }
+            _ => false,
+        };
+        if let Some((id, policy, retry_provider)) =
+            refusal_retry.filter(|_| !over_quota("refusal retry"))
+        {
+            let _ = tx.send(ChatOutput::Log(format!(
+                "refusal detected, retrying with provider={}",
+                retry_provider.name
+            )));
+            match refusal::retry(&retry_provider, id, &policy).await {
+                Ok(variant) => {
+                    let _ = tx.send(ChatOutput::Variant(variant));
+                }
+                Err(e) => {
+                    telemetry::log_error("server.chat", &format!("refusal retry failed: {}", e));
+                    let _ = tx.send(ChatOutput::Log(format!("refusal retry failed: {}", e)));
+                }
+            }
+        }
+        if let Some((id, mismatch)) = language_mismatch.filter(|_| !over_quota("language re-ask")) {
+            let _ = tx.send(ChatOutput::Log(format!(
+                "reply language {} != {}, re-asking",
+                mismatch.detected, mismatch.expected
+            )));
+            match language::enforce(&provider, id, &mismatch).await {
+                Ok(variant) => {
+                    let _ = tx.send(ChatOutput::Variant(variant));
+                }
+                Err(e) => {
+                    telemetry::log_error("server.chat", &format!("language re-ask failed: {}", e));
+                    let _ = tx.send(ChatOutput::Log(format!("language re-ask failed: {}", e)));
+                }
+            }
+        }
+        // 标题与重试并行生成，结束前只短暂等待
+        if let Some(title) = backfill::wait_first_title(first_title).await {
+            let _ = tx.send(ChatOutput::Title { chat_id, title });
+        }
+        let _ = tx.send(ChatOutput::Done {
+            chat_id,
+            message_id,
+            truncated: stop_trimmer.stopped(),
+        });
+    };
+    tokio::spawn(task.instrument(span));
 
-    let stream = UnboundedReceiverStream::new(rx);
-    Ok(Sse::new(stream).keep_alive(KeepAlive::new()))
+    Ok((rx, generation_id))
+}
+
+/** \brief OpenAI 格式的错误响应。 */
+fn openai_error(status: StatusCode, message: impl std::fmt::Display) -> Response {
+    let kind = if status.is_server_error() {
+        "server_error"
+    } else {
+        "invalid_request_error"
+    };
+    (
+        status,
+        Json(openai_compat::error_body(&message.to_string(), kind)),
+    )
+        .into_response()
+}
+
+/** \brief 在响应头中返回本次使用的会话 ID。 */
+fn with_chat_header(mut resp: Response, chat_id: i64) -> Response {
+    resp.headers_mut().insert(
+        openai_compat::CHAT_ID_HEADER,
+        axum::http::HeaderValue::from(chat_id),
+    );
+    resp
+}
+
+/** \brief 可按名称路由的 Provider：环境变量覆盖层在前，其后为数据库中的 Provider。 */
+fn routable_providers(conn: &rusqlite::Connection) -> Result<Vec<Provider>> {
+    let mut providers: Vec<Provider> = env_provider().cloned().into_iter().collect();
+    providers.extend(db::list_providers(conn)?);
+    Ok(providers)
+}
+
+/**
+ * \brief OpenAI 兼容模型列表：GET /v1/models，返回 `default` 与各 Provider 名称。
+ */
+async fn openai_models() -> Response {
+    match db::run(routable_providers).await {
+        Ok(providers) => Json(openai_compat::model_list(&providers)).into_response(),
+        Err(e) => openai_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/**
+ * \brief 保存网关生成的助手回复，失败只记遥测。
+ */
+async fn save_gateway_reply(
+    provider: &Provider,
+    chat_id: i64,
+    prompt: &[Message],
+    content: &str,
+    overrides: &llm::RequestOverrides,
+    timing: &db::GenerationTiming,
+) {
+    let provider = provider.clone();
+    let prompt = prompt.to_vec();
+    let content = content.to_string();
+    let metadata = overrides.to_metadata();
+    let timing = *timing;
+    let saved = db::run(move |conn| {
+        let reply = finalize::FinishedReply {
+            chat_id,
+            content: &content,
+            prompt: &prompt,
+            timing,
+            metadata,
+            partial: false,
+        };
+        finalize::finalize(conn, &reply)?;
+        autotag::spawn_if_due(conn, provider, chat_id);
+        Ok(())
+    })
+    .await;
+    if let Err(e) = saved {
+        telemetry::log_error("server.openai", &format!("save reply failed: {}", e));
+    }
+}
+
+/**
+ * \brief OpenAI 兼容聊天补全：POST /v1/chat/completions，支持流式与非流式。
+ * \details 按 `model` 路由到 Provider（见 `openai_compat::route_model`），请求消息与回复写入会话；
+ * 请求头 `X-DreamQuill-Chat-Id` 指定追加到的会话，响应头返回实际使用的会话。
+ */
+async fn openai_chat_completions(
+    headers: axum::http::HeaderMap,
+    payload: Result<Json<openai_compat::ChatCompletionRequest>, JsonRejection>,
+) -> Response {
+    let req = match payload {
+        Ok(Json(req)) => req,
+        Err(e) => return openai_error(StatusCode::BAD_REQUEST, e.body_text()),
+    };
+    let messages = match req.to_messages() {
+        Ok(messages) => messages,
+        Err(e) => return openai_error(StatusCode::BAD_REQUEST, e),
+    };
+    let chat_hint = match headers.get(openai_compat::CHAT_ID_HEADER) {
+        None => None,
+        Some(value) => match value.to_str().ok().and_then(|v| v.trim().parse().ok()) {
+            Some(id) => Some(id),
+            None => {
+                return openai_error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid X-DreamQuill-Chat-Id header",
+                )
+            }
+        },
+    };
+
+    let requested_model = req.model.clone();
+    let request_stops = req.stop_strings();
+    let request_messages = messages.clone();
+    let setup = with_db(move |conn| {
+        telemetry::set_enabled(db::get_telemetry_enabled(conn).unwrap_or(false));
+        let (provider, model) = routable_providers(conn)
+            .and_then(|providers| {
+                let default = resolve_default_provider(conn)?;
+                openai_compat::route_model(&providers, default.as_ref(), &requested_model)
+            })
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        let chat_id = openai_compat::persist_request(conn, chat_hint, &provider, &request_messages)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        let mut stops = db::get_stop_strings(conn).unwrap_or_default();
+        stops.extend(request_stops);
+        let max_output_chars = db::get_max_output_chars(conn).unwrap_or_default();
+        Ok((provider, model, chat_id, stops, max_output_chars))
+    })
+    .await;
+    let (provider, model, chat_id, stops, max_output_chars) = match setup {
+        Ok(setup) => setup,
+        Err((status, message)) => return openai_error(status, message),
+    };
+    let overrides = req.overrides(model);
+
+    let completion = openai_compat::Completion::new(chat_id, overrides.model_for(&provider));
+    telemetry::log_event(
+        "server.openai",
+        &format!(
+            "provider={}({}) chat_id={} model={} stream={} msgs={}",
+            provider.name,
+            provider.provider_type,
+            chat_id,
+            completion.model,
+            req.stream,
+            messages.len()
+        ),
+    );
+    let (cancel, generation_guard) = register_generation(chat_id);
+    let started = Instant::now();
+
+    if !req.stream {
+        let reply = llm::chat_once_cancellable(&provider, &messages, &overrides, &cancel)
+            .instrument(telemetry::chat_span(chat_id, &provider))
+            .await;
+        drop(generation_guard);
+        return match reply {
+            Ok(full) => {
+                let content = llm::StopTrimmer::new(&stops)
+                    .with_max_chars(max_output_chars)
+                    .trim_full(&full);
+                let elapsed = started.elapsed().as_millis() as i64;
+                let timing = db::GenerationTiming {
+                    provider_id: Some(provider.id),
+                    first_token_ms: Some(elapsed),
+                    duration_ms: Some(elapsed),
+                };
+                save_gateway_reply(&provider, chat_id, &messages, &content, &overrides, &timing)
+                    .await;
+                let usage = openai_compat::Usage::estimate(&messages, &content);
+                with_chat_header(
+                    Json(completion.response(&content, usage)).into_response(),
+                    chat_id,
+                )
+            }
+            Err(e) => {
+                telemetry::log_error("server.openai", &format!("chat_once failed: {}", e));
+                with_chat_header(openai_error(StatusCode::BAD_GATEWAY, e), chat_id)
+            }
+        };
+    }
+
+    let include_usage = req.include_usage();
+    let (tx, rx) = mpsc::unbounded_channel::<String>();
+    let span = telemetry::chat_span(chat_id, &provider);
+    let task = async move {
+        use futures_util::StreamExt;
+        let _generation_guard = generation_guard;
+        let send = |value: serde_json::Value| tx.send(value.to_string()).is_ok();
+        send(completion.chunk(
+            serde_json::json!({"role": "assistant", "content": ""}),
+            None,
+        ));
+        let mut stop_trimmer = llm::StopTrimmer::new(&stops).with_max_chars(max_output_chars);
+        let mut reply = String::new();
+        let mut first_token_ms = None;
+        let mut failure = None;
+        let stream_id = transcript::stream_id("openai", chat_id);
+        match llm::stream_chat_cancellable(&provider, &messages, &overrides, &cancel).await {
+            Ok(mut stream) => {
+                while let Some(item) = stream.next().await {
+                    let delta = match item {
+                        Ok(delta) => delta,
+                        Err(e) => {
+                            failure = Some(e.to_string());
+                            break;
+                        }
+                    };
+                    first_token_ms.get_or_insert(started.elapsed().as_millis() as i64);
+                    if let Some(tee) = TRANSCRIPT.get() {
+                        if let Err(e) = tee.append(&stream_id, &delta) {
+                            telemetry::log_error(
+                                "server.openai",
+                                &format!("transcript write failed: {}", e),
+                            );
+                        }
+                    }
+                    let visible = stop_trimmer.push(&delta);
+                    if !visible.is_empty() {
+                        reply.push_str(&visible);
+                        // 客户端断开后停止读取，已收到的部分照常保存
+                        if !send(completion.chunk(serde_json::json!({"content": visible}), None)) {
+                            break;
+                        }
+                    }
+                    if stop_trimmer.stopped() {
+                        break;
+                    }
+                }
+            }
+            Err(e) => failure = Some(e.to_string()),
+        }
+        let tail = stop_trimmer.finish();
+        if !tail.is_empty() {
+            reply.push_str(&tail);
+            send(completion.chunk(serde_json::json!({"content": tail}), None));
+        }
+        if !reply.is_empty() {
+            let timing = db::GenerationTiming {
+                provider_id: Some(provider.id),
+                first_token_ms,
+                duration_ms: Some(started.elapsed().as_millis() as i64),
+            };
+            save_gateway_reply(&provider, chat_id, &messages, &reply, &overrides, &timing).await;
+        }
+        match failure {
+            Some(error) => {
+                telemetry::log_error("server.openai", &format!("stream error: {}", error));
+                send(openai_compat::error_body(&error, "upstream_error"));
+            }
+            None => {
+                let finish_reason = if stop_trimmer.limit_reached() {
+                    "length"
+                } else {
+                    "stop"
+                };
+                send(completion.chunk(serde_json::json!({}), Some(finish_reason)));
+                if include_usage {
+                    send(completion.usage_chunk(openai_compat::Usage::estimate(&messages, &reply)));
+                }
+            }
+        }
+        let _ = tx.send("[DONE]".to_string());
+    };
+    tokio::spawn(task.instrument(span));
+
+    let events = {
+        use tokio_stream::StreamExt;
+        UnboundedReceiverStream::new(rx)
+            .map(|data| Ok::<_, Infallible>(Event::default().data(data)))
+    };
+    with_chat_header(
+        Sse::new(events)
+            .keep_alive(KeepAlive::default())
+            .into_response(),
+        chat_id,
+    )
+}
+
+/** \brief 增量事件：默认正文即增量文本，开启进度字段时为 `ChunkPayload` JSON。 */
+fn chunk_event(text: String, id: Option<String>, counter: Option<&mut llm::ChunkCounter>) -> Event {
+    let event = match counter {
+        Some(counter) => {
+            Event::default().data(serde_json::to_string(&counter.payload(text)).unwrap_or_default())
+        }
+        None => Event::default().data(text),
+    };
+    match id {
+        Some(id) => event.id(id),
+        None => event,
+    }
+}
+
+/** \brief 生成结束事件；客户端收到后应关闭连接，不再自动重连。 */
+fn done_event(chat_id: i64, message_id: Option<i64>, truncated: bool) -> Event {
+    Event::default().event("done").data(
+        serde_json::json!({
+            "chat_id": chat_id,
+            "message_id": message_id,
+            "truncated": truncated,
+        })
+        .to_string(),
+    )
+}
+
+/**
+ * \brief 断线重连续传：轮询生成检查点，发送客户端尚未收到的部分；
+ * 生成结束后从已保存的助手消息补齐剩余内容并发送 `done`。
+ */
+async fn resume_generation(
+    tx: mpsc::UnboundedSender<Result<Event, Infallible>>,
+    generation_id: i64,
+    mut offset: usize,
+    chat_hint: Option<i64>,
+    with_progress: bool,
+) {
+    let _ = tx.send(Ok(Event::default().retry(sse_retry()).event("meta").data(
+        serde_json::json!({
+            "chat_id": chat_hint,
+            "generation_id": generation_id,
+            "resumed_from": offset
+        })
+        .to_string(),
+    )));
+    let mut chat_id = chat_hint;
+    let mut counter: Option<llm::ChunkCounter> = None;
+    loop {
+        if tx.is_closed() {
+            return;
+        }
+        let progress = db::run(move |conn| db::generation_progress(conn, generation_id)).await;
+        match progress {
+            Ok(Some(p)) if !p.live => {
+                let _ = tx.send(Ok(Event::default()
+                    .event("error")
+                    .data("generation was interrupted by a server restart")));
+                return;
+            }
+            Ok(Some(p)) => {
+                chat_id = Some(p.chat_id);
+                if let Some(rest) = p.partial.get(offset..).filter(|r| !r.is_empty()) {
+                    if with_progress && counter.is_none() {
+                        let delivered = p.partial.get(..offset).unwrap_or_default();
+                        counter = Some(llm::ChunkCounter::resumed(delivered.chars().count()));
+                    }
+                    offset = p.partial.len();
+                    let _ = tx.send(Ok(chunk_event(
+                        rest.to_string(),
+                        Some(sse_event_id(generation_id, offset)),
+                        counter.as_mut(),
+                    )));
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                let _ = tx.send(Ok(Event::default().event("error").data(e.to_string())));
+                return;
+            }
+        }
+        tokio::time::sleep(RESUME_POLL_INTERVAL).await;
+    }
+
+    let Some(chat_id) = chat_id else {
+        let _ = tx.send(Ok(Event::default()
+            .event("error")
+            .data("generation already finished; reload the chat")));
+        return;
+    };
+    let last = db::run(move |conn| db::load_messages_with_meta(conn, chat_id))
+        .await
+        .ok()
+        .and_then(|messages| messages.into_iter().last())
+        .filter(|m| m.role == "assistant");
+    if let Some(rest) = last
+        .as_ref()
+        .and_then(|m| m.content.get(offset..))
+        .filter(|r| !r.is_empty())
+    {
+        let len = last.as_ref().map(|m| m.content.len()).unwrap_or(offset);
+        if with_progress && counter.is_none() {
+            let delivered = last
+                .as_ref()
+                .and_then(|m| m.content.get(..offset))
+                .unwrap_or_default();
+            counter = Some(llm::ChunkCounter::resumed(delivered.chars().count()));
+        }
+        let _ = tx.send(Ok(chunk_event(
+            rest.to_string(),
+            Some(sse_event_id(generation_id, len)),
+            counter.as_mut(),
+        )));
+    }
+    let truncated = last
+        .as_ref()
+        .and_then(|m| m.metadata.as_ref())
+        .is_some_and(|meta| meta.get("truncated").is_some());
+    let _ = tx.send(Ok(done_event(chat_id, last.map(|m| m.id), truncated)));
 }
 
 fn internal_err<E: std::fmt::Display>(e: E) -> (axum::http::StatusCode, String) {
     (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
 }
 
+/**
+ * \brief 在阻塞线程池中用共享连接执行处理逻辑（见 `db::run`），锁等待与重试不占用运行时工作线程。
+ */
+async fn with_db<T, F>(f: F) -> Result<T, (axum::http::StatusCode, String)>
+where
+    T: Send + 'static,
+    F: FnOnce(&rusqlite::Connection) -> Result<T, (axum::http::StatusCode, String)>
+        + Send
+        + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let conn = db::open_default_db().map_err(internal_err)?;
+        f(&conn)
+    })
+    .await
+    .map_err(internal_err)?
+}
+
+/**
+ * \brief 模型列表与健康检查使用的 Provider：指定 ID 或默认 Provider，并同步遥测开关。
+ */
+async fn query_provider(
+    provider_id: Option<i64>,
+) -> Result<Provider, (axum::http::StatusCode, String)> {
+    with_db(move |conn| {
+        let provider = if let Some(pid) = provider_id {
+            resolve_provider_by_id(conn, pid).map_err(internal_err)?
+        } else {
+            resolve_default_provider(conn).map_err(internal_err)?
+        };
+        let provider = provider.ok_or_else(|| internal_err(anyhow!("no provider available")))?;
+        let telemetry_enabled = db::get_telemetry_enabled(conn).map_err(internal_err)?;
+        telemetry::set_enabled(telemetry_enabled);
+        Ok(provider)
+    })
+    .await
+}
+
 async fn list_models(
     Query(q): Query<ModelQuery>,
 ) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    let provider = if let Some(pid) = q.provider_id {
-        db::get_provider_by_id(&conn, pid).map_err(internal_err)?
-    } else {
-        db::get_default_provider(&conn).map_err(internal_err)?
-    };
-    let provider = provider.ok_or_else(|| internal_err(anyhow!("no provider available")))?;
-    let telemetry_enabled = db::get_telemetry_enabled(&conn).map_err(internal_err)?;
-    telemetry::set_enabled(telemetry_enabled);
-    let models = llm::list_models(&provider).await.map_err(internal_err)?;
+    let provider = query_provider(q.provider_id).await?;
+    let models = model_cache::list_models_cached(&provider)
+        .await
+        .map_err(internal_err)?;
     Ok(Json(serde_json::json!({"models": models})))
 }
 
@@ -683,23 +5120,17 @@ async fn list_models(
 async fn health_check(
     Query(q): Query<ModelQuery>,
 ) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    let provider = if let Some(pid) = q.provider_id {
-        db::get_provider_by_id(&conn, pid).map_err(internal_err)?
-    } else {
-        db::get_default_provider(&conn).map_err(internal_err)?
-    };
-    let provider = provider.ok_or_else(|| internal_err(anyhow!("no provider available")))?;
-    let telemetry_enabled = db::get_telemetry_enabled(&conn).map_err(internal_err)?;
-    telemetry::set_enabled(telemetry_enabled);
-    match llm::list_models(&provider).await {
+    let provider = query_provider(q.provider_id).await?;
+    let deprecation = llm::model_deprecation(&provider, &provider.model);
+    match model_cache::list_models_cached(&provider).await {
         Ok(list) => Ok(Json(serde_json::json!({
             "ok": true,
             "provider_id": provider.id,
             "provider": provider.provider_type,
             "base": provider.api_base,
             "model": provider.model,
-            "models": list.len()
+            "models": list.len(),
+            "deprecation": deprecation
         }))),
         Err(e) => Ok(Json(serde_json::json!({
             "ok": false,
@@ -707,33 +5138,80 @@ async fn health_check(
             "provider": provider.provider_type,
             "base": provider.api_base,
             "model": provider.model,
-            "error": e.to_string()
+            "error": e.to_string(),
+            "deprecation": deprecation
         }))),
     }
 }
 
+/**
+ * \brief Provider 能力矩阵：GET /api/capabilities，`?probe=true` 时并发探测各 Provider 的模型列表。
+ */
+async fn list_capabilities(
+    Query(q): Query<CapabilitiesQuery>,
+) -> Result<Json<Vec<ProviderCapabilitiesDto>>, (axum::http::StatusCode, String)> {
+    let providers = with_db(|conn| {
+        let mut providers = db::list_providers(conn).map_err(internal_err)?;
+        if let Some(env) = env_provider() {
+            providers.insert(0, env.clone());
+        }
+        Ok(providers)
+    })
+    .await?;
+    let probes = futures_util::future::join_all(providers.iter().map(|p| async move {
+        if q.probe {
+            let (caps, err) = llm::probe_capabilities(p).await;
+            (caps, err.is_none(), err)
+        } else {
+            (llm::detect_capabilities(p, None), false, None)
+        }
+    }))
+    .await;
+    let items = providers
+        .into_iter()
+        .zip(probes)
+        .map(
+            |(p, (capabilities, probed, probe_error))| ProviderCapabilitiesDto {
+                provider_id: p.id,
+                name: p.name,
+                provider_type: p.provider_type,
+                model: p.model,
+                probed,
+                probe_error,
+                capabilities,
+            },
+        )
+        .collect();
+    Ok(Json(items))
+}
+
 /**
  * \brief 健康检查预检：使用未保存的 Provider 配置进行验证。
  */
 async fn health_check_preview(
     Json(payload): Json<HealthPreviewRequest>,
 ) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    let conn = db::open_default_db().map_err(internal_err)?;
-    let telemetry_enabled = db::get_telemetry_enabled(&conn).map_err(internal_err)?;
+    let telemetry_enabled =
+        with_db(|conn| db::get_telemetry_enabled(conn).map_err(internal_err)).await?;
     telemetry::set_enabled(telemetry_enabled);
+    validate_proxy(payload.proxy_url.as_deref())?;
 
     let provider = Provider {
         id: -1,
-        name: payload
-            .name
-            .unwrap_or_else(|| "临时健康检查".to_string()),
+        name: payload.name.unwrap_or_else(|| "临时健康检查".to_string()),
         api_base: payload.api_base,
         api_key: payload.api_key,
         model: payload.model,
         provider_type: payload.provider,
         secret_alias: None,
+        api_prefix: None,
+        signing: None,
+        generation: Default::default(),
+        proxy_url: payload.proxy_url.filter(|u| !u.trim().is_empty()),
+        api_key_unreadable: false,
     };
 
+    let deprecation = llm::model_deprecation(&provider, &provider.model);
     match llm::list_models(&provider).await {
         Ok(list) => Ok(Json(serde_json::json!({
             "ok": true,
@@ -741,7 +5219,8 @@ async fn health_check_preview(
             "provider": provider.provider_type,
             "base": provider.api_base,
             "model": provider.model,
-            "models": list.len()
+            "models": list.len(),
+            "deprecation": deprecation
         }))),
         Err(e) => Ok(Json(serde_json::json!({
             "ok": false,
@@ -749,7 +5228,71 @@ async fn health_check_preview(
             "provider": provider.provider_type,
             "base": provider.api_base,
             "model": provider.model,
-            "error": e.to_string()
+            "error": e.to_string(),
+            "deprecation": deprecation
         }))),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cors_origins_are_validated() {
+        assert_eq!(
+            parse_cors_origin("http://localhost:3000/").unwrap(),
+            "http://localhost:3000"
+        );
+        assert!(parse_cors_origin("localhost:3000").is_err());
+        assert!(parse_cors_origin("https://app.example.com/ui").is_err());
+        assert!(cors_layer(&[], &[]).unwrap().is_none());
+        assert!(cors_layer(&["*".to_string()], &[]).unwrap().is_some());
+        assert!(cors_layer(&["*".to_string(), "http://a.test".to_string()], &[]).is_err());
+        assert!(cors_layer(&["http://a.test".to_string()], &["G ET".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_stream_subscriber_gets_prefix_then_live_chunks() {
+        let (broadcast, guard) = register_stream("test-subscribe").unwrap();
+        assert_eq!(
+            register_stream("test-subscribe").err().unwrap().0,
+            StatusCode::CONFLICT
+        );
+        let (tx, mut origin) = mpsc::unbounded_channel();
+        let tx = ChatSender { tx, broadcast };
+        let _ = tx.send(ChatOutput::Meta {
+            chat_id: 7,
+            generation_id: Some(3),
+            stream_id: "test-subscribe".to_string(),
+        });
+        let chunk = |text: &str, offset| ChatOutput::Chunk {
+            text: text.to_string(),
+            offset,
+        };
+        let _ = tx.send(chunk("Hello", 5));
+
+        let (mut rx, generation_id) = subscribe_stream("test-subscribe", 0).unwrap();
+        assert_eq!(generation_id, Some(3));
+        let _ = tx.send(chunk(", world", 12));
+        let received: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert!(matches!(received[0], ChatOutput::Meta { chat_id: 7, .. }));
+        assert!(matches!(&received[1], ChatOutput::Chunk { text, offset: 5 } if text == "Hello"));
+        assert!(
+            matches!(&received[2], ChatOutput::Chunk { text, offset: 12 } if text == ", world")
+        );
+        assert_eq!(std::iter::from_fn(|| origin.try_recv().ok()).count(), 3);
+
+        let (mut resumed, _) = subscribe_stream("test-subscribe", 2).unwrap();
+        let received: Vec<_> = std::iter::from_fn(|| resumed.try_recv().ok()).collect();
+        assert!(matches!(&received[1], ChatOutput::Chunk { text, .. } if text == "llo, world"));
+
+        assert_eq!(cancel_stream("test-subscribe"), Some(Some(7)));
+        assert!(guard.cancel.is_cancelled());
+        drop(guard);
+        assert!(subscribe_stream("test-subscribe", 0).is_none());
+        assert_eq!(cancel_stream("test-subscribe"), None);
+        assert!(validate_stream_id("tab-1.a_b").is_ok());
+        assert!(validate_stream_id("a/b").is_err());
+    }
+}
@@ -0,0 +1,327 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use rusqlite::{
+    hooks::{AuthAction, AuthContext, Authorization},
+    types::ValueRef,
+    Connection, ErrorCode,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::db;
+
+/** \brief 未指定时单次查询返回的行数上限。 */
+pub const DEFAULT_MAX_ROWS: usize = 200;
+
+/** \brief 单次查询允许的行数上限。 */
+pub const MAX_ROWS_LIMIT: usize = 5000;
+
+/** \brief 未指定时单次查询的执行时限。 */
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/** \brief 单次查询允许的最长执行时限。 */
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(10);
+
+/** \brief 审计日志中的动作名。 */
+pub const AUDIT_ACTION: &str = "sql.query";
+
+/** \brief 读出时一律返回 NULL 的敏感列：(表, 列)。 */
+const HIDDEN_COLUMNS: &[(&str, &str)] = &[
+    ("providers", "api_key"),
+    ("providers", "signing"),
+    ("providers", "proxy_url"),
+    ("app_config", "value"),
+];
+
+/** \brief 进度回调的触发间隔（虚拟机指令数）。 */
+const PROGRESS_OPS: i32 = 1000;
+
+/**
+ * \brief 单次查询的行数与时间限制。
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct QueryLimits {
+    pub max_rows: usize,
+    pub timeout: Duration,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        Self {
+            max_rows: DEFAULT_MAX_ROWS,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl QueryLimits {
+    /**
+     * \brief 由可选参数构造限制，缺省取默认值，超出上限时收紧到上限。
+     */
+    pub fn new(max_rows: Option<usize>, timeout_ms: Option<u64>) -> Self {
+        Self {
+            max_rows: max_rows
+                .unwrap_or(DEFAULT_MAX_ROWS)
+                .clamp(1, MAX_ROWS_LIMIT),
+            timeout: timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_TIMEOUT)
+                .clamp(Duration::from_millis(1), MAX_TIMEOUT),
+        }
+    }
+}
+
+/**
+ * \brief 查询结果；BLOB 列以 "[blob N bytes]" 占位，不返回原始字节。
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+    /** \brief 结果超过行数上限而被截断。 */
+    pub truncated: bool,
+    pub elapsed_ms: u64,
+}
+
+/**
+ * \brief 控制台未在设置中开启。
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct ConsoleDisabled;
+
+impl fmt::Display for ConsoleDisabled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SQL 控制台未开启，请先在设置中启用")
+    }
+}
+
+impl std::error::Error for ConsoleDisabled {}
+
+/**
+ * \brief 检查开关后执行只读查询，并把每次尝试（含被拒绝的）写入审计日志。
+ * \details 审计写入失败时返回错误，保证不存在未留痕的查询。
+ */
+pub fn execute(
+    conn: &Connection,
+    actor: &str,
+    sql: &str,
+    limits: QueryLimits,
+) -> Result<QueryResult> {
+    if !db::get_sql_console_enabled(conn)? {
+        db::insert_audit_log(
+            conn,
+            actor,
+            AUDIT_ACTION,
+            None,
+            &json!({ "sql": sql, "outcome": "disabled" }),
+        )?;
+        return Err(ConsoleDisabled.into());
+    }
+    let result = run(conn, sql, limits);
+    let changes = match &result {
+        Ok(r) => json!({
+            "sql": sql,
+            "outcome": "ok",
+            "rows": r.rows.len(),
+            "truncated": r.truncated,
+            "elapsed_ms": r.elapsed_ms,
+        }),
+        Err(e) => json!({ "sql": sql, "outcome": "error", "error": e.to_string() }),
+    };
+    db::insert_audit_log(conn, actor, AUDIT_ACTION, None, &changes)?;
+    result
+}
+
+/**
+ * \brief 在沙箱中执行单条只读 SELECT，不检查开关也不写审计。
+ * \details 授权回调只放行读表、函数与递归 CTE，敏感列读出为 NULL；进度回调负责超时中断。
+ */
+pub fn run(conn: &Connection, sql: &str, limits: QueryLimits) -> Result<QueryResult> {
+    let sql = sql.trim();
+    if sql.is_empty() {
+        bail!("查询语句不能为空");
+    }
+    let started = Instant::now();
+    let _sandbox = Sandbox::install(conn, started, limits.timeout);
+    let mut stmt = conn
+        .prepare(sql)
+        .map_err(|e| describe_error(e, limits.timeout))?;
+    if !stmt.readonly() {
+        bail!("只允许只读的 SELECT 查询");
+    }
+    let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let mut rows = Vec::new();
+    let mut truncated = false;
+    let mut cursor = stmt
+        .query([])
+        .map_err(|e| describe_error(e, limits.timeout))?;
+    while let Some(row) = cursor
+        .next()
+        .map_err(|e| describe_error(e, limits.timeout))?
+    {
+        if rows.len() == limits.max_rows {
+            truncated = true;
+            break;
+        }
+        let mut values = Vec::with_capacity(columns.len());
+        for idx in 0..columns.len() {
+            values.push(json_value(row.get_ref(idx)?));
+        }
+        rows.push(values);
+    }
+    Ok(QueryResult {
+        columns,
+        rows,
+        truncated,
+        elapsed_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+/**
+ * \brief 查询期间安装授权与进度回调，离开作用域时卸载，连接随后可正常写入。
+ */
+struct Sandbox<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> Sandbox<'a> {
+    fn install(conn: &'a Connection, started: Instant, timeout: Duration) -> Self {
+        conn.authorizer(Some(authorize));
+        conn.progress_handler(PROGRESS_OPS, Some(move || started.elapsed() > timeout));
+        Self { conn }
+    }
+}
+
+impl Drop for Sandbox<'_> {
+    fn drop(&mut self) {
+        self.conn
+            .authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+        self.conn.progress_handler(0, None::<fn() -> bool>);
+    }
+}
+
+fn authorize(ctx: AuthContext<'_>) -> Authorization {
+    match ctx.action {
+        AuthAction::Read {
+            table_name,
+            column_name,
+        } if is_hidden(table_name, column_name) => Authorization::Ignore,
+        AuthAction::Select
+        | AuthAction::Read { .. }
+        | AuthAction::Function { .. }
+        | AuthAction::Recursive => Authorization::Allow,
+        _ => Authorization::Deny,
+    }
+}
+
+fn is_hidden(table: &str, column: &str) -> bool {
+    HIDDEN_COLUMNS
+        .iter()
+        .any(|(t, c)| t.eq_ignore_ascii_case(table) && c.eq_ignore_ascii_case(column))
+}
+
+fn describe_error(err: rusqlite::Error, timeout: Duration) -> anyhow::Error {
+    match err.sqlite_error_code() {
+        Some(ErrorCode::OperationInterrupted) => {
+            anyhow!("查询超过 {} ms 时限，已中止", timeout.as_millis())
+        }
+        Some(ErrorCode::AuthorizationForStatementDenied) => {
+            anyhow!("只允许只读的 SELECT 查询")
+        }
+        _ => anyhow!(err),
+    }
+}
+
+fn json_value(value: ValueRef<'_>) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => json!(i),
+        ValueRef::Real(f) => json!(f),
+        ValueRef::Text(t) => Value::String(String::from_utf8_lossy(t).into_owned()),
+        ValueRef::Blob(b) => Value::String(format!("[blob {} bytes]", b.len())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mem_conn() -> Connection {
+        let conn = Connection::open_in_memory().expect("open in-memory db");
+        db::migrate(&conn).expect("migrate");
+        conn
+    }
+
+    #[test]
+    fn test_run_selects_and_truncates() {
+        let conn = mem_conn();
+        let result = run(
+            &conn,
+            "WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 10)
+             SELECT x, 'v' || x AS label FROM n;",
+            QueryLimits::new(Some(3), None),
+        )
+        .unwrap();
+        assert_eq!(result.columns, vec!["x", "label"]);
+        assert_eq!(result.rows.len(), 3);
+        assert_eq!(result.rows[2], vec![json!(3), json!("v3")]);
+        assert!(result.truncated);
+    }
+
+    #[test]
+    fn test_run_rejects_writes_and_multiple_statements() {
+        let conn = mem_conn();
+        let limits = QueryLimits::default();
+        assert!(run(&conn, "DELETE FROM chats", limits).is_err());
+        assert!(run(&conn, "UPDATE providers SET name = 'x'", limits).is_err());
+        assert!(run(&conn, "PRAGMA user_version = 3", limits).is_err());
+        assert!(run(&conn, "ATTACH DATABASE ':memory:' AS other", limits).is_err());
+        assert!(run(&conn, "SELECT 1; DELETE FROM chats", limits).is_err());
+        // 沙箱卸载后连接恢复正常写入
+        conn.execute("DELETE FROM chats", []).unwrap();
+    }
+
+    #[test]
+    fn test_run_hides_secret_columns_and_times_out() {
+        let conn = mem_conn();
+        db::insert_provider(&conn, "p", "openai", "http://x", "sk-secret", "m", None).unwrap();
+        let result = run(
+            &conn,
+            "SELECT name, api_key FROM providers",
+            QueryLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(result.rows, vec![vec![json!("p"), Value::Null]]);
+
+        let err = run(
+            &conn,
+            "WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n)
+             SELECT count(*) FROM n",
+            QueryLimits::new(None, Some(50)),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("时限"));
+    }
+
+    #[test]
+    fn test_execute_requires_opt_in_and_audits() {
+        let conn = mem_conn();
+        let err = execute(&conn, "cli", "SELECT 1", QueryLimits::default()).unwrap_err();
+        assert!(err.downcast_ref::<ConsoleDisabled>().is_some());
+
+        db::set_sql_console_enabled(&conn, true).unwrap();
+        execute(&conn, "cli", "SELECT 1", QueryLimits::default()).unwrap();
+        assert!(execute(&conn, "cli", "DROP TABLE chats", QueryLimits::default()).is_err());
+
+        let outcomes: Vec<String> = conn
+            .prepare("SELECT json_extract(changes, '$.outcome') FROM audit_log WHERE action = ?1 ORDER BY id")
+            .unwrap()
+            .query_map([AUDIT_ACTION], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(outcomes, vec!["disabled", "ok", "error"]);
+    }
+}
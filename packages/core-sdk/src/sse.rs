@@ -0,0 +1,121 @@
+use std::borrow::Cow;
+
+/** \brief 已消费的字节超过该值时才整体前移缓冲区，避免每个事件都搬移剩余数据。 */
+const COMPACT_THRESHOLD: usize = 8 * 1024;
+
+/**
+ * \brief 增量 SSE 解码器：按到达的字节块切出 `data:` 行，供各 Provider 的流式解析共用。
+ * \details 缓冲区只追加、以读位置前移代替逐事件拷贝，已消费部分超过阈值时才整体前移；
+ * 未完成的行只扫描新到达的字节。返回的数据行直接借用缓冲区，UTF-8 合法时不分配内存。
+ * 行尾的 `\r` 会被去掉，因此 LF 与 CRLF 分隔的事件流都可直接处理。
+ */
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buf: Vec<u8>,
+    /** \brief 下一行的起始位置。 */
+    pos: usize,
+    /** \brief 从 `pos` 起已确认不含换行的字节数。 */
+    scanned: usize,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** \brief 追加一个网络字节块。 */
+    pub fn push(&mut self, chunk: &[u8]) {
+        if self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+        } else if self.pos >= COMPACT_THRESHOLD {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /** \brief 取出下一条完整的 `data:` 行（已去掉前缀与首尾空白）；其他字段与空行直接跳过。 */
+    pub fn next_data(&mut self) -> Option<Cow<'_, str>> {
+        loop {
+            let start = self.pos;
+            let rest = &self.buf[start + self.scanned..];
+            let Some(offset) = rest.iter().position(|&b| b == b'\n') else {
+                self.scanned = self.buf.len() - start;
+                return None;
+            };
+            let end = start + self.scanned + offset;
+            self.pos = end + 1;
+            self.scanned = 0;
+            if let Some(data) = data_payload(&self.buf[start..end]) {
+                return Some(data);
+            }
+        }
+    }
+
+    /** \brief 流结束时处理末尾没有换行的最后一行。 */
+    pub fn finish(&mut self) -> Option<Cow<'_, str>> {
+        let start = self.pos;
+        self.pos = self.buf.len();
+        self.scanned = 0;
+        data_payload(&self.buf[start..])
+    }
+}
+
+/** \brief 单行中的 `data:` 内容，非 data 行返回 `None`。 */
+fn data_payload(line: &[u8]) -> Option<Cow<'_, str>> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let rest = line.trim_ascii_start().strip_prefix(b"data:")?;
+    Some(String::from_utf8_lossy(rest.trim_ascii()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_in_chunks(input: &[u8], size: usize) -> Vec<String> {
+        let mut decoder = SseDecoder::new();
+        let mut out = Vec::new();
+        for chunk in input.chunks(size) {
+            decoder.push(chunk);
+            while let Some(data) = decoder.next_data() {
+                out.push(data.into_owned());
+            }
+        }
+        if let Some(data) = decoder.finish() {
+            out.push(data.into_owned());
+        }
+        out
+    }
+
+    #[test]
+    fn test_decoder_is_independent_of_chunk_boundaries() {
+        let input =
+            "event: x\ndata: {\"a\":1}\n\n: ping\n\ndata:{\"b\":\"中文\"}\r\n\r\ndata: [DONE]";
+        let expected = vec![
+            "{\"a\":1}".to_string(),
+            "{\"b\":\"中文\"}".to_string(),
+            "[DONE]".to_string(),
+        ];
+        for size in [1, 2, 3, 7, 64, input.len()] {
+            assert_eq!(
+                decode_in_chunks(input.as_bytes(), size),
+                expected,
+                "chunk size {}",
+                size
+            );
+        }
+    }
+
+    #[test]
+    fn test_decoder_compacts_long_streams() {
+        let mut decoder = SseDecoder::new();
+        for i in 0..10_000 {
+            decoder.push(format!("data: {}\n\n", i).as_bytes());
+            assert_eq!(decoder.next_data().as_deref(), Some(i.to_string().as_str()));
+            assert!(decoder.next_data().is_none());
+        }
+        assert!(decoder.buf.len() <= COMPACT_THRESHOLD + 64);
+        assert!(decoder.finish().is_none());
+    }
+}
@@ -0,0 +1,268 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use anyhow::{bail, Result};
+use once_cell::sync::Lazy;
+use rusqlite::Connection;
+
+use crate::{
+    db::{self, ConversationSummary, StoredMessage},
+    llm,
+    models::{Message, Provider},
+    telemetry,
+};
+
+/** \brief 单条消息送入摘要请求的字符上限，超长的代码或日志只保留开头。 */
+const MESSAGE_CHAR_LIMIT: usize = 2000;
+
+/** \brief 正在生成摘要的会话，避免连续几轮回复重复发起。 */
+static IN_FLIGHT: Lazy<Mutex<HashSet<i64>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/**
+ * \brief 一次滚动摘要的输入：已有摘要与其后待并入的消息。
+ */
+#[derive(Debug, Clone)]
+pub struct SummaryPlan {
+    pub previous: Option<ConversationSummary>,
+    pub messages: Vec<StoredMessage>,
+}
+
+/**
+ * \brief 判断会话是否需要（再次）摘要：摘要之后的非系统消息超过阈值时，
+ * 除最近 `keep_recent` 条以外的消息并入摘要；未开启或未达阈值时返回 `None`。
+ */
+pub fn plan(conn: &Connection, chat_id: i64) -> Result<Option<SummaryPlan>> {
+    let config = db::get_summary_config(conn)?;
+    if config.threshold <= 0 {
+        return Ok(None);
+    }
+    let previous = db::get_conversation_summary(conn, chat_id)?;
+    let through = previous.as_ref().map_or(0, |s| s.through_message_id);
+    let mut messages: Vec<StoredMessage> = db::load_messages_with_meta(conn, chat_id)?
+        .into_iter()
+        .filter(|m| m.role != "system" && m.id > through)
+        .collect();
+    if messages.len() as i64 <= config.threshold {
+        return Ok(None);
+    }
+    messages.truncate(messages.len().saturating_sub(config.keep_recent as usize));
+    Ok(Some(SummaryPlan { previous, messages }))
+}
+
+/**
+ * \brief 构造摘要请求：把已有摘要与新消息合并为一份新的摘要。
+ */
+pub fn build_prompt(previous: Option<&str>, messages: &[Message]) -> Vec<Message> {
+    let mut input = String::new();
+    if let Some(previous) = previous {
+        input.push_str("Existing summary:\n");
+        input.push_str(previous.trim());
+        input.push_str("\n\nNew messages:\n");
+    }
+    for msg in messages.iter().filter(|m| m.role != "system") {
+        let content: String = msg
+            .content
+            .trim()
+            .chars()
+            .take(MESSAGE_CHAR_LIMIT)
+            .collect();
+        input.push_str(&format!("[{}] {}\n", msg.role, content));
+    }
+
+    vec![
+        Message {
+            role: "system".to_string(),
+            content: "You maintain a running summary of a conversation. Merge the existing summary (if any) \
+                      and the new messages into one concise summary in the conversation's language. Keep facts, \
+                      decisions, names, numbers, code identifiers and open questions; drop greetings and filler. \
+                      Reply with the summary only."
+                .to_string(),
+        },
+        Message {
+            role: "user".to_string(),
+            content: input,
+        },
+    ]
+}
+
+/**
+ * \brief 注入请求上下文的摘要消息，放在会话开头的 system 消息之后。
+ */
+pub fn system_message(summary: &str) -> Message {
+    Message {
+        role: "system".to_string(),
+        content: format!(
+            "Summary of the earlier part of this conversation (those messages are not repeated below):\n{}",
+            summary.trim()
+        ),
+    }
+}
+
+/**
+ * \brief 按需生成或滚动更新会话摘要，返回新摘要；无需摘要时返回 `None`。
+ * \details 数据库连接不跨越 await 持有；生成期间摘要已被改写（如删除了被覆盖的消息）时放弃本次结果。
+ */
+pub async fn summarize_chat(
+    provider: &Provider,
+    chat_id: i64,
+) -> Result<Option<ConversationSummary>> {
+    let plan = db::run(move |conn| plan(conn, chat_id)).await?;
+    let Some(SummaryPlan { previous, messages }) = plan else {
+        return Ok(None);
+    };
+    let Some(last) = messages.last().map(|m| m.id) else {
+        return Ok(None);
+    };
+    let input: Vec<Message> = messages
+        .iter()
+        .map(|m| Message {
+            role: m.role.clone(),
+            content: m.content.clone(),
+        })
+        .collect();
+    let reply = llm::chat_once(
+        provider,
+        &build_prompt(previous.as_ref().map(|s| s.content.as_str()), &input),
+    )
+    .await?;
+    let content = reply.trim();
+    if content.is_empty() {
+        bail!("empty summary reply");
+    }
+
+    let summary = ConversationSummary {
+        chat_id,
+        content: content.to_string(),
+        through_message_id: last,
+        message_count: previous.as_ref().map_or(0, |s| s.message_count) + messages.len() as i64,
+        provider_id: Some(provider.id),
+        updated_at: db::unix_now(),
+    };
+    let expected = previous.map(|s| s.through_message_id);
+    let summary = db::run(move |conn| {
+        let current = db::get_conversation_summary(conn, chat_id)?;
+        if current.map(|s| s.through_message_id) != expected {
+            return Ok(None);
+        }
+        db::save_conversation_summary(conn, &summary)?;
+        Ok(Some(summary))
+    })
+    .await?;
+    let Some(summary) = summary else {
+        return Ok(None);
+    };
+    telemetry::log_event(
+        "summary",
+        &format!(
+            "chat_id={} messages={} chars={}",
+            chat_id,
+            summary.message_count,
+            summary.content.chars().count()
+        ),
+    );
+    Ok(Some(summary))
+}
+
+/**
+ * \brief 会话达到摘要阈值时在后台生成摘要；同一会话同时只运行一个任务，失败只记遥测。
+ */
+pub fn spawn_if_due(conn: &Connection, provider: Provider, chat_id: i64) {
+    match plan(conn, chat_id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return,
+        Err(e) => {
+            telemetry::log_error("summary", &format!("check due failed: {}", e));
+            return;
+        }
+    }
+    match IN_FLIGHT.lock() {
+        Ok(mut running) => {
+            if !running.insert(chat_id) {
+                return;
+            }
+        }
+        Err(_) => return,
+    }
+    tokio::spawn(async move {
+        if let Err(e) = summarize_chat(&provider, chat_id).await {
+            telemetry::log_error("summary", &format!("chat_id={} err={}", chat_id, e));
+        }
+        if let Ok(mut running) = IN_FLIGHT.lock() {
+            running.remove(&chat_id);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_rolls_past_existing_summary() {
+        let conn = Connection::open_in_memory().expect("open db");
+        db::migrate(&conn).expect("migrate");
+        let chat_id = db::create_chat_at(&conn, "long", None, 1).expect("chat");
+        db::insert_message(&conn, chat_id, "system", "be brief").expect("system");
+        let ids: Vec<i64> = (0..6)
+            .map(|i| {
+                let role = if i % 2 == 0 { "user" } else { "assistant" };
+                db::insert_message(&conn, chat_id, role, &format!("m{}", i)).expect("insert")
+            })
+            .collect();
+        assert!(
+            plan(&conn, chat_id).unwrap().is_none(),
+            "disabled by default"
+        );
+
+        db::set_summary_config(
+            &conn,
+            &db::SummaryConfig {
+                threshold: 4,
+                keep_recent: 2,
+            },
+        )
+        .expect("config");
+        let first = plan(&conn, chat_id).unwrap().expect("due");
+        assert!(first.previous.is_none());
+        assert_eq!(
+            first.messages.iter().map(|m| m.id).collect::<Vec<_>>(),
+            ids[..4].to_vec()
+        );
+
+        db::save_conversation_summary(
+            &conn,
+            &ConversationSummary {
+                chat_id,
+                content: "earlier".to_string(),
+                through_message_id: ids[3],
+                message_count: 4,
+                provider_id: None,
+                updated_at: 1,
+            },
+        )
+        .expect("save");
+        assert!(plan(&conn, chat_id).unwrap().is_none());
+        for i in 0..3 {
+            db::insert_message(&conn, chat_id, "user", &format!("n{}", i)).expect("insert");
+        }
+        let next = plan(&conn, chat_id).unwrap().expect("due again");
+        assert_eq!(
+            next.previous.map(|s| s.content),
+            Some("earlier".to_string())
+        );
+        assert_eq!(next.messages.len(), 3);
+        assert_eq!(next.messages[0].id, ids[4]);
+
+        db::delete_messages_from(&conn, chat_id, ids[2]).expect("delete");
+        assert!(db::get_conversation_summary(&conn, chat_id)
+            .unwrap()
+            .is_none());
+        assert!(db::set_summary_config(
+            &conn,
+            &db::SummaryConfig {
+                threshold: 2,
+                keep_recent: 2,
+            },
+        )
+        .is_err());
+    }
+}
@@ -1,11 +1,240 @@
-use std::{fs::OpenOptions, io::Write, path::PathBuf};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
 
 use anyhow::Result;
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Span, Subscriber,
+};
+use tracing_subscriber::{
+    filter::Targets,
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    Layer, Registry,
+};
 
-static TELEMETRY_ENABLED: Lazy<std::sync::RwLock<bool>> =
-    Lazy::new(|| std::sync::RwLock::new(false));
+pub use tracing::Instrument;
+
+use crate::{models::Provider, paths};
+
+/** \brief 日志文件名；轮转后的旧文件依次为 `dreamquill.1.log`、`dreamquill.2.log`……数字越大越旧。 */
+const LOG_FILE_NAME: &str = "dreamquill.log";
+
+/** \brief 默认单个日志文件的大小上限。 */
+pub const DEFAULT_MAX_SIZE: u64 = 5 * 1024 * 1024;
+
+/** \brief 默认最多保留的日志文件数（含当前文件）。 */
+pub const DEFAULT_MAX_FILES: usize = 5;
+
+/** \brief 设为 `1` 或 `true` 时把事件同时输出到标准错误。 */
+pub const STDERR_ENV: &str = "DREAMQUILL_LOG_STDERR";
+
+/** \brief OTLP/HTTP 导出地址，如 `http://localhost:4318/v1/traces`；需启用 `otlp` 特性。 */
+pub const OTLP_ENDPOINT_ENV: &str = "DREAMQUILL_OTLP_ENDPOINT";
+
+/** \brief 本项目各 crate 的 tracing target 前缀，依赖库自身的事件不进入任何输出。 */
+const TARGET_PREFIX: &str = "dreamquill";
+
+static TELEMETRY_ENABLED: Lazy<RwLock<bool>> = Lazy::new(|| RwLock::new(false));
+
+static CONFIG: Lazy<RwLock<LogConfig>> = Lazy::new(|| RwLock::new(LogConfig::default()));
+
+/** \brief 串行化写入与轮转，避免并发写入时重复轮转。 */
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+#[cfg(feature = "otlp")]
+static TRACER_PROVIDER: once_cell::sync::OnceCell<opentelemetry_sdk::trace::SdkTracerProvider> =
+    once_cell::sync::OnceCell::new();
+
+/**
+ * \brief 日志目录与轮转设置。
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    pub dir: PathBuf,
+    /** \brief 当前文件超过该字节数时轮转。 */
+    pub max_size: u64,
+    /** \brief 最多保留的文件数（含当前文件），至少为 1。 */
+    pub max_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            dir: paths::log_dir(),
+            max_size: DEFAULT_MAX_SIZE,
+            max_files: DEFAULT_MAX_FILES,
+        }
+    }
+}
+
+impl LogConfig {
+    fn file(&self, index: usize) -> PathBuf {
+        match index {
+            0 => self.dir.join(LOG_FILE_NAME),
+            n => self.dir.join(format!("dreamquill.{}.log", n)),
+        }
+    }
+}
+
+/**
+ * \brief 一条结构化遥测事件，日志文件中每行一个 JSON 对象。
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TelemetryEvent {
+    /** \brief RFC 3339 UTC 时间。 */
+    pub ts: String,
+    pub level: String,
+    pub category: String,
+    pub message: String,
+    /** \brief 所在 span 的字段、消息中的 `key=value` 片段与事件自带字段，数字与布尔值按类型保存。 */
+    #[serde(default)]
+    pub fields: Map<String, Value>,
+}
+
+/**
+ * \brief 遥测输出目标，由 `init` 安装为 tracing 订阅者的各层。
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sinks {
+    /** \brief JSON 日志文件（见 `configure`），仍只在遥测开关打开时写入。 */
+    pub file: bool,
+    /** \brief 以可读格式输出到标准错误，便于运维在终端或容器日志中查看。 */
+    pub stderr: bool,
+    /** \brief OTLP/HTTP 导出地址；未启用 `otlp` 特性时只打印警告。 */
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Default for Sinks {
+    fn default() -> Self {
+        Self {
+            file: true,
+            stderr: false,
+            otlp_endpoint: None,
+        }
+    }
+}
+
+impl Sinks {
+    /**
+     * \brief 默认输出到文件，`DREAMQUILL_LOG_STDERR` 与 `DREAMQUILL_OTLP_ENDPOINT` 追加其他输出。
+     */
+    pub fn from_env() -> Self {
+        let stderr = std::env::var(STDERR_ENV)
+            .map(|v| matches!(v.trim(), "1" | "true"))
+            .unwrap_or(false);
+        let otlp_endpoint = std::env::var(OTLP_ENDPOINT_ENV)
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        Self {
+            file: true,
+            stderr,
+            otlp_endpoint,
+        }
+    }
+}
+
+/**
+ * \brief 把遥测接入 tracing：安装全局订阅者，按 `sinks` 组合文件、标准错误与 OTLP 输出。
+ * \details 之后 `log_event` / `log_error` 作为 tracing 事件发出，携带所在 span（如 `chat_span`、
+ * LLM 请求）的字段。未调用时（如嵌入 SDK 的程序）事件直接写入日志文件。进程内只能安装一次。
+ */
+pub fn init(sinks: &Sinks) -> Result<()> {
+    let otlp = otlp_layer(sinks.otlp_endpoint.as_deref())?;
+    let file = sinks
+        .file
+        .then(|| FileLayer::default().with_filter(own_targets()));
+    let stderr = sinks.stderr.then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_filter(own_targets())
+    });
+    tracing_subscriber::registry()
+        .with(otlp)
+        .with(file)
+        .with(stderr)
+        .try_init()?;
+    Ok(())
+}
+
+/**
+ * \brief 退出前调用：把 OTLP 导出器缓冲中的 span 发送出去。
+ */
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        if let Err(err) = provider.shutdown() {
+            eprintln!("otlp shutdown failed: {}", err);
+        }
+    }
+}
+
+/**
+ * \brief 一次对话生成的 span；其中的遥测事件与 LLM 请求 span 都带上会话与 Provider。
+ */
+pub fn chat_span(chat_id: i64, provider: &Provider) -> Span {
+    tracing::info_span!(
+        "chat",
+        chat_id,
+        provider = provider.name.as_str(),
+        provider_id = provider.id
+    )
+}
+
+fn own_targets() -> Targets {
+    Targets::new().with_target(TARGET_PREFIX, Level::INFO)
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_layer(endpoint: Option<&str>) -> Result<Option<Box<dyn Layer<Registry> + Send + Sync>>> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::WithExportConfig;
+
+    let Some(endpoint) = endpoint else {
+        return Ok(None);
+    };
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            opentelemetry_sdk::Resource::builder()
+                .with_service_name("dreamquill")
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer("dreamquill");
+    let _ = TRACER_PROVIDER.set(provider);
+    Ok(Some(
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(own_targets())
+            .boxed(),
+    ))
+}
+
+#[cfg(not(feature = "otlp"))]
+fn otlp_layer(endpoint: Option<&str>) -> Result<Option<Box<dyn Layer<Registry> + Send + Sync>>> {
+    if let Some(endpoint) = endpoint {
+        eprintln!(
+            "Warning: ignoring OTLP endpoint {}, this build lacks the otlp feature",
+            endpoint
+        );
+    }
+    Ok(None)
+}
 
 /**
  * \brief 更新遥测开关状态。
@@ -23,40 +252,385 @@ pub fn is_enabled() -> bool {
     TELEMETRY_ENABLED.read().map(|g| *g).unwrap_or(false)
 }
 
+/**
+ * \brief 设置日志目录与轮转策略，之后的写入与 `read_recent` 均使用新设置。
+ */
+pub fn configure(dir: impl Into<PathBuf>, max_size: u64, max_files: usize) {
+    if let Ok(mut config) = CONFIG.write() {
+        *config = LogConfig {
+            dir: dir.into(),
+            max_size,
+            max_files: max_files.max(1),
+        };
+    }
+}
+
+/** \brief 当前日志设置。 */
+pub fn config() -> LogConfig {
+    CONFIG.read().map(|c| c.clone()).unwrap_or_default()
+}
+
 /**
  * \brief 记录常规事件。
  */
 pub fn log_event(category: &str, message: &str) {
-    if !is_enabled() {
-        return;
-    }
-    if let Err(err) = write_line("INFO", category, message) {
-        eprintln!("telemetry write failed: {}", err);
-    }
+    log(Level::INFO, category, message);
 }
 
 /**
  * \brief 记录错误事件。
  */
 pub fn log_error(category: &str, message: &str) {
+    log(Level::ERROR, category, message);
+}
+
+/**
+ * \brief 当前遥测日志文件路径（默认为数据目录下的 `logs/dreamquill.log`）。
+ */
+pub fn log_path() -> PathBuf {
+    config().file(0)
+}
+
+/**
+ * \brief 读取最近 `n` 条事件（从旧到新），必要时跨越已轮转的文件；供桌面端诊断面板展示。
+ * \details 旧版纯文本格式的行会尽量解析为事件，无法解析的行被跳过。
+ */
+pub fn read_recent(n: usize) -> Result<Vec<TelemetryEvent>> {
+    read_recent_in(&config(), n)
+}
+
+fn log(level: Level, category: &str, message: &str) {
+    if tracing::dispatcher::has_been_set() {
+        if level == Level::ERROR {
+            tracing::error!(target: TARGET_PREFIX, category, "{}", message);
+        } else {
+            tracing::info!(target: TARGET_PREFIX, category, "{}", message);
+        }
+        return;
+    }
     if !is_enabled() {
         return;
     }
-    if let Err(err) = write_line("ERROR", category, message) {
+    let written = build_event(level.as_str(), category, message)
+        .and_then(|event| write_locked(&config(), &event));
+    if let Err(err) = written {
         eprintln!("telemetry write failed: {}", err);
     }
 }
 
-fn write_line(level: &str, category: &str, message: &str) -> Result<()> {
-    let log_dir = PathBuf::from("logs");
-    if !log_dir.exists() {
-        std::fs::create_dir_all(&log_dir)?;
+fn write_locked(config: &LogConfig, event: &TelemetryEvent) -> Result<()> {
+    let _guard = WRITE_LOCK.lock();
+    write_event(config, event)
+}
+
+/**
+ * \brief 文件输出层：把 tracing 事件连同所在 span 的字段写成 JSON 行。
+ */
+#[derive(Default)]
+struct FileLayer {
+    /** \brief 固定的日志设置，且不受遥测开关控制；为空时使用全局设置。 */
+    pinned: Option<LogConfig>,
+}
+
+/** \brief span 上记录的字段，写入事件时由外到内合并。 */
+struct SpanFields(Map<String, Value>);
+
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(
+            field.name().to_string(),
+            Value::from(format!("{:?}", value)),
+        );
+    }
+}
+
+impl<S> Layer<S> for FileLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut fields = Map::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields));
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(SpanFields(fields)) = span.extensions_mut().get_mut::<SpanFields>() {
+                values.record(&mut FieldVisitor(fields));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if self.pinned.is_none() && !is_enabled() {
+            return;
+        }
+        let mut fields = Map::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(SpanFields(values)) = span.extensions().get::<SpanFields>() {
+                    fields.extend(values.clone());
+                }
+            }
+        }
+        let mut own = Map::new();
+        event.record(&mut FieldVisitor(&mut own));
+        let message = match own.remove("message") {
+            Some(Value::String(text)) => text,
+            Some(other) => other.to_string(),
+            None => String::new(),
+        };
+        let category = match own.remove("category") {
+            Some(Value::String(text)) => text,
+            _ => event.metadata().target().to_string(),
+        };
+        fields.extend(parse_fields(&message));
+        fields.extend(own);
+
+        let config = self.pinned.clone().unwrap_or_else(config);
+        let written = build_event(event.metadata().level().as_str(), &category, &message).and_then(
+            |mut built| {
+                built.fields = fields;
+                write_locked(&config, &built)
+            },
+        );
+        if let Err(err) = written {
+            eprintln!("telemetry write failed: {}", err);
+        }
+    }
+}
+
+fn build_event(level: &str, category: &str, message: &str) -> Result<TelemetryEvent> {
+    Ok(TelemetryEvent {
+        ts: OffsetDateTime::now_utc().format(&Rfc3339)?,
+        level: level.to_string(),
+        category: category.to_string(),
+        message: message.to_string(),
+        fields: parse_fields(message),
+    })
+}
+
+/** \brief 提取消息中的 `key=value` 片段（键由小写字母、数字、`_`、`.` 组成）。 */
+fn parse_fields(message: &str) -> Map<String, Value> {
+    let mut fields = Map::new();
+    for token in message.split_whitespace() {
+        let Some((key, value)) = token.split_once('=') else {
+            continue;
+        };
+        let valid_key = key.starts_with(|c: char| c.is_ascii_lowercase())
+            && key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '.');
+        if !valid_key || value.is_empty() {
+            continue;
+        }
+        let value = if let Ok(n) = value.parse::<i64>() {
+            Value::from(n)
+        } else if let Ok(b) = value.parse::<bool>() {
+            Value::from(b)
+        } else {
+            Value::from(value)
+        };
+        fields.insert(key.to_string(), value);
+    }
+    fields
+}
+
+fn write_event(config: &LogConfig, event: &TelemetryEvent) -> Result<()> {
+    if !config.dir.exists() {
+        std::fs::create_dir_all(&config.dir)?;
     }
-    let timestamp = OffsetDateTime::now_utc().format(&Rfc3339)?;
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(log_dir.join("dreamquill.log"))?;
-    writeln!(file, "{} [{}] {} - {}", timestamp, level, category, message)?;
+    let line = serde_json::to_string(event)?;
+    let path = config.file(0);
+    if needs_rotation(&path, line.len() as u64 + 1, config.max_size) {
+        rotate(config)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    writeln!(file, "{}", line)?;
     Ok(())
 }
+
+/** \brief 写入后将超过大小上限，或当前文件最后写入于更早的日期（UTC）时需要轮转；空文件不轮转。 */
+fn needs_rotation(path: &Path, incoming: u64, max_size: u64) -> bool {
+    let Ok(meta) = std::fs::metadata(path) else {
+        return false;
+    };
+    if meta.len() == 0 {
+        return false;
+    }
+    if meta.len() + incoming > max_size {
+        return true;
+    }
+    meta.modified()
+        .map(|modified| OffsetDateTime::from(modified).date() != OffsetDateTime::now_utc().date())
+        .unwrap_or(false)
+}
+
+fn rotate(config: &LogConfig) -> Result<()> {
+    let oldest = config.file(config.max_files.max(1) - 1);
+    if config.max_files <= 1 {
+        std::fs::remove_file(&oldest)?;
+        return Ok(());
+    }
+    if oldest.exists() {
+        std::fs::remove_file(&oldest)?;
+    }
+    for index in (0..config.max_files - 1).rev() {
+        let from = config.file(index);
+        if from.exists() {
+            std::fs::rename(&from, config.file(index + 1))?;
+        }
+    }
+    Ok(())
+}
+
+fn read_recent_in(config: &LogConfig, n: usize) -> Result<Vec<TelemetryEvent>> {
+    let mut events = Vec::new();
+    for index in 0..config.max_files.max(1) {
+        if events.len() >= n {
+            break;
+        }
+        let path = config.file(index);
+        if !path.exists() {
+            continue;
+        }
+        let text = std::fs::read_to_string(&path)?;
+        let mut chunk: Vec<TelemetryEvent> = text.lines().filter_map(parse_line).collect();
+        chunk.append(&mut events);
+        events = chunk;
+    }
+    let skip = events.len().saturating_sub(n);
+    Ok(events.split_off(skip))
+}
+
+/** \brief 解析一行日志：JSON 事件，或旧版 `时间 [级别] 分类 - 消息` 文本。 */
+fn parse_line(line: &str) -> Option<TelemetryEvent> {
+    if let Ok(event) = serde_json::from_str(line) {
+        return Some(event);
+    }
+    let (ts, rest) = line.split_once(" [")?;
+    let (level, rest) = rest.split_once("] ")?;
+    let (category, message) = rest.split_once(" - ")?;
+    Some(TelemetryEvent {
+        ts: ts.to_string(),
+        level: level.to_string(),
+        category: category.to_string(),
+        message: message.to_string(),
+        fields: parse_fields(message),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_rotate_and_read_back_in_order() {
+        let dir = std::env::temp_dir().join(format!(
+            "dreamquill-telemetry-{}",
+            crate::db::process_session_id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = LogConfig {
+            dir: dir.clone(),
+            max_size: 400,
+            max_files: 3,
+        };
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            config.file(0),
+            "2024-01-01T00:00:00Z [INFO] legacy - chat_id=1\n",
+        )
+        .unwrap();
+        for i in 0..20 {
+            let event =
+                build_event("INFO", "test", &format!("seq={} ok=true note=x{}", i, i)).unwrap();
+            write_event(&config, &event).unwrap();
+        }
+        assert!(config.file(2).exists());
+        assert!(!config.file(3).exists(), "oldest files are dropped");
+        for index in 0..3 {
+            assert!(std::fs::metadata(config.file(index)).unwrap().len() <= 400);
+        }
+
+        let recent = read_recent_in(&config, 4).unwrap();
+        let seqs: Vec<_> = recent.iter().map(|e| e.fields["seq"].clone()).collect();
+        assert_eq!(seqs, [16, 17, 18, 19].map(Value::from).to_vec());
+        assert_eq!(recent[3].fields["ok"], Value::Bool(true));
+        assert_eq!(recent[3].fields["note"], Value::from("x19"));
+        assert!(read_recent_in(&config, 1000).unwrap().len() < 21);
+
+        let legacy =
+            parse_line("2024-01-01T00:00:00Z [ERROR] summary - chat_id=3 err=boom").unwrap();
+        assert_eq!(legacy.level, "ERROR");
+        assert_eq!(legacy.category, "summary");
+        assert_eq!(legacy.fields["chat_id"], Value::from(3));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_file_layer_merges_span_fields() {
+        let dir = std::env::temp_dir().join(format!(
+            "dreamquill-telemetry-layer-{}",
+            crate::db::process_session_id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = LogConfig {
+            dir: dir.clone(),
+            max_size: DEFAULT_MAX_SIZE,
+            max_files: 2,
+        };
+        let layer = FileLayer {
+            pinned: Some(config.clone()),
+        };
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            let chat = tracing::info_span!("chat", chat_id = 7_i64, provider = "local");
+            let _entered = chat.enter();
+            let request = tracing::info_span!("llm.request", latency_ms = tracing::field::Empty);
+            request.record("latency_ms", 120_u64);
+            tracing::info!(parent: &request, category = "llm", ok = true, "request finished tokens=12");
+        });
+
+        let events = read_recent_in(&config, 10).unwrap();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.level, "INFO");
+        assert_eq!(event.category, "llm");
+        assert_eq!(event.message, "request finished tokens=12");
+        for (key, value) in [
+            ("chat_id", Value::from(7)),
+            ("provider", Value::from("local")),
+            ("latency_ms", Value::from(120)),
+            ("ok", Value::from(true)),
+            ("tokens", Value::from(12)),
+        ] {
+            assert_eq!(event.fields[key], value, "{}", key);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/**
+ * \brief 流式转录的一行记录（JSON Lines）。
+ */
+#[derive(Debug, Serialize)]
+struct TranscriptLine<'a> {
+    ts: String,
+    stream_id: &'a str,
+    delta: &'a str,
+}
+
+/**
+ * \brief 将流式增量原样追加到转录文件，便于审计长会话或在落库失败时找回输出。
+ * \details 每个增量写一行 JSON 并立即 flush；多个流可共享同一实例，按 `stream_id` 区分。
+ */
+pub struct TranscriptTee {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl TranscriptTee {
+    /**
+     * \brief 以追加模式打开（必要时创建）转录文件。
+     */
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("create transcript dir {}", dir.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("open transcript {}", path.display()))?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /**
+     * \brief 追加一条增量记录。
+     */
+    pub fn append(&self, stream_id: &str, delta: &str) -> Result<()> {
+        let line = TranscriptLine {
+            ts: OffsetDateTime::now_utc().format(&Rfc3339)?,
+            stream_id,
+            delta,
+        };
+        let mut text = serde_json::to_string(&line)?;
+        text.push('\n');
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| anyhow::anyhow!("transcript lock poisoned"))?;
+        file.write_all(text.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/**
+ * \brief 生成转录用的流 ID：`{来源}-{会话 ID}-{毫秒时间戳}`。
+ */
+pub fn stream_id(origin: &str, chat_id: i64) -> String {
+    let millis = OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000;
+    format!("{}-{}-{}", origin, chat_id, millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tee_appends_one_json_line_per_delta() {
+        let path = std::env::temp_dir().join(format!(
+            "dreamquill-transcript-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let tee = TranscriptTee::open(&path).expect("open");
+        tee.append("cli-1-0", "Hello").expect("append");
+        tee.append("cli-1-0", ",\nworld").expect("append");
+        drop(tee);
+        let reopened = TranscriptTee::open(&path).expect("reopen");
+        reopened.append("server-2-0", "!").expect("append");
+
+        let text = std::fs::read_to_string(&path).expect("read");
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).expect("json line"))
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["stream_id"], "cli-1-0");
+        assert_eq!(lines[1]["delta"], ",\nworld");
+        assert_eq!(lines[2]["stream_id"], "server-2-0");
+        assert!(OffsetDateTime::parse(lines[0]["ts"].as_str().unwrap(), &Rfc3339).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_stream_id_carries_origin_and_chat() {
+        let id = stream_id("cli", 42);
+        assert!(id.starts_with("cli-42-"));
+    }
+}
@@ -1,4 +1,5 @@
 import type * as Types from '../types';
+import { chunkWithProgress } from '../transport';
 import type {
   Transport,
   TransportRequestOptions,
@@ -10,9 +11,12 @@ import type {
 export class HttpTransport implements Transport {
   /** @brief 基础路径，默认为 /api。 */
   private readonly base: string;
+  /** @brief 访问令牌；EventSource 无法设置请求头，流式请求改用 `access_token` 查询参数。 */
+  private readonly authToken?: string;
 
-  constructor(basePath: string = '/api') {
+  constructor(basePath: string = '/api', authToken?: string) {
     this.base = basePath.replace(/\/$/, '');
+    this.authToken = authToken || undefined;
   }
 
   /** @brief 拼装查询字符串。 */
@@ -29,9 +33,13 @@ export class HttpTransport implements Transport {
 
   async request<TResponse>(options: TransportRequestOptions<TResponse>): Promise<TResponse> {
     const url = this.buildUrl(options.path, options.query);
+    const headers: Record<string, string> = { 'content-type': 'application/json' };
+    if (this.authToken) {
+      headers.authorization = `Bearer ${this.authToken}`;
+    }
     const resp = await fetch(url, {
       method: options.method,
-      headers: { 'content-type': 'application/json' },
+      headers,
       body: options.body !== undefined ? JSON.stringify(options.body) : undefined,
     });
     if (!resp.ok) {
@@ -43,13 +51,21 @@ export class HttpTransport implements Transport {
   }
 
   stream(options: TransportStreamOptions): TransportStreamHandle {
+    // 固定流 ID，取消时据此通知服务端中止生成
+    const streamId =
+      options.streamId ?? `${Date.now().toString(36)}-${Math.random().toString(36).slice(2)}`;
     const url = this.buildUrl(options.path, {
       prompt: options.prompt,
       chat_id: options.chatId,
       provider_id: options.providerId,
       regen_message_id: options.regenMessageId,
+      quote_ids: options.quoteIds?.length ? options.quoteIds.join(',') : undefined,
       stream: options.stream === false ? 'false' : undefined,
       debug: options.debug ? 'true' : undefined,
+      progress: options.progress ? 'true' : undefined,
+      redact_prompt: options.redactPrompt ? 'true' : undefined,
+      stream_id: streamId,
+      access_token: this.authToken,
     });
 
     let stopped = false;
@@ -79,13 +95,30 @@ export class HttpTransport implements Transport {
           try {
             const payload = JSON.parse(ev.data || '{}');
             if (typeof payload.chat_id === 'number') {
-              enqueue({ type: 'meta', chatId: payload.chat_id });
+              enqueue({
+                type: 'meta',
+                chatId: payload.chat_id,
+                streamId: typeof payload.stream_id === 'string' ? payload.stream_id : undefined,
+              });
             }
           } catch (error) {
             enqueue({ type: 'log', level: 'error', message: `meta parse error: ${String(error)}` });
           }
         });
 
+        listeners.set('title', (ev) => {
+          try {
+            const payload = JSON.parse(ev.data || '{}');
+            enqueue({
+              type: 'title',
+              chatId: Number(payload.chat_id),
+              title: String(payload.title ?? ''),
+            });
+          } catch (error) {
+            enqueue({ type: 'log', level: 'error', message: `title parse error: ${String(error)}` });
+          }
+        });
+
         listeners.set('log', (ev) => {
           enqueue({ type: 'log', level: 'log', message: ev.data || '' });
         });
@@ -94,6 +127,10 @@ export class HttpTransport implements Transport {
           const data = (ev as MessageEvent).data as string | undefined;
           if (data) {
             enqueue({ type: 'error', message: data });
+          } else if (es?.readyState === EventSource.CONNECTING) {
+            // 网络抖动：浏览器按服务端下发的 retry 自动重连，并携带 Last-Event-ID 续传
+            enqueue({ type: 'log', level: 'info', message: 'SSE reconnecting' });
+            return;
           } else {
             enqueue({ type: 'log', level: 'info', message: 'SSE closed' });
           }
@@ -101,9 +138,22 @@ export class HttpTransport implements Transport {
           es?.close();
         });
 
+        listeners.set('done', () => {
+          stopped = true;
+          es?.close();
+        });
+
         listeners.forEach((handler, name) => es?.addEventListener(name, handler as EventListener));
         es.onmessage = (ev) => {
-          enqueue({ type: 'chunk', text: ev.data ?? '' });
+          if (!options.progress) {
+            enqueue({ type: 'chunk', text: ev.data ?? '' });
+            return;
+          }
+          try {
+            enqueue(chunkWithProgress(JSON.parse(ev.data || '{}')));
+          } catch (error) {
+            enqueue({ type: 'log', level: 'error', message: `chunk parse error: ${String(error)}` });
+          }
         };
 
         while (!stopped) {
@@ -135,6 +185,11 @@ export class HttpTransport implements Transport {
       if (es) {
         es.close();
       }
+      // 已生成的部分由服务端保存；取消失败不阻塞 UI
+      this.request({
+        method: 'POST',
+        path: `/chat/cancel/${encodeURIComponent(streamId)}`,
+      }).catch(() => undefined);
     };
 
     return { events, cancel };
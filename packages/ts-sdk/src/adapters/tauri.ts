@@ -1,4 +1,5 @@
 import type * as Types from '../types';
+import { chunkWithProgress } from '../transport';
 import type {
   Transport,
   TransportRequestOptions,
@@ -71,7 +72,9 @@ export class TauriTransport implements Transport {
         });
       }
       case route === 'GET /chats': {
-        return invoke<TResponse>('dq_list_chats');
+        return invoke<TResponse>('dq_list_chats', {
+          include_archived: options.query?.include_archived === true,
+        });
       }
       case /^GET \/chats\/\d+\/messages$/.test(route): {
         const id = Number(options.path.split('/')[2]);
@@ -89,6 +92,34 @@ export class TauriTransport implements Transport {
           title: body.title ?? '',
         });
       }
+      case /^PATCH \/chats\/\d+$/.test(route): {
+        const id = Number(options.path.split('/')[2]);
+        const body = (options.body ?? {}) as { title?: string; pinned?: boolean; archived?: boolean };
+        let result: unknown;
+        if (body.title !== undefined) {
+          result = await invoke('dq_rename_chat', { chat_id: id, title: body.title });
+        }
+        if (body.pinned !== undefined) {
+          result = await invoke('dq_set_chat_pinned', { chat_id: id, pinned: body.pinned });
+        }
+        if (body.archived !== undefined) {
+          result = await invoke('dq_set_chat_archived', { chat_id: id, archived: body.archived });
+        }
+        return result as TResponse;
+      }
+      case /^PUT \/chats\/\d+\/provider$/.test(route): {
+        const id = Number(options.path.split('/')[2]);
+        const body = (options.body ?? {}) as { provider_id?: number | null };
+        return invoke<TResponse>('dq_set_chat_provider', {
+          chat_id: id,
+          provider_id: body.provider_id ?? null,
+        });
+      }
+      case /^PATCH \/chats\/\d+\/model$/.test(route): {
+        const id = Number(options.path.split('/')[2]);
+        const body = (options.body ?? {}) as { model?: string | null };
+        return invoke<TResponse>('dq_set_chat_model', { chat_id: id, model: body.model ?? null });
+      }
       case /^POST \/chats\/\d+\/branch$/.test(route): {
         const id = Number(options.path.split('/')[2]);
         return invoke<TResponse>('dq_branch_chat', { chat_id: id, payload: options.body });
@@ -103,7 +134,8 @@ export class TauriTransport implements Transport {
 
   stream(options: TransportStreamOptions): TransportStreamHandle {
     // 生成本次流的唯一标识
-    const streamId = `${Date.now().toString(36)}-${Math.random().toString(36).slice(2)}`;
+    const streamId =
+      options.streamId ?? `${Date.now().toString(36)}-${Math.random().toString(36).slice(2)}`;
     const queue: Types.StreamEvent[] = [];
     let ended = false;
     let startedResolve: () => void = () => {};
@@ -131,7 +163,7 @@ export class TauriTransport implements Transport {
       try {
         unlisteners.push(
           await listen('dq:meta', (ev: any) =>
-            tryEnqueue(ev.payload, (d) => ({ type: 'meta', chatId: d.chat_id })),
+            tryEnqueue(ev.payload, (d) => ({ type: 'meta', chatId: d.chat_id, streamId })),
           ),
         );
         unlisteners.push(
@@ -141,7 +173,18 @@ export class TauriTransport implements Transport {
         );
         unlisteners.push(
           await listen('dq:chunk', (ev: any) =>
-            tryEnqueue(ev.payload, (d) => ({ type: 'chunk', text: String(d) })),
+            tryEnqueue(ev.payload, (d) =>
+              options.progress ? chunkWithProgress(d) : { type: 'chunk', text: String(d) },
+            ),
+          ),
+        );
+        unlisteners.push(
+          await listen('dq:title', (ev: any) =>
+            tryEnqueue(ev.payload, (d) => ({
+              type: 'title',
+              chatId: Number(d?.chat_id),
+              title: String(d?.title ?? ''),
+            })),
           ),
         );
         unlisteners.push(
@@ -163,8 +206,11 @@ export class TauriTransport implements Transport {
           chat_id: options.chatId,
           provider_id: options.providerId,
           regen_message_id: options.regenMessageId,
+          quote_ids: options.quoteIds,
           stream: options.stream,
           debug: options.debug,
+          progress: options.progress,
+          redact_prompt: options.redactPrompt,
         });
 
         startedResolve();
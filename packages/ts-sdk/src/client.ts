@@ -2,7 +2,7 @@ import { HttpTransport } from './adapters/http';
 import { TauriTransport } from './adapters/tauri';
 import { ChatService } from './services/chat';
 import { ProviderService } from './services/providers';
-import type { RuntimeMode } from './types';
+import type { ChatMiddleware, RuntimeMode } from './types';
 import type { Transport } from './transport';
 
 /** @brief 客户端初始化选项。 */
@@ -11,8 +11,12 @@ export interface ClientOptions {
   mode?: RuntimeMode;
   /** @brief HTTP 模式下的基础路径。 */
   basePath?: string;
+  /** @brief HTTP 模式下的访问令牌，服务以 `--auth-token` 启动时必填。 */
+  authToken?: string;
   /** @brief 自定义传输实现，用于测试或扩展。 */
   transport?: Transport;
+  /** @brief 预先注册的聊天中间件，之后可通过 `chat.use` 追加。 */
+  middlewares?: ChatMiddleware[];
 }
 
 /** @brief SDK 客户端实例。 */
@@ -37,11 +41,11 @@ export function createDreamQuillClient(options: ClientOptions = {}): DreamQuillC
   const mode = options.mode ?? detectMode();
   const transport =
     options.transport ??
-    (mode === 'tauri' ? new TauriTransport() : new HttpTransport(options.basePath ?? '/api'));
+    (mode === 'tauri' ? new TauriTransport() : new HttpTransport(options.basePath ?? '/api', options.authToken));
 
   return {
     transport,
     providers: new ProviderService(transport),
-    chat: new ChatService(transport),
+    chat: new ChatService(transport, options.middlewares),
   };
 }
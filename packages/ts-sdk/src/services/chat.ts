@@ -2,36 +2,183 @@ import type {
   BranchChatOptions,
   BranchResult,
   ChatMessagesPayload,
+  ChatMiddleware,
+  ChatMiddlewareContext,
   ChatSummary,
   SendChatParams,
+  StreamEvent,
+  UpdateChatParams,
 } from '../types';
 import type { Transport, TransportStreamHandle } from '../transport';
 
+/** @brief 服务端返回的会话概要。 */
+interface RawChatSummary {
+  id: number;
+  title: string;
+  provider_id: number | null;
+  pinned?: boolean;
+  archived?: boolean;
+  model?: string | null;
+}
+
+/** @brief 转换会话概要字段命名。 */
+function toChatSummary(item: RawChatSummary): ChatSummary {
+  return {
+    id: item.id,
+    title: item.title,
+    providerId: item.provider_id,
+    pinned: item.pinned ?? false,
+    archived: item.archived ?? false,
+    model: item.model ?? null,
+  };
+}
+
+/** @brief 将中间件抛出的异常转为带名称与阶段的错误信息。 */
+function middlewareError(middleware: ChatMiddleware, stage: string, error: unknown): string {
+  const message = error instanceof Error ? error.message : String(error);
+  return `${middleware.name ?? 'middleware'} ${stage} failed: ${message}`;
+}
+
 /** @brief 聊天服务封装。 */
 export class ChatService {
-  constructor(private readonly transport: Transport) {}
+  /** @brief 已注册的中间件，按注册顺序排列。 */
+  private readonly middlewares: ChatMiddleware[];
+
+  constructor(
+    private readonly transport: Transport,
+    middlewares: ChatMiddleware[] = [],
+  ) {
+    this.middlewares = [...middlewares];
+  }
 
-  /** @brief 发起聊天并返回流式事件句柄。 */
+  /**
+   * @brief 注册聊天中间件，返回注销函数。
+   * @details 只影响之后发起的 `send`，进行中的流沿用发起时的中间件列表。
+   */
+  use(middleware: ChatMiddleware): () => void {
+    this.middlewares.push(middleware);
+    return () => {
+      const index = this.middlewares.indexOf(middleware);
+      if (index >= 0) {
+        this.middlewares.splice(index, 1);
+      }
+    };
+  }
+
+  /**
+   * @brief 发起聊天并返回流式事件句柄。
+   * @details 依次经过 `beforeRequest` 改写参数、`onChunk` 处理每个增量，流结束后逆序调用 `afterResponse`；
+   * `beforeRequest`/`onChunk` 抛错时以 `error` 事件结束本次流，`afterResponse` 抛错时产生 `log` 事件。
+   */
   send(params: SendChatParams): TransportStreamHandle {
-    return this.transport.stream({
-      path: '/chat/sse',
-      ...params,
-    });
+    if (this.middlewares.length === 0) {
+      return this.transport.stream({
+        path: '/chat/sse',
+        ...params,
+      });
+    }
+    const middlewares = [...this.middlewares];
+    const transport = this.transport;
+    const ctx: ChatMiddlewareContext = { params, text: '', cancelled: false, state: {} };
+    let inner: TransportStreamHandle | null = null;
+    let finished = false;
+
+    const finish = async (): Promise<string[]> => {
+      if (finished) {
+        return [];
+      }
+      finished = true;
+      const failures: string[] = [];
+      for (const middleware of [...middlewares].reverse()) {
+        try {
+          await middleware.afterResponse?.(ctx);
+        } catch (error) {
+          failures.push(middlewareError(middleware, 'afterResponse', error));
+        }
+      }
+      return failures;
+    };
+
+    const events = (async function* (): AsyncGenerator<StreamEvent> {
+      try {
+        try {
+          for (const middleware of middlewares) {
+            if (!middleware.beforeRequest) continue;
+            try {
+              const next = await middleware.beforeRequest(ctx.params, ctx);
+              if (next) {
+                ctx.params = next;
+              }
+            } catch (error) {
+              throw new Error(middlewareError(middleware, 'beforeRequest', error));
+            }
+          }
+          if (!ctx.cancelled) {
+            inner = transport.stream({ path: '/chat/sse', ...ctx.params });
+            for await (const event of inner.events) {
+              if (event.type === 'chunk') {
+                let text: string | null = event.text;
+                for (const middleware of middlewares) {
+                  if (text === null) break;
+                  if (!middleware.onChunk) continue;
+                  try {
+                    const out = middleware.onChunk(text, ctx);
+                    if (typeof out === 'string' || out === null) {
+                      text = out;
+                    }
+                  } catch (error) {
+                    throw new Error(middlewareError(middleware, 'onChunk', error));
+                  }
+                }
+                if (text === null) continue;
+                ctx.text += text;
+                yield { ...event, text };
+                continue;
+              }
+              if (event.type === 'meta') {
+                ctx.chatId = event.chatId;
+                ctx.streamId = event.streamId ?? ctx.streamId;
+              } else if (event.type === 'error') {
+                ctx.error = event.message;
+              }
+              yield event;
+            }
+          }
+        } catch (error) {
+          ctx.error = error instanceof Error ? error.message : String(error);
+          yield { type: 'error', message: ctx.error };
+        }
+        for (const message of await finish()) {
+          yield { type: 'log', level: 'error', message };
+        }
+      } finally {
+        // 调用方提前结束迭代时同样视为取消
+        if (!finished) {
+          ctx.cancelled = true;
+          await finish();
+        }
+      }
+    })();
+
+    return {
+      events,
+      cancel() {
+        ctx.cancelled = true;
+        inner?.cancel();
+      },
+    };
   }
 
-  /** @brief 列出历史会话。 */
-  async listChats(): Promise<ChatSummary[]> {
+  /** @brief 列出历史会话（置顶在前），默认不含已归档会话。 */
+  async listChats(options: { includeArchived?: boolean } = {}): Promise<ChatSummary[]> {
     const response = await this.transport.request<{
-      chats: Array<{ id: number; title: string; provider_id: number | null }>;
+      chats: RawChatSummary[];
     }>({
       method: 'GET',
       path: '/chats',
+      query: options.includeArchived ? { include_archived: true } : undefined,
     });
-    return (response.chats ?? []).map((item) => ({
-      id: item.id,
-      title: item.title,
-      providerId: item.provider_id,
-    }));
+    return (response.chats ?? []).map(toChatSummary);
   }
 
   /** @brief 获取指定会话的消息。 */
@@ -58,16 +205,12 @@ export class ChatService {
   /** @brief 删除会话并返回剩余会话列表。 */
   async deleteChat(chatId: number): Promise<ChatSummary[]> {
     const response = await this.transport.request<{
-      chats: Array<{ id: number; title: string; provider_id: number | null }>;
+      chats: RawChatSummary[];
     }>({
       method: 'DELETE',
       path: `/chats/${chatId}`,
     });
-    return (response.chats ?? []).map((item) => ({
-      id: item.id,
-      title: item.title,
-      providerId: item.provider_id,
-    }));
+    return (response.chats ?? []).map(toChatSummary);
   }
 
   /** @brief 针对会话创建分支。 */
@@ -88,19 +231,41 @@ export class ChatService {
 
   /** @brief 重命名会话标题。 */
   async renameChat(chatId: number, title: string): Promise<ChatSummary> {
-    const response = await this.transport.request<{
-      id: number;
-      title: string;
-      provider_id: number | null;
-    }>({
+    const response = await this.transport.request<RawChatSummary>({
       method: 'PUT',
       path: `/chats/${chatId}`,
       body: { title },
     });
-    return {
-      id: response.id,
-      title: response.title,
-      providerId: response.provider_id,
-    };
+    return toChatSummary(response);
+  }
+
+  /** @brief 局部更新会话的标题、置顶或归档状态。 */
+  async updateChat(chatId: number, params: UpdateChatParams): Promise<ChatSummary> {
+    const response = await this.transport.request<RawChatSummary>({
+      method: 'PATCH',
+      path: `/chats/${chatId}`,
+      body: params,
+    });
+    return toChatSummary(response);
+  }
+
+  /** @brief 切换会话绑定的模型服务，传 null 解除绑定。 */
+  async setChatProvider(chatId: number, providerId: number | null): Promise<ChatSummary> {
+    const response = await this.transport.request<RawChatSummary>({
+      method: 'PUT',
+      path: `/chats/${chatId}/provider`,
+      body: { provider_id: providerId },
+    });
+    return toChatSummary(response);
+  }
+
+  /** @brief 设置会话级模型，传 null 恢复 Provider 的默认模型。 */
+  async setChatModel(chatId: number, model: string | null): Promise<ChatSummary> {
+    const response = await this.transport.request<RawChatSummary>({
+      method: 'PATCH',
+      path: `/chats/${chatId}/model`,
+      body: { model },
+    });
+    return toChatSummary(response);
   }
 }
@@ -108,6 +108,11 @@ function toRequestPayload(config: ProviderConfig, options?: SaveOptions) {
     api_base: config.apiBase,
     api_key: config.apiKey,
     model: config.model,
+    temperature: config.temperature,
+    top_p: config.topP,
+    max_tokens: config.maxTokens,
+    stop: config.stop,
+    proxy_url: config.proxyUrl,
     set_default: options?.setDefault ?? false,
     telemetry_enabled: options?.telemetryEnabled,
   };
@@ -120,6 +125,7 @@ function toPreviewPayload(config: ProviderConfig) {
     api_base: config.apiBase,
     api_key: config.apiKey,
     model: config.model,
+    proxy_url: config.proxyUrl,
   };
 }
 
@@ -142,6 +148,12 @@ interface ProviderRecordResponse {
   api_base?: string;
   api_key?: string;
   model?: string;
+  temperature?: number;
+  top_p?: number;
+  max_tokens?: number;
+  stop?: string[];
+  proxy_url?: string | null;
+  api_key_unreadable?: boolean;
   is_default?: boolean;
 }
 
@@ -159,7 +171,13 @@ function normalizeRecord(raw: ProviderRecordResponse): ProviderRecord {
     apiBase: raw.api_base ?? '',
     apiKey: raw.api_key ?? '',
     model: raw.model ?? '',
+    temperature: raw.temperature,
+    topP: raw.top_p,
+    maxTokens: raw.max_tokens,
+    stop: raw.stop,
+    proxyUrl: raw.proxy_url ?? undefined,
     isDefault: Boolean(raw.is_default),
+    apiKeyUnreadable: Boolean(raw.api_key_unreadable),
   };
 }
 
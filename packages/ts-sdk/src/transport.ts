@@ -3,7 +3,7 @@ import type * as Types from './types';
 /** @brief 通用请求选项。 */
 export interface TransportRequestOptions<TResponse = unknown> {
   /** @brief HTTP 动作。 */
-  method: 'GET' | 'POST' | 'PUT' | 'DELETE';
+  method: 'GET' | 'POST' | 'PUT' | 'PATCH' | 'DELETE';
   /** @brief 请求路径。 */
   path: string;
   /** @brief 查询参数集合。 */
@@ -35,3 +35,16 @@ export interface Transport {
   /** @brief 流式聊天请求。 */
   stream(options: TransportStreamOptions): TransportStreamHandle;
 }
+
+/** @brief 解析开启进度字段时的增量载荷。 */
+export function chunkWithProgress(data: any): Types.StreamEvent {
+  return {
+    type: 'chunk',
+    text: String(data?.delta ?? ''),
+    progress: {
+      cumulativeChars: Number(data?.cumulative_chars ?? 0),
+      chunkIndex: Number(data?.chunk_index ?? 0),
+      elapsedMs: Number(data?.elapsed_ms ?? 0),
+    },
+  };
+}
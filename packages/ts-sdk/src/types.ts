@@ -12,6 +12,16 @@ export interface ProviderConfig {
   apiKey: string;
   /** @brief 默认模型名称。 */
   model: string;
+  /** @brief 采样温度，未设置时交给服务端默认值。 */
+  temperature?: number;
+  /** @brief nucleus 采样阈值。 */
+  topP?: number;
+  /** @brief 输出 token 上限。 */
+  maxTokens?: number;
+  /** @brief 停止序列。 */
+  stop?: string[];
+  /** @brief 代理地址（http/https/socks5/socks5h），为空时直连。 */
+  proxyUrl?: string;
 }
 
 /** @brief Provider 记录，附带 ID 与默认标记。 */
@@ -20,6 +30,8 @@ export interface ProviderRecord extends ProviderConfig {
   id: number;
   /** @brief 是否为默认 Provider。 */
   isDefault: boolean;
+  /** @brief 已保存的 API Key 无法用当前主密钥解密，需要重新填写。 */
+  apiKeyUnreadable: boolean;
 }
 
 /** @brief Provider 状态载体。 */
@@ -54,6 +66,22 @@ export interface ChatSummary {
   title: string;
   /** @brief 关联模型服务 ID。 */
   providerId: number | null;
+  /** @brief 是否置顶，置顶会话排在列表最前。 */
+  pinned: boolean;
+  /** @brief 是否已归档，默认列表不包含归档会话。 */
+  archived: boolean;
+  /** @brief 会话级模型，为空时使用 Provider 的默认模型。 */
+  model: string | null;
+}
+
+/** @brief 会话属性的局部更新，缺省字段保持不变。 */
+export interface UpdateChatParams {
+  /** @brief 新标题。 */
+  title?: string;
+  /** @brief 置顶开关。 */
+  pinned?: boolean;
+  /** @brief 归档开关。 */
+  archived?: boolean;
 }
 
 /** @brief 发送聊天的参数。 */
@@ -70,15 +98,70 @@ export interface SendChatParams {
   debug?: boolean;
   /** @brief 针对助手消息的重新生成。 */
   regenMessageId?: number;
+  /** @brief 引用同一会话中的早先消息，作为引用块随提示发送。 */
+  quoteIds?: number[];
+  /** @brief 增量事件附带累计字符数、序号与耗时，便于绘制进度。 */
+  progress?: boolean;
+  /** @brief 提示原文只发送给模型，会话中保存为占位文本 `[redacted prompt]`。 */
+  redactPrompt?: boolean;
+  /** @brief 流 ID，其他窗口可经 `/api/streams/{id}/subscribe` 接入本次生成；缺省自动生成。 */
+  streamId?: string;
 }
 
 /** @brief 流式事件层级。 */
 export type StreamEvent =
-  | { type: 'meta'; chatId: number }
-  | { type: 'chunk'; text: string }
+  | { type: 'meta'; chatId: number; streamId?: string }
+  | { type: 'chunk'; text: string; progress?: ChunkProgress }
   | { type: 'log'; level: 'info' | 'error' | 'log'; message: string }
+  | { type: 'title'; chatId: number; title: string }
   | { type: 'error'; message: string };
 
+/** @brief 一次发送在各中间件间共享的上下文。 */
+export interface ChatMiddlewareContext {
+  /** @brief 经 `beforeRequest` 处理后实际发送的参数。 */
+  params: SendChatParams;
+  /** @brief 会话 ID，收到 `meta` 事件后填入。 */
+  chatId?: number;
+  /** @brief 流 ID，收到 `meta` 事件后填入。 */
+  streamId?: string;
+  /** @brief 已交给调用方的回复文本（经 `onChunk` 处理后）。 */
+  text: string;
+  /** @brief 流中的错误信息。 */
+  error?: string;
+  /** @brief 调用方是否主动取消。 */
+  cancelled: boolean;
+  /** @brief 供中间件跨阶段暂存数据，按中间件名区分键即可。 */
+  state: Record<string, unknown>;
+}
+
+/**
+ * @brief 聊天中间件，按注册顺序组合。
+ * @details `beforeRequest`、`onChunk` 按注册顺序执行，`afterResponse` 按逆序执行；任一阶段均可省略。
+ */
+export interface ChatMiddleware {
+  /** @brief 名称，出错时写入日志事件。 */
+  name?: string;
+  /** @brief 发送前调用，可返回改写后的参数；抛错则不发送并产生 `error` 事件。 */
+  beforeRequest?(
+    params: SendChatParams,
+    ctx: ChatMiddlewareContext,
+  ): SendChatParams | void | Promise<SendChatParams | void>;
+  /** @brief 每个增量调用，返回字符串替换文本，返回 `null` 丢弃该增量，不返回则原样保留。 */
+  onChunk?(text: string, ctx: ChatMiddlewareContext): string | null | void;
+  /** @brief 流结束（完成、出错或取消）后调用一次。 */
+  afterResponse?(ctx: ChatMiddlewareContext): void | Promise<void>;
+}
+
+/** @brief 增量进度（请求时开启 `progress` 才会出现）。 */
+export interface ChunkProgress {
+  /** @brief 截至本增量（含）已输出的字符数。 */
+  cumulativeChars: number;
+  /** @brief 增量序号，从 0 开始；断线续传后重新计数。 */
+  chunkIndex: number;
+  /** @brief 距流开始（或续传开始）的毫秒数。 */
+  elapsedMs: number;
+}
+
 /** @brief SDK 可用模式。 */
 export type RuntimeMode = 'http' | 'tauri';
 
@@ -15,7 +15,20 @@ import type {
   SendChatParams,
 } from './types';
 
-const client = createDreamQuillClient();
+/** @brief 访问令牌在 localStorage 中的键名。 */
+const AUTH_TOKEN_KEY = 'dreamquill.authToken';
+
+/** @brief 读取访问令牌：以 `#token=...` 打开页面时保存下来，并从地址栏移除。 */
+function resolveAuthToken(): string | undefined {
+  const match = window.location.hash.match(/(?:^#|&)token=([^&]+)/);
+  if (match) {
+    localStorage.setItem(AUTH_TOKEN_KEY, decodeURIComponent(match[1]));
+    history.replaceState(null, '', window.location.pathname + window.location.search);
+  }
+  return localStorage.getItem(AUTH_TOKEN_KEY) ?? undefined;
+}
+
+const client = createDreamQuillClient({ authToken: resolveAuthToken() });
 
 /** @brief UI 层流式句柄。 */
 export interface ChatStreamHandle {
@@ -42,6 +55,10 @@ async function consumeStream(
         case 'log':
           callbacks.onLog({ level: event.level, text: event.message });
           break;
+        case 'title':
+          // 会话列表在流结束后刷新，此处只记录
+          callbacks.onLog({ level: 'info', text: `会话标题：${event.title}` });
+          break;
         case 'error':
           callbacks.onError(event.message);
           callbacks.onLog({ level: 'error', text: event.message });